@@ -0,0 +1,462 @@
+use crate::{diagnostic::Span, fun::FunType, scanner::Token};
+
+/// Identifies a node the resolver reports on, like a variable or a declaration.
+/// Ids are unique within the file they were parsed from.
+pub type NodeId = usize;
+
+/// The statements of a file, and the end of file token the script returns at
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+    pub end: Token,
+}
+
+/// A name being declared, like a variable, function, class or parameter
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub id: NodeId,
+    pub name: Token,
+}
+
+/// A use of a variable, which may be a global of a namespace like `math.sqrt`
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub id: NodeId,
+    /// The name, and the names after it when it is a namespace
+    pub path: Vec<Token>,
+    /// Index right after the name, like `items[0]`, which the variable instructions take as an operand
+    pub index: Option<Index>,
+}
+
+/// An index in brackets
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub open: Token,
+    pub expr: Box<Expr>,
+    pub close: Token,
+}
+
+/// What an assignment or an increment changes
+#[derive(Debug, Clone)]
+pub enum Target {
+    Variable(Variable),
+    Property {
+        object: Box<Expr>,
+        name: Token,
+    },
+    /// An element of a value that isn't a variable, like `f()[0]`
+    Index {
+        object: Box<Expr>,
+        index: Index,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    /// `true`, `false` or `nil`
+    Literal(Token),
+    Number {
+        token: Token,
+        value: f64,
+    },
+    String {
+        token: Token,
+        value: String,
+    },
+    /// A string with embedded `${...}` expressions, which come between its literal parts.
+    /// Empty literal parts are kept, so there is always one more of them than there are expressions.
+    Interpolation {
+        strings: Vec<(Token, String)>,
+        expressions: Vec<Expr>,
+    },
+    Variable(Variable),
+    /// `=`, `+=` or `-=`
+    Assign {
+        target: Target,
+        operator: Token,
+        value: Box<Expr>,
+    },
+    /// `++` or `--`, before a variable or after any target
+    Increment {
+        target: Target,
+        operator: Token,
+        prefix: bool,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Index {
+        object: Box<Expr>,
+        index: Index,
+    },
+    /// `object[start:end]`, where either bound may be left out
+    Slice {
+        object: Box<Expr>,
+        open: Token,
+        start: Option<Box<Expr>>,
+        colon: Token,
+        end: Option<Box<Expr>>,
+        close: Token,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+        close: Token,
+    },
+    Unary {
+        operator: Token,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    /// `and` or `or`, which only evaluate their right operand when needed
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Grouping {
+        open: Token,
+        expr: Box<Expr>,
+        close: Token,
+    },
+    Array {
+        open: Token,
+        elements: Vec<Expr>,
+        close: Token,
+    },
+    Map {
+        open: Token,
+        entries: Vec<(Expr, Expr)>,
+        close: Token,
+    },
+    /// An anonymous or arrow function
+    Function(Box<Function>),
+    /// `super.method`, which reads the `this` and `super` variables of the method it is in
+    Super {
+        keyword: Token,
+        method: Token,
+        this_id: NodeId,
+        super_id: NodeId,
+    },
+    /// An expression that didn't parse, at the token where it went wrong
+    Error(Token),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub id: NodeId,
+    pub kind: FunType,
+    /// `function`, the name of a method or the '(' of an arrow function
+    pub start: Token,
+    /// `None` for anonymous and arrow functions
+    pub name: Option<Token>,
+    pub params: Vec<Param>,
+    /// The ')' after the parameters
+    pub close: Token,
+    pub body: FunctionBody,
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: Declaration,
+    /// The value of a parameter left out of a call, after its '='
+    pub default: Option<(Token, Expr)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum FunctionBody {
+    Block {
+        doc: Option<Token>,
+        block: Block,
+    },
+    /// The expression an arrow function returns
+    Expression(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub open: Token,
+    pub statements: Vec<Stmt>,
+    pub close: Token,
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub keyword: Token,
+    pub name: Declaration,
+    pub superclass: Option<Expr>,
+    /// The hidden local holding the superclass, which methods capture for `super`
+    pub super_id: NodeId,
+    pub methods: Vec<Function>,
+    pub close: Token,
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub keyword: Token,
+    /// A string with the path of the file, or the name of a builtin module
+    pub module: Token,
+    pub alias: Option<Token>,
+    pub path: String,
+    /// The alias, or the name of the file or module when there is none
+    pub namespace: String,
+    pub semicolon: Token,
+}
+
+#[derive(Debug, Clone)]
+pub struct Catch {
+    pub keyword: Token,
+    pub name: Option<Declaration>,
+    pub block: Block,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Expression {
+        expr: Expr,
+        semicolon: Token,
+    },
+    Print {
+        keyword: Token,
+        expr: Expr,
+        semicolon: Token,
+    },
+    /// `var name = value;`, or `var name[size];` for an array
+    Var {
+        keyword: Token,
+        name: Declaration,
+        size: Option<Expr>,
+        initializer: Option<Expr>,
+        semicolon: Token,
+    },
+    Function {
+        keyword: Token,
+        name: Declaration,
+        function: Function,
+    },
+    Class(Class),
+    Import(Import),
+    Block(Block),
+    If {
+        keyword: Token,
+        condition: Expr,
+        close: Token,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        label: Option<Token>,
+        keyword: Token,
+        condition: Expr,
+        close: Token,
+        body: Box<Stmt>,
+    },
+    For {
+        label: Option<Token>,
+        keyword: Token,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        /// The ';' after the condition
+        semicolon: Token,
+        increment: Option<Expr>,
+        close: Token,
+        body: Box<Stmt>,
+    },
+    /// `for (var x in items)` or `for (var k, v in items)`
+    ForIn {
+        label: Option<Token>,
+        keyword: Token,
+        variables: Vec<Declaration>,
+        iterable: Expr,
+        close: Token,
+        body: Box<Stmt>,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+        semicolon: Token,
+    },
+    Break {
+        keyword: Token,
+        label: Option<Token>,
+        semicolon: Token,
+    },
+    Continue {
+        keyword: Token,
+        label: Option<Token>,
+        semicolon: Token,
+    },
+    Throw {
+        keyword: Token,
+        value: Expr,
+        semicolon: Token,
+    },
+    Try {
+        keyword: Token,
+        block: Block,
+        catch: Option<Catch>,
+        /// The `finally` keyword and its block
+        finally: Option<(Token, Block)>,
+    },
+    /// A statement that didn't parse, at the token where it went wrong
+    Error(Token),
+}
+
+impl Variable {
+    /// The name with the names after a namespace, like `math.sqrt`
+    pub fn name(&self) -> String {
+        let names: Vec<&str> = self.path.iter().map(|token| token.source.as_ref()).collect();
+        names.join(".")
+    }
+
+    /// A token for the whole name, at the position of its first part
+    pub fn token(&self) -> Token {
+        let mut token = self.path[0].clone();
+        if self.path.len() > 1 {
+            token.source = self.name().into();
+        }
+        token
+    }
+
+    pub fn last_token(&self) -> &Token {
+        match &self.index {
+            Some(index) => &index.close,
+            None => self.path.last().unwrap(),
+        }
+    }
+}
+
+impl Target {
+    pub fn first_token(&self) -> &Token {
+        match self {
+            Target::Variable(variable) => &variable.path[0],
+            Target::Property { object, .. } | Target::Index { object, .. } => object.first_token(),
+        }
+    }
+}
+
+impl Function {
+    /// The last token of the body, where the function returns
+    pub fn last_token(&self) -> &Token {
+        match &self.body {
+            FunctionBody::Block { block, .. } => &block.close,
+            FunctionBody::Expression(expr) => expr.last_token(),
+        }
+    }
+}
+
+impl Expr {
+    pub fn first_token(&self) -> &Token {
+        match self {
+            Expr::Literal(token) | Expr::Error(token) => token,
+            Expr::Number { token, .. } | Expr::String { token, .. } => token,
+            Expr::Interpolation { strings, .. } => &strings[0].0,
+            Expr::Variable(variable) => &variable.path[0],
+            Expr::Assign { target, .. } => target.first_token(),
+            Expr::Increment { target, operator, prefix } => match prefix {
+                true => operator,
+                false => target.first_token(),
+            },
+            Expr::Get { object, .. } | Expr::Index { object, .. } | Expr::Slice { object, .. } => object.first_token(),
+            Expr::Call { callee, .. } => callee.first_token(),
+            Expr::Unary { operator, .. } => operator,
+            Expr::Binary { left, .. } | Expr::Logical { left, .. } => left.first_token(),
+            Expr::Grouping { open, .. } | Expr::Array { open, .. } | Expr::Map { open, .. } => open,
+            Expr::Function(function) => &function.start,
+            Expr::Super { keyword, .. } => keyword,
+        }
+    }
+
+    /// The last token of the expression, where the code compiled for it points once its operands are compiled
+    pub fn last_token(&self) -> &Token {
+        match self {
+            Expr::Literal(token) | Expr::Error(token) => token,
+            Expr::Number { token, .. } | Expr::String { token, .. } => token,
+            Expr::Interpolation { strings, .. } => &strings.last().unwrap().0,
+            Expr::Variable(variable) => variable.last_token(),
+            Expr::Assign { value, .. } => value.last_token(),
+            Expr::Increment { target, operator, prefix } => match (target, prefix) {
+                (Target::Variable(variable), true) => variable.last_token(),
+                _ => operator,
+            },
+            Expr::Get { name, .. } => name,
+            Expr::Index { index, .. } => &index.close,
+            Expr::Slice { close, .. } | Expr::Call { close, .. } => close,
+            Expr::Unary { operand, .. } => operand.last_token(),
+            Expr::Binary { right, .. } | Expr::Logical { right, .. } => right.last_token(),
+            Expr::Grouping { close, .. } | Expr::Array { close, .. } | Expr::Map { close, .. } => close,
+            Expr::Function(function) => function.last_token(),
+            Expr::Super { method, .. } => method,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.first_token().span.start,
+            end: self.last_token().span.end,
+        }
+    }
+}
+
+impl Stmt {
+    pub fn first_token(&self) -> &Token {
+        match self {
+            Stmt::Expression { expr, .. } => expr.first_token(),
+            Stmt::Print { keyword, .. }
+            | Stmt::Var { keyword, .. }
+            | Stmt::Function { keyword, .. }
+            | Stmt::Return { keyword, .. }
+            | Stmt::Break { keyword, .. }
+            | Stmt::Continue { keyword, .. }
+            | Stmt::Throw { keyword, .. }
+            | Stmt::If { keyword, .. }
+            | Stmt::Try { keyword, .. } => keyword,
+            Stmt::Class(class) => &class.keyword,
+            Stmt::Import(import) => &import.keyword,
+            Stmt::Block(block) => &block.open,
+            Stmt::While { label, keyword, .. } | Stmt::For { label, keyword, .. } | Stmt::ForIn { label, keyword, .. } => {
+                label.as_ref().unwrap_or(keyword)
+            }
+            Stmt::Error(token) => token,
+        }
+    }
+
+    /// The last token of the statement, where the code compiled for it points once its parts are compiled
+    pub fn last_token(&self) -> &Token {
+        match self {
+            Stmt::Expression { semicolon, .. }
+            | Stmt::Print { semicolon, .. }
+            | Stmt::Var { semicolon, .. }
+            | Stmt::Return { semicolon, .. }
+            | Stmt::Break { semicolon, .. }
+            | Stmt::Continue { semicolon, .. }
+            | Stmt::Throw { semicolon, .. } => semicolon,
+            Stmt::Function { function, .. } => function.last_token(),
+            Stmt::Class(class) => &class.close,
+            Stmt::Import(import) => &import.semicolon,
+            Stmt::Block(block) => &block.close,
+            Stmt::If {
+                then_branch, else_branch, ..
+            } => else_branch.as_ref().unwrap_or(then_branch).last_token(),
+            Stmt::While { body, .. } | Stmt::For { body, .. } | Stmt::ForIn { body, .. } => body.last_token(),
+            Stmt::Try { block, catch, finally, .. } => match (catch, finally) {
+                (_, Some((_, finally))) => &finally.close,
+                (Some(catch), None) => &catch.block.close,
+                (None, None) => &block.close,
+            },
+            Stmt::Error(token) => token,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.first_token().span.start,
+            end: self.last_token().span.end,
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    diagnostic::Span,
+    fun::{Fun, LocalName},
+    interner::{Interner, StrId},
+    value::Value,
+};
+
+/// Start of every compiled file, so `run_file` can tell bytecode from source
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the format or the instruction set changes, since older files can't be run by a newer VM
+pub const VERSION: u32 = 4;
+pub const EXTENSION: &str = "loxc";
+
+const NO_STRING: u32 = u32::MAX;
+
+// Constant tags
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STR: u8 = 4;
+const IDENTIFIER: u8 = 5;
+const FUNCTION: u8 = 6;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serialize compiled functions, with the script last as `Vm::new` expects.
+///
+/// All numbers are little endian. After the magic and version come the interned strings the functions refer to,
+/// then the functions themselves: their arity, name, help text, file, native imports, code, line table, spans,
+/// local variable names and constants.
+pub fn serialize(functions: &[Fun], interner: &Interner) -> Result<Vec<u8>> {
+    let mut writer = Writer {
+        bytes: Vec::new(),
+        strings: Vec::new(),
+        string_indices: FxHashMap::default(),
+        interner,
+    };
+    writer.u32(functions.len() as u32);
+    for fun in functions {
+        writer.function(fun)?;
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(writer.strings.len() as u32).to_le_bytes());
+    for string in &writer.strings {
+        let string = interner.lookup(string);
+        bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(string.as_bytes());
+    }
+    bytes.extend_from_slice(&writer.bytes);
+    Ok(bytes)
+}
+
+/// Load functions serialized by `serialize`, interning their strings into `interner`
+pub fn deserialize(bytes: &[u8], interner: &mut Interner) -> Result<Vec<Fun>> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("Not a compiled Lox file");
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        bail!("Compiled with bytecode version {version}, but this VM runs version {VERSION}. Recompile the source file");
+    }
+
+    let string_count = reader.u32()? as usize;
+    let mut strings = Vec::with_capacity(string_count.min(bytes.len()));
+    for _ in 0..string_count {
+        let len = reader.u32()? as usize;
+        let string = std::str::from_utf8(reader.take(len)?).map_err(|_| anyhow!("String is not valid UTF-8"))?;
+        strings.push(interner.intern(string));
+    }
+
+    let function_count = reader.u32()? as usize;
+    if function_count == 0 {
+        bail!("No script to run");
+    }
+    let mut functions = Vec::with_capacity(function_count.min(bytes.len()));
+    for _ in 0..function_count {
+        functions.push(reader.function(&strings, function_count)?);
+    }
+    // The VM runs the script without arguments or a closure, so its code can't count on either
+    let script = functions.last().unwrap();
+    if script.arity != 0 || script.upvalue_count != 0 {
+        bail!("The script can't take arguments or capture variables");
+    }
+    for fun in &functions {
+        verify(fun, &functions)?;
+    }
+    if reader.position != bytes.len() {
+        bail!("Unexpected data after the last function");
+    }
+    Ok(functions)
+}
+
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    strings: Vec<StrId>,
+    string_indices: FxHashMap<StrId, u32>,
+    interner: &'a Interner,
+}
+
+impl Writer<'_> {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn text(&mut self, text: &str) {
+        self.u32(text.len() as u32);
+        self.bytes.extend_from_slice(text.as_bytes());
+    }
+
+    /// Write a reference to an entry in the string table
+    fn string(&mut self, id: StrId) {
+        let next = self.strings.len() as u32;
+        let index = *self.string_indices.entry(id).or_insert(next);
+        if index == next {
+            self.strings.push(id);
+        }
+        self.u32(index);
+    }
+
+    fn function(&mut self, fun: &Fun) -> Result<()> {
+        self.u32(fun.arity as u32);
+        self.u32(fun.min_arity as u32);
+        self.u32(fun.upvalue_count as u32);
+        match fun.name {
+            Some(name) => self.string(name),
+            None => self.u32(NO_STRING),
+        }
+        match &fun.help {
+            Some(help) => {
+                self.bytes.push(1);
+                self.text(help);
+            }
+            None => self.bytes.push(0),
+        }
+        match &fun.file {
+            Some(file) => {
+                self.bytes.push(1);
+                self.text(file);
+            }
+            None => self.bytes.push(0),
+        }
+        self.u32(fun.native_imports.len() as u32);
+        for (path, alias) in &fun.native_imports {
+            self.text(path);
+            self.text(alias);
+        }
+
+        let chunk = &fun.chunk;
+        self.u32(chunk.code.len() as u32);
+        self.bytes.extend_from_slice(&chunk.code);
+
+        // Lines are stored as runs of instructions on the same line
+        let mut lines: Vec<(usize, usize)> = chunk.lines.iter().map(|(&offset, &line)| (offset, line)).collect();
+        lines.sort_unstable();
+        lines.dedup_by_key(|(_, line)| *line);
+        self.u32(lines.len() as u32);
+        for (offset, line) in lines {
+            self.u32(offset as u32);
+            self.u32(line as u32);
+        }
+
+        // Spans too, since every byte of an instruction usually has the same one
+        let mut spans: Vec<(usize, Span)> = chunk.spans.iter().map(|(&offset, &span)| (offset, span)).collect();
+        spans.sort_unstable_by_key(|(offset, _)| *offset);
+        spans.dedup_by_key(|(_, span)| *span);
+        self.u32(spans.len() as u32);
+        for (offset, span) in spans {
+            self.u32(offset as u32);
+            self.u32(span.start as u32);
+            self.u32(span.end as u32);
+        }
+
+        self.u32(fun.locals.len() as u32);
+        for local in &fun.locals {
+            self.text(&local.name);
+            self.u32(local.slot as u32);
+            self.u32(local.start as u32);
+            self.u32(local.end as u32);
+        }
+
+        self.u32(chunk.constants.len() as u32);
+        for constant in chunk.constants.iter() {
+            match constant {
+                Value::Nil => self.bytes.push(NIL),
+                Value::Bool(false) => self.bytes.push(FALSE),
+                Value::Bool(true) => self.bytes.push(TRUE),
+                Value::Number(number) => {
+                    self.bytes.push(NUMBER);
+                    self.bytes.extend_from_slice(&number.to_le_bytes());
+                }
+                Value::Str(id) => {
+                    self.bytes.push(STR);
+                    self.string(*id);
+                }
+                Value::Identifier(id) => {
+                    self.bytes.push(IDENTIFIER);
+                    self.string(*id);
+                }
+                Value::Function(idx) => {
+                    self.bytes.push(FUNCTION);
+                    self.u32(*idx as u32);
+                }
+                other => bail!("Can't serialize constant {}", crate::value::value_as_string(other, self.interner)),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.position.checked_add(len).filter(|&end| end <= self.bytes.len());
+        let Some(end) = end else {
+            bail!("Unexpected end of file");
+        };
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn text(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let text = self.take(len)?.to_vec();
+        String::from_utf8(text).map_err(|_| anyhow!("String is not valid UTF-8"))
+    }
+
+    fn string(&mut self, strings: &[StrId]) -> Result<StrId> {
+        let index = self.u32()?;
+        string_at(strings, index)
+    }
+
+    fn function(&mut self, strings: &[StrId], function_count: usize) -> Result<Fun> {
+        let mut fun = Fun::new();
+        fun.arity = self.u32()? as usize;
+        fun.min_arity = self.u32()? as usize;
+        fun.upvalue_count = self.u32()? as usize;
+        fun.name = match self.u32()? {
+            NO_STRING => None,
+            index => Some(string_at(strings, index)?),
+        };
+        if self.u8()? == 1 {
+            fun.help = Some(self.text()?);
+        }
+        if self.u8()? == 1 {
+            fun.file = Some(self.text()?.into());
+        }
+        let import_count = self.u32()?;
+        for _ in 0..import_count {
+            let path = self.text()?;
+            let alias = self.text()?;
+            fun.native_imports.push((path, alias));
+        }
+
+        let code_len = self.u32()? as usize;
+        let mut chunk = Chunk {
+            code: self.take(code_len)?.to_vec(),
+            lines: HashMap::new(),
+            spans: HashMap::new(),
+            constants: Vec::new(),
+        };
+
+        let run_count = self.u32()? as usize;
+        let mut runs = Vec::with_capacity(run_count.min(self.bytes.len()));
+        for _ in 0..run_count {
+            runs.push((self.u32()? as usize, self.u32()? as usize));
+        }
+        for (i, &(start, line)) in runs.iter().enumerate() {
+            let end = runs.get(i + 1).map_or(code_len, |&(next, _)| next).min(code_len);
+            chunk.lines.extend((start..end).map(|offset| (offset, line)));
+        }
+
+        let run_count = self.u32()? as usize;
+        let mut runs = Vec::with_capacity(run_count.min(self.bytes.len()));
+        for _ in 0..run_count {
+            let offset = self.u32()? as usize;
+            let span = Span {
+                start: self.u32()? as usize,
+                end: self.u32()? as usize,
+            };
+            runs.push((offset, span));
+        }
+        for (i, &(start, span)) in runs.iter().enumerate() {
+            let end = runs.get(i + 1).map_or(code_len, |&(next, _)| next).min(code_len);
+            chunk.spans.extend((start..end).map(|offset| (offset, span)));
+        }
+
+        let local_count = self.u32()?;
+        for _ in 0..local_count {
+            fun.locals.push(LocalName {
+                name: self.text()?,
+                slot: self.u32()? as usize,
+                start: self.u32()? as usize,
+                end: self.u32()? as usize,
+            });
+        }
+
+        let constant_count = self.u32()? as usize;
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                NIL => Value::Nil,
+                FALSE => Value::Bool(false),
+                TRUE => Value::Bool(true),
+                NUMBER => Value::Number(f64::from_le_bytes(self.take(8)?.try_into()?)),
+                STR => Value::Str(self.string(strings)?),
+                IDENTIFIER => Value::Identifier(self.string(strings)?),
+                FUNCTION => match self.u32()? as usize {
+                    idx if idx < function_count => Value::Function(idx),
+                    idx => bail!("Function {idx} is out of range"),
+                },
+                tag => bail!("Unknown constant tag {tag}"),
+            };
+            chunk.constants.push(constant);
+        }
+
+        fun.chunk = chunk;
+        Ok(fun)
+    }
+}
+
+fn string_at(strings: &[StrId], index: u32) -> Result<StrId> {
+    let string = strings.get(index as usize).copied();
+    string.ok_or_else(|| anyhow!("String {index} is out of range"))
+}
+
+/// Check that the code of `fun` only holds whole, valid instructions whose constant operands and upvalues exist,
+/// whose jumps land on an instruction, that never pop more values than the function has pushed on top of its
+/// arguments, and that only use local slots below the values they pop, since the VM decodes instructions, reads
+/// constants, upvalues and locals, jumps and pops without checking them.
+fn verify(fun: &Fun, functions: &[Fun]) -> Result<()> {
+    let chunk = &fun.chunk;
+    let code = &chunk.code;
+    let mut lens = vec![None; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let Ok(op) = Opcode::try_from(code[offset]) else {
+            bail!("Invalid opcode {} at offset {offset}", code[offset]);
+        };
+        let operands = &code[(offset + 1).min(code.len())..];
+        let mut len = op.operand_len();
+        if operands.len() < len {
+            bail!("Instruction {op} at offset {offset} is cut off");
+        }
+
+        if op.takes_constant() {
+            let index = match op.is_long() {
+                true => u24(operands),
+                false => operands[0] as usize,
+            };
+            if index >= chunk.constants.len() {
+                bail!("Instruction {op} at offset {offset} refers to missing constant {index}");
+            }
+        }
+        if let Opcode::Closure | Opcode::ClosureLong = op {
+            len += 2 * operands[len - 1] as usize;
+            if operands.len() < len {
+                bail!("Instruction {op} at offset {offset} is cut off");
+            }
+        }
+        check_upvalues(op, offset, &operands[..len], fun, functions)?;
+        lens[offset] = Some(1 + len);
+        offset += 1 + len;
+    }
+
+    // Follow every path through the code, with the fewest values each instruction can find on the stack
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(0, fun.arity)];
+    while let Some((offset, depth)) = pending.pop() {
+        if depths[offset].is_some_and(|known| known <= depth) {
+            continue;
+        }
+        depths[offset] = Some(depth);
+
+        let op = Opcode::try_from(code[offset]).unwrap();
+        let operands = &code[offset + 1..offset + lens[offset].unwrap()];
+        let Some(successors) = successors(op, offset, operands, lens[offset].unwrap(), depth) else {
+            bail!("Instruction {op} at offset {offset} pops more values than there are on the stack");
+        };
+        let (slots, reach) = local_slots(op, operands, depth);
+        if let Some(slot) = slots.into_iter().find(|&slot| slot >= reach) {
+            bail!("Instruction {op} at offset {offset} uses local slot {slot}, which isn't on the stack");
+        }
+        for (target, depth) in successors {
+            match usize::try_from(target) {
+                Ok(target) if lens.get(target).is_some_and(Option::is_some) => pending.push((target, depth)),
+                _ if target == code.len() as isize => bail!("Code runs past the end after offset {offset}"),
+                _ => bail!("Jump at offset {offset} doesn't land on an instruction"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the upvalues `op` reads, writes or captures from `fun` exist, and that functions that capture variables
+/// are only created as closures of the captures they expect, since the VM indexes the upvalues of a closure unchecked
+fn check_upvalues(op: Opcode, offset: usize, operands: &[u8], fun: &Fun, functions: &[Fun]) -> Result<()> {
+    match op {
+        Opcode::GetUpvalue | Opcode::SetUpvalue if operands[0] as usize >= fun.upvalue_count => {
+            bail!("Instruction {op} at offset {offset} uses missing upvalue {}", operands[0]);
+        }
+        Opcode::Constant | Opcode::ConstantLong => {
+            let index = if op.is_long() { u24(operands) } else { operands[0] as usize };
+            if let Value::Function(idx) = fun.chunk.constants[index] {
+                if functions[idx].upvalue_count != 0 {
+                    bail!("Instruction {op} at offset {offset} creates a function that captures variables without a closure");
+                }
+            }
+        }
+        Opcode::Closure | Opcode::ClosureLong => {
+            let (index, captures) = match op.is_long() {
+                true => (u24(operands), &operands[4..]),
+                false => (operands[0] as usize, &operands[2..]),
+            };
+            let Value::Function(idx) = fun.chunk.constants[index] else {
+                bail!("Instruction {op} at offset {offset} refers to constant {index}, which isn't a function");
+            };
+            if captures.len() / 2 != functions[idx].upvalue_count {
+                bail!("Instruction {op} at offset {offset} captures a different number of variables than its function uses");
+            }
+            let missing = |capture: &&[u8]| capture[0] != 1 && capture[1] as usize >= fun.upvalue_count;
+            if let Some(capture) = captures.chunks(2).find(missing) {
+                bail!("Instruction {op} at offset {offset} captures missing upvalue {}", capture[1]);
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// The local slots the instruction `op` reads, writes or captures when it starts with `depth` values on the stack,
+/// with how many slots it can reach. Those are the ones below the values it pops, and a closure can also capture
+/// the slot it is pushed into, which is how a local function refers to itself.
+fn local_slots(op: Opcode, operands: &[u8], depth: usize) -> (Vec<usize>, usize) {
+    match op {
+        Opcode::GetLocal => (vec![operands[0] as usize], depth - 1),
+        Opcode::GetLocalLong => (vec![u24(operands)], depth - 1),
+        Opcode::SetLocal => (vec![operands[0] as usize], depth - 2),
+        Opcode::SetLocalLong => (vec![u24(operands)], depth - 2),
+        Opcode::IterNext => (vec![u24(operands)], depth),
+        Opcode::Closure | Opcode::ClosureLong => {
+            let captures = if op.is_long() { &operands[4..] } else { &operands[2..] };
+            let locals = captures.chunks(2).filter(|capture| capture[0] == 1);
+            (locals.map(|capture| capture[1] as usize).collect(), depth + 1)
+        }
+        _ => (Vec::new(), depth),
+    }
+}
+
+/// The offsets the instruction `op` of `len` bytes at `offset` can continue at, with how many values it leaves on
+/// the stack there when it starts with `depth` values, or `None` if it pops more than that
+fn successors(op: Opcode, offset: usize, operands: &[u8], len: usize, depth: usize) -> Option<Vec<(isize, usize)>> {
+    let (pops, pushes) = match op {
+        Opcode::Jump | Opcode::Loop | Opcode::DefaultArg | Opcode::Try | Opcode::EndTry | Opcode::EndFinally => (0, 0),
+        Opcode::Constant
+        | Opcode::ConstantLong
+        | Opcode::Nil
+        | Opcode::True
+        | Opcode::False
+        | Opcode::Closure
+        | Opcode::ClosureLong
+        | Opcode::Class
+        | Opcode::ClassLong
+        | Opcode::GetReceiver => (0, 1),
+        Opcode::Pop
+        | Opcode::Print
+        | Opcode::DefineGlobal
+        | Opcode::DefineGlobalLong
+        | Opcode::CloseUpvalue
+        | Opcode::Throw
+        | Opcode::Return => (1, 0),
+        // Reading a variable pops the index it is read at, which is nil when it isn't indexed
+        Opcode::Not
+        | Opcode::Negate
+        | Opcode::DeclareArray
+        | Opcode::IterInit
+        | Opcode::JumpIfFalse
+        | Opcode::GetGlobal
+        | Opcode::GetGlobalLong
+        | Opcode::GetLocal
+        | Opcode::GetLocalLong
+        | Opcode::GetUpvalue
+        | Opcode::GetProperty
+        | Opcode::GetPropertyLong => (1, 1),
+        Opcode::Dup => (1, 2),
+        // Inherit and Method leave the class they work on where it was
+        Opcode::Add
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Modulo
+        | Opcode::Divide
+        | Opcode::Greater
+        | Opcode::Equal
+        | Opcode::Less
+        | Opcode::SetGlobal
+        | Opcode::SetGlobalLong
+        | Opcode::SetLocal
+        | Opcode::SetLocalLong
+        | Opcode::SetUpvalue
+        | Opcode::Inherit
+        | Opcode::Method
+        | Opcode::MethodLong
+        | Opcode::SetProperty
+        | Opcode::SetPropertyLong
+        | Opcode::GetSuper
+        | Opcode::GetSuperLong
+        | Opcode::GetIndex => (2, 1),
+        Opcode::SetIndex | Opcode::Slice => (3, 1),
+        Opcode::ArrayLiteral | Opcode::Interpolate => (operands[0] as usize, 1),
+        Opcode::MapLiteral => (2 * operands[0] as usize, 1),
+        Opcode::Call => (operands[0] as usize + 1, 1),
+        Opcode::IterNext => (0, operands[3] as usize),
+        // Leaving try blocks drops everything above the locals of the loop
+        Opcode::Leave => (depth.checked_sub(u24(&operands[1..]))?, 0),
+    };
+    let after = depth.checked_sub(pops)? + pushes;
+
+    let mut successors = match op {
+        Opcode::Jump | Opcode::Loop | Opcode::Throw | Opcode::Return => vec![],
+        _ => vec![((offset + len) as isize, after)],
+    };
+    // Jump offsets are relative to the end of the operand they're read from
+    match op {
+        Opcode::Jump | Opcode::JumpIfFalse => successors.push(((offset + 4 + u24(operands)) as isize, after)),
+        Opcode::Loop => successors.push(((offset + 4) as isize - u24(operands) as isize, after)),
+        Opcode::DefaultArg => successors.push(((offset + 5 + u24(&operands[1..])) as isize, after)),
+        // Leaving the loop pushes no variables
+        Opcode::IterNext => successors.push(((offset + 8 + u24(&operands[4..])) as isize, depth)),
+        Opcode::Try => {
+            // A zero offset means there is no catch or finally block. The catch block starts with the exception pushed.
+            let (catch, finally) = (u24(operands), u24(&operands[3..]));
+            if catch != 0 {
+                successors.push(((offset + 4 + catch) as isize, after + 1));
+            }
+            if finally != 0 {
+                successors.push(((offset + 7 + finally) as isize, after));
+            }
+        }
+        _ => (),
+    }
+    Some(successors)
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
@@ -4,6 +4,7 @@ use crate::{
     common::*,
     dbgln,
     debug::disassemble_instruction,
+    diagnostic::Span,
     interner::Interner,
     value::{Value, ValueArray},
 };
@@ -12,16 +13,19 @@ use crate::{
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: HashMap<usize, usize>,
+    /// Source span of the token each byte was compiled from, for pointing at the code in errors
+    pub spans: HashMap<usize, Span>,
     pub constants: ValueArray,
 }
 
 impl Chunk {
-    pub fn write_opcode(&mut self, opcode: Opcode, line: usize) {
-        self.write_byte(opcode as u8, line);
+    pub fn write_opcode(&mut self, opcode: Opcode, line: usize, span: Span) {
+        self.write_byte(opcode as u8, line, span);
     }
 
-    pub fn write_byte(&mut self, data: u8, line: usize) {
+    pub fn write_byte(&mut self, data: u8, line: usize, span: Span) {
         self.lines.insert(self.code.len(), line);
+        self.spans.insert(self.code.len(), span);
         self.code.push(data);
     }
 
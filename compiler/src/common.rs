@@ -2,9 +2,10 @@ use crate::scanner::Token;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 #[repr(u8)]
-#[derive(Eq, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
+#[derive(Debug, Clone, Copy, Eq, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
 pub enum Opcode {
     Constant,
+    ConstantLong,
     Not,
     Print,
     Negate,
@@ -21,26 +22,181 @@ pub enum Opcode {
     Equal,
     Less,
     DefineGlobal,
+    DefineGlobalLong,
     DeclareArray,
     ArrayLiteral,
+    MapLiteral,
+    Interpolate,
     SetGlobal,
+    SetGlobalLong,
     GetGlobal,
+    GetGlobalLong,
     GetLocal,
+    GetLocalLong,
     SetLocal,
+    SetLocalLong,
+    GetUpvalue,
+    SetUpvalue,
     JumpIfFalse,
     Jump,
     Loop,
+    Try,
+    EndTry,
+    EndFinally,
+    Throw,
+    IterInit,
+    IterNext,
     Call,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
     Return,
     Dup,
     DefaultArg,
     Class,
+    ClassLong,
+    Inherit,
     Method,
+    MethodLong,
     GetProperty,
+    GetPropertyLong,
     SetProperty,
+    SetPropertyLong,
     GetReceiver,
+    GetSuper,
+    GetSuperLong,
     GetIndex,
     SetIndex,
+    Slice,
+    Leave,
+}
+
+/// Largest operand of a jump, or of the `Long` variant of an instruction, which take three bytes
+pub const U24_MAX: usize = (1 << 24) - 1;
+
+impl Opcode {
+    /// The variant of an instruction that takes a three byte index, for indices that don't fit in a byte
+    pub fn long(self) -> Opcode {
+        match self {
+            Opcode::Constant => Opcode::ConstantLong,
+            Opcode::DefineGlobal => Opcode::DefineGlobalLong,
+            Opcode::SetGlobal => Opcode::SetGlobalLong,
+            Opcode::GetGlobal => Opcode::GetGlobalLong,
+            Opcode::GetLocal => Opcode::GetLocalLong,
+            Opcode::SetLocal => Opcode::SetLocalLong,
+            Opcode::Closure => Opcode::ClosureLong,
+            Opcode::Class => Opcode::ClassLong,
+            Opcode::Method => Opcode::MethodLong,
+            Opcode::GetProperty => Opcode::GetPropertyLong,
+            Opcode::SetProperty => Opcode::SetPropertyLong,
+            Opcode::GetSuper => Opcode::GetSuperLong,
+            other => unreachable!("{other} has no long variant"),
+        }
+    }
+
+    /// Number of operand bytes after the opcode. `Closure` is followed by two more bytes for each captured variable.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Opcode::Not
+            | Opcode::Print
+            | Opcode::Negate
+            | Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Modulo
+            | Opcode::Divide
+            | Opcode::Nil
+            | Opcode::True
+            | Opcode::False
+            | Opcode::Greater
+            | Opcode::Pop
+            | Opcode::Equal
+            | Opcode::Less
+            | Opcode::DeclareArray
+            | Opcode::EndTry
+            | Opcode::EndFinally
+            | Opcode::Throw
+            | Opcode::IterInit
+            | Opcode::CloseUpvalue
+            | Opcode::Return
+            | Opcode::Dup
+            | Opcode::Inherit
+            | Opcode::GetReceiver
+            | Opcode::GetIndex
+            | Opcode::SetIndex
+            | Opcode::Slice => 0,
+            Opcode::Constant
+            | Opcode::DefineGlobal
+            | Opcode::SetGlobal
+            | Opcode::GetGlobal
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::GetUpvalue
+            | Opcode::SetUpvalue
+            | Opcode::Class
+            | Opcode::Method
+            | Opcode::GetProperty
+            | Opcode::SetProperty
+            | Opcode::GetSuper
+            | Opcode::Call
+            | Opcode::ArrayLiteral
+            | Opcode::MapLiteral
+            | Opcode::Interpolate => 1,
+            Opcode::Closure => 2,
+            Opcode::ConstantLong
+            | Opcode::DefineGlobalLong
+            | Opcode::SetGlobalLong
+            | Opcode::GetGlobalLong
+            | Opcode::GetLocalLong
+            | Opcode::SetLocalLong
+            | Opcode::ClassLong
+            | Opcode::MethodLong
+            | Opcode::GetPropertyLong
+            | Opcode::SetPropertyLong
+            | Opcode::GetSuperLong
+            | Opcode::JumpIfFalse
+            | Opcode::Jump
+            | Opcode::Loop => 3,
+            Opcode::ClosureLong | Opcode::DefaultArg | Opcode::Leave => 4,
+            Opcode::Try => 6,
+            Opcode::IterNext => 7,
+        }
+    }
+
+    /// Whether the first operand is an index into the chunk's constants
+    pub fn takes_constant(self) -> bool {
+        matches!(
+            self,
+            Opcode::Constant
+                | Opcode::DefineGlobal
+                | Opcode::SetGlobal
+                | Opcode::GetGlobal
+                | Opcode::Closure
+                | Opcode::Class
+                | Opcode::Method
+                | Opcode::GetProperty
+                | Opcode::SetProperty
+                | Opcode::GetSuper
+        ) || (self.is_long() && !matches!(self, Opcode::GetLocalLong | Opcode::SetLocalLong))
+    }
+
+    pub fn is_long(&self) -> bool {
+        matches!(
+            self,
+            Opcode::ConstantLong
+                | Opcode::DefineGlobalLong
+                | Opcode::SetGlobalLong
+                | Opcode::GetGlobalLong
+                | Opcode::GetLocalLong
+                | Opcode::SetLocalLong
+                | Opcode::ClosureLong
+                | Opcode::ClassLong
+                | Opcode::MethodLong
+                | Opcode::GetPropertyLong
+                | Opcode::SetPropertyLong
+                | Opcode::GetSuperLong
+        )
+    }
 }
 
 pub fn variant_eq<T>(a: &T, b: &T) -> bool {
@@ -1,372 +1,248 @@
 use crate::{
-    common::{identifiers_equal, Opcode},
-    dbgln,
-    fun::{Fun, FunType},
+    ast::{Class, Declaration, Expr, Function, FunctionBody, Import, NodeId, Program, Stmt, Target, Variable},
+    common::{Opcode, U24_MAX},
+    diagnostic::{Diagnostic, Severity},
+    fun::{Fun, FunType, LocalName, ANONYMOUS_FUNCTION_NAME},
     interner::Interner,
-    scanner::{Scanner, Token, TokenType},
+    lint::{self, Lint, LintConfig},
+    native, parser,
+    resolver::{self, Access, Resolution, Resolver},
+    scanner::{Token, TokenType},
     value::Value,
-    xprint, xprintln,
 };
 use anyhow::*;
-use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::{collections::HashMap, rc::Rc};
-
-#[repr(u8)]
-#[derive(Eq, Clone, Copy, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
-// Low to High precedence
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
-}
-
-type Parsefn<'src> = fn(&mut Compiler<'src>, bool);
-
-struct ParseRule<'src> {
-    prefix: Option<Parsefn<'src>>,
-    infix: Option<Parsefn<'src>>,
-    precedence: Precedence,
-}
+use std::{collections::HashSet, rc::Rc};
 
 struct Local {
-    name: Token,
+    /// `None` for the locals the compiler adds, like the superclass and the iterator of a for-in loop
+    name: Option<Rc<str>>,
     depth: isize,
+    is_captured: bool,
+    start: usize, // Offset of the code where the variable holds its value
 }
 
-/// This is a table that, given a token type, lets us find
-/// 1. the function to compile a prefix expression starting with a token of that type,
-/// 2. the function to compile an infix expression whose left operand is followed by a token of that type, and
-/// 3. the precedence of an infix expression that uses that token as an operator.
-fn get_rules<'src>() -> HashMap<TokenType, ParseRule<'src>> {
-    let mut map = HashMap::new();
-    use TokenType::*;
-
-    macro_rules! add_rule {
-        ($map: expr, $tokentype: expr, $prefix: expr, $infix: expr, $precedence: expr) => {
-            $map.insert(
-                $tokentype,
-                ParseRule {
-                    prefix: $prefix,
-                    infix: $infix,
-                    precedence: $precedence,
-                },
-            );
-        };
-    }
-
-    add_rule!(map, LeftParen, Some(Compiler::grouping), Some(Compiler::call), Precedence::Call);
-    add_rule!(map, RightParen, None, None, Precedence::None);
-    add_rule!(map, LeftBrace, None, None, Precedence::None);
-    add_rule!(map, RightBrace, None, None, Precedence::None);
-    add_rule!(map, LeftBracket, Some(Compiler::array_literal), Some(Compiler::index_access), Precedence::Call);
-    add_rule!(map, RightBracket, None, None, Precedence::None);
-    add_rule!(map, Comma, None, None, Precedence::None);
-    add_rule!(map, Dot, None, Some(Compiler::dot), Precedence::Call);
-    add_rule!(map, Minus, Some(Compiler::unary), Some(Compiler::binary), Precedence::Term);
-    add_rule!(map, MinusEqual, None, None, Precedence::None);
-    add_rule!(map, MinusMinus, Some(Compiler::prefix_increment_decrement), None, Precedence::None);
-    add_rule!(map, Plus, None, Some(Compiler::binary), Precedence::Term);
-    add_rule!(map, PlusEqual, None, None, Precedence::None);
-    add_rule!(map, PlusPlus, Some(Compiler::prefix_increment_decrement), None, Precedence::None);
-    add_rule!(map, Semicolon, None, None, Precedence::None);
-    add_rule!(map, Slash, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Star, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Modulo, None, Some(Compiler::binary), Precedence::Factor);
-    add_rule!(map, Bang, Some(Compiler::unary), None, Precedence::None);
-    add_rule!(map, BangEqual, None, Some(Compiler::binary), Precedence::Equality);
-    add_rule!(map, Equal, None, None, Precedence::None);
-    add_rule!(map, EqualEqual, None, Some(Compiler::binary), Precedence::Equality);
-    add_rule!(map, Greater, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, GreaterEqual, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, Less, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, LessEqual, None, Some(Compiler::binary), Precedence::Comparison);
-    add_rule!(map, Identifier, Some(Compiler::variable), None, Precedence::None);
-    add_rule!(map, String, Some(Compiler::string), None, Precedence::None);
-    add_rule!(map, Number, Some(Compiler::number), None, Precedence::None);
-    add_rule!(map, And, None, Some(Compiler::and), Precedence::And);
-    add_rule!(map, Class, None, None, Precedence::None);
-    add_rule!(map, Else, None, None, Precedence::None);
-    add_rule!(map, False, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, For, None, None, Precedence::None);
-    add_rule!(map, Fun, None, None, Precedence::None);
-    add_rule!(map, If, None, None, Precedence::None);
-    add_rule!(map, Nil, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, Or, None, Some(Compiler::or), Precedence::Or);
-    add_rule!(map, Print, None, None, Precedence::None);
-    add_rule!(map, Return, None, None, Precedence::None);
-    add_rule!(map, Super, None, None, Precedence::None);
-    add_rule!(map, This, Some(Compiler::this), None, Precedence::None);
-    add_rule!(map, True, Some(Compiler::literal), None, Precedence::None);
-    add_rule!(map, Var, None, None, Precedence::None);
-    add_rule!(map, While, None, None, Precedence::None);
-    add_rule!(map, Error, None, None, Precedence::None);
-    add_rule!(map, EOF, None, None, Precedence::None);
-
-    map
+/// A loop enclosing the code being compiled, that `break` and `continue` can target
+struct Loop {
+    label: Option<Rc<str>>,
+    continue_target: usize,
+    scope_depth: isize,      // Locals deeper than this belong to the loop body
+    break_jumps: Vec<usize>, // Jumps to patch to the end of the loop
+    try_depth: usize,        // Try statements entered before the loop
 }
 
-fn increment_prec(prec: Precedence) -> Precedence {
-    (prec as u8 + 1).try_into().unwrap()
+/// Lint settings of the file being compiled, and what the code generator knows about its globals.
+/// Some lints can only be checked once every global of the file is known.
+struct LintState {
+    config: LintConfig,
+    /// Aliases of the native modules that weren't loaded, so the globals they define aren't known
+    native_modules: HashSet<String>,
 }
 
-struct Parser {
-    pub scanner: Scanner,
-    pub current: Token,
-    pub previous: Token,
-    pub had_error: bool,
-    pub panic_mode: bool,
+/// What a file is compiled from: its statements, or the single expression the REPL evaluates
+enum Parsed {
+    Program(Program),
+    Expression(Expr, Token),
 }
 
-impl Parser {
-    fn new(scanner: Scanner) -> Parser {
-        Parser {
-            scanner,
-            current: Token::new(),
-            previous: Token::new(),
-            had_error: false,
-            panic_mode: false,
-        }
-    }
-
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(true, message);
-    }
-
-    fn error_at_previous(&mut self, message: &str) {
-        self.error_at(false, message);
-    }
-
-    fn error_at(&mut self, current: bool, message: &str) {
-        let current = if current { &self.current } else { &self.previous };
-
-        if self.panic_mode {
-            return;
-        }
-
-        self.panic_mode = true;
-        xprint!(" [line {}] Error", current.line);
-
-        if current.typ == TokenType::EOF {
-            xprint!(" at end");
-        } else if current.typ == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at '{}'", current.source);
-        }
-
-        xprintln!(": {}", message);
-        self.had_error = true;
-    }
-
-    fn consume(&mut self, typ: TokenType, message: &str) {
-        if self.current.typ == typ {
-            self.advance();
-            return;
-        }
-
-        self.error_at_current(message);
-    }
-
-    fn check_tt(&mut self, typ: TokenType) -> bool {
-        self.current.typ == typ
-    }
-
-    fn match_tt(&mut self, typ: TokenType) -> bool {
-        if !self.check_tt(typ) {
-            return false;
-        }
-
-        self.advance();
-
-        true
-    }
-
-    fn advance(&mut self) {
-        self.previous = self.current.clone();
-
-        loop {
-            self.current = self.scanner.scan_token();
-            dbgln!("Current token: {}", self.current.typ);
-            if self.current.typ != TokenType::Error {
-                break;
-            }
-
-            let current_source: Rc<str> = self.current.source.clone();
-            self.error_at_current(current_source.as_ref());
-        }
-    }
-
-    fn synchronize(&mut self) {
-        self.panic_mode = false;
-
-        while self.current.typ != TokenType::EOF {
-            if self.previous.typ == TokenType::Semicolon {
-                return;
-            }
-
-            match self.current.typ {
-                TokenType::Class
-                | TokenType::Fun
-                | TokenType::Var
-                | TokenType::For
-                | TokenType::If
-                | TokenType::While
-                | TokenType::Print
-                | TokenType::Return => {
-                    return;
-                }
-                _ => {}
-            }
+/// What every file of a program is compiled with, passed from a file to the files it imports
+struct Session<'a> {
+    interner: &'a mut Interner,
+    functions: &'a mut Vec<Fun>,
+    imported_files: &'a mut HashSet<std::path::PathBuf>,
+    import_stack: &'a mut Vec<std::path::PathBuf>,
+    declared_globals: &'a mut HashSet<Rc<str>>,
+    lints: &'a LintConfig,
+}
 
-            self.advance()
-        }
-    }
+/// The state shared by the compilers of every function of a file
+struct Context<'src> {
+    interner: &'src mut Interner,
+    functions: &'src mut Vec<Fun>,
+    current_dir: Option<std::path::PathBuf>,
+    namespace_prefix: Option<String>,
+    imported_files: &'src mut HashSet<std::path::PathBuf>,
+    import_stack: &'src mut Vec<std::path::PathBuf>,
+    /// Globals declared by every file of the program so far, with the namespace prefix of imported files
+    declared_globals: &'src mut HashSet<Rc<str>>,
+    lints: LintState,
+    resolution: Resolution,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
 }
 
-fn is_native_function(name: &str) -> bool {
-    matches!(
-        name,
-        "clock"
-            | "sleep"
-            | "typeof"
-            | "str"
-            | "int"
-            | "float"
-            | "bool"
-            | "stringat"
-            | "len"
-            | "ceil"
-            | "floor"
-            | "abs"
-            | "sort"
-            | "indexof"
-            | "rand"
-            | "printf"
-            | "input"
-            | "errString"
-            | "clear"
-    )
+/// The instructions a binary operator compiles to. `!=`, `>=` and `<=` negate the result of another comparison.
+pub(crate) fn binary_opcodes(operator: TokenType) -> &'static [Opcode] {
+    match operator {
+        TokenType::Plus => &[Opcode::Add],
+        TokenType::Minus => &[Opcode::Subtract],
+        TokenType::Star => &[Opcode::Multiply],
+        TokenType::Modulo => &[Opcode::Modulo],
+        TokenType::Slash => &[Opcode::Divide],
+        TokenType::BangEqual => &[Opcode::Equal, Opcode::Not],
+        TokenType::EqualEqual => &[Opcode::Equal],
+        TokenType::Greater => &[Opcode::Greater],
+        TokenType::GreaterEqual => &[Opcode::Less, Opcode::Not],
+        TokenType::Less => &[Opcode::Less],
+        TokenType::LessEqual => &[Opcode::Greater, Opcode::Not],
+        _ => &[],
+    }
 }
 
-pub struct Compiler<'src> {
+/// Generates the bytecode of a function from its syntax tree, once the resolver has found what its names refer to
+pub struct Compiler<'ctx, 'src> {
+    context: &'ctx mut Context<'src>,
     fun: Fun,
     fun_typ: FunType,
-    parser: Parser,
-    interner: &'src mut Interner,
-    rules: HashMap<TokenType, ParseRule<'src>>,
     locals: Vec<Local>,
+    loops: Vec<Loop>,
+    /// Try statements enclosing the code being compiled
+    try_depth: usize,
     scope_depth: isize,
-    functions: *mut Vec<Fun>,
-    current_dir: Option<std::path::PathBuf>,
-    namespace_prefix: Option<String>,
-    imported_files: *mut std::collections::HashSet<std::path::PathBuf>,
-    import_stack: *mut Vec<std::path::PathBuf>,
-    namespaces: *mut std::collections::HashSet<String>,
+    /// Token the code emitted next comes from, for its line and span
+    location: Token,
 }
 
-impl<'src> Compiler<'src> {
+impl<'ctx, 'src> Compiler<'ctx, 'src> {
+    /// Compile a program, returning its script along with every error and warning found.
+    /// The program compiled if none of the diagnostics is an error. Warnings are only reported for lints enabled in `lints`.
     pub fn compile(
         source: Rc<str>,
         current_dir: Option<std::path::PathBuf>,
         interner: &mut Interner,
         functions: &'src mut Vec<Fun>,
         fun_typ: FunType,
-    ) -> Result<(Fun, bool)> {
-        let mut imported_files = std::collections::HashSet::new();
-        let mut import_stack = Vec::new();
-        Self::compile_internal(
-            source,
-            current_dir,
-            None,
-            &mut imported_files,
-            &mut import_stack,
+        lints: &LintConfig,
+    ) -> Result<(Fun, Vec<Diagnostic>)> {
+        let session = Session {
             interner,
             functions,
-            fun_typ,
-        )
+            imported_files: &mut HashSet::new(),
+            import_stack: &mut Vec::new(),
+            declared_globals: &mut HashSet::new(),
+            lints,
+        };
+        Compiler::compile_internal(source, current_dir, None, fun_typ, session)
     }
 
     fn compile_internal(
         source: Rc<str>,
         current_dir: Option<std::path::PathBuf>,
         namespace_prefix: Option<String>,
-        imported_files: &mut std::collections::HashSet<std::path::PathBuf>,
-        import_stack: &mut Vec<std::path::PathBuf>,
-        interner: &mut Interner,
-        functions: &mut Vec<Fun>,
         fun_typ: FunType,
-    ) -> Result<(Fun, bool)> {
-        let scanner: Scanner = Scanner::new(source);
-        let parser = Parser::new(scanner);
-        let rules = get_rules();
-
-        let locals = Vec::new();
-        let mut namespaces = std::collections::HashSet::new();
+        session: Session,
+    ) -> Result<(Fun, Vec<Diagnostic>)> {
+        let Session {
+            interner,
+            functions,
+            imported_files,
+            import_stack,
+            declared_globals,
+            lints,
+        } = session;
+        let mut resolver = Resolver::new(fun_typ, namespace_prefix.as_deref(), lints, declared_globals);
+        let (parsed, mut diagnostics) = if fun_typ == FunType::ReplExpression {
+            let (expr, end, diagnostics) = parser::parse_expression(source.clone());
+            resolver.expression(&expr);
+            (Parsed::Expression(expr, end), diagnostics)
+        } else {
+            let (program, diagnostics) = parser::parse(source.clone());
+            resolver.statements(&program.statements);
+            (Parsed::Program(program), diagnostics)
+        };
+        let (resolution, resolver_diagnostics) = resolver.finish();
+        diagnostics.extend(resolver_diagnostics);
 
-        let mut compiler = Compiler {
-            fun: Fun::new(),
-            fun_typ,
-            parser,
+        let mut context = Context {
             interner,
-            rules,
-            locals,
-            scope_depth: 0,
-            functions: functions as *mut _,
+            functions,
             current_dir,
             namespace_prefix,
-            imported_files: imported_files as *mut _,
-            import_stack: import_stack as *mut _,
-            namespaces: &mut namespaces as *mut _,
+            imported_files,
+            import_stack,
+            declared_globals,
+            lints: LintState {
+                config: lints.clone(),
+                native_modules: HashSet::new(),
+            },
+            resolution,
+            diagnostics,
+            panic_mode: false,
         };
 
-        dbgln!("== Parser (Scan on demand) ==");
-
-        compiler.parser.advance();
-        if compiler.fun_typ == FunType::ReplExpression {
-            compiler.expression();
-            compiler.parser.consume(TokenType::EOF, "Expect end of expression.");
-        } else {
-            while !compiler.parser.match_tt(TokenType::EOF) {
-                compiler.declaration();
+        let mut compiler = Compiler::new(&mut context, fun_typ);
+        match &parsed {
+            Parsed::Program(program) => {
+                compiler.statements(&program.statements);
+                compiler.at(&program.end);
+            }
+            Parsed::Expression(expr, end) => {
+                compiler.expression(expr);
+                compiler.at(end);
             }
         }
+        let fun = compiler.end();
+        context.check_globals();
 
-        let had_error = compiler.parser.had_error;
-        Ok((compiler.end(), had_error))
+        // Diagnostics of imported files were already filtered by the comments in those files.
+        // Some lints are only checked at the end, so sort to list them in the order of the source.
+        let suppressions = lint::Suppressions::new(&source);
+        let mut diagnostics = context.diagnostics;
+        diagnostics.retain(|diagnostic| diagnostic.file.is_some() || !suppressions.suppresses(diagnostic));
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.file.clone(), diagnostic.span.start));
+        Ok((fun, diagnostics))
     }
 
-    fn line(&self) -> usize {
-        self.parser.previous.line
+    fn new(context: &'ctx mut Context<'src>, fun_typ: FunType) -> Compiler<'ctx, 'src> {
+        Compiler {
+            context,
+            fun: Fun::new(),
+            fun_typ,
+            locals: Vec::new(),
+            loops: Vec::new(),
+            try_depth: 0,
+            scope_depth: 0,
+            location: Token::new(),
+        }
     }
 
-    fn end(&mut self) -> Fun {
+    /// Point the code emitted next at `token`
+    fn at(&mut self, token: &Token) {
+        self.location = token.clone();
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = self.location.clone();
+        self.context.error(&token, message);
+    }
+
+    /// Note a problem with the current token that doesn't stop the program from compiling
+    #[cfg(target_arch = "wasm32")]
+    fn warning(&mut self, message: &str) {
+        let token = self.location.clone();
+        self.context.diagnostic_at(&token, Severity::Warning, None, message, None);
+    }
+
+    fn end(mut self) -> Fun {
         if self.fun_typ == FunType::ReplExpression {
             self.emit_byte(Opcode::Print as u8);
         }
         self.emit_return();
+        self.name_locals(-1);
 
         #[cfg(feature = "print_code")]
-        if !self.parser.had_error {
+        if !self.context.diagnostics.iter().any(Diagnostic::is_error) {
             let name = if self.fun_typ == FunType::Script {
                 "script"
             } else if let Some(fn_name) = self.fun.name {
-                self.interner.lookup(&fn_name)
+                self.context.interner.lookup(&fn_name)
             } else {
                 "unnamed"
             };
 
-            self.fun.chunk.disassemble(name, self.interner);
+            self.fun.chunk.disassemble(name, self.context.interner);
         }
 
-        std::mem::replace(&mut self.fun, Fun::new())
+        self.fun
     }
 
     fn begin_scope(&mut self) {
@@ -375,508 +251,538 @@ impl<'src> Compiler<'src> {
 
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
+        self.name_locals(self.scope_depth);
 
         while !self.locals.is_empty() && self.locals.last().unwrap().depth > self.scope_depth {
-            self.emit_byte(Opcode::Pop as u8);
+            if self.locals.last().unwrap().is_captured {
+                self.emit_byte(Opcode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(Opcode::Pop as u8);
+            }
             self.locals.pop();
         }
     }
 
-    fn get_rule(&self, token_type: TokenType) -> &ParseRule<'src> {
-        self.rules.get(&token_type).unwrap()
-    }
-
-    fn binary(&mut self, _can_assign: bool) {
-        let operator_type = self.parser.previous.typ;
-        let rule = self.get_rule(operator_type);
-        self.parse_precedence(increment_prec(rule.precedence));
-
-        match operator_type {
-            TokenType::Plus => self.emit_byte(Opcode::Add as u8),
-            TokenType::Minus => self.emit_byte(Opcode::Subtract as u8),
-            TokenType::Star => self.emit_byte(Opcode::Multiply as u8),
-            TokenType::Modulo => self.emit_byte(Opcode::Modulo as u8),
-            TokenType::Slash => self.emit_byte(Opcode::Divide as u8),
-            TokenType::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
-            TokenType::EqualEqual => self.emit_byte(Opcode::Equal as u8),
-            TokenType::Greater => self.emit_byte(Opcode::Greater as u8),
-            TokenType::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
-            TokenType::Less => self.emit_byte(Opcode::Less as u8),
-            TokenType::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
-            _ => (),
-        }
-    }
-
-    fn call(&mut self, _can_assign: bool) {
-        let arg_count = self.argument_list();
-        self.emit_bytes(Opcode::Call as u8, arg_count);
-    }
-
-    fn argument_list(&mut self) -> u8 {
-        let mut arg_count = 0;
-
-        if !self.parser.check_tt(TokenType::RightParen) {
-            loop {
-                self.expression();
-
-                if arg_count == 255 {
-                    self.parser.error_at_previous("Can't have more than 255 arguments.");
-                }
-
-                arg_count += 1;
-
-                if !self.parser.match_tt(TokenType::Comma) {
-                    break;
-                }
+    /// Emit pops for the locals deeper than `depth`, without ending their scope.
+    /// Used when jumping out of scopes, as the code after the jump still sees them.
+    fn discard_locals(&mut self, depth: isize) {
+        for i in (0..self.locals.len()).rev() {
+            if self.locals[i].depth <= depth {
+                break;
             }
-        }
 
-        self.parser.consume(TokenType::RightParen, "Expect ')' after arguments.");
-        arg_count
-    }
-
-    fn literal(&mut self, _can_assign: bool) {
-        match self.parser.previous.typ {
-            TokenType::False => self.emit_byte(Opcode::False as u8),
-            TokenType::Nil => self.emit_byte(Opcode::Nil as u8),
-            TokenType::True => self.emit_byte(Opcode::True as u8),
-            _ => (),
+            if self.locals[i].is_captured {
+                self.emit_byte(Opcode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(Opcode::Pop as u8);
+            }
         }
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
-    }
-
-    fn block(&mut self) {
-        while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
-            self.declaration();
+    /// Compile the statements of a file or a block. Like the parser, errors are reported once per statement.
+    fn statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+            self.context.panic_mode = false;
         }
-
-        self.parser.consume(TokenType::RightBrace, "Expect '}' after block");
     }
 
-    fn function(&mut self, typ: FunType) {
-        let line_num = self.line();
-        let raw_name = self.parser.previous.source.as_ref();
-        let name_str = if let Some(ref prefix) = self.namespace_prefix {
-            format!("{}.{}", prefix, raw_name)
-        } else {
-            raw_name.to_string()
-        };
-        let name = Some(self.interner.intern(&name_str));
-        let dummy_parser = Parser::new(Scanner::new(Rc::from("")));
-
-        let mut fn_compiler = Compiler {
-            fun: Fun::new(),
-            fun_typ: typ,
-            parser: std::mem::replace(&mut self.parser, dummy_parser),
-            interner: self.interner,
-            rules: get_rules(),
-            locals: Vec::new(),
-            scope_depth: 0,
-            functions: self.functions,
-            current_dir: self.current_dir.clone(),
-            namespace_prefix: self.namespace_prefix.clone(),
-            imported_files: self.imported_files,
-            import_stack: self.import_stack,
-            namespaces: self.namespaces,
-        };
-
-        let mut min_arity = 0;
-        let mut has_defaults = false;
-
-        fn_compiler.fun.name = name;
-        fn_compiler.begin_scope();
-        fn_compiler.parser.consume(TokenType::LeftParen, "Expect '(' after function name");
-        if !fn_compiler.parser.check_tt(TokenType::RightParen) {
-            loop {
-                fn_compiler.fun.arity += 1;
-                let arity = fn_compiler.fun.arity;
-                if arity > 255 {
-                    fn_compiler.parser.error_at_current("Can't have more than 255 parameters");
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Expression { expr, semicolon } => {
+                self.expression(expr);
+                self.at(semicolon);
+                self.emit_byte(Opcode::Pop as u8);
+            }
+            Stmt::Print { expr, semicolon, .. } => {
+                self.expression(expr);
+                self.at(semicolon);
+                self.emit_byte(Opcode::Print as u8);
+            }
+            Stmt::Var {
+                name,
+                size,
+                initializer,
+                semicolon,
+                ..
+            } => {
+                let global = self.declare(name);
+                if let Some(size) = size {
+                    self.expression(size);
+                    self.at(size.last_token());
+                    self.emit_byte(Opcode::DeclareArray as u8);
+                } else if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                } else {
+                    self.at(&name.name);
+                    self.emit_byte(Opcode::Nil as u8);
                 }
 
-                let (constant, is_array) = fn_compiler.parse_variable("Expect parameter name");
-
-                if is_array {
-                    fn_compiler.parser.error_at_current("Array parameters are not supported");
+                self.at(semicolon);
+                self.define(global);
+            }
+            Stmt::Function { name, function, .. } => {
+                let global = self.declare(name);
+                self.mark_initialized();
+                self.function(function);
+                self.define(global);
+            }
+            Stmt::Class(class) => self.class(class),
+            Stmt::Import(import) => self.import(import),
+            Stmt::Block(block) => {
+                self.begin_scope();
+                self.statements(&block.statements);
+                self.at(&block.close);
+                self.end_scope();
+            }
+            Stmt::If {
+                condition,
+                close,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.at(close);
+                let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+                self.emit_byte(Opcode::Pop as u8);
+                self.statement(then_branch);
+
+                self.at(then_branch.last_token());
+                let else_jump = self.emit_jump(Opcode::Jump as u8);
+                self.patch_jump(then_jump);
+                self.emit_byte(Opcode::Pop as u8);
+
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
                 }
 
-                fn_compiler.define_global_if_needed(constant, is_array);
+                self.patch_jump(else_jump);
+            }
+            Stmt::While {
+                label,
+                condition,
+                close,
+                body,
+                ..
+            } => {
+                let loop_start = self.fun.chunk.code.len();
+                self.expression(condition);
+                self.at(close);
+
+                let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+                self.emit_byte(Opcode::Pop as u8);
+                self.begin_loop(label.as_ref(), loop_start);
+                self.statement(body);
+                self.at(body.last_token());
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_byte(Opcode::Pop as u8);
+                self.end_loop();
+            }
+            Stmt::For {
+                label,
+                initializer,
+                condition,
+                semicolon,
+                increment,
+                close,
+                body,
+                ..
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
 
-                if fn_compiler.parser.match_tt(TokenType::Equal) {
-                    has_defaults = true;
+                let mut loop_start = self.fun.chunk.code.len();
 
-                    fn_compiler.emit_byte(Opcode::DefaultArg as u8);
-                    fn_compiler.emit_byte(arity as u8);
-                    fn_compiler.emit_bytes(0xff, 0xff);
-                    let jump_offset = fn_compiler.fun.chunk.code.len() - 2;
+                let mut exit_jump = None;
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                    self.at(semicolon);
+                    exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse as u8));
+                    self.emit_byte(Opcode::Pop as u8);
+                }
 
-                    fn_compiler.emit_constant(Value::Nil);
-                    fn_compiler.expression();
+                if let Some(increment) = increment {
+                    self.at(semicolon);
+                    let body_jump = self.emit_jump(Opcode::Jump as u8);
+                    let increment_start = self.fun.chunk.code.len();
+                    self.expression(increment);
+                    self.at(increment.last_token());
+                    self.emit_byte(Opcode::Pop as u8);
+
+                    self.at(close);
+                    self.emit_loop(loop_start);
+                    loop_start = increment_start;
+                    self.patch_jump(body_jump);
+                }
 
-                    fn_compiler.emit_bytes(Opcode::SetLocal as u8, (arity - 1) as u8);
-                    fn_compiler.emit_byte(Opcode::Pop as u8);
+                self.begin_loop(label.as_ref(), loop_start);
+                self.statement(body);
+                self.at(body.last_token());
+                self.emit_loop(loop_start);
 
-                    fn_compiler.patch_jump(jump_offset);
-                } else {
-                    if has_defaults {
-                        fn_compiler.parser.error_at_current("Non-default argument follows default argument");
-                    }
-                    min_arity += 1;
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump);
+                    self.emit_byte(Opcode::Pop as u8);
                 }
 
-                if !fn_compiler.parser.match_tt(TokenType::Comma) {
-                    break;
+                self.end_loop();
+                self.end_scope();
+            }
+            Stmt::ForIn {
+                label,
+                variables,
+                iterable,
+                close,
+                body,
+                ..
+            } => {
+                self.begin_scope();
+
+                // The iterator lives in a hidden local for the whole loop
+                self.expression(iterable);
+                self.at(close);
+                self.emit_byte(Opcode::IterInit as u8);
+                let iterator_slot = self.locals.len();
+                self.add_local(None, None);
+                self.mark_initialized();
+
+                let loop_start = self.fun.chunk.code.len();
+                self.begin_loop(label.as_ref(), loop_start);
+                self.emit_byte(Opcode::IterNext as u8);
+                self.emit_u24(iterator_slot);
+                self.emit_byte(variables.len() as u8);
+                let exit_jump = self.fun.chunk.code.len();
+                self.emit_u24(U24_MAX);
+
+                // Loop variables are scoped to a single iteration, so closures capture each value separately
+                self.begin_scope();
+                for variable in variables {
+                    self.add_local(Some(variable.name.source.clone()), Some(variable.id));
+                    self.mark_initialized();
                 }
+                self.statement(body);
+                self.at(body.last_token());
+                self.end_scope();
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.end_loop();
+                self.end_scope();
             }
-        }
-        fn_compiler.fun.min_arity = min_arity;
-
-        fn_compiler.parser.consume(TokenType::RightParen, "Expect ')' after parameters");
-
-        if typ == FunType::Method || typ == FunType::Initializer {
-            let token = Token {
-                typ: TokenType::This,
-                source: Rc::from("this"),
-                line: line_num,
-            };
-            fn_compiler.locals.push(Local { name: token, depth: 0 });
-            fn_compiler.emit_byte(Opcode::GetReceiver as u8);
-        }
-
-        fn_compiler.parser.consume(TokenType::LeftBrace, "Expect '{' before function body");
-        
-        // Parse python-style docstring if present
-        if fn_compiler.parser.check_tt(TokenType::String) {
-            fn_compiler.parser.advance();
-            let raw_doc = fn_compiler.parser.previous.source.clone();
-            let doc = &raw_doc[1..raw_doc.len() - 1];
-            fn_compiler.fun.help = Some(doc.to_string());
-            fn_compiler.parser.consume(TokenType::Semicolon, "Expect ';' after function docstring");
-        }
-
-        fn_compiler.block();
-        let fun = fn_compiler.end();
-
-        unsafe { &mut *fn_compiler.functions }.push(fun);
-        _ = std::mem::replace(&mut self.parser, fn_compiler.parser);
-        let fun_len = unsafe { &*self.functions }.len();
-        let constant_idx = self.make_constant(Value::Function(fun_len - 1)) as u8;
-        self.emit_bytes(Opcode::Constant as u8, constant_idx);
-    }
+            Stmt::Return { value, semicolon, .. } => match value {
+                None => {
+                    self.at(semicolon);
+                    self.emit_return();
+                }
+                Some(value) => {
+                    self.expression(value);
+                    self.at(semicolon);
+                    self.emit_byte(Opcode::Return as u8);
+                }
+            },
+            Stmt::Break { label, semicolon, .. } => {
+                self.at(semicolon);
+                let Some(target) = self.target_loop(label.as_ref()) else {
+                    return;
+                };
 
-    fn fun_declaration(&mut self) {
-        let (global, is_array) = self.parse_variable("Expect function name");
-        self.mark_initialized();
-        self.function(FunType::Function);
-        self.define_global_if_needed(global, is_array);
-    }
+                self.leave_loop_body(target);
+                let jump = self.emit_jump(Opcode::Jump as u8);
+                self.loops[target].break_jumps.push(jump);
+            }
+            Stmt::Continue { label, semicolon, .. } => {
+                self.at(semicolon);
+                let Some(target) = self.target_loop(label.as_ref()) else {
+                    return;
+                };
 
-    fn class_declaration(&mut self) {
-        self.parser.consume(TokenType::Identifier, "Expect class name.");
-        let class_name_token = self.parser.previous.clone();
-        let class_name = self.interner.intern(class_name_token.source.as_ref());
-        let name_constant = self.make_constant(Value::Identifier(class_name)) as u8;
+                self.leave_loop_body(target);
+                self.emit_loop(self.loops[target].continue_target);
+            }
+            Stmt::Throw { value, .. } => {
+                self.expression(value);
+                // Emitted at the thrown value so uncaught exceptions point at it
+                self.at(value.last_token());
+                self.emit_byte(Opcode::Throw as u8);
+            }
+            Stmt::Try {
+                keyword,
+                block,
+                catch,
+                finally,
+            } => {
+                self.at(keyword);
+                self.emit_byte(Opcode::Try as u8);
+                self.emit_u24(0);
+                self.emit_u24(0);
+                let catch_operand = self.fun.chunk.code.len() - 6;
+                let finally_operand = catch_operand + 3;
+                self.try_depth += 1;
+
+                self.begin_scope();
+                self.statements(&block.statements);
+                self.at(&block.close);
+                self.end_scope();
+                self.emit_byte(Opcode::EndTry as u8);
+
+                if let Some(catch) = catch {
+                    self.at(&catch.keyword);
+                    let try_end_jump = self.emit_jump(Opcode::Jump as u8);
+                    self.patch_jump(catch_operand);
+
+                    // The exception is on top of the stack when the catch block is entered
+                    self.begin_scope();
+                    match &catch.name {
+                        Some(name) => {
+                            self.add_local(Some(name.name.source.clone()), Some(name.id));
+                            self.mark_initialized();
+                        }
+                        None => self.emit_byte(Opcode::Pop as u8),
+                    }
+                    self.statements(&catch.block.statements);
+                    self.at(&catch.block.close);
+                    self.end_scope();
+                    self.emit_byte(Opcode::EndTry as u8);
 
-        self.declare_local_variable(None);
+                    self.patch_jump(try_end_jump);
+                }
 
-        self.emit_bytes(Opcode::Class as u8, name_constant);
+                if let Some((_, block)) = finally {
+                    self.patch_jump(finally_operand);
+                    self.begin_scope();
+                    self.statements(&block.statements);
+                    self.at(&block.close);
+                    self.end_scope();
+                    self.emit_byte(Opcode::EndFinally as u8);
+                }
 
-        self.parser.consume(TokenType::LeftBrace, "Expect '{' before class body.");
-        while !self.parser.check_tt(TokenType::RightBrace) && !self.parser.check_tt(TokenType::EOF) {
-            let class_name_str = class_name_token.source.clone();
-            self.method(&class_name_str);
+                self.try_depth -= 1;
+            }
+            Stmt::Error(_) => {}
         }
-        self.parser.consume(TokenType::RightBrace, "Expect '}' after class body.");
-
-        self.define_global_if_needed(name_constant as usize, false);
-    }
-
-    fn method(&mut self, class_name: &str) {
-        self.parser.consume(TokenType::Identifier, "Expect method name.");
-        let method_name_token = self.parser.previous.clone();
-        let method_name = self.interner.intern(method_name_token.source.as_ref());
-        let name_constant = self.make_constant(Value::Identifier(method_name)) as u8;
-
-        let is_initializer = method_name_token.source.as_ref() == class_name;
-        let typ = if is_initializer { FunType::Initializer } else { FunType::Method };
-
-        self.function(typ);
-        self.emit_bytes(Opcode::Method as u8, name_constant);
     }
 
-    fn dot(&mut self, can_assign: bool) {
-        self.parser.consume(TokenType::Identifier, "Expect property name after '.'.");
-        let name_token = self.parser.previous.clone();
-        let name_id = self.interner.intern(name_token.source.as_ref());
-        let name_constant = self.make_constant(Value::Identifier(name_id)) as u8;
-
-        if can_assign && self.parser.match_tt(TokenType::Equal) {
-            self.expression();
-            self.emit_bytes(Opcode::SetProperty as u8, name_constant);
-        } else if can_assign && self.parser.match_tt(TokenType::PlusEqual) {
-            // compound +=
-            self.emit_bytes(Opcode::GetProperty as u8, name_constant);
-            self.expression();
-            self.emit_byte(Opcode::Add as u8);
-            self.emit_bytes(Opcode::SetProperty as u8, name_constant);
-        } else if can_assign && self.parser.match_tt(TokenType::MinusEqual) {
-            // compound -=
-            self.emit_bytes(Opcode::GetProperty as u8, name_constant);
-            self.expression();
-            self.emit_byte(Opcode::Subtract as u8);
-            self.emit_bytes(Opcode::SetProperty as u8, name_constant);
-        } else if can_assign && self.parser.match_tt(TokenType::PlusPlus) {
-            // postfix ++ on property
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(Opcode::GetProperty as u8, name_constant);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Add as u8);
-            self.emit_bytes(Opcode::SetProperty as u8, name_constant);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Subtract as u8);
-        } else if can_assign && self.parser.match_tt(TokenType::MinusMinus) {
-            // postfix -- on property
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(Opcode::GetProperty as u8, name_constant);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Subtract as u8);
-            self.emit_bytes(Opcode::SetProperty as u8, name_constant);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Add as u8);
-        } else {
-            self.emit_bytes(Opcode::GetProperty as u8, name_constant);
+    /// Start declaring a variable, function or class, returning the constant with its name if it is a global
+    fn declare(&mut self, declaration: &Declaration) -> Option<usize> {
+        match self.access(declaration.id) {
+            Access::Global(name) => Some(self.identifier_constant(&name)),
+            _ => {
+                self.add_local(Some(declaration.name.source.clone()), Some(declaration.id));
+                None
+            }
         }
     }
 
-    fn this(&mut self, _can_assign: bool) {
-        if self.fun_typ != FunType::Method && self.fun_typ != FunType::Initializer {
-            self.parser.error_at_previous("Can't use 'this' outside of a class method.");
-            return;
+    /// Finish a declaration once the variable holds its value
+    fn define(&mut self, global: Option<usize>) {
+        match global {
+            Some(global) => self.emit_indexed(Opcode::DefineGlobal, global),
+            None => self.mark_initialized(),
         }
-        self.variable(false);
     }
 
-    fn var_declaration(&mut self) {
-        let (global_variable_idx, is_array) = self.parse_variable("Expect variable name");
+    fn function(&mut self, function: &Function) {
+        let name = match (&function.name, &self.context.namespace_prefix) {
+            (Some(name), Some(prefix)) => format!("{}.{}", prefix, name.source),
+            (Some(name), None) => name.source.to_string(),
+            (None, _) => ANONYMOUS_FUNCTION_NAME.to_string(),
+        };
+        let name = self.context.interner.intern(&name);
+        let upvalues = self.context.resolution.upvalues.remove(&function.id).unwrap_or_default();
 
-        if is_array {
-            self.expression();
-            self.emit_byte(Opcode::DeclareArray as u8);
-            self.parser.consume(TokenType::RightBracket, "Expect ']' after array size");
-        } else if self.parser.match_tt(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.emit_byte(Opcode::Nil as u8);
-        }
+        let mut fn_compiler = Compiler::new(self.context, function.kind);
+        fn_compiler.fun.name = Some(name);
+        fn_compiler.fun.upvalue_count = upvalues.len();
 
-        self.parser.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
-        self.define_global_if_needed(global_variable_idx, is_array);
-    }
+        let mut min_arity = 0;
+        fn_compiler.begin_scope();
+        for param in &function.params {
+            fn_compiler.fun.arity += 1;
+            let arity = fn_compiler.fun.arity;
+            fn_compiler.add_local(Some(param.name.name.source.clone()), Some(param.name.id));
+            fn_compiler.mark_initialized();
+
+            let Some((equal, default)) = &param.default else {
+                min_arity += 1;
+                continue;
+            };
+            fn_compiler.at(equal);
+            fn_compiler.emit_byte(Opcode::DefaultArg as u8);
+            fn_compiler.emit_byte(arity as u8);
+            fn_compiler.emit_u24(U24_MAX);
+            let jump_offset = fn_compiler.fun.chunk.code.len() - 3;
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.parser.consume(TokenType::Semicolon, "Expect ';' after expression");
-        self.emit_byte(Opcode::Pop as u8);
-    }
+            fn_compiler.emit_constant(Value::Nil);
+            fn_compiler.expression(default);
 
-    fn for_statement(&mut self) {
-        self.begin_scope();
-        self.parser.consume(TokenType::LeftParen, "Expect '(' after for");
+            fn_compiler.at(default.last_token());
+            fn_compiler.emit_bytes(Opcode::SetLocal as u8, (arity - 1) as u8);
+            fn_compiler.emit_byte(Opcode::Pop as u8);
 
-        if self.parser.match_tt(TokenType::Semicolon) {
-            // No initializer
-        } else if self.parser.match_tt(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.expression_statement();
+            fn_compiler.patch_jump(jump_offset);
         }
+        fn_compiler.fun.min_arity = min_arity;
 
-        let mut loop_start = self.fun.chunk.code.len();
-
-        let mut exit_jump = usize::MAX;
-        if !self.parser.match_tt(TokenType::Semicolon) {
-            self.expression();
-            self.parser.consume(TokenType::Semicolon, "Expect ';' after loop condition");
-
-            exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-            self.emit_byte(Opcode::Pop as u8);
+        if function.kind == FunType::Method || function.kind == FunType::Initializer {
+            fn_compiler.locals.push(Local {
+                name: Some(Rc::from("this")),
+                depth: 0,
+                is_captured: false,
+                start: fn_compiler.fun.chunk.code.len(),
+            });
+            fn_compiler.at(&function.close);
+            fn_compiler.emit_byte(Opcode::GetReceiver as u8);
         }
 
-        if !self.parser.match_tt(TokenType::RightParen) {
-            let body_jump = self.emit_jump(Opcode::Jump as u8);
-            let increment_start = self.fun.chunk.code.len();
-            self.expression();
-            self.emit_byte(Opcode::Pop as u8);
-            self.parser.consume(TokenType::RightParen, "Expect ')' after for clauses");
-
-            self.emit_loop(loop_start);
-            loop_start = increment_start;
-            self.patch_jump(body_jump);
+        match &function.body {
+            FunctionBody::Expression(expr) => {
+                fn_compiler.expression(expr);
+                fn_compiler.at(expr.last_token());
+                fn_compiler.emit_byte(Opcode::Return as u8);
+            }
+            FunctionBody::Block { doc, block } => {
+                if let Some(doc) = doc {
+                    fn_compiler.fun.help = Some(doc.source[1..doc.source.len() - 1].to_string());
+                }
+                fn_compiler.statements(&block.statements);
+            }
         }
+        fn_compiler.at(function.last_token());
+        let fun = fn_compiler.end();
 
-        self.statement();
-        self.emit_loop(loop_start);
+        self.context.functions.push(fun);
+        let fun_len = self.context.functions.len();
+        let constant_idx = self.make_constant(Value::Function(fun_len - 1));
+        // The function is created where it is declared, which is the line a debugger pauses on before running it
+        self.at(&function.start);
 
-        if exit_jump != usize::MAX {
-            self.patch_jump(exit_jump);
-            self.emit_byte(Opcode::Pop as u8);
+        // Functions that capture nothing stay plain function values
+        if upvalues.is_empty() {
+            self.emit_indexed(Opcode::Constant, constant_idx);
+            return;
         }
 
-        self.end_scope();
+        self.emit_indexed(Opcode::Closure, constant_idx);
+        self.emit_byte(upvalues.len() as u8);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index as u8);
+        }
     }
 
-    fn print_statement(&mut self) {
-        self.expression();
-        self.parser.consume(TokenType::Semicolon, "Expect ';' after expression");
-        self.emit_byte(Opcode::Print as u8);
-    }
+    fn class(&mut self, class: &Class) {
+        let name = &class.name.name;
+        let class_name = self.context.interner.intern(name.source.as_ref());
+        let name_constant = self.make_constant(Value::Identifier(class_name));
+        let global = resolver::global_name(self.context.namespace_prefix.as_deref(), &name.source);
+        let variable_constant = self.identifier_constant(&global);
 
-    fn return_statement(&mut self) {
-        if self.fun_typ == FunType::Script {
-            self.parser.error_at_previous("Can't return from top-level code");
-        }
-
-        if self.parser.match_tt(TokenType::Semicolon) {
-            self.emit_return();
-        } else {
-            if self.fun_typ == FunType::Initializer {
-                self.parser.error_at_previous("Can't return a value from an initializer.");
-            }
-            self.expression();
-            self.parser.consume(TokenType::Semicolon, "Expect ';' after return value");
-            self.emit_byte(Opcode::Return as u8);
+        if self.scope_depth > 0 {
+            self.add_local(Some(name.source.clone()), Some(class.name.id));
         }
-    }
 
-    fn if_statement(&mut self) {
-        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'if'");
-        self.expression();
-        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
+        self.at(name);
+        self.emit_indexed(Opcode::Class, name_constant);
+        self.define((self.scope_depth == 0).then_some(variable_constant));
 
-        let then_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        self.emit_byte(Opcode::Pop as u8);
-        self.statement();
+        if let Some(superclass) = &class.superclass {
+            self.expression(superclass);
 
-        let else_jump = self.emit_jump(Opcode::Jump as u8);
-        self.patch_jump(then_jump);
-        self.emit_byte(Opcode::Pop as u8);
+            // The superclass lives in a scope around the class body, so methods capture it as 'super'
+            self.begin_scope();
+            self.add_local(None, Some(class.super_id));
+            self.mark_initialized();
 
-        if self.parser.match_tt(TokenType::Else) {
-            self.statement();
+            self.at(superclass.last_token());
+            self.load_class(class);
+            self.emit_byte(Opcode::Inherit as u8);
         }
 
-        self.patch_jump(else_jump);
-    }
-
-    fn while_statement(&mut self) {
-        let loop_start = self.fun.chunk.code.len();
-        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'while'");
-        self.expression();
-        self.parser.consume(TokenType::RightParen, "Expect ')' after condition");
-
-        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        self.emit_byte(Opcode::Pop as u8);
-        self.statement();
-        self.emit_loop(loop_start);
+        // Keep the class on top of the stack while its methods are attached
+        self.load_class(class);
+        for method in &class.methods {
+            let method_name = method.name.as_ref().unwrap();
+            let method_name = self.context.interner.intern(method_name.source.as_ref());
+            let name_constant = self.make_constant(Value::Identifier(method_name));
 
-        self.patch_jump(exit_jump);
-        self.emit_byte(Opcode::Pop as u8);
-    }
-
-    fn declaration(&mut self) {
-        if self.parser.match_tt(TokenType::Class) {
-            self.class_declaration();
-        } else if self.parser.match_tt(TokenType::Fun) {
-            self.fun_declaration();
-        } else if self.parser.match_tt(TokenType::Var) {
-            self.var_declaration();
-        } else if self.parser.match_tt(TokenType::Import) {
-            self.import_declaration();
-        } else {
-            self.statement();
+            self.function(method);
+            self.emit_indexed(Opcode::Method, name_constant);
         }
+        self.at(&class.close);
+        self.emit_byte(Opcode::Pop as u8);
 
-        if self.parser.panic_mode {
-            self.parser.synchronize();
+        if class.superclass.is_some() {
+            self.end_scope();
         }
     }
 
-    fn prefix_token(&self, token: Token) -> Token {
-        if let Some(ref prefix) = self.namespace_prefix {
-            if is_native_function(token.source.as_ref()) {
-                token
-            } else {
-                let prefix_dot = format!("{}.", prefix);
-                if token.source.starts_with(&prefix_dot) {
-                    token
-                } else {
-                    let mut new_token = token.clone();
-                    new_token.source = Rc::from(format!("{}.{}", prefix, token.source));
-                    new_token
-                }
-            }
-        } else {
-            token
-        }
+    /// Push the class being declared, which lives where its declaration put it
+    fn load_class(&mut self, class: &Class) {
+        let (get_op, _, arg) = self.variable_ops(class.name.id);
+        self.emit_constant(Value::Nil);
+        self.emit_indexed(get_op, arg);
     }
 
-    fn import_declaration(&mut self) {
-        let path_str_unescaped: String;
-        let alias_str: String;
-
-        if self.parser.match_tt(TokenType::Identifier) {
-            let ident_token = self.parser.previous.clone();
-            let name = ident_token.source.as_ref().to_string();
-            path_str_unescaped = name.clone();
-            alias_str = name;
-        } else if self.parser.match_tt(TokenType::String) {
-            let path_token = self.parser.previous.clone();
-            let raw_path = &path_token.source[1..path_token.source.len() - 1];
-            path_str_unescaped = unescape_string(raw_path);
-
-            if self.parser.match_tt(TokenType::As) {
-                self.parser.consume(TokenType::Identifier, "Expect namespace alias after 'as'.");
-                alias_str = self.parser.previous.source.as_ref().to_string();
-            } else {
-                let p = std::path::Path::new(&path_str_unescaped);
-                alias_str = p.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| path_str_unescaped.clone());
-            }
-        } else {
-            self.parser.error_at_current("Expect string literal or module identifier after 'import'.");
-            return;
-        }
-
-        unsafe { &mut *self.namespaces }.insert(alias_str.clone());
-        self.parser.consume(TokenType::Semicolon, "Expect ';' after import declaration.");
+    fn import(&mut self, import: &Import) {
+        // Problems with the import are reported at the module name
+        self.at(import.alias.as_ref().unwrap_or(&import.module));
 
         #[cfg(target_arch = "wasm32")]
-        {
-            xprintln!("Warning: import is not supported in the WASM environment.");
-            return;
-        }
+        self.warning("Import is not supported in the WASM environment.");
 
         #[cfg(not(target_arch = "wasm32"))]
         {
-            let is_lox_script = path_str_unescaped.ends_with(".lox");
+            let path = import.path.clone();
+            let alias = import.namespace.clone();
+            if !path.ends_with(".lox") {
+                self.check_native_import(&path, &alias);
+                self.fun.native_imports.push((path, alias));
+            } else if let Err(e) = self.execute_import(&path, &alias) {
+                self.error(&e);
+            }
+        }
+    }
 
-            if !is_lox_script {
-                self.fun.native_imports.push((path_str_unescaped, alias_str));
-            } else {
-                if let Err(e) = self.execute_import(&path_str_unescaped, &alias_str) {
-                    self.parser.error_at_current(&e);
+    /// Load a native module if the lint config asks for it, to check it exists and declare the globals it defines.
+    /// The module is unloaded again, as it is only loaded for good when the program runs.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn check_native_import(&mut self, path: &str, alias: &str) {
+        let context = &mut *self.context;
+        if !context.lints.config.loads_modules() {
+            context.lints.native_modules.insert(alias.to_string());
+            return;
+        }
+
+        let mut globals = crate::native::Globals::default();
+        let library = match crate::vm::load_builtin_module(path, alias, context.interner, &mut globals) {
+            true => None,
+            false => match crate::ffi::load_native_module(path, alias, context.interner, &mut globals) {
+                std::result::Result::Ok(library) => Some(library),
+                std::result::Result::Err(e) => {
+                    self.error(&format!("Can't load native module '{path}': {e}"));
+                    return;
                 }
-            }
+            },
+        };
+
+        for name in globals.keys() {
+            context.declared_globals.insert(Rc::from(context.interner.lookup(name)));
         }
+        // The functions of a library have to be dropped before the library
+        drop(globals);
+        drop(library);
     }
 
     #[cfg(not(target_arch = "wasm32"))]
     fn execute_import(&mut self, path_str: &str, alias_str: &str) -> std::result::Result<(), String> {
         use std::fs;
 
-        let base_dir = self
+        let context = &mut *self.context;
+        let base_dir = context
             .current_dir
             .clone()
             .unwrap_or_else(|| std::env::current_dir().unwrap_or_default());
@@ -887,13 +793,11 @@ impl<'src> Compiler<'src> {
             std::result::Result::Err(_) => return std::result::Result::Err(format!("Could not resolve import path: {}", path_str)),
         };
 
-        let stack = unsafe { &mut *self.import_stack };
-        if stack.contains(&canonical_path) {
+        if context.import_stack.contains(&canonical_path) {
             return std::result::Result::Err(format!("Circular import detected: {} is already being imported", path_str));
         }
 
-        let imported = unsafe { &mut *self.imported_files };
-        if imported.contains(&canonical_path) {
+        if context.imported_files.contains(&canonical_path) {
             return std::result::Result::Ok(());
         }
 
@@ -902,417 +806,471 @@ impl<'src> Compiler<'src> {
             std::result::Result::Err(_) => return std::result::Result::Err(format!("Could not read import file: {}", path_str)),
         };
 
-        stack.push(canonical_path.clone());
+        context.import_stack.push(canonical_path.clone());
 
         let source: Rc<str> = Rc::from(content);
         let new_current_dir = canonical_path.parent().map(|p| p.to_path_buf());
 
-        let combined_prefix = if let Some(ref parent) = self.namespace_prefix {
+        let combined_prefix = if let Some(ref parent) = context.namespace_prefix {
             format!("{}.{}", parent, alias_str)
         } else {
             alias_str.to_string()
         };
 
-        let (fun, had_error) = match Self::compile_internal(
-            source,
-            new_current_dir,
-            Some(combined_prefix),
-            imported,
-            stack,
-            self.interner,
-            unsafe { &mut *self.functions },
-            FunType::Script,
-        ) {
+        let first_function = context.functions.len();
+        let session = Session {
+            interner: context.interner,
+            functions: context.functions,
+            imported_files: context.imported_files,
+            import_stack: context.import_stack,
+            declared_globals: context.declared_globals,
+            lints: &context.lints.config,
+        };
+        let compiled = Compiler::compile_internal(source, new_current_dir, Some(combined_prefix), FunType::Script, session);
+        let (mut fun, diagnostics) = match compiled {
             std::result::Result::Ok(res) => res,
             std::result::Result::Err(_) => return std::result::Result::Err(format!("Compilation failed for import: {}", path_str)),
         };
 
-        stack.pop();
+        context.import_stack.pop();
+
+        // Problems in the imported file are reported with the file they are in
+        let had_error = diagnostics.iter().any(Diagnostic::is_error);
+        let file = canonical_path.to_string_lossy();
+        for mut diagnostic in diagnostics {
+            diagnostic.file.get_or_insert_with(|| file.to_string());
+            context.diagnostics.push(diagnostic);
+        }
 
         if had_error {
             return std::result::Result::Err(format!("Compilation errors in import: {}", path_str));
         }
 
-        imported.insert(canonical_path);
+        // Functions of files imported by this one already have their own file
+        let file: Rc<str> = Rc::from(canonical_path.to_string_lossy());
+        let functions = context.functions[first_function..].iter_mut();
+        for imported_fun in functions.chain(std::iter::once(&mut fun)) {
+            imported_fun.file.get_or_insert_with(|| Rc::clone(&file));
+        }
+
+        context.imported_files.insert(canonical_path);
 
-        unsafe { &mut *self.functions }.push(fun);
+        context.functions.push(fun);
 
-        let fun_len = unsafe { &*self.functions }.len();
+        let fun_len = context.functions.len();
         let fun_const_idx = self.make_constant(Value::Function(fun_len - 1));
-        self.emit_bytes(Opcode::Constant as u8, fun_const_idx as u8);
+        self.emit_indexed(Opcode::Constant, fun_const_idx);
         self.emit_bytes(Opcode::Call as u8, 0);
         self.emit_byte(Opcode::Pop as u8);
 
         std::result::Result::Ok(())
     }
 
-    fn statement(&mut self) {
-        if self.parser.match_tt(TokenType::Print) {
-            self.print_statement();
-        } else if self.parser.match_tt(TokenType::Return) {
-            self.return_statement();
-        } else if self.parser.match_tt(TokenType::If) {
-            self.if_statement();
-        } else if self.parser.match_tt(TokenType::While) {
-            self.while_statement();
-        } else if self.parser.match_tt(TokenType::For) {
-            self.for_statement();
-        } else if self.parser.match_tt(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block();
-            self.end_scope();
-        } else {
-            self.expression_statement();
-        }
-    }
-
-    fn grouping(&mut self, _can_assign: bool) {
-        self.expression();
-        self.parser.consume(TokenType::RightParen, "Expect ')' after expression.");
-    }
-
-    fn number(&mut self, _can_assign: bool) {
-        let num = self.parser.previous.source.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(num));
-    }
-
-    fn string(&mut self, _can_assign: bool) {
-        let data = self.parser.previous.source.clone();
-        let raw_str = &data[1..data.len() - 1];
-        let unescaped = unescape_string(raw_str);
-        let id = self.interner.intern(&unescaped);
-        self.emit_constant(Value::Str(id));
+    fn begin_loop(&mut self, label: Option<&Token>, continue_target: usize) {
+        self.loops.push(Loop {
+            label: label.map(|label| label.source.clone()),
+            continue_target,
+            scope_depth: self.scope_depth,
+            break_jumps: Vec::new(),
+            try_depth: self.try_depth,
+        });
     }
 
-    fn array_literal(&mut self, _can_assign: bool) {
-        let mut element_count: u8 = 0;
-        if !self.parser.check_tt(TokenType::RightBracket) {
-            loop {
-                self.expression();
-                if element_count == 255 {
-                    self.parser
-                        .error_at_previous("Can't have more than 255 elements in an array literal.");
-                }
-                element_count += 1;
-                if !self.parser.match_tt(TokenType::Comma) {
-                    break;
-                }
-            }
+    /// Patch the breaks out of the innermost loop to jump here
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().unwrap();
+        for jump in finished.break_jumps {
+            self.patch_jump(jump);
         }
-        self.parser
-            .consume(TokenType::RightBracket, "Expect ']' after array literal elements.");
-        self.emit_bytes(Opcode::ArrayLiteral as u8, element_count);
     }
 
-    fn index_access(&mut self, can_assign: bool) {
-        self.expression();
-        self.parser.consume(TokenType::RightBracket, "Expect ']' after array index.");
-
-        if can_assign && self.parser.match_tt(TokenType::Equal) {
-            self.expression();
-            self.emit_byte(Opcode::SetIndex as u8);
-        } else {
-            self.emit_byte(Opcode::GetIndex as u8);
+    /// Find the loop targeted by a `break` or `continue`, which the resolver already checked exists
+    fn target_loop(&self, label: Option<&Token>) -> Option<usize> {
+        match label {
+            Some(label) => self.loops.iter().rposition(|l| l.label.as_ref() == Some(&label.source)),
+            None => self.loops.len().checked_sub(1),
         }
     }
 
-    fn named_variable(&mut self, token: &Token, can_assign: bool) {
-        let prefixed_token = self.prefix_token(token.clone());
-        let get_op: Opcode;
-        let set_op: Opcode;
-        let mut arg: isize = self.resolve_local(&prefixed_token);
-
-        if arg != -1 {
-            set_op = Opcode::SetLocal;
-            get_op = Opcode::GetLocal;
-        } else {
-            arg = self.identifier_constant(&prefixed_token) as isize;
-            set_op = Opcode::SetGlobal;
-            get_op = Opcode::GetGlobal;
-        }
-
-        if self.array_access_index() {
-            dbgln!("{} is array access", token.source);
+    /// Drop the locals of the body of the loop `target` before a `break` or `continue` jumps out of it.
+    /// Jumping out of try statements also removes their handlers and runs their finally blocks first, with the locals
+    /// they use still in place, so the VM drops the locals once those have run.
+    fn leave_loop_body(&mut self, target: usize) {
+        let (scope_depth, try_depth) = (self.loops[target].scope_depth, self.loops[target].try_depth);
+        if try_depth == self.try_depth {
+            self.discard_locals(scope_depth);
+            return;
         }
 
-        if can_assign && self.parser.match_tt(TokenType::Equal) {
-            self.expression();
-            self.emit_bytes(set_op as u8, arg as u8);
-        } else if can_assign && self.parser.match_tt(TokenType::PlusEqual) {
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(get_op as u8, arg as u8);
-            self.expression();
-            self.emit_byte(Opcode::Add as u8);
-            self.emit_bytes(set_op as u8, arg as u8);
-        } else if can_assign && self.parser.match_tt(TokenType::MinusEqual) {
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(get_op as u8, arg as u8);
-            self.expression();
-            self.emit_byte(Opcode::Subtract as u8);
-            self.emit_bytes(set_op as u8, arg as u8);
-        } else if self.parser.match_tt(TokenType::PlusPlus) {
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(get_op as u8, arg as u8);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Add as u8);
-            self.emit_bytes(set_op as u8, arg as u8);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Subtract as u8);
-        } else if self.parser.match_tt(TokenType::MinusMinus) {
-            self.emit_byte(Opcode::Dup as u8);
-            self.emit_bytes(get_op as u8, arg as u8);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Subtract as u8);
-            self.emit_bytes(set_op as u8, arg as u8);
-            self.emit_constant(Value::Number(1.0));
-            self.emit_byte(Opcode::Add as u8);
-        } else {
-            self.emit_bytes(get_op as u8, arg as u8);
+        let tries = self.try_depth - try_depth;
+        if tries > u8::MAX as usize {
+            self.error("Too many try statements to jump out of");
         }
+        let slots = self.locals.iter().filter(|local| local.depth <= scope_depth).count();
+        self.emit_bytes(Opcode::Leave as u8, tries as u8);
+        self.emit_u24(slots);
     }
 
-    fn prefix_increment_decrement(&mut self, _can_assign: bool) {
-        let is_increment = self.parser.previous.typ == TokenType::PlusPlus;
-        self.parser.consume(TokenType::Identifier, "Expect variable name.");
-        let token = self.parser.previous.clone();
-
-        let get_op: Opcode;
-        let set_op: Opcode;
-        let mut arg: isize = self.resolve_local(&token);
-
-        if arg != -1 {
-            set_op = Opcode::SetLocal;
-            get_op = Opcode::GetLocal;
-        } else {
-            arg = self.identifier_constant(&token) as isize;
-            set_op = Opcode::SetGlobal;
-            get_op = Opcode::GetGlobal;
-        }
-
-        if self.array_access_index() {
-            dbgln!("{} is array access", token.source);
-        }
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(token) => {
+                self.at(token);
+                match token.typ {
+                    TokenType::False => self.emit_byte(Opcode::False as u8),
+                    TokenType::Nil => self.emit_byte(Opcode::Nil as u8),
+                    TokenType::True => self.emit_byte(Opcode::True as u8),
+                    _ => (),
+                }
+            }
+            Expr::Number { token, value } => {
+                self.at(token);
+                self.emit_constant(Value::Number(*value));
+            }
+            Expr::String { token, value } => {
+                self.at(token);
+                let id = self.context.interner.intern(value);
+                self.emit_constant(Value::Str(id));
+            }
+            Expr::Interpolation { strings, expressions } => {
+                // Each part is pushed, and `Interpolate` joins their string forms
+                let mut part_count: usize = 0;
+                for (i, (token, value)) in strings.iter().enumerate() {
+                    self.at(token);
+                    part_count += self.string_part(value);
+                    if let Some(expr) = expressions.get(i) {
+                        self.expression(expr);
+                        part_count += 1;
+                    }
+                }
+                self.emit_bytes(Opcode::Interpolate as u8, part_count as u8);
+            }
+            Expr::Variable(variable) => {
+                let (get_op, _, arg) = self.variable_ops(variable.id);
+                self.variable_index(variable);
+                self.at(variable.last_token());
+                self.emit_indexed(get_op, arg);
+            }
+            Expr::Assign { target, operator, value } => self.assignment(target, operator, value),
+            Expr::Increment { target, operator, prefix } => self.increment(target, operator, *prefix),
+            Expr::Get { object, name } => {
+                self.expression(object);
+                let name_constant = self.identifier_constant(&name.source);
+                self.at(name);
+                self.emit_indexed(Opcode::GetProperty, name_constant);
+            }
+            Expr::Index { object, index } => {
+                self.expression(object);
+                self.expression(&index.expr);
+                self.at(&index.close);
+                self.emit_byte(Opcode::GetIndex as u8);
+            }
+            Expr::Slice {
+                object,
+                start,
+                colon,
+                end,
+                close,
+                ..
+            } => {
+                self.expression(object);
+                // Either bound of a slice may be left out
+                for bound in [start, end] {
+                    match bound {
+                        Some(bound) => self.expression(bound),
+                        None => {
+                            self.at(colon);
+                            self.emit_byte(Opcode::Nil as u8);
+                        }
+                    }
+                }
+                self.at(close);
+                self.emit_byte(Opcode::Slice as u8);
+            }
+            Expr::Call { callee, arguments, close } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.at(close);
+                self.emit_bytes(Opcode::Call as u8, arguments.len() as u8);
+            }
+            Expr::Unary { operator, operand } => {
+                self.expression(operand);
+                self.at(operand.last_token());
+                match operator.typ {
+                    TokenType::Minus => self.emit_byte(Opcode::Negate as u8),
+                    TokenType::Bang => self.emit_byte(Opcode::Not as u8),
+                    _ => (),
+                }
+            }
+            Expr::Binary { left, operator, right } => {
+                self.expression(left);
+                self.expression(right);
+                self.at(right.last_token());
+                for &opcode in binary_opcodes(operator.typ) {
+                    self.emit_byte(opcode as u8);
+                }
+            }
+            Expr::Logical { left, operator, right } => {
+                self.expression(left);
+                self.at(operator);
+                if operator.typ == TokenType::And {
+                    let end_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
 
-        self.emit_byte(Opcode::Dup as u8);
-        self.emit_bytes(get_op as u8, arg as u8);
-        self.emit_constant(Value::Number(1.0));
-        if is_increment {
-            self.emit_byte(Opcode::Add as u8);
-        } else {
-            self.emit_byte(Opcode::Subtract as u8);
-        }
-        self.emit_bytes(set_op as u8, arg as u8);
-    }
+                    self.emit_byte(Opcode::Pop as u8);
+                    self.expression(right);
 
-    // Array index (or max value if not an array index)
-    fn array_access_index(&mut self) -> bool {
-        // Array index (or max value if not an array index)
-        if self.parser.match_tt(TokenType::LeftBracket) {
-            self.expression();
-            self.parser.consume(TokenType::RightBracket, "Expect ']' after array index");
-            true
-        } else {
-            self.emit_constant(Value::Nil);
-            false
-        }
-    }
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+                    let end_jump = self.emit_jump(Opcode::Jump as u8);
 
-    fn variable(&mut self, can_assign: bool) {
-        let mut token = self.parser.previous.clone();
+                    self.patch_jump(else_jump);
+                    self.emit_byte(Opcode::Pop as u8);
 
-        let is_namespace = unsafe { &*self.namespaces }.contains(token.source.as_ref());
-        if is_namespace {
-            while self.parser.match_tt(TokenType::Dot) {
-                self.parser
-                    .consume(TokenType::Identifier, "Expect property/method name after '.' in namespace access.");
-                let prop_token = self.parser.previous.clone();
-                token.source = Rc::from(format!("{}.{}", token.source, prop_token.source));
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
             }
-        }
-
-        self.named_variable(&token, can_assign);
-    }
-
-    fn unary(&mut self, can_assign: bool) {
-        let _ = can_assign;
-        let operator_type = self.parser.previous.typ;
-        self.parse_precedence(Precedence::Unary);
-
-        match operator_type {
-            TokenType::Minus => self.emit_byte(Opcode::Negate as u8),
-            TokenType::Bang => self.emit_byte(Opcode::Not as u8),
-            _ => (),
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Array { elements, close, .. } => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.at(close);
+                self.emit_bytes(Opcode::ArrayLiteral as u8, elements.len() as u8);
+            }
+            Expr::Map { entries, close, .. } => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.at(close);
+                self.emit_bytes(Opcode::MapLiteral as u8, entries.len() as u8);
+            }
+            Expr::Function(function) => self.function(function),
+            Expr::Super {
+                method, this_id, super_id, ..
+            } => {
+                let name_constant = self.identifier_constant(&method.source);
+                self.at(method);
+                for id in [this_id, super_id] {
+                    let (get_op, _, arg) = self.variable_ops(*id);
+                    self.emit_constant(Value::Nil);
+                    self.emit_indexed(get_op, arg);
+                }
+                self.emit_indexed(Opcode::GetSuper, name_constant);
+            }
+            Expr::Error(_) => {}
         }
     }
 
-    /// Parse a variable.
-    /// If its a global, the return value is the index in the constant pool.
-    /// If its an array, the boolean value is true.
-    fn parse_variable(&mut self, error_message: &str) -> (usize, bool) {
-        self.parser.consume(TokenType::Identifier, error_message);
-        let array_name: Token = self.parser.previous.clone();
-        let is_array = self.parser.match_tt(TokenType::LeftBracket);
-
-        if is_array {
-            self.declare_local_variable(Some(array_name.clone()));
-        } else {
-            self.declare_local_variable(None);
-        }
+    fn assignment(&mut self, target: &Target, operator: &Token, value: &Expr) {
+        let compound = match operator.typ {
+            TokenType::PlusEqual => Some(Opcode::Add),
+            TokenType::MinusEqual => Some(Opcode::Subtract),
+            _ => None,
+        };
 
-        if self.scope_depth > 0 {
-            return (0, is_array);
+        match target {
+            Target::Variable(variable) => {
+                let (get_op, set_op, arg) = self.variable_ops(variable.id);
+                self.variable_index(variable);
+                if let Some(op) = compound {
+                    self.at(operator);
+                    self.emit_byte(Opcode::Dup as u8);
+                    self.emit_indexed(get_op, arg);
+                    self.expression(value);
+                    self.at(value.last_token());
+                    self.emit_byte(op as u8);
+                } else {
+                    self.expression(value);
+                    self.at(value.last_token());
+                }
+                self.emit_indexed(set_op, arg);
+            }
+            Target::Property { object, name } => {
+                self.expression(object);
+                let name_constant = self.identifier_constant(&name.source);
+                if let Some(op) = compound {
+                    self.at(operator);
+                    self.emit_indexed(Opcode::GetProperty, name_constant);
+                    self.expression(value);
+                    self.at(value.last_token());
+                    self.emit_byte(op as u8);
+                } else {
+                    self.expression(value);
+                    self.at(value.last_token());
+                }
+                self.emit_indexed(Opcode::SetProperty, name_constant);
+            }
+            Target::Index { object, index } => {
+                self.expression(object);
+                self.expression(&index.expr);
+                self.expression(value);
+                self.at(value.last_token());
+                self.emit_byte(Opcode::SetIndex as u8);
+            }
         }
-
-        let previous = if is_array { array_name } else { self.parser.previous.clone() };
-        let prefixed_name = self.prefix_token(previous);
-        (self.identifier_constant(&prefixed_name), is_array)
     }
 
-    /// Parse expressions with equal or higher precedence
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.parser.advance();
-        let prefix_rule = self.get_rule(self.parser.previous.typ).prefix;
-        let can_assign = precedence <= Precedence::Assignment;
+    /// Compile `++` or `--`. The postfix forms leave the value from before the change.
+    fn increment(&mut self, target: &Target, operator: &Token, prefix: bool) {
+        let (op, undo) = match operator.typ {
+            TokenType::PlusPlus => (Opcode::Add, Opcode::Subtract),
+            _ => (Opcode::Subtract, Opcode::Add),
+        };
 
-        match prefix_rule {
-            Some(rule) => rule(self, can_assign),
-            None => {
-                self.parser.error_at_previous("Expect expression");
-                return;
+        match target {
+            Target::Variable(variable) => {
+                let (get_op, set_op, arg) = self.variable_ops(variable.id);
+                self.variable_index(variable);
+                self.at(if prefix { variable.last_token() } else { operator });
+                self.emit_byte(Opcode::Dup as u8);
+                self.emit_indexed(get_op, arg);
+                self.emit_constant(Value::Number(1.0));
+                self.emit_byte(op as u8);
+                self.emit_indexed(set_op, arg);
+            }
+            Target::Property { object, name } => {
+                self.expression(object);
+                let name_constant = self.identifier_constant(&name.source);
+                self.at(operator);
+                self.emit_byte(Opcode::Dup as u8);
+                self.emit_indexed(Opcode::GetProperty, name_constant);
+                self.emit_constant(Value::Number(1.0));
+                self.emit_byte(op as u8);
+                self.emit_indexed(Opcode::SetProperty, name_constant);
             }
+            Target::Index { .. } => unreachable!("the parser only increments variables and properties"),
         }
 
-        while precedence <= self.get_rule(self.parser.current.typ).precedence {
-            self.parser.advance();
-            let infix_rule = self.get_rule(self.parser.previous.typ).infix;
-
-            match infix_rule {
-                Some(rule) => rule(self, can_assign),
-                None => {
-                    self.parser.error_at_previous("Expect expression");
-                    return;
-                }
-            }
+        if !prefix {
+            self.emit_constant(Value::Number(1.0));
+            self.emit_byte(undo as u8);
         }
+    }
 
-        if can_assign && self.parser.match_tt(TokenType::Equal) {
-            self.parser.error_at_current("Invalid assignment target");
+    /// Pushes a literal part of an interpolated string unless it's empty, returning how many parts were pushed
+    fn string_part(&mut self, value: &str) -> usize {
+        if value.is_empty() {
+            return 0;
         }
+        let id = self.context.interner.intern(value);
+        self.emit_constant(Value::Str(id));
+        1
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> usize {
-        let identifier = self.interner.intern(name.source.as_ref());
-        self.make_constant(Value::Identifier(identifier))
+    fn access(&self, id: NodeId) -> Access {
+        self.context.resolution.variables[&id].clone()
     }
 
-    fn mark_initialized(&mut self) {
-        if self.scope_depth == 0 {
-            return;
+    /// The get and set opcodes of a variable the resolver found, and the operand they take
+    fn variable_ops(&mut self, id: NodeId) -> (Opcode, Opcode, usize) {
+        match self.access(id) {
+            Access::Local(slot) => (Opcode::GetLocal, Opcode::SetLocal, slot),
+            Access::Upvalue(index) => (Opcode::GetUpvalue, Opcode::SetUpvalue, index),
+            Access::Global(name) => (Opcode::GetGlobal, Opcode::SetGlobal, self.identifier_constant(&name)),
         }
-        self.locals.last_mut().unwrap().depth = self.scope_depth;
     }
 
-    fn resolve_local(&mut self, name: &Token) -> isize {
-        dbgln!("Resolving local: {}", name.source);
-        for (i, local) in self.locals.iter().enumerate().rev() {
-            if identifiers_equal(&local.name, name) {
-                if local.depth == -1 {
-                    self.parser.error_at_current("Can't read local variable in its own initializer")
-                }
-
-                dbgln!("Resolved to {i}");
-                return i as isize;
+    /// Push the index right after a variable's name, or nil when it has none, for the variable instructions
+    fn variable_index(&mut self, variable: &Variable) {
+        match &variable.index {
+            Some(index) => self.expression(&index.expr),
+            None => {
+                self.at(variable.path.last().unwrap());
+                self.emit_constant(Value::Nil);
             }
         }
+    }
 
-        dbgln!("Resolved to -1");
-        -1
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let identifier = self.context.interner.intern(name);
+        self.make_constant(Value::Identifier(identifier))
     }
 
-    fn add_local(&mut self, name: Token) {
-        let local = Local {
-            name: name.clone(),
+    fn add_local(&mut self, name: Option<Rc<str>>, declaration: Option<NodeId>) {
+        let is_captured = declaration.is_some_and(|id| self.context.resolution.captured.contains(&id));
+        self.locals.push(Local {
+            name,
             depth: -1,
-        };
-
-        dbgln!("Adding local: {}", name.source);
-
-        self.locals.push(local);
+            is_captured,
+            start: self.fun.chunk.code.len(),
+        });
     }
 
-    fn declare_local_variable(&mut self, array: Option<Token>) {
+    fn mark_initialized(&mut self) {
         if self.scope_depth == 0 {
             return;
         }
+        let start = self.fun.chunk.code.len();
+        let local = self.locals.last_mut().unwrap();
+        local.depth = self.scope_depth;
+        local.start = start;
+    }
 
-        let name = match array {
-            Some(token) => token,
-            None => self.parser.previous.clone(),
-        };
-
-        let prefixed_name = self.prefix_token(name);
-
-        for local in self.locals.iter().rev() {
-            if local.depth != -1 && local.depth < self.scope_depth {
-                break;
-            }
-
-            if identifiers_equal(&prefixed_name, &local.name) {
-                self.parser.error_at_current("Already a variable with this name in this scope");
+    /// Record the names of the locals deeper than `depth`, whose scope ends here, for debuggers.
+    /// Hidden locals, like the superclass and the iterator of a for-in loop, aren't named.
+    fn name_locals(&mut self, depth: isize) {
+        let end = self.fun.chunk.code.len();
+        for (slot, local) in self.locals.iter().enumerate() {
+            let Some(name) = &local.name else {
+                continue;
+            };
+            if local.depth <= depth {
+                continue;
             }
+            self.fun.locals.push(LocalName {
+                name: name.to_string(),
+                slot,
+                start: local.start,
+                end,
+            });
         }
-
-        self.add_local(prefixed_name);
     }
 
-    fn define_global_if_needed(&mut self, global: usize, _is_array: bool) {
-        if self.scope_depth > 0 {
-            self.mark_initialized();
-            return;
+    fn make_constant(&mut self, value: Value) -> usize {
+        let index = self.fun.chunk.add_constant(value);
+        if index > U24_MAX {
+            self.error("Too many constants in one chunk");
+            return 0;
         }
-
-        self.emit_bytes(Opcode::DefineGlobal as u8, global as u8);
-    }
-
-    fn and(&mut self, _can_assign: bool) {
-        let end_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-
-        self.emit_byte(Opcode::Pop as u8);
-        self.parse_precedence(Precedence::And);
-
-        self.patch_jump(end_jump);
+        index
     }
 
-    fn or(&mut self, _can_assign: bool) {
-        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
-        let end_jump = self.emit_jump(Opcode::Jump as u8);
-
-        self.patch_jump(else_jump);
-        self.emit_byte(Opcode::Pop as u8);
-
-        self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump);
+    fn emit_constant(&mut self, value: Value) {
+        let index = self.make_constant(value);
+        self.emit_indexed(Opcode::Constant, index);
     }
 
-    fn make_constant(&mut self, value: Value) -> usize {
-        self.fun.chunk.add_constant(value)
+    /// Emit an instruction taking a constant index or a slot, using its `Long` variant when the index doesn't fit in a byte
+    fn emit_indexed(&mut self, op: Opcode, index: usize) {
+        if index <= u8::MAX as usize {
+            self.emit_bytes(op as u8, index as u8);
+        } else {
+            self.emit_byte(op.long() as u8);
+            self.emit_u24(index);
+        }
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let index = self.fun.chunk.add_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, index as u8);
+    fn emit_u24(&mut self, value: usize) {
+        self.emit_byte(((value >> 16) & 0xff) as u8);
+        self.emit_byte(((value >> 8) & 0xff) as u8);
+        self.emit_byte((value & 0xff) as u8);
     }
 
+    /// Point the jump operand at `offset` to the end of the code emitted so far
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.fun.chunk.code.len() - offset - 2;
+        let jump = self.fun.chunk.code.len() - offset - 3;
 
-        if jump > u16::MAX as usize {
-            self.parser.error_at_current("Too much code to jump over");
+        if jump > U24_MAX {
+            self.error("Too much code to jump over");
         }
 
-        let jump = jump as u16;
-        self.fun.chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.fun.chunk.code[offset + 1] = (jump & 0xff) as u8;
+        self.fun.chunk.code[offset] = ((jump >> 16) & 0xff) as u8;
+        self.fun.chunk.code[offset + 1] = ((jump >> 8) & 0xff) as u8;
+        self.fun.chunk.code[offset + 2] = (jump & 0xff) as u8;
     }
 
     fn emit_return(&mut self) {
@@ -1327,13 +1285,13 @@ impl<'src> Compiler<'src> {
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.fun.chunk.write_byte(byte, self.line());
+        self.fun.chunk.write_byte(byte, self.location.line, self.location.span);
     }
 
     fn emit_jump(&mut self, instr: u8) -> usize {
         self.emit_byte(instr);
-        self.emit_bytes(0xff, 0xff);
-        self.fun.chunk.code.len() - 2
+        self.emit_u24(U24_MAX);
+        self.fun.chunk.code.len() - 3
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -1344,54 +1302,101 @@ impl<'src> Compiler<'src> {
     fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(Opcode::Loop as u8);
 
-        let offset = self.fun.chunk.code.len() - loop_start + 2;
-        if offset > u16::MAX as usize {
-            self.parser.error_at_current("Loop body too large");
+        let offset = self.fun.chunk.code.len() - loop_start + 3;
+        if offset > U24_MAX {
+            self.error("Loop body too large");
         }
 
-        let offset = offset as u16;
-        self.emit_byte(((offset >> 8) & 0xff) as u8);
-        self.emit_byte((offset & 0xff) as u8);
+        self.emit_u24(offset);
     }
 }
 
-fn unescape_string(s: &str) -> String {
-    let mut result = String::new();
-    let mut chars = s.chars().peekable();
-    while let Some(c) = chars.next() {
-        if c == '\\' {
-            if let Some(&next_c) = chars.peek() {
-                match next_c {
-                    '"' => {
-                        result.push('"');
-                        chars.next();
-                    }
-                    '\\' => {
-                        result.push('\\');
-                        chars.next();
-                    }
-                    'n' => {
-                        result.push('\n');
-                        chars.next();
-                    }
-                    't' => {
-                        result.push('\t');
-                        chars.next();
-                    }
-                    'r' => {
-                        result.push('\r');
-                        chars.next();
-                    }
-                    _ => {
-                        result.push('\\');
-                    }
-                }
-            } else {
-                result.push('\\');
+impl Context<'_> {
+    fn error(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.diagnostic_at(token, Severity::Error, None, message, None);
+    }
+
+    /// Add a diagnostic without entering panic mode, as the code around it still compiles
+    fn diagnostic_at(&mut self, token: &Token, severity: Severity, lint: Option<Lint>, message: &str, hint: Option<String>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            hint,
+            file: None,
+            lint,
+        });
+    }
+
+    /// Report a problem found by `lint` at `token`, as a warning unless the lint is allowed or denied
+    fn warn(&mut self, lint: Lint, token: &Token, message: &str, hint: Option<String>) {
+        if let Some(severity) = self.lints.config.severity(lint) {
+            self.diagnostic_at(token, severity, Some(lint), message, hint);
+        }
+    }
+
+    /// Whether the global `name` exists when the program runs, as far as the compiler knows
+    fn is_known_global(&self, name: &str) -> bool {
+        // Namespaces of modules are globals too, and the globals of native modules that weren't loaded are unknown
+        let namespace = name.split_once('.').map_or(name, |(namespace, _)| namespace);
+        self.declared_globals.contains(name)
+            || self.resolution.namespaces.contains(name)
+            || self.lints.native_modules.contains(namespace)
+            || native::is_builtin_global(name)
+    }
+
+    /// Report the lints that need every global of the file to be known, once the whole file is compiled
+    fn check_globals(&mut self) {
+        for token in std::mem::take(&mut self.resolution.global_assignments) {
+            let name = token.source.as_ref();
+            if self.is_known_global(name) {
+                continue;
             }
-        } else {
-            result.push(c);
+            let message = format!("Assignment to undeclared global '{name}'");
+            let hint = format!("Declare it with 'var {name}' first, assigning to an undeclared global fails at runtime");
+            self.warn(Lint::UndeclaredGlobal, &token, &message, Some(hint));
+        }
+
+        for token in std::mem::take(&mut self.resolution.global_reads) {
+            let name = token.source.as_ref();
+            if self.is_known_global(name) {
+                continue;
+            }
+            let message = format!("Undefined variable '{name}'");
+            let hint = "Declare it with 'var' or 'function', or check the spelling".to_string();
+            self.warn(Lint::UndefinedVariable, &token, &message, Some(hint));
+        }
+
+        for (token, arg_count) in std::mem::take(&mut self.resolution.builtin_calls) {
+            let name = token.source.as_ref();
+            let Some((min, max)) = native::builtin_arity(name) else {
+                continue;
+            };
+            if self.declared_globals.contains(name) || (arg_count >= min && max.is_none_or(|max| arg_count <= max)) {
+                continue;
+            }
+            let expected = match max {
+                Some(max) if max == min => arguments(min),
+                Some(max) => format!("{min} to {max} arguments"),
+                None => format!("at least {}", arguments(min)),
+            };
+            let message = format!("'{name}' expects {expected} but got {arg_count}");
+            self.warn(Lint::NativeArity, &token, &message, None);
         }
     }
-    result
+}
+
+/// Count of arguments, like "1 argument" or "2 arguments"
+fn arguments(count: usize) -> String {
+    match count {
+        1 => "1 argument".to_string(),
+        _ => format!("{count} arguments"),
+    }
 }
@@ -1,133 +1,199 @@
-use crate::{chunk::Chunk, common::Opcode, dbg, dbgln, interner::Interner};
-
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner) -> usize {
-    dbg!("{offset:04} ");
-    dbg!("{:4} ", chunk.lines[&offset]);
-
-    let instruction = Opcode::try_from(chunk.code[offset]);
-    let Ok(instruction) = instruction else {
-        dbg!("Invalid opcode {:04}", chunk.code[offset],);
-        return offset + 1;
-    };
-
-    let ret: usize = match instruction {
-        Opcode::Constant
-        | Opcode::DefineGlobal
-        | Opcode::GetGlobal
-        | Opcode::SetGlobal
-        | Opcode::Class
-        | Opcode::Method
-        | Opcode::GetProperty
-        | Opcode::SetProperty => constant_instruction(chunk, instruction, offset, interner),
-        Opcode::Add
-        | Opcode::Return
-        | Opcode::Negate
-        | Opcode::Subtract
-        | Opcode::Multiply
-        | Opcode::Modulo
-        | Opcode::Divide
-        | Opcode::False
-        | Opcode::True
-        | Opcode::Nil
-        | Opcode::Equal
-        | Opcode::Greater
-        | Opcode::Less
-        | Opcode::Print
-        | Opcode::DeclareArray
-        | Opcode::Pop
-        | Opcode::Dup
-        | Opcode::GetReceiver
-        | Opcode::GetIndex
-        | Opcode::SetIndex
-        | Opcode::Not => simple_instruction(chunk, instruction, offset),
-
-        Opcode::Jump | Opcode::JumpIfFalse => jump_instruction(chunk, instruction, 1, offset),
-
-        Opcode::Loop => jump_instruction(chunk, instruction, -1, offset),
-
-        Opcode::GetLocal | Opcode::SetLocal | Opcode::Call | Opcode::ArrayLiteral => byte_instruction(chunk, instruction, offset),
-        Opcode::DefaultArg => default_arg_instruction(chunk, instruction, offset),
-    };
-
-    dbgln!("");
-
-    ret
+use std::fmt::Write;
+
+use crate::{
+    chunk::Chunk,
+    common::Opcode,
+    dbgln,
+    fun::Fun,
+    interner::Interner,
+    value::{value_as_string, Value},
+};
+
+/// An instruction decoded from a chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    /// Offset of the instruction that follows this one
+    pub next: usize,
 }
 
-///////////////////////////
-
-#[cfg(feature = "tracing")]
-fn jump_instruction(chunk: &Chunk, instruction: Opcode, sign: i32, offset: usize) -> usize {
-    let jump = chunk.code[offset + 1] as u16 | (chunk.code[offset + 2] as u16) << 8;
-    let mut target: isize = offset as isize + 3;
-    target += (sign * jump as i32) as isize;
-    dbgln!("{instruction} {jump} -> {}", target);
-    offset + 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// Index into the constants of the chunk
+    Constant(usize),
+    /// A stack slot, upvalue index or count, named after what it is
+    Number { name: &'static str, value: usize },
+    /// Offset of the instruction a jump lands on
+    Jump { name: &'static str, target: usize },
+    /// Variable captured by a closure, either a local of the enclosing function or one of its upvalues
+    Capture { is_local: bool, index: usize },
 }
 
-#[cfg(not(feature = "tracing"))]
-fn jump_instruction(_chunk: &Chunk, _instruction: Opcode, _sign: i32, offset: usize) -> usize {
-    offset + 3
+/// Decode the instruction at `offset`, or `None` if it isn't a valid opcode
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Option<Instruction> {
+    let opcode = Opcode::try_from(chunk.code[offset]).ok()?;
+    let code = &chunk.code[offset + 1..];
+    let end = offset + 1 + opcode.operand_len();
+    let index = match opcode.operand_len() {
+        0 => 0,
+        _ if opcode.is_long() => read_u24(code),
+        _ => code[0] as usize,
+    };
+    let number = |name, value| Operand::Number { name, value };
+    let jump = |name, target| Operand::Jump { name, target };
+
+    // Jumps are relative to the end of the operand they are read from
+    let mut operands = Vec::new();
+    match opcode {
+        _ if opcode.takes_constant() => operands.push(Operand::Constant(index)),
+        Opcode::GetLocal | Opcode::GetLocalLong | Opcode::SetLocal | Opcode::SetLocalLong => operands.push(number("slot", index)),
+        Opcode::GetUpvalue | Opcode::SetUpvalue => operands.push(number("upvalue", index)),
+        Opcode::Call => operands.push(number("args", index)),
+        Opcode::ArrayLiteral | Opcode::Interpolate => operands.push(number("items", index)),
+        Opcode::MapLiteral => operands.push(number("entries", index)),
+        Opcode::Jump | Opcode::JumpIfFalse => operands.push(jump("", end + read_u24(code))),
+        Opcode::Loop => operands.push(jump("", end - read_u24(code))),
+        Opcode::DefaultArg => {
+            operands.push(number("arg", index));
+            operands.push(jump("", end + read_u24(&code[1..])));
+        }
+        Opcode::Try => {
+            // A zero offset means there is no catch or finally block
+            for (name, jump_offset, base) in [("catch", 0, offset + 4), ("finally", 3, end)] {
+                let distance = read_u24(&code[jump_offset..]);
+                if distance != 0 {
+                    operands.push(jump(name, base + distance));
+                }
+            }
+        }
+        Opcode::Leave => {
+            operands.push(number("tries", index));
+            operands.push(number("slots", read_u24(&code[1..])));
+        }
+        Opcode::IterNext => {
+            operands.push(number("slot", read_u24(code)));
+            operands.push(number("vars", code[3] as usize));
+            operands.push(jump("exit", end + read_u24(&code[4..])));
+        }
+        _ => (),
+    }
+
+    let mut next = end;
+    if matches!(opcode, Opcode::Closure | Opcode::ClosureLong) {
+        let capture_count = code[opcode.operand_len() - 1];
+        for _ in 0..capture_count {
+            operands.push(Operand::Capture {
+                is_local: chunk.code[next] == 1,
+                index: chunk.code[next + 1] as usize,
+            });
+            next += 2;
+        }
+    }
+
+    Some(Instruction {
+        offset,
+        line: chunk.lines[&offset],
+        opcode,
+        operands,
+        next,
+    })
 }
 
-///////////////////////////
-
-#[allow(unused_variables)]
-fn simple_instruction(_chunk: &Chunk, instruction: Opcode, offset: usize) -> usize {
-    dbg!("{instruction}");
-    offset + 1
+impl Instruction {
+    /// The opcode and its operands, with constants resolved through the interner
+    pub fn text(&self, chunk: &Chunk, functions: &[Fun], interner: &Interner) -> String {
+        let mut text = self.opcode.to_string();
+        for operand in &self.operands {
+            text.push(' ');
+            text.push_str(&operand.text(chunk, functions, interner));
+        }
+        text
+    }
 }
 
-///////////////////////////
-
-#[cfg(feature = "tracing")]
-fn constant_instruction(chunk: &Chunk, instruction: Opcode, offset: usize, interner: &Interner) -> usize {
-    use crate::value::print_value;
-
-    let constant_idx: usize = chunk.code[offset + 1].into();
-    dbg!("{instruction} Idx {constant_idx} ");
-    print_value(&chunk.constants[constant_idx], interner);
-
-    offset + 2
+impl Operand {
+    pub fn text(&self, chunk: &Chunk, functions: &[Fun], interner: &Interner) -> String {
+        match *self {
+            Operand::Constant(index) => format!("{index} {}", constant_text(&chunk.constants[index], functions, interner)),
+            Operand::Number { name, value } => format!("{name} {value}"),
+            Operand::Jump { name: "", target } => format!("-> {target:04}"),
+            Operand::Jump { name, target } => format!("{name} -> {target:04}"),
+            Operand::Capture { is_local: true, index } => format!("(local {index})"),
+            Operand::Capture { is_local: false, index } => format!("(upvalue {index})"),
+        }
+    }
 }
 
-#[cfg(not(feature = "tracing"))]
-fn constant_instruction(_chunk: &Chunk, _instruction: Opcode, offset: usize, _interner: &Interner) -> usize {
-    offset + 2
+/// Show a constant the way it appears in source, so strings are quoted and functions are named
+pub fn constant_text(value: &Value, functions: &[Fun], interner: &Interner) -> String {
+    match value {
+        Value::Str(id) => format!("{:?}", interner.lookup(id)),
+        Value::Identifier(id) => interner.lookup(id).to_string(),
+        Value::Function(idx) if *idx < functions.len() => format!("<Function {}>", function_name(&functions[*idx], interner)),
+        other => value_as_string(other, interner),
+    }
 }
 
-///////////////////////////
-
-#[cfg(feature = "tracing")]
-fn byte_instruction(chunk: &Chunk, instruction: Opcode, offset: usize) -> usize {
-    let slot = chunk.code[offset + 1];
-    dbg!("{instruction} {slot}");
-    offset + 2
+/// Name of a function for listings. Only the script has no name.
+pub fn function_name<'a>(fun: &Fun, interner: &'a Interner) -> &'a str {
+    match &fun.name {
+        Some(name) => interner.lookup(name),
+        None => "<script>",
+    }
 }
 
-#[cfg(not(feature = "tracing"))]
-fn byte_instruction(_chunk: &Chunk, _instruction: Opcode, offset: usize) -> usize {
-    offset + 2
+/// List every instruction of every function with its offset and line, the script last as it is in `functions`
+pub fn disassemble_program(functions: &[Fun], interner: &Interner) -> String {
+    let mut listing = String::new();
+    for (idx, fun) in functions.iter().enumerate() {
+        let name = function_name(fun, interner);
+        let _ = writeln!(
+            listing,
+            "== {name} (function {idx}, arity {}, upvalues {}) ==",
+            fun.arity, fun.upvalue_count
+        );
+
+        let chunk = &fun.chunk;
+        let mut offset = 0;
+        let mut last_line = None;
+        while offset < chunk.code.len() {
+            let Some(instruction) = decode_instruction(chunk, offset) else {
+                let _ = writeln!(listing, "{offset:04}    ? Invalid opcode {}", chunk.code[offset]);
+                offset += 1;
+                continue;
+            };
+            // Like clox, a bar marks instructions on the same line as the one before
+            let line = match last_line == Some(instruction.line) {
+                true => "|".to_string(),
+                false => instruction.line.to_string(),
+            };
+            let _ = writeln!(listing, "{offset:04} {line:>4} {}", instruction.text(chunk, functions, interner));
+            last_line = Some(instruction.line);
+            offset = instruction.next;
+        }
+        listing.push('\n');
+    }
+    listing
 }
 
-///////////////////////////
-
-#[cfg(feature = "tracing")]
-fn default_arg_instruction(chunk: &Chunk, instruction: Opcode, offset: usize) -> usize {
-    let arg_index = chunk.code[offset + 1];
-    let jump = chunk.code[offset + 2] as u16 | (chunk.code[offset + 3] as u16) << 8;
-    let target = offset + 4 + jump as usize;
-    dbg!("{instruction} ArgIndex {arg_index} Jump {jump} -> {target}");
-    offset + 4
+#[allow(unused_variables)]
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner) -> usize {
+    let Some(instruction) = decode_instruction(chunk, offset) else {
+        dbgln!("{offset:04} Invalid opcode {:04}", chunk.code[offset]);
+        return offset + 1;
+    };
+    dbgln!("{offset:04} {:4} {}", instruction.line, instruction.text(chunk, &[], interner));
+    instruction.next
 }
 
-#[cfg(not(feature = "tracing"))]
-fn default_arg_instruction(_chunk: &Chunk, _instruction: Opcode, offset: usize) -> usize {
-    offset + 4
+/// Read a three byte operand
+fn read_u24(code: &[u8]) -> usize {
+    (code[0] as usize) << 16 | (code[1] as usize) << 8 | code[2] as usize
 }
 
-///////////////////////////
-
 #[cfg(feature = "tracing")]
 pub fn line() {
     dbgln!("");
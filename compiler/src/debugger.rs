@@ -0,0 +1,317 @@
+use std::rc::Rc;
+
+use crate::{
+    ast::Expr,
+    common::Opcode,
+    compiler::binary_opcodes,
+    interner::Interner,
+    parser,
+    scanner::TokenType,
+    value::{value_as_string, Value},
+    vm::{self, is_falsey},
+};
+
+/// A line that pauses the program before its first instruction runs
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    /// Imported file the line is in, or `None` for the main program. Matches any path ending with it.
+    pub file: Option<String>,
+    pub line: usize,
+    /// Expression that has to be truthy for the program to pause, as it was written
+    pub condition: Option<String>,
+    expr: Option<Expr>,
+}
+
+impl Breakpoint {
+    /// Whether the breakpoint is on `line` of the function compiled from `file`
+    pub fn is_at(&self, file: Option<&str>, line: usize) -> bool {
+        let same_file = match (&self.file, file) {
+            (None, None) => true,
+            (Some(breakpoint_file), Some(file)) => std::path::Path::new(file).ends_with(breakpoint_file),
+            _ => false,
+        };
+        same_file && self.line == line
+    }
+}
+
+/// The breakpoints set in a debugger, numbered in the order they were added
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    /// Add a breakpoint, returning its id, or why its condition isn't a valid expression
+    pub fn add(&mut self, file: Option<String>, line: usize, condition: Option<String>) -> Result<usize, String> {
+        let expr = condition.as_deref().map(parse_condition).transpose()?;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id: self.next_id,
+            file,
+            line,
+            condition,
+            expr,
+        });
+        Ok(self.next_id)
+    }
+
+    /// Remove the breakpoint with `id`, returning whether there was one
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.list.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+}
+
+/// Why the program paused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PauseReason {
+    /// Before the first instruction of the program runs
+    Entry,
+    /// A step finished
+    Step,
+    /// The program reached the breakpoint with this id
+    Breakpoint(usize),
+    /// The condition of the breakpoint with this id couldn't be evaluated, with why
+    ConditionError(usize, String),
+    /// The frontend asked to pause the running program
+    Requested,
+}
+
+/// A variable and its value, shown the way the program would print it except that strings are quoted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+}
+
+/// An active function call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// Name of the function, or `<script>` for top level code
+    pub function: String,
+    /// Imported file the function is from, or `None` for the main program
+    pub file: Option<String>,
+    pub line: usize,
+    /// Local variables in scope, in the order of their slots
+    pub locals: Vec<Variable>,
+}
+
+/// The state of a paused program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pause {
+    pub reason: PauseReason,
+    /// Active calls, most recent first
+    pub frames: Vec<StackFrame>,
+    /// Globals defined by the program, sorted by name. Builtin functions are left out.
+    pub globals: Vec<Variable>,
+}
+
+/// How to continue a paused program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until a breakpoint
+    Continue,
+    /// Pause at the next line run, in any function
+    StepInto,
+    /// Pause at the next line of the current function, or once it returns
+    StepOver,
+    /// Pause once the current function returns
+    StepOut,
+}
+
+/// What a debugger shows the user, and how it asks them what to do next
+pub trait DebugFrontend {
+    /// Called whenever the program pauses. Breakpoints can be changed before returning how to resume.
+    fn paused(&mut self, pause: &Pause, breakpoints: &mut Breakpoints) -> Resume;
+
+    /// Called while the program runs, whenever it moves on to another line.
+    /// Breakpoints can be changed, and returning true pauses the program before the line runs.
+    fn poll(&mut self, _breakpoints: &mut Breakpoints) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Entry,
+    Continue,
+    Into,
+    /// Stepping over or out of the call this many frames deep
+    Over(usize),
+    Out(usize),
+}
+
+/// Pauses a program run by the VM at breakpoints and after steps, handing it to a frontend while paused
+pub struct Debugger {
+    pub breakpoints: Breakpoints,
+    frontend: Box<dyn DebugFrontend>,
+    step: Step,
+    /// Function and line of the instruction last run in each active frame, outermost first
+    lines: Vec<Option<(usize, usize)>>,
+}
+
+impl Debugger {
+    /// A debugger that pauses before the first instruction of the program runs
+    pub fn new(frontend: Box<dyn DebugFrontend>) -> Debugger {
+        Debugger {
+            breakpoints: Breakpoints::default(),
+            frontend,
+            step: Step::Entry,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Note that the instruction about to run is on `line` of the function `fun_idx`, in the frame `depth` deep.
+    /// Returns whether the frame has moved on to another line.
+    pub(crate) fn enter_line(&mut self, depth: usize, fun_idx: usize, line: usize) -> bool {
+        self.lines.resize(depth, None);
+        let location = Some((fun_idx, line));
+        let last = std::mem::replace(&mut self.lines[depth - 1], location);
+        last != location
+    }
+
+    /// Why the current step pauses before the instruction about to run, if it does
+    pub(crate) fn step_pause(&self, depth: usize, new_line: bool) -> Option<PauseReason> {
+        let done = match self.step {
+            Step::Entry => return Some(PauseReason::Entry),
+            Step::Continue => false,
+            Step::Into => new_line,
+            Step::Over(start) => depth < start || (depth == start && new_line),
+            Step::Out(start) => depth < start,
+        };
+        done.then_some(PauseReason::Step)
+    }
+
+    /// Whether the frontend asks to pause the running program
+    pub(crate) fn poll(&mut self) -> bool {
+        self.frontend.poll(&mut self.breakpoints)
+    }
+
+    /// Hand the paused program to the frontend, and get ready to resume it the way it asks
+    pub(crate) fn pause(&mut self, pause: &Pause) {
+        let depth = pause.frames.len();
+        self.step = match self.frontend.paused(pause, &mut self.breakpoints) {
+            Resume::Continue => Step::Continue,
+            Resume::StepInto => Step::Into,
+            Resume::StepOver => Step::Over(depth),
+            Resume::StepOut => Step::Out(depth),
+        };
+    }
+}
+
+/// Evaluate the condition of a breakpoint, looking variables up with `lookup`.
+/// Returns whether it is truthy, or `None` if the breakpoint has no condition.
+pub(crate) fn condition_holds(
+    breakpoint: &Breakpoint,
+    lookup: &mut dyn FnMut(&str, &mut Interner) -> Option<Value>,
+    interner: &mut Interner,
+) -> Option<Result<bool, String>> {
+    let expr = breakpoint.expr.as_ref()?;
+    Some(evaluate(expr, lookup, interner).map(|value| !is_falsey(&value)))
+}
+
+/// Show a value for the debugger, with strings quoted so they can be told apart from other values
+pub(crate) fn value_text(value: &Value, interner: &Interner) -> String {
+    match value {
+        Value::Str(id) => format!("{:?}", interner.lookup(id)),
+        other => value_as_string(other, interner),
+    }
+}
+
+/// Why a condition can't be evaluated, as conditions mustn't change the program
+const CONDITION_ERROR: &str = "Conditions can only read variables and fields, and combine them with operators";
+
+/// Parse a breakpoint condition with the parser of the language, checking it only uses what `evaluate` supports
+fn parse_condition(source: &str) -> Result<Expr, String> {
+    let (expr, _, diagnostics) = parser::parse_expression(Rc::from(source));
+    if let Some(diagnostic) = diagnostics.first() {
+        return Err(diagnostic.message.clone());
+    }
+    is_readonly(&expr).then_some(expr).ok_or_else(|| CONDITION_ERROR.to_string())
+}
+
+/// Whether `expr` only reads variables and fields, without calling, assigning or indexing
+fn is_readonly(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) | Expr::Number { .. } | Expr::String { .. } => true,
+        Expr::Interpolation { expressions, .. } => expressions.iter().all(is_readonly),
+        Expr::Variable(variable) => variable.index.is_none(),
+        Expr::Get { object: expr, .. } | Expr::Unary { operand: expr, .. } | Expr::Grouping { expr, .. } => is_readonly(expr),
+        Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => is_readonly(left) && is_readonly(right),
+        _ => false,
+    }
+}
+
+/// Evaluate a condition the way the VM runs the code compiled from it
+fn evaluate(expr: &Expr, lookup: &mut dyn FnMut(&str, &mut Interner) -> Option<Value>, interner: &mut Interner) -> Result<Value, String> {
+    let value = match expr {
+        Expr::Literal(token) => match token.typ {
+            TokenType::True => Value::Bool(true),
+            TokenType::False => Value::Bool(false),
+            _ => Value::Nil,
+        },
+        Expr::Number { value, .. } => Value::Number(*value),
+        Expr::String { value, .. } => Value::Str(interner.intern(value)),
+        Expr::Interpolation { strings, expressions } => {
+            let mut text = String::new();
+            for (i, (_, string)) in strings.iter().enumerate() {
+                text.push_str(string);
+                if let Some(expr) = expressions.get(i) {
+                    let value = evaluate(expr, lookup, interner)?;
+                    text.push_str(&value_as_string(&value, interner));
+                }
+            }
+            Value::Str(interner.intern(&text))
+        }
+        Expr::Variable(variable) => {
+            let name = variable.name();
+            lookup(&name, interner).ok_or_else(|| format!("Undefined variable '{name}'"))?
+        }
+        Expr::Get { object, name } => {
+            let object = evaluate(object, lookup, interner)?;
+            vm::get_property(object, interner.intern(&name.source))?
+        }
+        Expr::Unary { operator, operand } => {
+            let value = evaluate(operand, lookup, interner)?;
+            match operator.typ {
+                TokenType::Bang => Value::Bool(is_falsey(&value)),
+                _ => vm::negate(value)?,
+            }
+        }
+        Expr::Binary { left, operator, right } => {
+            let left = evaluate(left, lookup, interner)?;
+            let right = evaluate(right, lookup, interner)?;
+            let Some((&opcode, rest)) = binary_opcodes(operator.typ).split_first() else {
+                return Err(format!("Unexpected operator '{}'", operator.source));
+            };
+            let value = vm::binary_op(opcode, left, right, interner)?;
+            match rest {
+                [Opcode::Not] => Value::Bool(is_falsey(&value)),
+                _ => value,
+            }
+        }
+        // `and` and `or` give back the operand that decided them
+        Expr::Logical { left, operator, right } => {
+            let left = evaluate(left, lookup, interner)?;
+            match operator.typ {
+                TokenType::And if is_falsey(&left) => left,
+                TokenType::Or if !is_falsey(&left) => left,
+                _ => evaluate(right, lookup, interner)?,
+            }
+        }
+        Expr::Grouping { expr, .. } => evaluate(expr, lookup, interner)?,
+        _ => return Err(CONDITION_ERROR.to_string()),
+    };
+    Ok(value)
+}
@@ -0,0 +1,131 @@
+use std::fmt::{self, Write};
+
+use crate::lint::Lint;
+
+/// Byte range of a token in the source it was scanned from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The program can't be compiled
+    Error,
+    /// Probably a mistake, but the program still compiles
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A problem in the source, with where it is and how it might be fixed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    /// Column of the first character of the span, counting characters from 1
+    pub column: usize,
+    pub span: Span,
+    pub hint: Option<String>,
+    /// Imported file the problem is in, or `None` for the file being compiled
+    pub file: Option<String>,
+    /// Lint that reported the warning, which can be allowed or suppressed
+    pub lint: Option<Lint>,
+}
+
+impl Diagnostic {
+    /// A diagnostic for `span` in `source`, finding its line and column
+    pub fn new(source: &str, span: Span, message: &str) -> Diagnostic {
+        let start = span.start.min(source.len());
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
+            hint: None,
+            file: None,
+            lint: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Diagnostic {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Number of characters the span covers on its first line, which is the width of the underline
+    pub fn width(&self, source: &str) -> usize {
+        let start = self.span.start.min(source.len());
+        let end = self.span.end.clamp(start, source.len());
+        let text = &source[start..end];
+        text.split('\n').next().unwrap_or_default().chars().count()
+    }
+
+    /// The message followed by the code frame
+    pub fn render(&self, source: &str) -> String {
+        format!("{self}\n{}", self.code_frame(source))
+    }
+
+    /// The offending line of `source` with the span underlined, followed by the hint if there is one
+    pub fn code_frame(&self, source: &str) -> String {
+        let text = source.lines().nth(self.line - 1).unwrap_or_default();
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        // Keep tabs in the padding so the carets line up with the text above them
+        let pad = |c| if c == '\t' { '\t' } else { ' ' };
+        let padding: String = text.chars().take(self.column - 1).map(pad).collect();
+        let underline = "^".repeat(self.width(source).max(1));
+
+        let mut frame = format!("{gutter} |\n{} | {text}\n{gutter} | {padding}{underline}", self.line);
+        if let Some(hint) = &self.hint {
+            let _ = write!(frame, "\n{gutter} = hint: {hint}");
+        }
+        frame
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        if let Some(file) = &self.file {
+            write!(f, "{file}, ")?;
+        }
+        write!(f, "line {}, column {}] {}", self.line, self.column, self.severity)?;
+        if let Some(lint) = self.lint {
+            write!(f, " ({})", lint.name())?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Render every diagnostic with its code frame. `source` is the file that was compiled,
+/// and diagnostics in files it imported are shown with those files read from disk.
+pub fn render_all(diagnostics: &[Diagnostic], source: &str) -> String {
+    let rendered: Vec<String> = diagnostics
+        .iter()
+        .map(|diagnostic| match &diagnostic.file {
+            None => diagnostic.render(source),
+            Some(file) => match std::fs::read_to_string(file) {
+                Ok(source) => diagnostic.render(&source),
+                Err(_) => diagnostic.to_string(),
+            },
+        })
+        .collect();
+    rendered.join("\n")
+}
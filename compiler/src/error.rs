@@ -0,0 +1,132 @@
+use std::fmt;
+
+use crate::diagnostic::{Diagnostic, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An error raised by the VM, like a type error or a failed native call
+    Error,
+    /// A value thrown by the program that was never caught
+    UncaughtException,
+}
+
+/// One function call in a traceback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Name of the function, or `<script>` for top level code
+    pub function: String,
+    pub line: usize,
+    /// Code being run in the frame. Only known for frames of errors raised by the VM.
+    pub span: Option<Span>,
+    /// Imported file the function is from, or `None` for the main program
+    pub file: Option<String>,
+}
+
+impl TraceFrame {
+    /// Read back a frame from a traceback line, as stored in the `traceback` of an error
+    pub fn parse(line: &str) -> Option<TraceFrame> {
+        let (line_number, function) = line.strip_prefix("[line ")?.split_once("] in ")?;
+        Some(TraceFrame {
+            function: function.to_string(),
+            line: line_number.trim().parse().ok()?,
+            span: None,
+            file: None,
+        })
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {:3}] in {}", self.line, self.function)
+    }
+}
+
+/// An error that ended a program. The traceback starts with the most recent call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub traceback: Vec<TraceFrame>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Runtime error: {}", self.message)?;
+        write!(f, "Traceback (most recent call first):")?;
+        for frame in &self.traceback {
+            write!(f, "\n{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl RuntimeError {
+    /// Diagnostic pointing at the code that raised the error, if known.
+    /// `source` has to be the file of the innermost frame.
+    pub fn diagnostic(&self, source: &str) -> Option<Diagnostic> {
+        let span = self.traceback.first()?.span?;
+        let diagnostic = Diagnostic::new(source, span, &self.message);
+        Some(match hint(&self.message) {
+            Some(hint) => diagnostic.with_hint(hint),
+            None => diagnostic,
+        })
+    }
+
+    /// Same as `Display`, with the code that raised the error shown under the message
+    pub fn render(&self, source: &str) -> String {
+        let Some(diagnostic) = self.diagnostic(source) else {
+            return self.to_string();
+        };
+        let frames: Vec<String> = self.traceback.iter().map(|frame| frame.to_string()).collect();
+        format!(
+            "Runtime error: {}\n{}\nTraceback (most recent call first):\n{}",
+            self.message,
+            diagnostic.code_frame(source),
+            frames.join("\n")
+        )
+    }
+}
+
+/// Suggestion for the runtime errors that usually come from a typo or a missing check
+fn hint(message: &str) -> Option<&'static str> {
+    if message.starts_with("Undefined variable") {
+        Some("Declare it with 'var' before it is used, or check the spelling")
+    } else if message.starts_with("Only instances and maps have properties") {
+        Some("Check for nil, or a value of the wrong type, before reading its properties")
+    } else {
+        None
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Why running a program failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpretError {
+    /// The program didn't compile. The errors were already reported by the compiler.
+    Compile(Vec<Diagnostic>),
+    /// A compiled file couldn't be written or loaded
+    Bytecode(String),
+    /// The file of the program couldn't be read
+    Io(String),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile(_) => write!(f, "Compilation failed"),
+            InterpretError::Bytecode(msg) => write!(f, "Bytecode error: {msg}"),
+            InterpretError::Io(msg) => write!(f, "{msg}"),
+            InterpretError::Runtime(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+impl From<RuntimeError> for InterpretError {
+    fn from(error: RuntimeError) -> Self {
+        InterpretError::Runtime(error)
+    }
+}
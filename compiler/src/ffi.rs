@@ -262,10 +262,10 @@ fn ffi_to_value(ffi: &LoxFfiValue, interner: &mut Interner) -> Value {
         LoxValueType::Map => {
             let ptr = unsafe { ffi.as_val.map };
             if ptr.is_null() {
-                Value::Map(std::rc::Rc::new(std::cell::RefCell::new(rustc_hash::FxHashMap::default())))
+                Value::Map(std::rc::Rc::new(std::cell::RefCell::new(LoxMap::default())))
             } else {
                 let ffi_map = unsafe { Box::from_raw(ptr) };
-                let mut map = rustc_hash::FxHashMap::default();
+                let mut map = LoxMap::default();
                 if !ffi_map.entries.is_null() && ffi_map.length > 0 {
                     let vec = unsafe { Vec::from_raw_parts(ffi_map.entries, ffi_map.length as usize, ffi_map.capacity as usize) };
                     for entry in &vec {
@@ -0,0 +1,359 @@
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::scanner::{Scanner, Token, TokenType};
+
+const INDENT: &str = "    ";
+
+/// What a bracket encloses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bracket {
+    /// Statements, or the methods of a class
+    Block,
+    /// Arguments, parameters or a grouped expression
+    Paren,
+    /// Index or slice of a value
+    Index,
+    Array,
+    Map,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Open {
+    bracket: Bracket,
+    /// Whether the contents start on a line of their own, which indents them by a level
+    broken: bool,
+}
+
+/// Format `source` in the canonical style: four spaces of indentation, braces on the line of the statement they belong to,
+/// one statement per line and single spaces around binary operators. Comments are kept, and so are blank lines,
+/// but never more than one in a row. Line breaks inside a statement are kept too, and the lines they start are indented.
+/// Returns diagnostics instead if `source` can't be scanned or has unbalanced brackets,
+/// or if formatting it would change its tokens, which would be a bug of the formatter.
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let tokens = tokens(source)?;
+    let errors = unbalanced(source, &tokens);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut formatter = Formatter {
+        source,
+        out: String::with_capacity(source.len()),
+        open: Vec::new(),
+        opened: None,
+        closed: None,
+        after_prefix: false,
+        class_body: false,
+        embedded: 0,
+    };
+    formatter.write(&tokens);
+
+    // Formatting only changes the space between tokens, so anything else is a bug of the formatter
+    let same_token = |a: &Token, b: &Token| a.typ == b.typ && a.source == b.source;
+    let formatted_tokens = self::tokens(&formatter.out).unwrap_or_default();
+    if formatted_tokens.len() != tokens.len() || !tokens.iter().zip(&formatted_tokens).all(|(a, b)| same_token(a, b)) {
+        let message = "Formatting would change the program, so it is left as it is";
+        return Err(vec![Diagnostic::new(source, Span::default(), message)]);
+    }
+    Ok(formatter.out)
+}
+
+/// Every token of `source`, or a diagnostic for each token that can't be scanned
+fn tokens(source: &str) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut scanner = Scanner::new(Rc::from(source));
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.typ {
+            TokenType::EOF => break,
+            TokenType::Error => errors.push(Diagnostic::new(source, token.span, &token.source)),
+            _ => tokens.push(token),
+        }
+    }
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+/// A diagnostic for each bracket without a matching one, as the indentation of the code in them would be wrong
+fn unbalanced(source: &str, tokens: &[Token]) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    let mut open: Vec<&Token> = Vec::new();
+    for token in tokens {
+        let opener = match token.typ {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => {
+                open.push(token);
+                continue;
+            }
+            TokenType::RightParen => "(",
+            TokenType::RightBrace => "{",
+            TokenType::RightBracket => "[",
+            _ => continue,
+        };
+        if open.last().is_some_and(|last| &*last.source == opener) {
+            open.pop();
+        } else {
+            errors.push(Diagnostic::new(source, token.span, &format!("Unmatched '{}'.", token.source)));
+        }
+    }
+    for token in open {
+        errors.push(Diagnostic::new(source, token.span, &format!("Unclosed '{}'.", token.source)));
+    }
+    errors
+}
+
+fn is_closer(typ: TokenType) -> bool {
+    matches!(typ, TokenType::RightBrace | TokenType::RightParen | TokenType::RightBracket)
+}
+
+struct Formatter<'src> {
+    source: &'src str,
+    out: String,
+    /// Brackets that are open, innermost last
+    open: Vec<Open>,
+    /// Bracket the last token opened, if it opened one
+    opened: Option<Bracket>,
+    /// Bracket the last token closed, if it closed one
+    closed: Option<Bracket>,
+    /// Whether the last token was a prefix operator, which is written right before its operand
+    after_prefix: bool,
+    /// Whether the next `{` opens the body of a class
+    class_body: bool,
+    /// How many expressions embedded in strings the last token is in, which are kept on one line
+    embedded: usize,
+}
+
+impl Formatter<'_> {
+    /// Whether a value can end with `token`, the last token written, so an operator after it is binary
+    /// and a bracket after it calls or indexes it. A `}` only ends a value when it closes a map literal.
+    fn ends_operand(&self, token: &Token) -> bool {
+        use TokenType::*;
+        match token.typ {
+            Identifier | Number | String | True | False | Nil | This | Super | RightParen | RightBracket | PlusPlus | MinusMinus => true,
+            RightBrace => self.closed == Some(Bracket::Map),
+            _ => false,
+        }
+    }
+
+    fn write(&mut self, tokens: &[Token]) {
+        let source = self.source;
+        let mut prev: Option<&Token> = None;
+        for (idx, token) in tokens.iter().enumerate() {
+            let gap = &source[prev.map_or(0, |prev| prev.span.end)..token.span.start];
+            let newlines = self.comments(gap, prev);
+            self.token(prev, token, tokens.get(idx + 1), newlines);
+            prev = Some(token);
+        }
+        self.comments(&source[prev.map_or(0, |prev| prev.span.end)..], prev);
+
+        self.out.truncate(self.out.trim_end().len());
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    fn newline(&mut self) {
+        self.out.truncate(self.out.trim_end_matches([' ', '\t']).len());
+        self.out.push('\n');
+    }
+
+    /// Write the comments in the `gap` between two tokens, returning how many line breaks follow the last of them
+    fn comments(&mut self, gap: &str, prev: Option<&Token>) -> usize {
+        let mut rest = gap;
+        while let Some(start) = rest.find("//") {
+            let newlines = rest[..start].matches('\n').count();
+            let end = rest[start..].find('\n').map_or(rest.len(), |end| start + end);
+            // A comment on the line of a token stays after it
+            if newlines == 0 && !self.at_line_start() {
+                self.out.push(' ');
+            } else {
+                self.start_line(prev, None, newlines);
+            }
+            self.out.push_str(rest[start..end].trim_end());
+            self.newline();
+            rest = &rest[end..];
+        }
+        rest.matches('\n').count()
+    }
+
+    /// Start a line for `next`, or for a comment without it, keeping a blank line where the source has any.
+    /// `newlines` is the number of line breaks before it in the source.
+    fn start_line(&mut self, prev: Option<&Token>, next: Option<&Token>, newlines: usize) {
+        if !self.at_line_start() {
+            self.newline();
+        }
+        let closes = next.is_some_and(|next| is_closer(next.typ));
+        if newlines > 1 && !self.out.is_empty() && self.opened.is_none() && !closes {
+            self.out.push('\n');
+        }
+
+        let continues = !closes && self.continues_statement(prev);
+        let levels = self.open.iter().filter(|open| open.broken).count() + usize::from(continues);
+        self.out.push_str(&INDENT.repeat(levels));
+    }
+
+    /// Whether a line after `prev` is in the middle of a statement, which indents it by one more level
+    fn continues_statement(&self, prev: Option<&Token>) -> bool {
+        let Some(prev) = prev else {
+            return false;
+        };
+        match prev.typ {
+            TokenType::Semicolon | TokenType::LeftBrace | TokenType::RightBrace => false,
+            TokenType::LeftParen | TokenType::LeftBracket => false,
+            // Items of brackets broken over lines start lines of their own
+            TokenType::Comma => !self.open.last().is_some_and(|open| open.broken),
+            _ => true,
+        }
+    }
+
+    fn token(&mut self, prev: Option<&Token>, token: &Token, next: Option<&Token>, newlines: usize) {
+        // Closing brackets line up with the line of the opening one
+        let closed = if is_closer(token.typ) { self.open.pop() } else { None };
+        let is_block = token.typ == TokenType::LeftBrace && self.opens_block(prev);
+
+        if self.at_line_start() || self.breaks_before(prev, token, is_block, closed, newlines) {
+            self.start_line(prev, Some(token), newlines);
+        } else if self.spaced(prev, token, closed) {
+            self.out.push(' ');
+        }
+        self.out.push_str(&token.source);
+
+        let after_operand = prev.is_some_and(|prev| self.ends_operand(prev));
+        self.after_prefix = match token.typ {
+            TokenType::Bang => true,
+            TokenType::Minus | TokenType::PlusPlus | TokenType::MinusMinus => !after_operand,
+            _ => false,
+        };
+        self.closed = closed.map(|open| open.bracket);
+        self.opened = match token.typ {
+            TokenType::LeftBrace if is_block => Some(Bracket::Block),
+            TokenType::LeftBrace => Some(Bracket::Map),
+            TokenType::LeftParen => Some(Bracket::Paren),
+            TokenType::LeftBracket if after_operand => Some(Bracket::Index),
+            TokenType::LeftBracket => Some(Bracket::Array),
+            _ => None,
+        };
+        if token.typ == TokenType::Class {
+            self.class_body = true;
+        }
+        // The `}` ending an embedded expression starts the rest of the string
+        match token.typ {
+            TokenType::Interpolation if !token.source.starts_with('}') => self.embedded += 1,
+            TokenType::String if token.source.starts_with('}') => self.embedded -= 1,
+            _ => (),
+        }
+
+        if let Some(bracket) = self.opened {
+            let next = next.map(|next| (next, &self.source[token.span.end..next.span.start]));
+            let broken = match (bracket, next) {
+                _ if self.embedded > 0 => false,
+                (_, None) => false,
+                // Blocks have their statements on lines of their own, unless they are empty
+                (Bracket::Block, Some((next, gap))) => next.typ != TokenType::RightBrace || gap.contains("//"),
+                (_, Some((next, gap))) => gap.contains('\n') && !is_closer(next.typ),
+            };
+            self.open.push(Open { bracket, broken });
+            if is_block {
+                self.class_body = false;
+            }
+        }
+    }
+
+    /// Whether a `{` after `prev` opens a block rather than a map
+    fn opens_block(&self, prev: Option<&Token>) -> bool {
+        let Some(prev) = prev else {
+            return true;
+        };
+        self.class_body
+            || match prev.typ {
+                TokenType::RightParen | TokenType::Else | TokenType::Try | TokenType::Catch | TokenType::Finally => true,
+                TokenType::Semicolon | TokenType::Arrow => true,
+                TokenType::LeftBrace => self.opened == Some(Bracket::Block),
+                TokenType::RightBrace => self.closed == Some(Bracket::Block),
+                _ => false,
+            }
+    }
+
+    /// Whether `token` starts a new line, because the style puts it there or because the source does
+    fn breaks_before(&self, prev: Option<&Token>, token: &Token, is_block: bool, closed: Option<Open>, newlines: usize) -> bool {
+        let Some(prev) = prev else {
+            return false;
+        };
+        if closed.is_some_and(|open| open.broken) {
+            return true;
+        }
+        if self.embedded > 0 {
+            return false;
+        }
+        let in_block = self.open.last().is_none_or(|open| open.bracket == Bracket::Block);
+        let joins_block = matches!(token.typ, TokenType::Else | TokenType::Catch | TokenType::Finally);
+        match prev.typ {
+            TokenType::Semicolon if in_block => return true,
+            // An empty pair of brackets was closed by this token
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::LeftBracket if closed.is_some() => return false,
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::LeftBracket => {
+                return self.open.last().is_some_and(|open| open.broken);
+            }
+            TokenType::RightBrace if self.closed == Some(Bracket::Block) => {
+                use TokenType::*;
+                let continues = matches!(token.typ, RightParen | RightBracket | Comma | Semicolon | Dot | LeftParen);
+                return !joins_block && !continues;
+            }
+            _ => (),
+        }
+
+        // Other line breaks of the source are kept, but not before tokens that end the line before
+        let ends_line = matches!(token.typ, TokenType::Semicolon | TokenType::Comma) || is_closer(token.typ);
+        newlines > 0 && !ends_line && !joins_block && !is_block
+    }
+
+    /// Whether `token` is written after a space when it is on the line of `prev`
+    fn spaced(&self, prev: Option<&Token>, token: &Token, closed: Option<Open>) -> bool {
+        let Some(prev) = prev else {
+            return false;
+        };
+        // Operators that would run into another operator, or into a comment
+        let last = prev.source.chars().last();
+        let first = token.source.chars().next();
+        if matches!(
+            (last, first),
+            (Some('-'), Some('-')) | (Some('+'), Some('+')) | (Some('/'), Some('/'))
+        ) {
+            return true;
+        }
+        if self.after_prefix {
+            return false;
+        }
+
+        let in_slice = self.open.last().is_some_and(|open| open.bracket == Bracket::Index);
+        let after_operand = self.ends_operand(prev);
+        match prev.typ {
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot | TokenType::Interpolation => return false,
+            TokenType::LeftBrace if self.opened == Some(Bracket::Map) || closed.is_some() => return false,
+            TokenType::Colon if in_slice => return false,
+            _ => (),
+        }
+        match token.typ {
+            TokenType::RightParen | TokenType::RightBracket | TokenType::Comma | TokenType::Semicolon => false,
+            TokenType::Dot | TokenType::Colon => false,
+            TokenType::RightBrace => !closed.is_some_and(|open| open.bracket == Bracket::Map),
+            // The rest of a string after an embedded expression
+            TokenType::String | TokenType::Interpolation => !token.source.starts_with('}'),
+            TokenType::LeftParen => {
+                let is_call = after_operand && !matches!(prev.typ, TokenType::PlusPlus | TokenType::MinusMinus);
+                !is_call && !matches!(prev.typ, TokenType::Fun | TokenType::Print | TokenType::RightBrace)
+            }
+            TokenType::LeftBracket => !after_operand,
+            TokenType::PlusPlus | TokenType::MinusMinus => !after_operand,
+            _ => true,
+        }
+    }
+}
@@ -1,13 +1,35 @@
+use std::rc::Rc;
+
 use crate::{chunk::Chunk, interner::StrId};
 
+/// Name given to functions created by function expressions and arrow functions
+pub const ANONYMOUS_FUNCTION_NAME: &str = "anonymous";
+
 #[derive(Debug)]
 pub struct Fun {
     pub arity: usize,
     pub min_arity: usize,
+    pub upvalue_count: usize,
     pub chunk: Chunk,
     pub name: Option<StrId>,
     pub native_imports: Vec<(String, String)>,
     pub help: Option<String>,
+    /// Path of the imported file the function was compiled from, or `None` for the main program
+    pub file: Option<Rc<str>>,
+    /// Names of the local variables, so debuggers can show them
+    pub locals: Vec<LocalName>,
+}
+
+/// A local variable of a function, and the code it is in scope for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalName {
+    pub name: String,
+    /// Stack slot of the variable, counting from the first parameter
+    pub slot: usize,
+    /// Offset of the first instruction run once the variable holds its value
+    pub start: usize,
+    /// Offset of the first instruction run once the variable is out of scope
+    pub end: usize,
 }
 
 impl Default for Fun {
@@ -21,10 +43,13 @@ impl Fun {
         Fun {
             arity: 0,
             min_arity: 0,
+            upvalue_count: 0,
             chunk: Chunk::default(),
             name: None,
             native_imports: Vec::new(),
             help: None,
+            file: None,
+            locals: Vec::new(),
         }
     }
 }
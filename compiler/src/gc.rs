@@ -0,0 +1,281 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    interner::{Interner, StrId},
+    value::{InstanceData, IterState, LoxMap, Upvalue, Value, ValueArray},
+};
+
+/// When the collector runs on its own
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// Number of tracked objects that triggers a collection
+    pub threshold: usize,
+    /// After a collection, the next one runs once the surviving tracked objects have grown by this factor
+    pub growth_factor: f64,
+    /// Bytes of runtime strings that triggers a collection, grown by `growth_factor` like `threshold`
+    pub string_threshold: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            threshold: 1024,
+            growth_factor: 2.0,
+            string_threshold: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub collections: usize,
+    /// Objects emptied to break unreachable cycles, over all collections
+    pub collected: usize,
+    pub last_collected: usize,
+    /// Runtime strings freed, over all collections
+    pub strings_collected: usize,
+}
+
+enum Tracked {
+    Array(Weak<RefCell<ValueArray>>),
+    Map(Weak<RefCell<LoxMap>>),
+    Instance(Weak<RefCell<InstanceData>>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+}
+
+impl Tracked {
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Array(weak) => weak.strong_count() > 0,
+            Tracked::Map(weak) => weak.strong_count() > 0,
+            Tracked::Instance(weak) => weak.strong_count() > 0,
+            Tracked::Upvalue(weak) => weak.strong_count() > 0,
+        }
+    }
+
+    /// Drop everything the object refers to, which breaks any cycle running through it.
+    /// The contents are taken out before being dropped, so nothing is borrowed while the cycle is freed.
+    fn clear(&self) {
+        match self {
+            Tracked::Array(weak) => {
+                if let Some(array) = weak.upgrade() {
+                    drop(array.take());
+                }
+            }
+            Tracked::Map(weak) => {
+                if let Some(map) = weak.upgrade() {
+                    drop(map.take());
+                }
+            }
+            Tracked::Instance(weak) => {
+                if let Some(instance) = weak.upgrade() {
+                    let fields = instance.borrow().fields.take();
+                    drop(fields);
+                }
+            }
+            Tracked::Upvalue(weak) => {
+                if let Some(upvalue) = weak.upgrade() {
+                    drop(upvalue.replace(Upvalue::Closed(Value::Nil)));
+                }
+            }
+        }
+    }
+}
+
+/// Collector for reference cycles. Values are reference counted, which frees everything except cycles,
+/// so this only has to find cycles the program can no longer reach and break them.
+///
+/// A new object can only refer to objects that already exist, so every cycle contains a reference that was stored
+/// into an object after it was created. Only those objects are tracked: arrays, maps and instances that had a
+/// reference stored into them, and upvalues holding one. A collection marks everything reachable from the roots,
+/// then empties the tracked objects that weren't reached, dropping the references that held their cycles together.
+///
+/// The same marking finds the strings still in use, so runtime strings nobody can reach are freed from the interner.
+pub struct Gc {
+    config: GcConfig,
+    next_collection: usize,
+    next_string_collection: usize,
+    /// Tracked objects by address. Entries for objects that were freed normally are pruned at the next collection.
+    tracked: FxHashMap<usize, Tracked>,
+    stats: GcStats,
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        let config = GcConfig::default();
+        Gc {
+            config,
+            next_collection: config.threshold,
+            next_string_collection: config.string_threshold,
+            tracked: FxHashMap::default(),
+            stats: GcStats::default(),
+        }
+    }
+}
+
+/// Whether a value refers to objects that could lead back to whoever stores it
+fn holds_references(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Array(_)
+            | Value::Map(_)
+            | Value::Instance(_)
+            | Value::Class(_)
+            | Value::Closure(_)
+            | Value::BoundMethod { .. }
+            | Value::Iterator(_)
+    )
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+impl Gc {
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_collection = config.threshold;
+        self.next_string_collection = config.string_threshold;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub fn tracked_count(&self) -> usize {
+        self.tracked.len()
+    }
+
+    pub fn should_collect(&self, interner: &Interner) -> bool {
+        self.tracked.len() >= self.next_collection || interner.runtime_bytes() >= self.next_string_collection
+    }
+
+    /// Note that `stored` was written into `container`, which may have closed a cycle
+    pub fn track(&mut self, container: &Value, stored: &Value) {
+        if !holds_references(stored) {
+            return;
+        }
+        match container {
+            Value::Array(array) => self.insert(address(array), Tracked::Array(Rc::downgrade(array))),
+            Value::Map(map) => self.insert(address(map), Tracked::Map(Rc::downgrade(map))),
+            Value::Instance(instance) => self.insert(address(instance), Tracked::Instance(Rc::downgrade(instance))),
+            _ => (),
+        }
+    }
+
+    /// Note that a closed upvalue now holds `stored`
+    pub fn track_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>, stored: &Value) {
+        if holds_references(stored) {
+            self.insert(address(upvalue), Tracked::Upvalue(Rc::downgrade(upvalue)));
+        }
+    }
+
+    fn insert(&mut self, address: usize, tracked: Tracked) {
+        // A live entry at this address is the same object. A dead one belonged to a freed object whose memory was reused.
+        if !self.tracked.get(&address).is_some_and(Tracked::is_alive) {
+            self.tracked.insert(address, tracked);
+        }
+    }
+
+    /// Break every cycle not reachable from `roots` and free the runtime strings they can't reach,
+    /// returning how many tracked objects were emptied
+    pub fn collect(&mut self, roots: Vec<Value>, interner: &mut Interner) -> usize {
+        let (marked, strings) = mark(roots);
+
+        self.tracked.retain(|_, tracked| tracked.is_alive());
+        let unreachable: Vec<usize> = self.tracked.keys().filter(|address| !marked.contains(address)).copied().collect();
+        for address in &unreachable {
+            if let Some(tracked) = self.tracked.remove(address) {
+                tracked.clear();
+            }
+        }
+
+        self.stats.collections += 1;
+        self.stats.collected += unreachable.len();
+        self.stats.last_collected = unreachable.len();
+        let grown = (self.tracked.len() as f64 * self.config.growth_factor) as usize;
+        self.next_collection = grown.max(self.config.threshold);
+
+        // Strings only referenced by the objects emptied above weren't marked either
+        self.stats.strings_collected += interner.sweep(|id| strings.contains(&id));
+        let grown = (interner.runtime_bytes() as f64 * self.config.growth_factor) as usize;
+        self.next_string_collection = grown.max(self.config.string_threshold);
+        unreachable.len()
+    }
+}
+
+/// Find the address of every object, and every string, reachable from `roots`
+fn mark(roots: Vec<Value>) -> (FxHashSet<usize>, FxHashSet<StrId>) {
+    let mut marked = FxHashSet::default();
+    let mut strings = FxHashSet::default();
+    let mut pending = roots;
+    while let Some(value) = pending.pop() {
+        match &value {
+            Value::Str(string) | Value::Identifier(string) => {
+                strings.insert(*string);
+            }
+            Value::Array(array) if marked.insert(address(array)) => {
+                pending.extend(array.borrow().iter().cloned());
+            }
+            Value::Map(map) if marked.insert(address(map)) => {
+                for (key, value) in map.borrow().iter() {
+                    pending.push(key.clone());
+                    pending.push(value.clone());
+                }
+            }
+            Value::Instance(instance) if marked.insert(address(instance)) => {
+                let instance = instance.borrow();
+                pending.push(Value::Class(Rc::clone(&instance.class)));
+                for (name, value) in instance.fields.borrow().iter() {
+                    strings.insert(*name);
+                    pending.push(value.clone());
+                }
+            }
+            Value::Class(class) if marked.insert(address(class)) => {
+                strings.insert(class.name);
+                for (name, method) in class.methods.borrow().iter() {
+                    strings.insert(*name);
+                    pending.push(Value::Closure(Rc::clone(method)));
+                }
+                if let Some(superclass) = &*class.superclass.borrow() {
+                    pending.push(Value::Class(Rc::clone(superclass)));
+                }
+            }
+            Value::Closure(closure) if marked.insert(address(closure)) => {
+                for upvalue in &closure.upvalues {
+                    if !marked.insert(address(upvalue)) {
+                        continue;
+                    }
+                    if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                        pending.push(value.clone());
+                    }
+                }
+            }
+            Value::BoundMethod { instance, method } => {
+                pending.push(Value::Instance(Rc::clone(instance)));
+                pending.push(Value::Closure(Rc::clone(method)));
+            }
+            Value::Iterator(state) if marked.insert(address(state)) => match &*state.borrow() {
+                IterState::Array { array, .. } => pending.push(Value::Array(Rc::clone(array))),
+                IterState::Map { map, .. } => pending.push(Value::Map(Rc::clone(map))),
+                IterState::Object { object, .. } => pending.push(Value::Instance(Rc::clone(object))),
+                IterState::Str { string, .. } => {
+                    strings.insert(*string);
+                }
+                IterState::Buffer { .. } | IterState::Range { .. } => (),
+            },
+            // Values without references, and objects that were already marked
+            _ => (),
+        }
+    }
+    (marked, strings)
+}
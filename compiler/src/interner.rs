@@ -4,11 +4,20 @@ use std::mem;
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct StrId(u32);
 
+/// Strings are interned in one of two ways. Identifiers and literals from the compiler are copied into
+/// append-only buffers and live forever. Strings made while a program runs (concatenation, `str()`, file
+/// contents...) are allocated on their own, so the VM can free the ones it can no longer reach with `sweep`.
 pub struct Interner {
     map: HashMap<&'static str, StrId>,
     vec: Vec<&'static str>,
     buf: String,
     full: Vec<String>,
+    /// Whether new strings are runtime strings, set while the VM is running
+    runtime: bool,
+    runtime_strings: HashMap<StrId, Box<str>>,
+    runtime_bytes: usize,
+    /// IDs of freed runtime strings, reused before new ones are handed out
+    free: Vec<StrId>,
 }
 
 impl Interner {
@@ -20,18 +29,40 @@ impl Interner {
             vec: Vec::new(),
             buf: String::with_capacity(cap),
             full: Vec::new(),
+            runtime: false,
+            runtime_strings: HashMap::default(),
+            runtime_bytes: 0,
+            free: Vec::new(),
         }
     }
 
     /// Intern string, and get it's ID
     pub fn intern(&mut self, name: &str) -> StrId {
         if let Some(&id) = self.map.get(name) {
+            if !self.runtime && self.runtime_strings.contains_key(&id) {
+                self.make_permanent(id);
+            }
             return id;
         }
-        let name = unsafe { self.alloc(name) };
-        let id = StrId(self.map.len() as u32);
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.vec.push("");
+                StrId(self.vec.len() as u32 - 1)
+            }
+        };
+        let name = if self.runtime {
+            let owned: Box<str> = name.into();
+            self.runtime_bytes += owned.len();
+            // The boxed string never moves, and is only dropped after being removed from `map` and `vec`
+            let name = unsafe { &*(&*owned as *const str) };
+            self.runtime_strings.insert(id, owned);
+            name
+        } else {
+            unsafe { self.alloc(name) }
+        };
         self.map.insert(name, id);
-        self.vec.push(name);
+        self.vec[id.0 as usize] = name;
 
         debug_assert!(self.lookup(&id) == name);
         debug_assert!(self.intern(name) == id);
@@ -44,6 +75,47 @@ impl Interner {
         self.vec[id.0 as usize]
     }
 
+    /// Choose whether strings interned from now on are runtime strings, which `sweep` can free
+    pub fn set_runtime(&mut self, runtime: bool) {
+        self.runtime = runtime;
+    }
+
+    /// Total size of the runtime strings currently interned
+    pub fn runtime_bytes(&self) -> usize {
+        self.runtime_bytes
+    }
+
+    pub fn runtime_count(&self) -> usize {
+        self.runtime_strings.len()
+    }
+
+    /// Free every runtime string for which `is_live` returns false, returning how many were freed.
+    /// Their IDs are reused, so the caller must make sure nothing still refers to them.
+    pub fn sweep(&mut self, is_live: impl Fn(StrId) -> bool) -> usize {
+        let dead: Vec<StrId> = self.runtime_strings.keys().filter(|&&id| !is_live(id)).copied().collect();
+        for id in &dead {
+            if let Some(string) = self.runtime_strings.remove(id) {
+                self.map.remove(&*string);
+                self.vec[id.0 as usize] = "";
+                self.runtime_bytes -= string.len();
+            }
+        }
+        self.free.extend_from_slice(&dead);
+        dead.len()
+    }
+
+    /// Move a runtime string into the buffers, because the compiler now refers to it
+    fn make_permanent(&mut self, id: StrId) {
+        if let Some(string) = self.runtime_strings.remove(&id) {
+            self.runtime_bytes -= string.len();
+            // Replace the key too, since inserting an equal key keeps the old one, which points into `string`
+            self.map.remove(&*string);
+            let name = unsafe { self.alloc(&string) };
+            self.map.insert(name, id);
+            self.vec[id.0 as usize] = name;
+        }
+    }
+
     unsafe fn alloc(&mut self, name: &str) -> &'static str {
         let cap = self.buf.capacity();
         if cap < self.buf.len() + name.len() {
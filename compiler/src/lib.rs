@@ -1,17 +1,32 @@
+pub mod ast;
+pub mod bytecode;
 pub mod chunk;
 pub mod common;
 pub mod compiler;
 pub mod debug;
+pub mod debugger;
+pub mod diagnostic;
+pub mod error;
+pub mod format;
 pub mod fun;
 pub mod interner;
 pub mod native;
 pub mod ffi;
+pub mod gc;
+pub mod lint;
+pub mod parser;
+pub mod resolver;
 pub mod scanner;
 pub mod value;
 pub mod vm;
 use std::{future::Future, sync::OnceLock};
 
-use crate::vm::Vm;
+use crate::{
+    diagnostic::{Diagnostic, Span},
+    error::InterpretError,
+    lint::{Lint, LintConfig},
+    vm::Vm,
+};
 use std::rc::Rc;
 
 const INTERNER_DEFAULT_CAP: usize = 1024;
@@ -87,7 +102,7 @@ pub fn init(print_fn: fn(String) -> (), println_fn: fn(String) -> (), clear_fn:
     }
 }
 
-pub async fn run_code<F, Fut, SF, SFut>(code: &str, read_async: F, sleep_async: SF)
+pub async fn run_code<F, Fut, SF, SFut>(code: &str, read_async: F, sleep_async: SF) -> Result<(), InterpretError>
 where
     F: Fn(String) -> Fut,
     Fut: Future<Output = String>,
@@ -97,33 +112,129 @@ where
     let source: Rc<str> = Rc::from(code);
     let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
     let mut functions: Vec<fun::Fun> = Vec::new();
-    let (fun, had_error) = compiler::Compiler::compile(source, None, &mut interner, &mut functions, fun::FunType::Script).unwrap();
-    if !had_error {
-        functions.push(fun);
-        Vm::interpret(functions, &mut interner, read_async, sleep_async).await.unwrap();
+    let lints = LintConfig::default();
+    let compiled = compiler::Compiler::compile(source, None, &mut interner, &mut functions, fun::FunType::Script, &lints);
+    let (fun, diagnostics) = match compiled {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            let diagnostics = vec![compiler_failure(code, e)];
+            report(&diagnostics, code);
+            return Err(InterpretError::Compile(diagnostics));
+        }
+    };
+    report(&diagnostics, code);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(InterpretError::Compile(diagnostics));
     }
+    functions.push(fun);
+    Vm::interpret(functions, &mut interner, read_async, sleep_async).await?;
+    Ok(())
 }
 
-pub async fn run_file<F, Fut, SF, SFut>(file_path: &str, read_async: F, sleep_async: SF)
+pub async fn run_file<F, Fut, SF, SFut>(file_path: &str, read_async: F, sleep_async: SF) -> Result<(), InterpretError>
 where
     F: Fn(String) -> Fut,
     Fut: Future<Output = String>,
     SF: Fn(u64) -> SFut,
     SFut: Future<Output = ()>,
 {
+    let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let loaded = load_file(file_path, &mut interner, &LintConfig::default());
+    let diagnostics: &[Diagnostic] = match &loaded {
+        Ok((_, warnings)) => warnings,
+        Err(InterpretError::Compile(diagnostics)) => diagnostics,
+        Err(_) => &[],
+    };
+    if !diagnostics.is_empty() {
+        report(diagnostics, &std::fs::read_to_string(file_path).unwrap_or_default());
+    }
+
+    let (functions, _) = loaded?;
+    Vm::interpret(functions, &mut interner, read_async, sleep_async).await?;
+    Ok(())
+}
+
+/// Print diagnostics with the code they point at
+fn report(diagnostics: &[Diagnostic], source: &str) {
+    if !diagnostics.is_empty() {
+        xprintln!("{}", diagnostic::render_all(diagnostics, source));
+    }
+}
+
+/// Load the program in `file_path`, compiling it unless it is already bytecode.
+/// Returns every function of the program with the script last, as `Vm::interpret` expects, and any warnings of the enabled lints.
+pub fn load_file(
+    file_path: &str,
+    interner: &mut interner::Interner,
+    lints: &LintConfig,
+) -> Result<(Vec<fun::Fun>, Vec<Diagnostic>), InterpretError> {
     use std::fs;
     use std::path::Path;
 
     let path = Path::new(file_path);
-    let current_dir = path.parent().map(|p| p.to_path_buf());
-    let code = fs::read_to_string(path).expect("Failed to read file");
+    let bytes = fs::read(path).map_err(|e| read_error(file_path, e))?;
+    if bytecode::is_bytecode(&bytes) {
+        let functions = bytecode::deserialize(&bytes, interner).map_err(|e| InterpretError::Bytecode(e.to_string()))?;
+        return Ok((functions, Vec::new()));
+    }
+    let code = String::from_utf8(bytes).map_err(|e| read_error(file_path, e))?;
+    compile_source(code, path, interner, lints)
+}
+
+/// Compile the program in `file_path` to bytecode, which `run_file` can run without compiling the source again.
+/// Returns the bytecode and any warnings of the enabled lints.
+pub fn compile_file(file_path: &str, lints: &LintConfig) -> Result<(Vec<u8>, Vec<Diagnostic>), InterpretError> {
+    use std::fs;
+    use std::path::Path;
+
+    let path = Path::new(file_path);
+    let code = fs::read_to_string(path).map_err(|e| read_error(file_path, e))?;
 
-    let source: Rc<str> = Rc::from(code);
     let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let (functions, warnings) = compile_source(code, path, &mut interner, lints)?;
+    let bytes = bytecode::serialize(&functions, &interner).map_err(|e| InterpretError::Bytecode(e.to_string()))?;
+    Ok((bytes, warnings))
+}
+
+/// Check the program in `file_path`, and the files it imports, without running it.
+/// Native modules are loaded to check that they exist, and every global the program reads or assigns has to be declared
+/// somewhere, unless `lints` allows those lints. Returns the warnings of the enabled lints if the program is correct.
+pub fn check_file(file_path: &str, lints: &LintConfig) -> Result<Vec<Diagnostic>, InterpretError> {
+    let lints = lints
+        .clone()
+        .deny(Lint::UndefinedVariable)
+        .deny(Lint::UndeclaredGlobal)
+        .load_modules();
+    let mut interner = interner::Interner::with_capacity(INTERNER_DEFAULT_CAP);
+    let (_, warnings) = load_file(file_path, &mut interner, &lints)?;
+    Ok(warnings)
+}
+
+fn read_error(file_path: &str, error: impl std::fmt::Display) -> InterpretError {
+    InterpretError::Io(format!("Can't read {file_path}: {error}"))
+}
+
+/// An error for a failure the compiler returned instead of reporting it as a diagnostic, pointing at the start of `source`
+fn compiler_failure(source: &str, error: anyhow::Error) -> Diagnostic {
+    Diagnostic::new(source, Span::default(), &error.to_string())
+}
+
+/// Compile the source of the file at `path`, returning every function with the script last and any warnings
+fn compile_source(
+    code: String,
+    path: &std::path::Path,
+    interner: &mut interner::Interner,
+    lints: &LintConfig,
+) -> Result<(Vec<fun::Fun>, Vec<Diagnostic>), InterpretError> {
+    let current_dir = path.parent().map(|p| p.to_path_buf());
+    let source: Rc<str> = Rc::from(code);
     let mut functions: Vec<fun::Fun> = Vec::new();
-    let (fun, had_error) = compiler::Compiler::compile(source, current_dir, &mut interner, &mut functions, fun::FunType::Script).unwrap();
-    if !had_error {
-        functions.push(fun);
-        Vm::interpret(functions, &mut interner, read_async, sleep_async).await.unwrap();
+    let (fun, diagnostics) =
+        compiler::Compiler::compile(source.clone(), current_dir, interner, &mut functions, fun::FunType::Script, lints)
+            .map_err(|e| InterpretError::Compile(vec![compiler_failure(&source, e)]))?;
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(InterpretError::Compile(diagnostics));
     }
+    functions.push(fun);
+    Ok((functions, diagnostics))
 }
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostic::{Diagnostic, Severity};
+
+/// A kind of warning the compiler reports for code that compiles but is probably a mistake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A local variable that is never used
+    UnusedVariable,
+    /// An assignment to a global that is never declared, which fails at runtime
+    UndeclaredGlobal,
+    /// A read of a variable that isn't declared anywhere, which fails at runtime
+    UndefinedVariable,
+    /// Code after a `return`, `break`, `continue` or `throw` in the same block
+    UnreachableCode,
+    /// A builtin function called with too few or too many arguments
+    NativeArity,
+    /// A variable, function or class named like a builtin function, hiding it
+    ShadowedBuiltin,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedVariable,
+        Lint::UndeclaredGlobal,
+        Lint::UndefinedVariable,
+        Lint::UnreachableCode,
+        Lint::NativeArity,
+        Lint::ShadowedBuiltin,
+    ];
+
+    /// Name used to configure and suppress the lint
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UndeclaredGlobal => "undeclared-global",
+            Lint::UndefinedVariable => "undefined-variable",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::NativeArity => "native-arity",
+            Lint::ShadowedBuiltin => "shadowed-builtin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+/// Which lints are reported, and how. Every lint is reported as a warning unless it is allowed or denied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LintConfig {
+    allowed: HashSet<Lint>,
+    denied: HashSet<Lint>,
+    load_modules: bool,
+}
+
+impl LintConfig {
+    /// Stop reporting `lint`
+    pub fn allow(mut self, lint: Lint) -> LintConfig {
+        self.allowed.insert(lint);
+        self
+    }
+
+    /// Report `lint` as an error, so programs it finds don't compile. Allowing the lint takes precedence.
+    pub fn deny(mut self, lint: Lint) -> LintConfig {
+        self.denied.insert(lint);
+        self
+    }
+
+    /// Load native modules while compiling, to check that they exist and to know the functions they define.
+    /// Otherwise they are only loaded when the program runs, and what they define isn't checked.
+    pub fn load_modules(mut self) -> LintConfig {
+        self.load_modules = true;
+        self
+    }
+
+    /// How `lint` is reported, or `None` if it is allowed
+    pub fn severity(&self, lint: Lint) -> Option<Severity> {
+        if self.allowed.contains(&lint) {
+            None
+        } else if self.denied.contains(&lint) {
+            Some(Severity::Error)
+        } else {
+            Some(Severity::Warning)
+        }
+    }
+
+    pub fn loads_modules(&self) -> bool {
+        self.load_modules
+    }
+}
+
+const IGNORE: &str = "lox-ignore";
+const IGNORE_FILE: &str = "lox-ignore-file";
+
+/// The warnings the `lox-ignore` comments of a source suppress, parsed once per compile.
+///
+/// `// lox-ignore` suppresses warnings on its line, or on the next line when the comment is alone on its line.
+/// `// lox-ignore-file` suppresses them in the whole file. Both take an optional list of lints,
+/// like `// lox-ignore: unused-variable, shadowed-builtin`, and suppress every lint without one.
+pub struct Suppressions<'src> {
+    /// The lints of each `lox-ignore-file` comment, empty for all of them
+    file: Vec<Vec<&'src str>>,
+    /// The lints of the `lox-ignore` comments that apply to each line, by line number
+    lines: HashMap<usize, Vec<Vec<&'src str>>>,
+}
+
+impl<'src> Suppressions<'src> {
+    pub fn new(source: &'src str) -> Self {
+        let mut file = Vec::new();
+        let mut lines: HashMap<usize, Vec<Vec<&str>>> = HashMap::new();
+        for (index, line) in source.lines().enumerate() {
+            if let Some(lints) = comment_directive(line, IGNORE_FILE) {
+                file.push(lints);
+            } else if let Some(lints) = comment_directive(line, IGNORE) {
+                if line.trim_start().starts_with("//") {
+                    lines.entry(index + 2).or_default().push(lints.clone());
+                }
+                lines.entry(index + 1).or_default().push(lints);
+            }
+        }
+        Suppressions { file, lines }
+    }
+
+    /// Whether a comment suppresses the warning
+    pub fn suppresses(&self, diagnostic: &Diagnostic) -> bool {
+        let Some(lint) = diagnostic.lint else {
+            return false;
+        };
+        let covers = |lints: &Vec<&str>| lints.is_empty() || lints.contains(&lint.name());
+        self.file.iter().any(covers) || self.lines.get(&diagnostic.line).is_some_and(|lines| lines.iter().any(covers))
+    }
+}
+
+/// The lints listed by a `directive` comment on `line`, empty for all of them, or `None` if there is no such comment
+fn comment_directive<'a>(line: &'a str, directive: &str) -> Option<Vec<&'a str>> {
+    // A string on the line may contain slashes too, so try each of them
+    let rest = line
+        .match_indices("//")
+        .find_map(|(start, _)| line[start + 2..].trim_start().strip_prefix(directive))?;
+    if rest.is_empty() {
+        return Some(Vec::new());
+    }
+    let lints = rest.strip_prefix(':')?;
+    Some(lints.split(',').map(str::trim).filter(|name| !name.is_empty()).collect())
+}
@@ -1,39 +1,196 @@
 #![allow(unused_variables)]
 
 use crate::{
+    fun::ANONYMOUS_FUNCTION_NAME,
     interner::{Interner, StrId},
-    value::{print_value, value_as_string, Value},
-    vm::ERR_STRING,
+    value::{print_value, value_as_string, IterState, LoxMap, Value},
+    vm::{resolve_index, ERR_STRING},
     xclear, xprintln,
 };
 use rustc_hash::FxHashMap;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::rc::Rc;
+use std::sync::OnceLock;
 use web_time::SystemTime;
 
 pub type Globals = FxHashMap<StrId, Value>;
 
 pub trait Callable: Debug {
+    /// Fewest arguments the function takes
     fn arity(&self) -> usize;
+    /// Most arguments the function takes, or `None` if it takes any number
+    fn max_arity(&self) -> Option<usize> {
+        Some(self.arity())
+    }
     fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value;
     fn name(&self) -> &str;
     fn help(&self) -> Option<String> {
         None
     }
+    /// What the VM runs in place of `call`, for builtins that need the VM's own state
+    fn vm_function(&self) -> Option<VmFunction> {
+        None
+    }
+}
+
+/// A builtin the VM runs itself, since it needs more than the interner and the globals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmFunction {
+    /// `gc()`, which collects from the VM's roots
+    CollectGarbage,
+    /// `gc_stats()`, which reads the collector of the VM
+    GarbageStats,
+}
+
+/// Every builtin function, which programs can call without importing anything
+pub fn builtins() -> Vec<Rc<dyn Callable>> {
+    vec![
+        Rc::new(Clock),
+        Rc::new(Sleep),
+        Rc::new(TypeOf),
+        Rc::new(Print),
+        Rc::new(Printf),
+        Rc::new(ReadString),
+        Rc::new(StrCast),
+        Rc::new(BufCast),
+        Rc::new(ChrCast),
+        Rc::new(HelpCast),
+        Rc::new(IntCast),
+        Rc::new(FloatCast),
+        Rc::new(BoolCast),
+        Rc::new(StringAt),
+        Rc::new(Len),
+        Rc::new(Ceil),
+        Rc::new(Floor),
+        Rc::new(Abs),
+        Rc::new(Sort),
+        Rc::new(IndexOf),
+        Rc::new(Rand),
+        Rc::new(Clear),
+        Rc::new(Sin),
+        Rc::new(Cos),
+        Rc::new(Sqrt),
+        Rc::new(Pow),
+        Rc::new(Pi),
+        Rc::new(MapConstructor),
+        Rc::new(Keys),
+        Rc::new(Values),
+        Rc::new(Has),
+        Rc::new(RangeIter),
+        Rc::new(CollectGarbage),
+        Rc::new(GarbageStats),
+    ]
+}
+
+/// Numbers of arguments a native function takes, written as a number, or as a range when some are optional
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl From<usize> for Arity {
+    fn from(count: usize) -> Arity {
+        Arity {
+            min: count,
+            max: Some(count),
+        }
+    }
+}
+
+impl From<std::ops::RangeInclusive<usize>> for Arity {
+    fn from(counts: std::ops::RangeInclusive<usize>) -> Arity {
+        Arity {
+            min: *counts.start(),
+            max: Some(*counts.end()),
+        }
+    }
+}
+
+/// Fewest and most arguments of every builtin function, by name
+fn builtin_arities() -> &'static HashMap<String, (usize, Option<usize>)> {
+    static ARITIES: OnceLock<HashMap<String, (usize, Option<usize>)>> = OnceLock::new();
+    ARITIES.get_or_init(|| {
+        builtins()
+            .iter()
+            .map(|builtin| (builtin.name().to_string(), (builtin.arity(), builtin.max_arity())))
+            .collect()
+    })
+}
+
+/// Number of arguments the builtin function `name` takes, as the fewest and the most, or `None` if it isn't a builtin.
+/// The most is `None` for functions that take any number of arguments.
+pub fn builtin_arity(name: &str) -> Option<(usize, Option<usize>)> {
+    builtin_arities().get(name).copied()
+}
+
+/// Whether every program has the global `name`: a builtin function, or the error string builtins set
+pub fn is_builtin_global(name: &str) -> bool {
+    name == ERR_STRING || builtin_arity(name).is_some()
 }
 
 pub fn set_global_error(interner: &mut Interner, globals: &mut Globals, message: &str) {
     globals.insert(interner.intern(ERR_STRING), Value::Str(interner.intern(message)));
 }
 
+thread_local! {
+    static RAISED: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Raise a runtime error once the native function being called returns, for mistakes a program can't carry on from
+/// the way it can after an error it finds in `errString`
+pub fn raise(message: &str) {
+    RAISED.with(|raised| *raised.borrow_mut() = Some(message.to_string()));
+}
+
+/// The runtime error raised by the native function that just returned, if any
+pub(crate) fn take_raised() -> Option<String> {
+    RAISED.with(|raised| raised.borrow_mut().take())
+}
+
 #[macro_export]
 macro_rules! callable_struct {
+    ($struct_name:ident, $lox_name:expr, $arity:expr, $help:expr, vm: $vm_function:expr) => {
+        #[derive(Debug, Default)]
+        pub struct $struct_name;
+
+        impl Callable for $struct_name {
+            fn arity(&self) -> usize {
+                $crate::native::Arity::from($arity).min
+            }
+
+            fn max_arity(&self) -> Option<usize> {
+                $crate::native::Arity::from($arity).max
+            }
+
+            fn call(&self, _: &mut Interner, _: &mut Globals, _: &[Value]) -> Value {
+                unreachable!("The VM runs {} itself", $lox_name)
+            }
+
+            fn name(&self) -> &str {
+                $lox_name
+            }
+
+            fn help(&self) -> Option<String> {
+                Some($help.to_string())
+            }
+
+            fn vm_function(&self) -> Option<VmFunction> {
+                Some($vm_function)
+            }
+        }
+    };
     ($struct_name:ident, $lox_name:expr, $arity:expr, $help:expr, $interner:ident: &mut Interner, $globals:ident: &mut Globals, $args:ident: &[Value], $body:block) => {
         #[derive(Debug, Default)]
         pub struct $struct_name;
 
         impl Callable for $struct_name {
             fn arity(&self) -> usize {
-                $arity
+                $crate::native::Arity::from($arity).min
+            }
+
+            fn max_arity(&self) -> Option<usize> {
+                $crate::native::Arity::from($arity).max
             }
 
             fn call(&self, $interner: &mut Interner, $globals: &mut Globals, $args: &[Value]) -> Value {
@@ -55,7 +212,11 @@ macro_rules! callable_struct {
 
         impl Callable for $struct_name {
             fn arity(&self) -> usize {
-                $arity
+                $crate::native::Arity::from($arity).min
+            }
+
+            fn max_arity(&self) -> Option<usize> {
+                $crate::native::Arity::from($arity).max
             }
 
             fn call(&self, $interner: &mut Interner, $globals: &mut Globals, $args: &[Value]) -> Value {
@@ -108,7 +269,7 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value],{
 });
 
 // Arg is what the user gave
-callable_struct!(ReadString, "input", 1, "input(prompt)
+callable_struct!(ReadString, "input", 0..=1, "input(prompt)
 Prints prompt and reads a line of input from stdin.
 Arguments:
   prompt: String to display before input.
@@ -140,10 +301,11 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
         Value::Number(_) => Value::Str(interner.intern("Number")),
         Value::Bool(_) => Value::Str(interner.intern("Bool")),
         Value::Nil => Value::Str(interner.intern("Nil")),
-        Value::Function(_) | Value::NativeFunction(_) => Value::Str(interner.intern("Function")),
+        Value::Function(_) | Value::Closure(_) | Value::NativeFunction(_) => Value::Str(interner.intern("Function")),
         Value::Class(_) => Value::Str(interner.intern("Class")),
         Value::Instance(_) => Value::Str(interner.intern("Instance")),
         Value::BoundMethod { .. } => Value::Str(interner.intern("BoundMethod")),
+        Value::Iterator(_) => Value::Str(interner.intern("Iterator")),
     }
 });
 
@@ -316,19 +478,22 @@ callable_struct!(StringAt, "stringat", 2, "stringat(str, index)
 Returns the single-character string at the specified 0-based index.
 Arguments:
   str: String to index.
-  index: Number representing the 0-based position.
+  index: Number representing the 0-based position. Negative indices count back from the end.
 Returns: Single-character String, or Nil if index is out of bounds.
 Error Cases: Sets error if arguments are invalid.",
 interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     match (&args[0], &args[1]) {
         (Value::Str(s), Value::Number(n)) => {
             let str = interner.lookup(s);
-            let index = *n as usize;
-            if let Some(c) = str.chars().nth(index) {
-                Value::Str(interner.intern(&c.to_string()))
-            } else {
-                set_global_error(interner, globals, "Index out of bounds");
-                Value::Nil
+            match resolve_index(&args[0], *n, str.chars().count()) {
+                Ok(index) => {
+                    let c = str.chars().nth(index).unwrap_or_default();
+                    Value::Str(interner.intern(&c.to_string()))
+                }
+                Err(err) => {
+                    set_global_error(interner, globals, &err.to_string());
+                    Value::Nil
+                }
             }
         }
         _ => {
@@ -360,13 +525,13 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     }
 });
 
-callable_struct!(MapConstructor, "map", 0, "map(entries)
-Constructs a new unordered Map.
+callable_struct!(MapConstructor, "map", 0..=1, "map(entries)
+Constructs a new Map, which keeps its keys in insertion order.
 Arguments:
   entries: (Optional) Array of key-value pair arrays, e.g. [[\"k1\", \"v1\"], [\"k2\", \"v2\"]].
 Returns: New Map object.",
 interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
-    let map = rustc_hash::FxHashMap::default();
+    let map = LoxMap::default();
     let map_rc = std::rc::Rc::new(std::cell::RefCell::new(map));
     if !args.is_empty() {
         if let Value::Array(arr) = &args[0] {
@@ -450,6 +615,64 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
     }
 });
 
+callable_struct!(RangeIter, "range", 1..=3, "range(start, end, step)
+Returns a lazy iterator over numbers from start up to (but not including) end.
+Arguments:
+  start: Number to start from. If only one argument is given, it is the end and start is 0.
+  end: Number to stop before.
+  step: (Optional) Non-zero number to advance by each iteration, default 1. Negative steps count down.
+Returns: Iterator for use in for-in loops.
+Error Cases: Sets error if arguments are not numbers. Raises a runtime error if step is 0.",
+interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
+    let mut numbers = Vec::with_capacity(args.len());
+    for arg in args.iter().take(3) {
+        match arg {
+            Value::Number(n) => numbers.push(*n),
+            _ => {
+                set_global_error(interner, globals, "Expected numbers as arguments to range");
+                return Value::Nil;
+            }
+        }
+    }
+    let (start, end, step) = match numbers[..] {
+        [end] => (0.0, end, 1.0),
+        [start, end] => (start, end, 1.0),
+        [start, end, step] => (start, end, step),
+        _ => unreachable!(),
+    };
+    if step == 0.0 {
+        raise("range step can't be 0");
+        return Value::Nil;
+    }
+    let state = IterState::Range {
+        next: start,
+        end,
+        step,
+        index: 0,
+    };
+    Value::Iterator(std::rc::Rc::new(std::cell::RefCell::new(state)))
+});
+
+callable_struct!(CollectGarbage, "gc", 0..=2, "gc(threshold, growth_factor)
+Runs the garbage collector, freeing objects that are only kept alive by reference cycles the program can no longer reach,
+and strings created while running that are no longer used.
+The collector also runs on its own once enough objects that could be part of a cycle are being tracked,
+or enough memory is taken up by strings created while running.
+Arguments:
+  threshold: (Optional) Number of tracked objects that triggers an automatic collection, default 1024.
+  growth_factor: (Optional) How much the tracked objects that survive a collection may grow before the next one, default 2.
+Returns: Number of objects emptied to break unreachable cycles.
+Error Cases: Errors if arguments are not numbers, or are less than 1.",
+vm: VmFunction::CollectGarbage);
+
+callable_struct!(GarbageStats, "gc_stats", 0, "gc_stats()
+Reports on the garbage collector.
+Arguments: None.
+Returns: Map with the number of collections, objects collected in total and by the last collection,
+objects currently tracked, runtime strings freed in total, runtime strings currently interned and their size in bytes,
+and the automatic collection threshold and growth factor.",
+vm: VmFunction::GarbageStats);
+
 callable_struct!(Ceil, "ceil", 1, "ceil(x)
 Returns the smallest integer greater than or equal to x.
 Arguments:
@@ -633,7 +856,7 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
 
 // Printf: format string with variable number of arguments
 // Usage: printf("Hello {0} you are {1} years old", name, age)
-// or: printf("Hello {name} you are {age} years old") - requires variables in scope
+// or: printf("Hello {name} you are {age} years old") - only looks up globals, "Hello ${name}" interpolation also sees locals
 #[derive(Debug, Default)]
 pub struct Printf;
 
@@ -642,6 +865,10 @@ impl Callable for Printf {
         1 // Minimum arity is 1 (format string)
     }
 
+    fn max_arity(&self) -> Option<usize> {
+        None
+    }
+
     fn call(&self, interner: &mut Interner, globals: &mut Globals, args: &[Value]) -> Value {
         if args.is_empty() {
             return Value::Nil;
@@ -888,25 +1115,19 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value] ,{
             Value::NativeFunction(c) => {
                 resolved_callable = Some((c.name().to_string(), c.arity(), c.help()));
             }
-            Value::Function(idx) => {
-                crate::vm::RUNNING_FUNCTIONS.with(|funcs| {
-                    if let Some(ptr) = *funcs.borrow() {
-                        let functions = unsafe { &*ptr };
-                        if *idx < functions.len() {
-                            let f = &functions[*idx];
-                            let name = f.name.map(|id| interner.lookup(&id).to_string()).unwrap_or_else(|| "anonymous".to_string());
-                            resolved_callable = Some((name, f.arity, f.help.clone()));
-                        }
-                    }
-                });
-            }
-            Value::BoundMethod { instance: _, method_idx } => {
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod { .. } => {
+                let idx = match &args[0] {
+                    Value::Closure(closure) => closure.fun_idx,
+                    Value::BoundMethod { method, .. } => method.fun_idx,
+                    Value::Function(idx) => *idx,
+                    _ => unreachable!(),
+                };
                 crate::vm::RUNNING_FUNCTIONS.with(|funcs| {
                     if let Some(ptr) = *funcs.borrow() {
                         let functions = unsafe { &*ptr };
-                        if *method_idx < functions.len() {
-                            let f = &functions[*method_idx];
-                            let name = f.name.map(|id| interner.lookup(&id).to_string()).unwrap_or_else(|| "anonymous".to_string());
+                        if idx < functions.len() {
+                            let f = &functions[idx];
+                            let name = f.name.map(|id| interner.lookup(&id).to_string()).unwrap_or_else(|| ANONYMOUS_FUNCTION_NAME.to_string());
                             resolved_callable = Some((name, f.arity, f.help.clone()));
                         }
                     }
@@ -0,0 +1,1305 @@
+use crate::{
+    ast::{Block, Catch, Class, Declaration, Expr, Function, FunctionBody, Import, Index, NodeId, Param, Program, Stmt, Target, Variable},
+    common::identifiers_equal,
+    dbgln,
+    diagnostic::{Diagnostic, Severity},
+    fun::FunType,
+    scanner::{Scanner, Token, TokenType},
+};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+#[repr(u8)]
+#[derive(Eq, Clone, Copy, TryFromPrimitive, PartialEq, PartialOrd, IntoPrimitive, strum_macros::Display)]
+// Low to High precedence
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+type PrefixFn = fn(&mut Parser, bool) -> Expr;
+type InfixFn = fn(&mut Parser, Expr, bool) -> Expr;
+
+struct ParseRule {
+    prefix: Option<PrefixFn>,
+    infix: Option<InfixFn>,
+    precedence: Precedence,
+}
+
+/// This is a table that, given a token type, lets us find
+/// 1. the function to parse a prefix expression starting with a token of that type,
+/// 2. the function to parse an infix expression whose left operand is followed by a token of that type, and
+/// 3. the precedence of an infix expression that uses that token as an operator.
+fn get_rules() -> HashMap<TokenType, ParseRule> {
+    let mut map = HashMap::new();
+    use TokenType::*;
+
+    macro_rules! add_rule {
+        ($map: expr, $tokentype: expr, $prefix: expr, $infix: expr, $precedence: expr) => {
+            $map.insert(
+                $tokentype,
+                ParseRule {
+                    prefix: $prefix,
+                    infix: $infix,
+                    precedence: $precedence,
+                },
+            );
+        };
+    }
+
+    add_rule!(map, LeftParen, Some(Parser::grouping), Some(Parser::call), Precedence::Call);
+    add_rule!(map, RightParen, None, None, Precedence::None);
+    add_rule!(map, LeftBrace, Some(Parser::map_literal), None, Precedence::None);
+    add_rule!(map, RightBrace, None, None, Precedence::None);
+    add_rule!(
+        map,
+        LeftBracket,
+        Some(Parser::array_literal),
+        Some(Parser::index_access),
+        Precedence::Call
+    );
+    add_rule!(map, RightBracket, None, None, Precedence::None);
+    add_rule!(map, Comma, None, None, Precedence::None);
+    add_rule!(map, Dot, None, Some(Parser::dot), Precedence::Call);
+    add_rule!(map, Colon, None, None, Precedence::None);
+    add_rule!(map, Minus, Some(Parser::unary), Some(Parser::binary), Precedence::Term);
+    add_rule!(map, MinusEqual, None, None, Precedence::None);
+    add_rule!(map, MinusMinus, Some(Parser::prefix_increment_decrement), None, Precedence::None);
+    add_rule!(map, Plus, None, Some(Parser::binary), Precedence::Term);
+    add_rule!(map, PlusEqual, None, None, Precedence::None);
+    add_rule!(map, PlusPlus, Some(Parser::prefix_increment_decrement), None, Precedence::None);
+    add_rule!(map, Semicolon, None, None, Precedence::None);
+    add_rule!(map, Slash, None, Some(Parser::binary), Precedence::Factor);
+    add_rule!(map, Star, None, Some(Parser::binary), Precedence::Factor);
+    add_rule!(map, Modulo, None, Some(Parser::binary), Precedence::Factor);
+    add_rule!(map, Bang, Some(Parser::unary), None, Precedence::None);
+    add_rule!(map, BangEqual, None, Some(Parser::binary), Precedence::Equality);
+    add_rule!(map, Equal, None, None, Precedence::None);
+    add_rule!(map, EqualEqual, None, Some(Parser::binary), Precedence::Equality);
+    add_rule!(map, Arrow, None, None, Precedence::None);
+    add_rule!(map, Greater, None, Some(Parser::binary), Precedence::Comparison);
+    add_rule!(map, GreaterEqual, None, Some(Parser::binary), Precedence::Comparison);
+    add_rule!(map, Less, None, Some(Parser::binary), Precedence::Comparison);
+    add_rule!(map, LessEqual, None, Some(Parser::binary), Precedence::Comparison);
+    add_rule!(map, Identifier, Some(Parser::variable), None, Precedence::None);
+    add_rule!(map, String, Some(Parser::string), None, Precedence::None);
+    add_rule!(map, Interpolation, Some(Parser::interpolation), None, Precedence::None);
+    add_rule!(map, Number, Some(Parser::number), None, Precedence::None);
+    add_rule!(map, And, None, Some(Parser::logical), Precedence::And);
+    add_rule!(map, Class, None, None, Precedence::None);
+    add_rule!(map, Else, None, None, Precedence::None);
+    add_rule!(map, False, Some(Parser::literal), None, Precedence::None);
+    add_rule!(map, For, None, None, Precedence::None);
+    add_rule!(map, Fun, Some(Parser::function_expression), None, Precedence::None);
+    add_rule!(map, If, None, None, Precedence::None);
+    add_rule!(map, Nil, Some(Parser::literal), None, Precedence::None);
+    add_rule!(map, Or, None, Some(Parser::logical), Precedence::Or);
+    add_rule!(map, Print, None, None, Precedence::None);
+    add_rule!(map, Return, None, None, Precedence::None);
+    add_rule!(map, Super, Some(Parser::super_), None, Precedence::None);
+    add_rule!(map, This, None, None, Precedence::None);
+    add_rule!(map, True, Some(Parser::literal), None, Precedence::None);
+    add_rule!(map, Var, None, None, Precedence::None);
+    add_rule!(map, While, None, None, Precedence::None);
+    add_rule!(map, Break, None, None, Precedence::None);
+    add_rule!(map, Continue, None, None, Precedence::None);
+    add_rule!(map, Try, None, None, Precedence::None);
+    add_rule!(map, Catch, None, None, Precedence::None);
+    add_rule!(map, Finally, None, None, Precedence::None);
+    add_rule!(map, Throw, None, None, Precedence::None);
+    add_rule!(map, In, None, None, Precedence::None);
+    add_rule!(map, Error, None, None, Precedence::None);
+    add_rule!(map, EOF, None, None, Precedence::None);
+
+    map
+}
+
+fn increment_prec(prec: Precedence) -> Precedence {
+    (prec as u8 + 1).try_into().unwrap()
+}
+
+/// Parse a file into its statements, along with the syntax errors found.
+/// Statements that don't parse are left in the tree as `Stmt::Error` or with `Expr::Error` parts.
+pub fn parse(source: Rc<str>) -> (Program, Vec<Diagnostic>) {
+    let mut parser = Parser::new(source);
+    dbgln!("== Parser (Scan on demand) ==");
+
+    parser.advance();
+    let mut statements = Vec::new();
+    while !parser.match_tt(TokenType::EOF) {
+        statements.push(parser.declaration());
+    }
+
+    let program = Program {
+        statements,
+        end: parser.previous,
+    };
+    (program, parser.diagnostics)
+}
+
+/// Parse a single expression, like the REPL evaluates, returning it with the end of file token after it
+pub fn parse_expression(source: Rc<str>) -> (Expr, Token, Vec<Diagnostic>) {
+    let mut parser = Parser::new(source);
+
+    parser.advance();
+    let expr = parser.expression();
+    parser.consume(TokenType::EOF, "Expect end of expression.");
+    (expr, parser.previous, parser.diagnostics)
+}
+
+struct Parser {
+    scanner: Scanner,
+    current: Token,
+    previous: Token,
+    panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
+    rules: HashMap<TokenType, ParseRule>,
+    /// Aliases of the modules imported so far, whose globals are read with a dotted name like `math.sqrt`
+    namespaces: HashSet<String>,
+    next_id: NodeId,
+    /// How many blocks and class bodies the current token is in
+    blocks: usize,
+}
+
+impl Parser {
+    fn new(source: Rc<str>) -> Parser {
+        Parser {
+            scanner: Scanner::new(source),
+            current: Token::new(),
+            previous: Token::new(),
+            panic_mode: false,
+            diagnostics: Vec::new(),
+            rules: get_rules(),
+            namespaces: HashSet::new(),
+            next_id: 0,
+            blocks: 0,
+        }
+    }
+
+    fn id(&mut self) -> NodeId {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn declaration_of_previous(&mut self) -> Declaration {
+        Declaration {
+            id: self.id(),
+            name: self.previous.clone(),
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(true, message, None);
+    }
+
+    fn error_at_previous(&mut self, message: &str) {
+        self.error_at(false, message, None);
+    }
+
+    fn error_at(&mut self, current: bool, message: &str, hint: Option<String>) {
+        let token = if current { &self.current } else { &self.previous };
+
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            hint,
+            file: None,
+            lint: None,
+        });
+    }
+
+    fn consume(&mut self, typ: TokenType, message: &str) {
+        if self.current.typ == typ {
+            self.advance();
+            return;
+        }
+
+        // Point out where a closing token was probably forgotten, since the error is only found at the next token
+        let symbol = match typ {
+            TokenType::Semicolon => Some(";"),
+            TokenType::RightParen => Some(")"),
+            TokenType::RightBrace => Some("}"),
+            TokenType::RightBracket => Some("]"),
+            _ => None,
+        };
+        let hint = match symbol {
+            Some(symbol) if self.current.typ == TokenType::EOF => Some(format!("The file ended while looking for '{symbol}'")),
+            Some(symbol) if self.previous.line < self.current.line => {
+                Some(format!("Did you forget '{symbol}' at the end of line {}?", self.previous.line))
+            }
+            _ => None,
+        };
+        self.error_at(true, message, hint);
+    }
+
+    fn check_tt(&mut self, typ: TokenType) -> bool {
+        self.current.typ == typ
+    }
+
+    /// Type of the token after the current one, without consuming anything
+    fn peek_next_tt(&self) -> TokenType {
+        self.scanner.clone().scan_token().typ
+    }
+
+    /// Whether a `for` loop's clauses are `var x in ...` or `var k, v in ...`
+    fn at_for_in(&self) -> bool {
+        if self.current.typ != TokenType::Var {
+            return false;
+        }
+
+        let mut scanner = self.scanner.clone();
+        if scanner.scan_token().typ != TokenType::Identifier {
+            return false;
+        }
+        match scanner.scan_token().typ {
+            TokenType::In => true,
+            TokenType::Comma => scanner.scan_token().typ == TokenType::Identifier && scanner.scan_token().typ == TokenType::In,
+            _ => false,
+        }
+    }
+
+    /// Whether the '(' just consumed opens the parameter list of an arrow function
+    fn at_arrow_params(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut typ = self.current.typ;
+        loop {
+            match typ {
+                TokenType::Identifier | TokenType::Comma => typ = scanner.scan_token().typ,
+                TokenType::RightParen => return scanner.scan_token().typ == TokenType::Arrow,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether the current '[' starts a slice like `[start:end]` rather than an index
+    fn at_slice(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut depth = 0;
+        loop {
+            match scanner.scan_token().typ {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                TokenType::RightBracket if depth == 0 => return false,
+                TokenType::RightBracket => depth -= 1,
+                TokenType::Colon if depth == 0 => return true,
+                TokenType::EOF => return false,
+                _ => (),
+            }
+        }
+    }
+
+    fn match_tt(&mut self, typ: TokenType) -> bool {
+        if !self.check_tt(typ) {
+            return false;
+        }
+
+        self.advance();
+
+        true
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            self.current = self.scanner.scan_token();
+            dbgln!("Current token: {}", self.current.typ);
+            if self.current.typ != TokenType::Error {
+                break;
+            }
+
+            let current_source: Rc<str> = self.current.source.clone();
+            self.error_at_current(current_source.as_ref());
+        }
+    }
+
+    /// Skip to the start of the next statement after an error, or to the '}' closing the block the error is in,
+    /// so a broken statement doesn't take in the declarations after its block
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        let mut depth = 0;
+        while self.current.typ != TokenType::EOF {
+            if self.previous.typ == TokenType::Semicolon && depth == 0 {
+                return;
+            }
+
+            match self.current.typ {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth > 0 => depth -= 1,
+                TokenType::RightBrace if self.blocks > 0 => return,
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Return
+                | TokenType::Import
+                    if depth == 0 =>
+                {
+                    return;
+                }
+                _ => {}
+            }
+
+            self.advance()
+        }
+    }
+
+    fn get_rule(&self, token_type: TokenType) -> &ParseRule {
+        self.rules.get(&token_type).unwrap()
+    }
+
+    fn declaration(&mut self) -> Stmt {
+        let stmt = if self.match_tt(TokenType::Class) {
+            self.class_declaration()
+        } else if self.check_tt(TokenType::Fun) && self.peek_next_tt() == TokenType::Identifier {
+            self.advance();
+            self.fun_declaration()
+        } else if self.match_tt(TokenType::Var) {
+            self.var_declaration()
+        } else if self.match_tt(TokenType::Import) {
+            self.import_declaration()
+        } else {
+            self.statement()
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        stmt
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if self.match_tt(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_tt(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_tt(TokenType::If) {
+            self.if_statement()
+        } else if self.match_tt(TokenType::While) {
+            self.while_statement(None)
+        } else if self.match_tt(TokenType::For) {
+            self.for_statement(None)
+        } else if self.match_tt(TokenType::Break) {
+            let (keyword, label, semicolon) = self.jump_statement("break");
+            Stmt::Break { keyword, label, semicolon }
+        } else if self.match_tt(TokenType::Continue) {
+            let (keyword, label, semicolon) = self.jump_statement("continue");
+            Stmt::Continue { keyword, label, semicolon }
+        } else if self.match_tt(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_tt(TokenType::Throw) {
+            self.throw_statement()
+        } else if self.check_tt(TokenType::Identifier) && self.peek_next_tt() == TokenType::Colon {
+            self.labeled_statement()
+        } else if self.match_tt(TokenType::LeftBrace) {
+            Stmt::Block(self.block())
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    /// Parse the statements of a block whose '{' was just consumed, up to its '}'
+    fn block(&mut self) -> Block {
+        let open = self.previous.clone();
+        let mut statements = Vec::new();
+        self.blocks += 1;
+        while !self.check_tt(TokenType::RightBrace) && !self.check_tt(TokenType::EOF) {
+            statements.push(self.declaration());
+        }
+        self.blocks -= 1;
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block");
+        Block {
+            open,
+            statements,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn class_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let name = self.declaration_of_previous();
+
+        let mut superclass = None;
+        if self.match_tt(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            if identifiers_equal(&name.name, &self.previous) {
+                self.error_at_previous("A class can't inherit from itself.");
+            }
+            superclass = Some(self.variable(false));
+        }
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        let mut methods = Vec::new();
+        self.blocks += 1;
+        while !self.check_tt(TokenType::RightBrace) && !self.check_tt(TokenType::EOF) {
+            // Skip what can't start a method, so the methods after it are still parsed
+            if !self.check_tt(TokenType::Identifier) {
+                self.error_at_current("Expect method name.");
+                while !matches!(self.current.typ, TokenType::Identifier | TokenType::RightBrace | TokenType::EOF) {
+                    self.advance();
+                }
+                self.panic_mode = false;
+                continue;
+            }
+            methods.push(self.method(&name.name.source));
+        }
+        self.blocks -= 1;
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+
+        Stmt::Class(Class {
+            keyword,
+            name,
+            superclass,
+            super_id: self.id(),
+            methods,
+            close: self.previous.clone(),
+        })
+    }
+
+    fn method(&mut self, class_name: &str) -> Function {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let kind = match name.source.as_ref() == class_name {
+            true => FunType::Initializer,
+            false => FunType::Method,
+        };
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        self.function_body(kind, name.clone(), Some(name), false)
+    }
+
+    fn fun_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Identifier, "Expect function name");
+        let name = self.declaration_of_previous();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        let function = self.function_body(FunType::Function, keyword.clone(), Some(name.name.clone()), false);
+        Stmt::Function { keyword, name, function }
+    }
+
+    fn function_expression(&mut self, _can_assign: bool) -> Expr {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'function'");
+        Expr::Function(Box::new(self.function_body(FunType::Function, keyword, None, false)))
+    }
+
+    /// Parse the parameters and body of a function whose '(' was just consumed.
+    /// Arrow functions may have a single expression as their body, which is returned.
+    fn function_body(&mut self, kind: FunType, start: Token, name: Option<Token>, is_arrow: bool) -> Function {
+        let id = self.id();
+        let mut params: Vec<Param> = Vec::new();
+        let mut has_defaults = false;
+
+        if !self.check_tt(TokenType::RightParen) {
+            loop {
+                if params.len() == 255 {
+                    self.error_at_current("Can't have more than 255 parameters");
+                }
+
+                self.consume(TokenType::Identifier, "Expect parameter name");
+                let name = self.declaration_of_previous();
+                if self.match_tt(TokenType::LeftBracket) {
+                    self.error_at_current("Array parameters are not supported");
+                }
+
+                let mut default = None;
+                if self.match_tt(TokenType::Equal) {
+                    has_defaults = true;
+                    let equal = self.previous.clone();
+                    default = Some((equal, self.expression()));
+                } else if has_defaults {
+                    self.error_at_current("Non-default argument follows default argument");
+                }
+                params.push(Param { name, default });
+
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after parameters");
+        let close = self.previous.clone();
+
+        if is_arrow {
+            self.consume(TokenType::Arrow, "Expect '=>' after arrow function parameters");
+        }
+
+        let body = if is_arrow && !self.check_tt(TokenType::LeftBrace) {
+            FunctionBody::Expression(self.expression())
+        } else {
+            self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+            let open = self.previous.clone();
+
+            // Parse python-style docstring if present
+            let mut doc = None;
+            if self.check_tt(TokenType::String) {
+                self.advance();
+                doc = Some(self.previous.clone());
+                self.consume(TokenType::Semicolon, "Expect ';' after function docstring");
+            }
+
+            let mut block = self.block();
+            block.open = open;
+            FunctionBody::Block { doc, block }
+        };
+
+        Function {
+            id,
+            kind,
+            start,
+            name,
+            params,
+            close,
+            body,
+        }
+    }
+
+    fn var_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Identifier, "Expect variable name");
+        let name = self.declaration_of_previous();
+
+        let mut size = None;
+        let mut initializer = None;
+        if self.match_tt(TokenType::LeftBracket) {
+            size = Some(self.expression());
+            self.consume(TokenType::RightBracket, "Expect ']' after array size");
+        } else if self.match_tt(TokenType::Equal) {
+            initializer = Some(self.expression());
+        }
+
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration");
+        Stmt::Var {
+            keyword,
+            name,
+            size,
+            initializer,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn import_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let module;
+        let mut alias = None;
+        let path: String;
+        let namespace: String;
+
+        if self.match_tt(TokenType::Identifier) {
+            module = self.previous.clone();
+            path = module.source.to_string();
+            namespace = path.clone();
+        } else if self.match_tt(TokenType::String) {
+            module = self.previous.clone();
+            path = unescape_string(&module.source[1..module.source.len() - 1]);
+
+            if self.match_tt(TokenType::As) {
+                self.consume(TokenType::Identifier, "Expect namespace alias after 'as'.");
+                alias = Some(self.previous.clone());
+                namespace = self.previous.source.to_string();
+            } else {
+                let p = std::path::Path::new(&path);
+                namespace = p
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.clone());
+            }
+        } else {
+            self.error_at_current("Expect string literal or module identifier after 'import'.");
+            return Stmt::Error(keyword);
+        }
+
+        self.namespaces.insert(namespace.clone());
+        self.consume(TokenType::Semicolon, "Expect ';' after import declaration.");
+        Stmt::Import(Import {
+            keyword,
+            module,
+            alias,
+            path,
+            namespace,
+            semicolon: self.previous.clone(),
+        })
+    }
+
+    fn expression_statement(&mut self) -> Stmt {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression");
+        Stmt::Expression {
+            expr,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn print_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression");
+        Stmt::Print {
+            keyword,
+            expr,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let mut value = None;
+        if !self.match_tt(TokenType::Semicolon) {
+            value = Some(self.expression());
+            self.consume(TokenType::Semicolon, "Expect ';' after return value");
+        }
+
+        Stmt::Return {
+            keyword,
+            value,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        let close = self.previous.clone();
+
+        let then_branch = Box::new(self.statement());
+        let mut else_branch = None;
+        if self.match_tt(TokenType::Else) {
+            else_branch = Some(Box::new(self.statement()));
+        }
+
+        Stmt::If {
+            keyword,
+            condition,
+            close,
+            then_branch,
+            else_branch,
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<Token>) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        let close = self.previous.clone();
+
+        Stmt::While {
+            label,
+            keyword,
+            condition,
+            close,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn for_statement(&mut self, label: Option<Token>) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after for");
+
+        if self.at_for_in() {
+            return self.for_in_loop(label, keyword);
+        }
+
+        let initializer = if self.match_tt(TokenType::Semicolon) {
+            None
+        } else if self.match_tt(TokenType::Var) {
+            Some(Box::new(self.var_declaration()))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        let mut condition = None;
+        if !self.match_tt(TokenType::Semicolon) {
+            condition = Some(self.expression());
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition");
+        }
+        let semicolon = self.previous.clone();
+
+        let mut increment = None;
+        if !self.match_tt(TokenType::RightParen) {
+            increment = Some(self.expression());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses");
+        }
+        let close = self.previous.clone();
+
+        Stmt::For {
+            label,
+            keyword,
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            close,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    /// Parse the rest of a `for (var x in collection)` or `for (var k, v in collection)` loop
+    fn for_in_loop(&mut self, label: Option<Token>, keyword: Token) -> Stmt {
+        self.consume(TokenType::Var, "Expect 'var' in for-in loop");
+        self.consume(TokenType::Identifier, "Expect loop variable name");
+        let mut variables = vec![self.declaration_of_previous()];
+        if self.match_tt(TokenType::Comma) {
+            self.consume(TokenType::Identifier, "Expect loop variable name");
+            variables.push(self.declaration_of_previous());
+        }
+        self.consume(TokenType::In, "Expect 'in' after loop variables");
+
+        let iterable = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for-in clauses");
+        let close = self.previous.clone();
+
+        Stmt::ForIn {
+            label,
+            keyword,
+            variables,
+            iterable,
+            close,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn labeled_statement(&mut self) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect loop label");
+        let label = self.previous.clone();
+        self.consume(TokenType::Colon, "Expect ':' after loop label");
+
+        if self.match_tt(TokenType::While) {
+            self.while_statement(Some(label))
+        } else if self.match_tt(TokenType::For) {
+            self.for_statement(Some(label))
+        } else {
+            self.error_at_current("Expect 'while' or 'for' after loop label");
+            Stmt::Error(label)
+        }
+    }
+
+    /// Parse the optional label and ';' after a `break` or `continue`
+    fn jump_statement(&mut self, keyword: &str) -> (Token, Option<Token>, Token) {
+        let keyword_token = self.previous.clone();
+        let mut label = None;
+        if self.match_tt(TokenType::Identifier) {
+            label = Some(self.previous.clone());
+        }
+
+        self.consume(TokenType::Semicolon, &format!("Expect ';' after '{keyword}'"));
+        (keyword_token, label, self.previous.clone())
+    }
+
+    fn throw_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value");
+        Stmt::Throw {
+            keyword,
+            value,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn try_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'");
+        let block = self.block();
+
+        let mut catch = None;
+        if self.match_tt(TokenType::Catch) {
+            let catch_keyword = self.previous.clone();
+            let mut name = None;
+            if self.match_tt(TokenType::LeftParen) {
+                self.consume(TokenType::Identifier, "Expect exception name");
+                name = Some(self.declaration_of_previous());
+                self.consume(TokenType::RightParen, "Expect ')' after exception name");
+            }
+            self.consume(TokenType::LeftBrace, "Expect '{' after catch clause");
+            catch = Some(Catch {
+                keyword: catch_keyword,
+                name,
+                block: self.block(),
+            });
+        }
+
+        let mut finally = None;
+        if self.match_tt(TokenType::Finally) {
+            let finally_keyword = self.previous.clone();
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'");
+            finally = Some((finally_keyword, self.block()));
+        } else if catch.is_none() {
+            self.error_at_current("Expect 'catch' or 'finally' after try block");
+        }
+
+        Stmt::Try {
+            keyword,
+            block,
+            catch,
+            finally,
+        }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    /// Parse expressions with equal or higher precedence
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        // A missing expression right before a '}' leaves the '}' to close its block
+        if self.blocks > 0 && self.check_tt(TokenType::RightBrace) {
+            self.error_at_current("Expect expression");
+            return Expr::Error(self.current.clone());
+        }
+        self.advance();
+        let prefix_rule = self.get_rule(self.previous.typ).prefix;
+        let can_assign = precedence <= Precedence::Assignment;
+
+        let mut expr = match prefix_rule {
+            Some(rule) => rule(self, can_assign),
+            None => {
+                self.error_at_previous("Expect expression");
+                return Expr::Error(self.previous.clone());
+            }
+        };
+
+        while precedence <= self.get_rule(self.current.typ).precedence {
+            self.advance();
+            let infix_rule = self.get_rule(self.previous.typ).infix;
+
+            match infix_rule {
+                Some(rule) => expr = rule(self, expr, can_assign),
+                None => {
+                    self.error_at_previous("Expect expression");
+                    return expr;
+                }
+            }
+        }
+
+        if can_assign && self.match_tt(TokenType::Equal) {
+            self.error_at_current("Invalid assignment target");
+        }
+        expr
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let rule = self.get_rule(operator.typ);
+        let right = self.parse_precedence(increment_prec(rule.precedence));
+
+        Expr::Binary {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    fn logical(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let precedence = match operator.typ {
+            TokenType::And => Precedence::And,
+            _ => Precedence::Or,
+        };
+        let right = self.parse_precedence(precedence);
+
+        Expr::Logical {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> Expr {
+        let arguments = self.argument_list();
+        Expr::Call {
+            callee: Box::new(callee),
+            arguments,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut arguments = Vec::new();
+
+        if !self.check_tt(TokenType::RightParen) {
+            loop {
+                let argument = self.expression();
+
+                if arguments.len() == 255 {
+                    self.error_at_previous("Can't have more than 255 arguments.");
+                }
+
+                arguments.push(argument);
+
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arguments
+    }
+
+    fn dot(&mut self, object: Expr, can_assign: bool) -> Expr {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let target = Target::Property {
+            object: Box::new(object),
+            name,
+        };
+
+        let assigns = matches!(self.current.typ, TokenType::Equal | TokenType::PlusEqual | TokenType::MinusEqual);
+        let increments = matches!(self.current.typ, TokenType::PlusPlus | TokenType::MinusMinus);
+        if can_assign && assigns {
+            self.advance();
+            self.assignment(target)
+        } else if can_assign && increments {
+            self.advance();
+            Expr::Increment {
+                target,
+                operator: self.previous.clone(),
+                prefix: false,
+            }
+        } else {
+            let Target::Property { object, name } = target else {
+                unreachable!()
+            };
+            Expr::Get { object, name }
+        }
+    }
+
+    /// Parse the value assigned to `target` by the '=', '+=' or '-=' just consumed
+    fn assignment(&mut self, target: Target) -> Expr {
+        let operator = self.previous.clone();
+        let value = self.expression();
+        Expr::Assign {
+            target,
+            operator,
+            value: Box::new(value),
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> Expr {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+
+        Expr::Super {
+            keyword,
+            method: self.previous.clone(),
+            this_id: self.id(),
+            super_id: self.id(),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Expr {
+        let open = self.previous.clone();
+        if self.at_arrow_params() {
+            return Expr::Function(Box::new(self.function_body(FunType::Function, open, None, true)));
+        }
+
+        let expr = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        Expr::Grouping {
+            open,
+            expr: Box::new(expr),
+            close: self.previous.clone(),
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Expr {
+        Expr::Literal(self.previous.clone())
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let value = token.source.parse::<f64>().unwrap();
+        Expr::Number { token, value }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let value = unescape_string(&token.source[1..token.source.len() - 1]);
+        Expr::String { token, value }
+    }
+
+    /// Parses a string with embedded `${...}` expressions, made of an `Interpolation` token before each expression and a
+    /// `String` token after the last one
+    fn interpolation(&mut self, _can_assign: bool) -> Expr {
+        let mut strings = Vec::new();
+        let mut expressions = Vec::new();
+        loop {
+            let token = self.previous.clone();
+            // Strip the leading quote or closing brace, and the trailing "${"
+            let value = unescape_string(&token.source[1..token.source.len() - 2]);
+            strings.push((token, value));
+
+            // A string part starting right away means the braces were empty
+            let current = &self.current;
+            if current.typ == TokenType::String && current.source.starts_with('}') {
+                self.error_at_current("Expect expression inside '${}'.");
+                return Expr::Error(self.previous.clone());
+            }
+            expressions.push(self.expression());
+
+            if !self.match_tt(TokenType::Interpolation) {
+                break;
+            }
+        }
+
+        if !self.check_tt(TokenType::String) {
+            self.error_at_current("Expect '}' after interpolated expression.");
+            return Expr::Error(self.previous.clone());
+        }
+        self.advance();
+        let token = self.previous.clone();
+        let value = unescape_string(&token.source[1..token.source.len() - 1]);
+        strings.push((token, value));
+
+        let part_count = strings.iter().filter(|(_, value)| !value.is_empty()).count() + expressions.len();
+        if part_count > u8::MAX as usize {
+            self.error_at_previous("Can't have more than 255 parts in an interpolated string.");
+        }
+        Expr::Interpolation { strings, expressions }
+    }
+
+    fn array_literal(&mut self, _can_assign: bool) -> Expr {
+        let open = self.previous.clone();
+        let mut elements = Vec::new();
+        if !self.check_tt(TokenType::RightBracket) {
+            loop {
+                let element = self.expression();
+                if elements.len() == 255 {
+                    self.error_at_previous("Can't have more than 255 elements in an array literal.");
+                }
+                elements.push(element);
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after array literal elements.");
+        Expr::Array {
+            open,
+            elements,
+            close: self.previous.clone(),
+        }
+    }
+
+    /// Parses `{key: value, ...}`. Blocks only start statements, so a brace in expression position is always a map.
+    /// Keys are expressions, so `{name: 1}` uses the value of the variable `name` as the key.
+    fn map_literal(&mut self, _can_assign: bool) -> Expr {
+        let open = self.previous.clone();
+        let mut entries = Vec::new();
+        if !self.check_tt(TokenType::RightBrace) {
+            loop {
+                let key = self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                let value = self.expression();
+                if entries.len() == 255 {
+                    self.error_at_previous("Can't have more than 255 entries in a map literal.");
+                }
+                entries.push((key, value));
+                if !self.match_tt(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map literal entries.");
+        Expr::Map {
+            open,
+            entries,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn index_access(&mut self, object: Expr, can_assign: bool) -> Expr {
+        let open = self.previous.clone();
+        let object = Box::new(object);
+
+        // Either bound of a slice may be left out
+        let (start, is_slice) = if self.match_tt(TokenType::Colon) {
+            (None, true)
+        } else {
+            let start = self.expression();
+            (Some(Box::new(start)), self.match_tt(TokenType::Colon))
+        };
+        if is_slice {
+            let colon = self.previous.clone();
+            let mut end = None;
+            if !self.check_tt(TokenType::RightBracket) {
+                end = Some(Box::new(self.expression()));
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after slice.");
+            return Expr::Slice {
+                object,
+                open,
+                start,
+                colon,
+                end,
+                close: self.previous.clone(),
+            };
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after array index.");
+
+        let index = Index {
+            open,
+            expr: start.unwrap(),
+            close: self.previous.clone(),
+        };
+        if can_assign && self.match_tt(TokenType::Equal) {
+            self.assignment(Target::Index { object, index })
+        } else {
+            Expr::Index { object, index }
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let mut path = vec![self.previous.clone()];
+
+        if self.namespaces.contains(path[0].source.as_ref()) {
+            while self.match_tt(TokenType::Dot) {
+                self.consume(TokenType::Identifier, "Expect property/method name after '.' in namespace access.");
+                path.push(self.previous.clone());
+            }
+        }
+
+        let variable = Variable {
+            id: self.id(),
+            path,
+            index: self.variable_index(),
+        };
+
+        let assigns = matches!(self.current.typ, TokenType::Equal | TokenType::PlusEqual | TokenType::MinusEqual);
+        if can_assign && assigns {
+            self.advance();
+            self.assignment(Target::Variable(variable))
+        } else if self.match_tt(TokenType::PlusPlus) || self.match_tt(TokenType::MinusMinus) {
+            Expr::Increment {
+                target: Target::Variable(variable),
+                operator: self.previous.clone(),
+                prefix: false,
+            }
+        } else {
+            Expr::Variable(variable)
+        }
+    }
+
+    fn prefix_increment_decrement(&mut self, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let variable = Variable {
+            id: self.id(),
+            path: vec![self.previous.clone()],
+            index: self.variable_index(),
+        };
+
+        Expr::Increment {
+            target: Target::Variable(variable),
+            operator,
+            prefix: true,
+        }
+    }
+
+    /// Parse the index right after a variable's name. Slices are left for `index_access` once the variable is loaded.
+    fn variable_index(&mut self) -> Option<Index> {
+        if !self.check_tt(TokenType::LeftBracket) || self.at_slice() {
+            return None;
+        }
+
+        self.advance();
+        let open = self.previous.clone();
+        let expr = self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after array index");
+        Some(Index {
+            open,
+            expr: Box::new(expr),
+            close: self.previous.clone(),
+        })
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let operand = self.parse_precedence(Precedence::Unary);
+        Expr::Unary {
+            operator,
+            operand: Box::new(operand),
+        }
+    }
+}
+
+fn unescape_string(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next_c) = chars.peek() {
+                match next_c {
+                    '"' => {
+                        result.push('"');
+                        chars.next();
+                    }
+                    '\\' => {
+                        result.push('\\');
+                        chars.next();
+                    }
+                    'n' => {
+                        result.push('\n');
+                        chars.next();
+                    }
+                    't' => {
+                        result.push('\t');
+                        chars.next();
+                    }
+                    'r' => {
+                        result.push('\r');
+                        chars.next();
+                    }
+                    '$' => {
+                        result.push('$');
+                        chars.next();
+                    }
+                    _ => {
+                        result.push('\\');
+                    }
+                }
+            } else {
+                result.push('\\');
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
@@ -0,0 +1,764 @@
+use crate::{
+    ast::{Block, Catch, Class, Declaration, Expr, Function, FunctionBody, NodeId, Stmt, Target, Variable},
+    common::U24_MAX,
+    dbgln,
+    diagnostic::{Diagnostic, Severity},
+    fun::FunType,
+    lint::{Lint, LintConfig},
+    native,
+    scanner::Token,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Where a variable lives when the program runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// A stack slot of the function the variable is used in
+    Local(usize),
+    /// An upvalue of the function the variable is used in
+    Upvalue(usize),
+    /// A global, by its name with the namespace prefix of its file
+    Global(Rc<str>),
+}
+
+/// A variable captured by a function.
+/// `is_local` means `index` is a local slot of the enclosing function, otherwise it is an upvalue of the enclosing function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upvalue {
+    pub index: usize,
+    pub is_local: bool,
+}
+
+/// What the resolver found out about the names of a file, by the ids of the nodes naming them
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Where each variable, declaration and `super` expression finds its variables
+    pub variables: HashMap<NodeId, Access>,
+    /// Locals captured by a closure, which have to be closed rather than popped when their scope ends
+    pub captured: HashSet<NodeId>,
+    /// The variables each function captures, in the order of its upvalues
+    pub upvalues: HashMap<NodeId, Vec<Upvalue>>,
+    /// Aliases of the modules the file imports
+    pub namespaces: HashSet<String>,
+    /// Assignments to globals, which must be declared somewhere in the file
+    pub global_assignments: Vec<Token>,
+    /// Reads of globals, which must be declared somewhere in the program
+    pub global_reads: Vec<Token>,
+    /// Calls to builtin functions with their number of arguments, unless the file declares a global of the same name
+    pub builtin_calls: Vec<(Token, usize)>,
+}
+
+struct Local {
+    name: Rc<str>,
+    token: Token,
+    depth: isize,
+    used: bool,
+    /// `None` for the locals the compiler adds, like the iterator of a for-in loop
+    declaration: Option<NodeId>,
+    /// Whether it is a variable of the program, which can be reported as unused
+    named: bool,
+}
+
+/// A loop enclosing the code being resolved, that `break` and `continue` can target
+struct Loop {
+    label: Option<Rc<str>>,
+    try_depth: usize, // Try statements entered before the loop
+}
+
+/// A try statement enclosing the code being resolved
+struct TryBlock {
+    in_finally: bool, // The handler has already been removed once the finally block runs
+}
+
+/// The scopes of a function being resolved
+struct FunctionScope {
+    typ: FunType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    loops: Vec<Loop>,
+    tries: Vec<TryBlock>,
+    scope_depth: isize,
+}
+
+impl FunctionScope {
+    fn new(typ: FunType) -> FunctionScope {
+        FunctionScope {
+            typ,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            tries: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Name of the global `name` refers to in a file imported with the namespace `prefix`.
+/// Globals of imported files are prefixed with their namespace, except the builtin globals every program has.
+pub fn global_name(prefix: Option<&str>, name: &str) -> Rc<str> {
+    match prefix {
+        Some(prefix) if !native::is_builtin_global(name) && !name.starts_with(&format!("{prefix}.")) => {
+            Rc::from(format!("{prefix}.{name}"))
+        }
+        _ => Rc::from(name),
+    }
+}
+
+/// Finds what every name of a file refers to, and reports the mistakes in how names are used.
+/// Globals of imported files are given the namespace prefix of the file.
+pub struct Resolver<'a> {
+    prefix: Option<&'a str>,
+    lints: &'a LintConfig,
+    /// Globals declared by every file of the program so far, with the namespace prefix of imported files
+    declared_globals: &'a mut HashSet<Rc<str>>,
+    /// The function being resolved, and the functions it is nested in
+    functions: Vec<FunctionScope>,
+    /// Whether each class whose body is being resolved has a superclass
+    classes: Vec<bool>,
+    resolution: Resolution,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(typ: FunType, prefix: Option<&'a str>, lints: &'a LintConfig, declared_globals: &'a mut HashSet<Rc<str>>) -> Resolver<'a> {
+        Resolver {
+            prefix,
+            lints,
+            declared_globals,
+            functions: vec![FunctionScope::new(typ)],
+            classes: Vec::new(),
+            resolution: Resolution::default(),
+            diagnostics: Vec::new(),
+            panic_mode: false,
+        }
+    }
+
+    /// Finish resolving the file, returning what was found along with the errors and warnings
+    pub fn finish(mut self) -> (Resolution, Vec<Diagnostic>) {
+        self.warn_unused_locals(-1);
+        (self.resolution, self.diagnostics)
+    }
+
+    fn function_scope(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            hint: None,
+            file: None,
+            lint: None,
+        });
+    }
+
+    /// Report a problem found by `lint` at `token`, as a warning unless the lint is allowed or denied
+    fn warn(&mut self, lint: Lint, token: &Token, message: &str, hint: Option<String>) {
+        if let Some(severity) = self.lints.severity(lint) {
+            self.diagnostics.push(Diagnostic {
+                severity,
+                message: message.to_string(),
+                line: token.line,
+                column: token.column,
+                span: token.span,
+                hint,
+                file: None,
+                lint: Some(lint),
+            });
+        }
+    }
+
+    /// Resolve the statements of a file or a block, warning about the ones after a statement that leaves it.
+    /// Like the parser, errors are reported once per statement.
+    pub fn statements(&mut self, statements: &[Stmt]) {
+        // Statement that leaves the file or block, making the rest of it unreachable
+        let mut exit: Option<Rc<str>> = None;
+        for statement in statements {
+            if let Some(keyword) = exit.take() {
+                let message = format!("Unreachable code after '{keyword}'");
+                self.warn(Lint::UnreachableCode, statement.first_token(), &message, None);
+            } else if let Stmt::Return { keyword, .. }
+            | Stmt::Break { keyword, .. }
+            | Stmt::Continue { keyword, .. }
+            | Stmt::Throw { keyword, .. } = statement
+            {
+                exit = Some(keyword.source.clone());
+            }
+            self.statement(statement);
+            self.panic_mode = false;
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.statements(&block.statements);
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => self.expression(expr),
+            Stmt::Var {
+                name, size, initializer, ..
+            } => {
+                self.declare(name);
+                if let Some(expr) = size.as_ref().or(initializer.as_ref()) {
+                    self.expression(expr);
+                }
+                self.mark_initialized();
+            }
+            Stmt::Function { name, function, .. } => {
+                self.declare(name);
+                self.mark_initialized();
+                self.function(function);
+            }
+            Stmt::Class(class) => self.class(class),
+            Stmt::Import(import) => {
+                self.resolution.namespaces.insert(import.namespace.clone());
+            }
+            Stmt::Block(block) => {
+                self.begin_scope();
+                self.block(block);
+                self.end_scope();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::While {
+                label, condition, body, ..
+            } => {
+                self.check_label(label.as_ref());
+                self.expression(condition);
+                self.loop_body(label.as_ref(), body);
+            }
+            Stmt::For {
+                label,
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.check_label(label.as_ref());
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                for expr in condition.iter().chain(increment) {
+                    self.expression(expr);
+                }
+                self.loop_body(label.as_ref(), body);
+                self.end_scope();
+            }
+            Stmt::ForIn {
+                label,
+                variables,
+                iterable,
+                body,
+                ..
+            } => {
+                self.check_label(label.as_ref());
+                self.begin_scope();
+                self.expression(iterable);
+                // The iterator lives in a hidden local for the whole loop
+                self.add_local(Rc::from("in"), iterable.last_token(), None, false);
+                self.mark_initialized();
+
+                let try_depth = self.function_scope().tries.len();
+                let label = label.as_ref().map(|label| label.source.clone());
+                self.function_scope().loops.push(Loop { label, try_depth });
+                // Loop variables are scoped to a single iteration, so closures capture each value separately
+                self.begin_scope();
+                for variable in variables {
+                    self.check_shadowed_builtin(&variable.name);
+                    self.add_local(variable.name.source.clone(), &variable.name, Some(variable.id), true);
+                    self.mark_initialized();
+                    self.mark_used();
+                }
+                self.statement(body);
+                self.end_scope();
+                self.function_scope().loops.pop();
+                self.end_scope();
+            }
+            Stmt::Return { keyword, value, .. } => {
+                let typ = self.function_scope().typ;
+                if typ == FunType::Script {
+                    self.error(keyword, "Can't return from top-level code");
+                }
+                if let Some(value) = value {
+                    if typ == FunType::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.expression(value);
+                }
+            }
+            Stmt::Break { keyword, label, .. } | Stmt::Continue { keyword, label, .. } => self.jump(keyword, label.as_ref()),
+            Stmt::Throw { value, .. } => self.expression(value),
+            Stmt::Try { block, catch, finally, .. } => self.try_statement(block, catch.as_ref(), finally.as_ref()),
+            Stmt::Error(_) => {}
+        }
+    }
+
+    fn class(&mut self, class: &Class) {
+        self.declare(&class.name);
+        self.mark_initialized();
+        self.classes.push(class.superclass.is_some());
+
+        if let Some(superclass) = &class.superclass {
+            self.expression(superclass);
+
+            // The superclass lives in a scope around the class body, so methods capture it as 'super'
+            self.begin_scope();
+            self.add_local(Rc::from("super"), superclass.last_token(), Some(class.super_id), false);
+            self.mark_initialized();
+            self.class_reference(&class.name);
+        }
+
+        // The class is kept on the stack while its methods are attached
+        self.class_reference(&class.name);
+        for method in &class.methods {
+            self.function(method);
+        }
+
+        self.classes.pop();
+        if class.superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Note a read of the class being declared, which the compiler loads to attach its superclass and methods
+    fn class_reference(&mut self, name: &Declaration) {
+        if let Access::Global(global) = self.resolve(&name.name) {
+            let mut token = name.name.clone();
+            token.source = global;
+            self.resolution.global_reads.push(token);
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.functions.push(FunctionScope::new(function.kind));
+        self.begin_scope();
+        for param in &function.params {
+            self.declare(&param.name);
+            self.mark_initialized();
+            // Parameters are part of the signature, so leaving one unused isn't a mistake
+            self.mark_used();
+            if let Some((_, default)) = &param.default {
+                self.expression(default);
+            }
+        }
+
+        if function.kind == FunType::Method || function.kind == FunType::Initializer {
+            let name = Token {
+                source: Rc::from("this"),
+                ..function.close.clone()
+            };
+            self.add_local(name.source.clone(), &name, None, false);
+            let local = self.function_scope().locals.last_mut().unwrap();
+            local.depth = 0;
+            local.used = true;
+        }
+
+        match &function.body {
+            FunctionBody::Block { block, .. } => self.block(block),
+            FunctionBody::Expression(expr) => self.expression(expr),
+        }
+
+        self.warn_unused_locals(-1);
+        let scope = self.functions.pop().unwrap();
+        self.resolution.upvalues.insert(function.id, scope.upvalues);
+    }
+
+    fn loop_body(&mut self, label: Option<&Token>, body: &Stmt) {
+        let try_depth = self.function_scope().tries.len();
+        let label = label.map(|label| label.source.clone());
+        self.function_scope().loops.push(Loop { label, try_depth });
+        self.statement(body);
+        self.function_scope().loops.pop();
+    }
+
+    fn check_label(&mut self, label: Option<&Token>) {
+        let Some(label) = label else {
+            return;
+        };
+        if self.function_scope().loops.iter().any(|l| l.label.as_ref() == Some(&label.source)) {
+            self.error(label, "A loop with this label already encloses this one");
+        }
+    }
+
+    /// Check the loop targeted by a `break` or `continue` exists, and that the jump doesn't leave a finally block
+    fn jump(&mut self, keyword: &Token, label: Option<&Token>) {
+        let mut target = self.function_scope().loops.len().checked_sub(1);
+        if target.is_none() {
+            self.error(keyword, &format!("Can't use '{}' outside of a loop", keyword.source));
+        }
+
+        if let Some(label) = label {
+            target = self
+                .function_scope()
+                .loops
+                .iter()
+                .rposition(|l| l.label.as_ref() == Some(&label.source));
+            if target.is_none() {
+                self.error(label, &format!("No enclosing loop labeled '{}'", label.source));
+            }
+        }
+
+        let Some(target) = target else {
+            return;
+        };
+        let depth = self.function_scope().loops[target].try_depth;
+        for i in (depth..self.function_scope().tries.len()).rev() {
+            if self.function_scope().tries[i].in_finally {
+                let message = format!("Can't use '{}' to leave a 'finally' block", keyword.source);
+                self.error(keyword, &message);
+            }
+        }
+    }
+
+    fn try_statement(&mut self, block: &Block, catch: Option<&Catch>, finally: Option<&(Token, Block)>) {
+        self.function_scope().tries.push(TryBlock { in_finally: false });
+
+        self.begin_scope();
+        self.block(block);
+        self.end_scope();
+
+        if let Some(catch) = catch {
+            self.begin_scope();
+            if let Some(name) = &catch.name {
+                self.check_shadowed_builtin(&name.name);
+                self.add_local(name.name.source.clone(), &name.name, Some(name.id), true);
+                self.mark_initialized();
+                self.mark_used();
+            }
+            self.block(&catch.block);
+            self.end_scope();
+        }
+
+        if let Some((_, block)) = finally {
+            self.function_scope().tries.last_mut().unwrap().in_finally = true;
+
+            self.begin_scope();
+            self.block(block);
+            self.end_scope();
+        }
+
+        self.function_scope().tries.pop();
+    }
+
+    pub fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(_) | Expr::Number { .. } | Expr::String { .. } | Expr::Error(_) => {}
+            Expr::Interpolation { expressions, .. } => {
+                for expr in expressions {
+                    self.expression(expr);
+                }
+            }
+            Expr::Variable(variable) => self.variable(variable, false),
+            Expr::Assign { target, value, .. } => {
+                self.target(target);
+                self.expression(value);
+            }
+            Expr::Increment { target, .. } => self.target(target),
+            Expr::Get { object, .. } => self.expression(object),
+            Expr::Index { object, index } => {
+                self.expression(object);
+                self.expression(&index.expr);
+            }
+            Expr::Slice { object, start, end, .. } => {
+                self.expression(object);
+                for bound in start.iter().chain(end) {
+                    self.expression(bound);
+                }
+            }
+            Expr::Call { callee, arguments, .. } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+
+                if let Expr::Variable(variable) = callee.as_ref() {
+                    let token = variable.token();
+                    let is_global = matches!(self.resolution.variables.get(&variable.id), Some(Access::Global(_)));
+                    if is_global && native::builtin_arity(&token.source).is_some() {
+                        self.resolution.builtin_calls.push((token, arguments.len()));
+                    }
+                }
+            }
+            Expr::Unary { operand, .. } => self.expression(operand),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Array { elements, .. } => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expr::Function(function) => self.function(function),
+            Expr::Super {
+                keyword,
+                method,
+                this_id,
+                super_id,
+            } => {
+                match self.classes.last() {
+                    None => self.error(keyword, "Can't use 'super' outside of a class."),
+                    Some(false) => self.error(keyword, "Can't use 'super' in a class with no superclass."),
+                    Some(true) => {}
+                }
+
+                for (name, id) in [("this", this_id), ("super", super_id)] {
+                    let token = Token {
+                        source: Rc::from(name),
+                        ..method.clone()
+                    };
+                    let access = self.resolve_variable(&token, Rc::from(name));
+                    self.resolution.variables.insert(*id, access);
+                }
+            }
+        }
+    }
+
+    fn target(&mut self, target: &Target) {
+        match target {
+            Target::Variable(variable) => self.variable(variable, true),
+            Target::Property { object, .. } => self.expression(object),
+            Target::Index { object, index } => {
+                self.expression(object);
+                self.expression(&index.expr);
+            }
+        }
+    }
+
+    /// Resolve a use of a variable, noting the globals it reads or assigns for the lints checked once the file is compiled
+    fn variable(&mut self, variable: &Variable, assigns: bool) {
+        let token = variable.token();
+        let access = self.resolve(&token);
+        if let Access::Global(global) = &access {
+            let mut token = token;
+            token.source = global.clone();
+            if assigns {
+                self.resolution.global_assignments.push(token);
+            } else {
+                self.resolution.global_reads.push(token);
+            }
+        }
+        self.resolution.variables.insert(variable.id, access);
+
+        if let Some(index) = &variable.index {
+            self.expression(&index.expr);
+        }
+    }
+
+    /// Find the variable `name` refers to, which is a global of the file's namespace if no local of that name is in scope
+    fn resolve(&mut self, name: &Token) -> Access {
+        let global = global_name(self.prefix, &name.source);
+        self.resolve_variable(name, global)
+    }
+
+    fn resolve_variable(&mut self, name: &Token, global: Rc<str>) -> Access {
+        let depth = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(depth, name) {
+            return Access::Local(slot);
+        }
+        if let Some(index) = self.resolve_upvalue(depth, name) {
+            return Access::Upvalue(index);
+        }
+        Access::Global(global)
+    }
+
+    fn resolve_local(&mut self, function: usize, name: &Token) -> Option<usize> {
+        dbgln!("Resolving local: {}", name.source);
+        let slot = self.functions[function]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name.source)?;
+        let local = &mut self.functions[function].locals[slot];
+        local.used = true;
+        if local.depth == -1 {
+            self.error(name, "Can't read local variable in its own initializer");
+        }
+
+        dbgln!("Resolved to {slot}");
+        Some(slot)
+    }
+
+    /// Resolve a local of a function enclosing `function`, capturing it as an upvalue of every function in between
+    fn resolve_upvalue(&mut self, function: usize, name: &Token) -> Option<usize> {
+        if function == 0 {
+            return None;
+        }
+
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            // Closures capture locals by a single byte slot
+            if slot > u8::MAX as usize {
+                self.error(name, "Can't capture a local variable declared after the first 256 in a function");
+            }
+
+            if let Some(id) = self.functions[function - 1].locals[slot].declaration {
+                self.resolution.captured.insert(id);
+            }
+            dbgln!("Resolved {} to upvalue of local {slot}", name.source);
+            return Some(self.add_upvalue(function, name, slot, true));
+        }
+
+        let upvalue = self.resolve_upvalue(function - 1, name)?;
+        Some(self.add_upvalue(function, name, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, function: usize, name: &Token, index: usize, is_local: bool) -> usize {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+
+        // The upvalue count of a closure is a single byte
+        if upvalues.len() == u8::MAX as usize {
+            self.error(name, "Too many closure variables in function");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        upvalues.len() - 1
+    }
+
+    fn begin_scope(&mut self) {
+        self.function_scope().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.function_scope();
+        scope.scope_depth -= 1;
+        let depth = scope.scope_depth;
+        self.warn_unused_locals(depth);
+
+        let scope = self.function_scope();
+        while scope.locals.last().is_some_and(|local| local.depth > depth) {
+            scope.locals.pop();
+        }
+    }
+
+    fn add_local(&mut self, name: Rc<str>, token: &Token, declaration: Option<NodeId>, named: bool) {
+        if self.function_scope().locals.len() > U24_MAX {
+            self.error(token, "Too many local variables in function");
+            return;
+        }
+
+        dbgln!("Adding local: {name}");
+        self.function_scope().locals.push(Local {
+            name,
+            token: token.clone(),
+            depth: -1,
+            used: false,
+            declaration,
+            named,
+        });
+    }
+
+    /// Declare a variable, function, class or parameter, which is a global at the top level of a file.
+    /// Locals can't be read until they are marked initialized.
+    fn declare(&mut self, declaration: &Declaration) {
+        let name = &declaration.name;
+        self.check_shadowed_builtin(name);
+
+        let scope = self.function_scope();
+        if scope.scope_depth == 0 {
+            let global = global_name(self.prefix, &name.source);
+            self.declared_globals.insert(global.clone());
+            self.resolution.variables.insert(declaration.id, Access::Global(global));
+            return;
+        }
+
+        let depth = scope.scope_depth;
+        let duplicate = scope
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == -1 || local.depth >= depth)
+            .any(|local| local.name == name.source);
+        if duplicate {
+            self.error(name, "Already a variable with this name in this scope");
+        }
+
+        let slot = self.function_scope().locals.len();
+        self.add_local(name.source.clone(), name, Some(declaration.id), true);
+        self.resolution.variables.insert(declaration.id, Access::Local(slot));
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.function_scope();
+        if scope.scope_depth == 0 {
+            return;
+        }
+        let depth = scope.scope_depth;
+        if let Some(local) = scope.locals.last_mut() {
+            local.depth = depth;
+        }
+    }
+
+    /// Keep the last local declared out of the unused variable warnings.
+    /// Used for variables the syntax asks for, like parameters, loop variables and caught exceptions.
+    fn mark_used(&mut self) {
+        if let Some(local) = self.function_scope().locals.last_mut() {
+            local.used = true;
+        }
+    }
+
+    fn check_shadowed_builtin(&mut self, name: &Token) {
+        if native::builtin_arity(&name.source).is_some() {
+            let message = format!("'{}' shadows the builtin function of the same name", name.source);
+            let hint = format!("Rename it to keep calling the builtin '{}'", name.source);
+            self.warn(Lint::ShadowedBuiltin, name, &message, Some(hint));
+        }
+    }
+
+    /// Warn about the locals deeper than `depth` that were never used.
+    /// Locals named by the compiler, like `this`, and names starting with '_' are left alone.
+    fn warn_unused_locals(&mut self, depth: isize) {
+        let unused: Vec<Token> = self
+            .function_scope()
+            .locals
+            .iter()
+            .filter(|local| local.depth > depth && !local.used && local.named)
+            .map(|local| local.token.clone())
+            .collect();
+
+        for token in unused {
+            if token.source.starts_with('_') {
+                continue;
+            }
+            let message = format!("Unused variable '{}'", token.source);
+            let hint = "Remove it, or start its name with '_' if it is meant to be unused".to_string();
+            self.warn(Lint::UnusedVariable, &token, &message, Some(hint));
+        }
+    }
+}
@@ -1,10 +1,20 @@
 use std::{collections::HashMap, rc::Rc, sync::OnceLock};
 
+use crate::diagnostic::Span;
+
+#[derive(Clone)]
 pub struct Scanner {
     start: usize,
     current: usize,
     source: Rc<str>,
     pub line: usize,
+    /// Byte offset where the current line starts
+    line_start: usize,
+    /// Line and column the token being scanned starts at
+    start_line: usize,
+    start_column: usize,
+    /// Unclosed braces inside each `${...}` being scanned, innermost last
+    interpolations: Vec<usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -12,6 +22,9 @@ pub struct Token {
     pub typ: TokenType,
     pub source: Rc<str>,
     pub line: usize,
+    /// Column of the first character, counting characters from 1
+    pub column: usize,
+    pub span: Span,
 }
 
 impl Default for Token {
@@ -26,6 +39,8 @@ impl Token {
             typ: TokenType::Error,
             source: Rc::from(""),
             line: 0,
+            column: 0,
+            span: Span::default(),
         }
     }
 }
@@ -37,6 +52,10 @@ impl Scanner {
             current: 0,
             source,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -61,13 +80,27 @@ impl Scanner {
             m.insert("function", TokenType::Fun);
             m.insert("import", TokenType::Import);
             m.insert("as", TokenType::As);
+            m.insert("break", TokenType::Break);
+            m.insert("continue", TokenType::Continue);
+            m.insert("try", TokenType::Try);
+            m.insert("catch", TokenType::Catch);
+            m.insert("finally", TokenType::Finally);
+            m.insert("throw", TokenType::Throw);
+            m.insert("in", TokenType::In);
             m
         })
     }
 
+    /// Every keyword of the language
+    pub fn keywords() -> impl Iterator<Item = &'static str> {
+        Scanner::get_ident_tokentype_map().keys().copied()
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.source[self.line_start..self.start].chars().count() + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -76,12 +109,29 @@ impl Scanner {
         match self.advance() {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                // This brace closes a `${...}`, so the rest of the string follows
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
             '[' => self.make_token(TokenType::LeftBracket),
             ']' => self.make_token(TokenType::RightBracket),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
+            ':' => self.make_token(TokenType::Colon),
             '-' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::MinusEqual)
@@ -113,6 +163,8 @@ impl Scanner {
             '=' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -145,10 +197,10 @@ impl Scanner {
             c => {
                 if c.is_ascii_digit() {
                     self.number()
-                } else if c.is_alphabetic() {
+                } else if c.is_alphabetic() || c == '_' {
                     self.identifier()
                 } else {
-                    self.error_token(format!("Unexpected character '{}' at position {}", c, self.start))
+                    self.error_token(format!("Unexpected character '{c}'."))
                 }
             }
         }
@@ -198,24 +250,36 @@ impl Scanner {
 
     fn skip_whitespace(&mut self) {
         while let ' ' | '\r' | '\t' | '\n' = self.peek() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-
-            self.advance();
         }
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     pub fn make_token(&self, typ: TokenType) -> Token {
         Token {
             typ,
             source: self.source[self.start..self.current].into(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            span: Span {
+                start: self.start,
+                end: self.current,
+            },
         }
     }
 
+    /// A token reporting a scanning error, with the message as its source
     fn error_token(&self, msg: String) -> Token {
-        panic!("[Line {}] {}", self.line, msg);
+        Token {
+            source: msg.into(),
+            ..self.make_token(TokenType::Error)
+        }
     }
 
     fn string(&mut self) -> Token {
@@ -224,21 +288,22 @@ impl Scanner {
             if c == '"' {
                 break;
             }
-            if c == '\n' {
-                self.line += 1;
+            if c == '$' && self.peek2() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
             }
             if c == '\\' {
                 self.advance(); // consume '\\'
-                if !self.is_at_end() {
-                    self.advance(); // consume the escaped character
-                }
-            } else {
-                self.advance();
+            }
+            if !self.is_at_end() && self.advance() == '\n' {
+                self.new_line();
             }
         }
 
         if self.is_at_end() {
-            return self.error_token(String::from("Unterminated string"));
+            return self.error_token(String::from("Unterminated string."));
         }
 
         // The closing quote
@@ -288,6 +353,7 @@ pub enum TokenType {
     RightBracket,
     Comma,
     Dot,
+    Colon,
     Minus,
     MinusEqual,
     MinusMinus,
@@ -304,6 +370,7 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
@@ -312,6 +379,8 @@ pub enum TokenType {
     // Literals
     Identifier,
     String,
+    /// A string part that ends at a `${`, and is followed by the embedded expression's tokens
+    Interpolation,
     Number,
 
     // Keywords
@@ -333,6 +402,13 @@ pub enum TokenType {
     While,
     Import,
     As,
+    Break,
+    Continue,
+    Try,
+    Catch,
+    Finally,
+    Throw,
+    In,
     Error,
     EOF,
 }
@@ -9,7 +9,22 @@ use strum_macros::Display;
 #[derive(Debug, Clone)]
 pub struct ClassData {
     pub name: StrId,
-    pub methods: RefCell<rustc_hash::FxHashMap<StrId, usize>>,
+    pub methods: RefCell<rustc_hash::FxHashMap<StrId, Rc<ClosureData>>>,
+    pub superclass: RefCell<Option<Rc<ClassData>>>,
+}
+
+impl ClassData {
+    /// The initializer used to construct instances: the method named after the nearest class in the inheritance chain that has one
+    pub fn initializer(class: &Rc<ClassData>) -> Option<Rc<ClosureData>> {
+        let mut current = Some(Rc::clone(class));
+        while let Some(class) = current {
+            if let Some(initializer) = class.methods.borrow().get(&class.name) {
+                return Some(Rc::clone(initializer));
+            }
+            current = class.superclass.borrow().clone();
+        }
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -18,6 +33,135 @@ pub struct InstanceData {
     pub fields: RefCell<rustc_hash::FxHashMap<StrId, Value>>,
 }
 
+/// A captured variable. It points at a stack slot while the declaring frame is alive,
+/// and owns the value once that slot goes out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ClosureData {
+    pub fun_idx: usize,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// The position of a `for ... in` loop in the value it walks. Collections are walked in place by position,
+/// so entries added during the loop are visited, and the loop ends early if the collection shrinks.
+#[derive(Debug)]
+pub enum IterState {
+    Array {
+        array: Rc<RefCell<ValueArray>>,
+        index: usize,
+    },
+    Buffer {
+        buffer: Rc<RefCell<Vec<u8>>>,
+        index: usize,
+    },
+    Map {
+        map: Rc<RefCell<LoxMap>>,
+        index: usize,
+    },
+    Str {
+        string: StrId,
+        offset: usize,
+        index: usize,
+    },
+    Range {
+        next: f64,
+        end: f64,
+        step: f64,
+        index: usize,
+    },
+    /// An instance implementing the iterator protocol, which the VM advances by calling its methods
+    Object {
+        object: Rc<RefCell<InstanceData>>,
+        index: usize,
+        phase: ObjectPhase,
+    },
+}
+
+/// Where an `IterState::Object` is in the iterator protocol. `iter()` is called once, if the class has it,
+/// to get the object whose `next()` is then called for each element until it returns nil.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectPhase {
+    CallIter,
+    AwaitIter,
+    CallNext,
+    AwaitNext,
+}
+
+impl IterState {
+    pub fn new(value: &Value) -> Option<IterState> {
+        let state = match value {
+            Value::Array(array) => IterState::Array {
+                array: Rc::clone(array),
+                index: 0,
+            },
+            Value::Buffer(buffer) => IterState::Buffer {
+                buffer: Rc::clone(buffer),
+                index: 0,
+            },
+            Value::Map(map) => IterState::Map {
+                map: Rc::clone(map),
+                index: 0,
+            },
+            Value::Str(string) => IterState::Str {
+                string: *string,
+                offset: 0,
+                index: 0,
+            },
+            _ => return None,
+        };
+        Some(state)
+    }
+
+    /// Loops with a single variable get the keys of maps, and the elements of everything else
+    pub fn yields_keys(&self) -> bool {
+        matches!(self, IterState::Map { .. })
+    }
+
+    /// Advance to the next entry, returning its key and value. Sequences are keyed by position.
+    pub fn next(&mut self, interner: &mut Interner) -> Option<(Value, Value)> {
+        match self {
+            IterState::Array { array, index } => {
+                let item = array.borrow().get(*index).cloned()?;
+                *index += 1;
+                Some((Value::Number((*index - 1) as f64), item))
+            }
+            IterState::Buffer { buffer, index } => {
+                let byte = *buffer.borrow().get(*index)?;
+                *index += 1;
+                Some((Value::Number((*index - 1) as f64), Value::Number(byte as f64)))
+            }
+            IterState::Map { map, index } => {
+                let (key, value) = map.borrow().get_index(*index).map(|(k, v)| (k.clone(), v.clone()))?;
+                *index += 1;
+                Some((key, value))
+            }
+            IterState::Str { string, offset, index } => {
+                let ch = interner.lookup(string)[*offset..].chars().next()?;
+                *offset += ch.len_utf8();
+                *index += 1;
+                let ch = interner.intern(ch.encode_utf8(&mut [0; 4]));
+                Some((Value::Number((*index - 1) as f64), Value::Str(ch)))
+            }
+            IterState::Range { next, end, step, index } => {
+                let done = if *step > 0.0 { *next >= *end } else { *next <= *end };
+                if done {
+                    return None;
+                }
+                let value = *next;
+                *next += *step;
+                *index += 1;
+                Some((Value::Number((*index - 1) as f64), Value::Number(value)))
+            }
+            IterState::Object { .. } => unreachable!("Objects are advanced by calling their next() method"),
+        }
+    }
+}
+
 #[derive(Debug, Display, Clone)]
 pub enum Value {
     Bool(bool),
@@ -28,18 +172,34 @@ pub enum Value {
     Buffer(Rc<RefCell<Vec<u8>>>),
     Map(Rc<RefCell<LoxMap>>),
     Function(usize),
+    Closure(Rc<ClosureData>),
     NativeFunction(Rc<dyn Callable>),
     Nil,
     Class(Rc<ClassData>),
     Instance(Rc<RefCell<InstanceData>>),
     BoundMethod {
         instance: Rc<RefCell<InstanceData>>,
-        method_idx: usize,
+        method: Rc<ClosureData>,
     },
+    Iterator(Rc<RefCell<IterState>>),
 }
 
 pub type ValueArray = Vec<Value>;
-pub type LoxMap = rustc_hash::FxHashMap<Value, Value>;
+/// Maps keep their insertion order, which is the order they are printed and iterated in
+pub type LoxMap = indexmap::IndexMap<Value, Value, std::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
+
+/// Name of the function at `idx` in the program being run, falling back to its index outside of a run
+fn function_name(idx: usize, interner: &Interner) -> String {
+    let name = crate::vm::RUNNING_FUNCTIONS.with(|funcs| {
+        let functions = unsafe { &*(*funcs.borrow())? };
+        functions.get(idx)?.name
+    });
+
+    match name {
+        Some(name) => interner.lookup(&name).to_string(),
+        None => idx.to_string(),
+    }
+}
 
 pub fn print_value(value: &Value, interner: &Interner) {
     xprint!("{}", value_as_string(value, interner));
@@ -95,7 +255,10 @@ pub fn value_as_string(value: &Value, interner: &Interner) -> String {
             s
         }
         Value::Function(idx) => {
-            format!("<Function {idx}>")
+            format!("<Function {}>", function_name(*idx, interner))
+        }
+        Value::Closure(closure) => {
+            format!("<Function {}>", function_name(closure.fun_idx, interner))
         }
         Value::NativeFunction(fun) => {
             format!("<Native Function {}>", fun.as_ref().name())
@@ -106,13 +269,14 @@ pub fn value_as_string(value: &Value, interner: &Interner) -> String {
         Value::Instance(instance) => {
             format!("<Instance of {}>", interner.lookup(&instance.borrow().class.name))
         }
-        Value::BoundMethod { instance, method_idx } => {
+        Value::BoundMethod { instance, method } => {
             format!(
                 "<Bound Method {} of {}>",
-                method_idx,
+                function_name(method.fun_idx, interner),
                 interner.lookup(&instance.borrow().class.name)
             )
         }
+        Value::Iterator(_) => "<Iterator>".to_string(),
     }
 }
 
@@ -128,18 +292,20 @@ impl PartialEq<Value> for Value {
             (Array(a), Array(b)) => Rc::ptr_eq(a, b),
             (Buffer(a), Buffer(b)) => Rc::ptr_eq(a, b),
             (Map(a), Map(b)) => Rc::ptr_eq(a, b),
+            (Closure(a), Closure(b)) => Rc::ptr_eq(a, b),
             (Class(a), Class(b)) => Rc::ptr_eq(a, b),
             (Instance(a), Instance(b)) => Rc::ptr_eq(a, b),
             (
                 BoundMethod {
                     instance: a_inst,
-                    method_idx: a_idx,
+                    method: a_method,
                 },
                 BoundMethod {
                     instance: b_inst,
-                    method_idx: b_idx,
+                    method: b_method,
                 },
-            ) => Rc::ptr_eq(a_inst, b_inst) && a_idx == b_idx,
+            ) => Rc::ptr_eq(a_inst, b_inst) && Rc::ptr_eq(a_method, b_method),
+            (Iterator(a), Iterator(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -159,14 +325,16 @@ impl std::hash::Hash for Value {
             Value::Buffer(b) => Rc::as_ptr(b).hash(state),
             Value::Map(m) => Rc::as_ptr(m).hash(state),
             Value::Function(idx) => idx.hash(state),
+            Value::Closure(c) => Rc::as_ptr(c).hash(state),
             Value::NativeFunction(f) => (f.as_ref() as *const dyn Callable as *const ()).hash(state),
             Value::Nil => (),
             Value::Class(c) => Rc::as_ptr(c).hash(state),
             Value::Instance(i) => Rc::as_ptr(i).hash(state),
-            Value::BoundMethod { instance, method_idx } => {
+            Value::BoundMethod { instance, method } => {
                 Rc::as_ptr(instance).hash(state);
-                method_idx.hash(state);
+                Rc::as_ptr(method).hash(state);
             }
+            Value::Iterator(i) => Rc::as_ptr(i).hash(state),
         }
     }
 }
@@ -1,14 +1,17 @@
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::{cell::RefCell, future::Future};
 
 use crate::{
     common::Opcode,
     dbgln,
+    debugger::{self, Debugger, Pause, PauseReason, StackFrame, Variable},
+    error::{RuntimeError, RuntimeErrorKind, TraceFrame},
     fun::Fun,
+    gc::{Gc, GcConfig},
     interner::{Interner, StrId},
     native::*,
     value::{
-        print_value, ClassData, InstanceData,
+        print_value, value_as_string, ClassData, ClosureData, InstanceData, IterState, LoxMap, ObjectPhase, Upvalue,
         Value::{self, *},
     },
 };
@@ -21,17 +24,52 @@ use rustc_hash::FxHashMap;
 #[allow(unused_imports)]
 use crate::{xprint, xprintln};
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone)]
 struct CallFrame {
     pub fun_idx: usize,
     pub ip: usize,
     pub start_len: usize,   // Length of the stack before this frame
     pub slot_offset: usize, // Offset of this call-frame from the base of the stack
     pub arg_count: usize,
+    pub closure: Option<Rc<ClosureData>>, // Captured variables, if the function being run is a closure
+}
+
+/// An active `try` block
+#[derive(Debug, Clone)]
+struct Handler {
+    frame: usize,              // Index of the call-frame the try block is in
+    catch_ip: Option<usize>,   // Cleared once the catch block has been entered
+    finally_ip: Option<usize>, // Start of the finally block, if there is one
+    stack_len: usize,          // Length of the stack when the try block was entered
+    completions_len: usize,    // Finally blocks already running when the try block was entered
+}
+
+impl Handler {
+    /// A try block whose catch block has been entered, and that has no finally block, has nothing left to do
+    fn can_handle(&self) -> bool {
+        self.catch_ip.is_some() || self.finally_ip.is_some()
+    }
+}
+
+/// How control left a protected block, resumed by `EndFinally` after the finally block has run
+#[derive(Debug, Clone)]
+enum Completion {
+    Normal,
+    Throw(Value),
+    Return(Value),
+    /// A `break` or `continue` leaving `tries` more try blocks, then going on at `ip` with the stack cut to `stack_len`
+    Leave {
+        tries: usize,
+        ip: usize,
+        stack_len: usize,
+    },
 }
 
 pub const ERR_STRING: &str = "errString";
 
+/// Instructions run between checks for garbage to collect while no debugger is attached
+const CHECK_INTERVAL: u32 = 1024;
+
 pub type BuiltinLoader = fn(name: &str, alias: &str, interner: &mut Interner, globals: &mut Globals) -> bool;
 pub static BUILTIN_LOADER: std::sync::RwLock<Option<BuiltinLoader>> = std::sync::RwLock::new(None);
 
@@ -41,6 +79,14 @@ pub fn set_builtin_loader(loader: BuiltinLoader) {
     }
 }
 
+/// Register the functions of the builtin module `name` under `alias`, if the embedder has a module of that name
+pub fn load_builtin_module(name: &str, alias: &str, interner: &mut Interner, globals: &mut Globals) -> bool {
+    match BUILTIN_LOADER.read() {
+        std::result::Result::Ok(guard) => guard.is_some_and(|loader| loader(name, alias, interner, globals)),
+        std::result::Result::Err(_) => false,
+    }
+}
+
 thread_local! {
     pub static RUNNING_FUNCTIONS: std::cell::RefCell<Option<*const Vec<crate::fun::Fun>>> = std::cell::RefCell::new(None);
 }
@@ -55,32 +101,46 @@ where
     frames: Vec<CallFrame>,
     pub functions: Vec<Fun>,
     stack: Vec<Value>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>, // Upvalues still pointing into the stack, sorted by slot
+    handlers: Vec<Handler>,                   // Try blocks currently active in any frame, innermost last
+    /// What to do once each finally block being run ends, with the index of its call-frame, innermost last.
+    /// Kept out of the call-frames so that calls don't pay for try statements they don't use.
+    completions: Vec<(usize, Completion)>,
     pub interner: &'src mut Interner,
     globals: FxHashMap<StrId, Value>,
     global_error_id: StrId, // StrId of global error variable
+    gc: Gc,
+    error: Option<RuntimeError>, // Set by an uncaught exception, ending the run loop
+    /// Traceback of the last runtime error, kept with its exception since the exception only holds it as text
+    error_frames: Option<(Weak<RefCell<LoxMap>>, Vec<TraceFrame>)>,
     read_async: F,
     sleep_async: SF,
     #[cfg(not(target_arch = "wasm32"))]
     loaded_libs: Vec<libloading::Library>,
     #[cfg(target_arch = "wasm32")]
     loaded_libs: Vec<Box<dyn std::any::Any>>,
+    debugger: Option<Debugger>,
+    /// Instructions left to run before the run loop checks for an uncaught error, garbage to collect and the debugger
+    checks_in: u32,
 }
 
-macro_rules! binop {
-    ($vm: ident, $typ: tt, $op: tt) => {
-        {
-            let b = $vm.pop_unchecked();
-            let a = $vm.pop_unchecked();
-            match (a, b) {
-                (Number(a), Number(b)) => {
-                    let result = a $op b;
-                    $vm.stack.push($typ(result));
-                },
-                (first, second)=> { $vm.runtime_error(&format!("Operands must be numbers, but got {first} and {second}")); }
-            }
+/// Raise a runtime error as an exception, and resume the run loop at whichever handler catches it
+macro_rules! throw_error {
+    ($vm: ident, $($arg: tt)*) => {{
+        $vm.runtime_error(&format!($($arg)*));
+        continue;
+    }};
+}
 
+macro_rules! binop {
+    ($vm: ident, $opcode: expr) => {{
+        let b = $vm.pop_unchecked();
+        let a = $vm.pop_unchecked();
+        match binary_op($opcode, a, b, $vm.interner) {
+            std::result::Result::Ok(value) => $vm.stack.push(value),
+            std::result::Result::Err(message) => throw_error!($vm, "{message}"),
         }
-    };
+    }};
 }
 
 macro_rules! frame {
@@ -95,34 +155,146 @@ macro_rules! frame_mut {
     };
 }
 
-macro_rules! register_native {
-    ($vm: ident, $name: ident) => {
-        let func = Rc::new($name);
-        let name_str = func.name();
-        let name = $vm.interner.intern(name_str);
-        dbgln!("Registering native function {}", name_str);
-        $vm.globals.insert(name, Value::NativeFunction(func));
+pub(crate) fn is_falsey(value: &Value) -> bool {
+    match value {
+        Nil => true,
+        Bool(b) => !b,
+        Number(n) => (*n - 0.0).abs() < f64::EPSILON,
+        Array(arr) => arr.borrow().is_empty(),
+        Buffer(buf) => buf.borrow().is_empty(),
+        _ => false,
+    }
+}
+
+/// The result of the binary instruction `opcode` on `a` and `b`, or the message of the error it raises.
+/// Breakpoint conditions apply their operators with it too.
+#[inline(always)]
+pub(crate) fn binary_op(opcode: Opcode, a: Value, b: Value, interner: &mut Interner) -> std::result::Result<Value, String> {
+    let value = match (opcode, a, b) {
+        (Opcode::Equal, a, b) => Bool(a == b),
+        (Opcode::Add, Number(a), Number(b)) => Number(a + b),
+        (Opcode::Add, Str(a), Str(b)) => {
+            let mut new_string = String::from(interner.lookup(&a));
+            new_string.push_str(interner.lookup(&b));
+            Str(interner.intern(&new_string))
+        }
+        (Opcode::Add, Str(a), Number(b)) => {
+            let mut new_string = String::from(interner.lookup(&a));
+            new_string.push_str(&b.to_string());
+            Str(interner.intern(&new_string))
+        }
+        (Opcode::Add, a, b) => return Err(format!("Operands must be numbers but got {b} {a}")),
+        (opcode, Number(a), Number(b)) => match opcode {
+            Opcode::Subtract => Number(a - b),
+            Opcode::Multiply => Number(a * b),
+            Opcode::Modulo => Number(a % b),
+            Opcode::Divide => Number(a / b),
+            Opcode::Greater => Bool(a > b),
+            Opcode::Less => Bool(a < b),
+            _ => unreachable!("{opcode} isn't a binary instruction"),
+        },
+        (_, a, b) => return Err(format!("Operands must be numbers, but got {a} and {b}")),
+    };
+    std::result::Result::Ok(value)
+}
+
+pub(crate) fn negate(value: Value) -> std::result::Result<Value, String> {
+    match value {
+        Number(num) => std::result::Result::Ok(Number(-num)),
+        _ => Err("Operand must be a number".to_string()),
+    }
+}
+
+/// The field `name` of an instance or a map, or the method `name` bound to an instance. Missing ones are nil.
+pub(crate) fn get_property(object: Value, name: StrId) -> std::result::Result<Value, String> {
+    match object {
+        Value::Instance(instance) => {
+            if let Some(value) = instance.borrow().fields.borrow().get(&name).cloned() {
+                return std::result::Result::Ok(value);
+            }
+            let method = instance.borrow().class.methods.borrow().get(&name).cloned();
+            std::result::Result::Ok(match method {
+                Some(method) => Value::BoundMethod {
+                    instance: Rc::clone(&instance),
+                    method,
+                },
+                None => Value::Nil,
+            })
+        }
+        Value::Map(map) => std::result::Result::Ok(map.borrow().get(&Value::Str(name)).cloned().unwrap_or(Value::Nil)),
+        _ => Err("Only instances and maps have properties.".to_string()),
+    }
+}
+
+/// Turn an integer index into a position in a collection of length `len`. Negative indices count back from the end.
+pub(crate) fn resolve_index(collection: &Value, index: f64, len: usize) -> anyhow::Result<usize, Error> {
+    if index.fract() != 0.0 {
+        bail!("Index {index} must be an integer");
+    }
+    let position = if index < 0.0 { index + len as f64 } else { index };
+    if position < 0.0 || position >= len as f64 {
+        bail!("Index {index} out of bounds for {collection} of length {len}");
+    }
+    Ok(position as usize)
+}
+
+/// Resolve the integer bounds of `[start:end]` in a collection of length `len`. Omitted (nil) bounds default to the
+/// ends, negative bounds count back from the end, and bounds past either end are clamped rather than erroring.
+fn slice_bounds(start: &Value, end: &Value, len: usize) -> anyhow::Result<std::ops::Range<usize>, Error> {
+    let bound = |value: &Value, default: usize| match value {
+        Value::Nil => Ok(default),
+        Value::Number(n) if n.fract() != 0.0 => Err(anyhow!("Slice bounds must be integers, got {n}")),
+        Value::Number(n) => {
+            let position = if *n < 0.0 { n + len as f64 } else { *n };
+            Ok(position.clamp(0.0, len as f64) as usize)
+        }
+        other => Err(anyhow!("Slice bounds must be numbers, got {other}")),
     };
+    let start = bound(start, 0)?;
+    let end = bound(end, len)?;
+    Ok(start..end.max(start))
+}
+
+/// Copy part of an array, buffer or string into a new value of the same type
+fn slice(value: &Value, start: &Value, end: &Value, interner: &mut Interner) -> anyhow::Result<Value, Error> {
+    match value {
+        Value::Array(array) => {
+            let array = array.borrow();
+            let range = slice_bounds(start, end, array.len())?;
+            Ok(Value::Array(Rc::new(RefCell::new(array[range].to_vec()))))
+        }
+        Value::Buffer(buf) => {
+            let bytes = buf.borrow();
+            let range = slice_bounds(start, end, bytes.len())?;
+            Ok(Value::Buffer(Rc::new(RefCell::new(bytes[range].to_vec()))))
+        }
+        Value::Str(s) => {
+            let string = interner.lookup(s);
+            let range = slice_bounds(start, end, string.chars().count())?;
+            let sliced: String = string.chars().skip(range.start).take(range.len()).collect();
+            Ok(Value::Str(interner.intern(&sliced)))
+        }
+        other => bail!("Can't slice value of type {other}"),
+    }
 }
 
-fn get_array(arr: &Value, index: &Value) -> anyhow::Result<Value, Error> {
+fn get_array(arr: &Value, index: &Value, interner: &mut Interner) -> anyhow::Result<Value, Error> {
     match (arr, index) {
         (Value::Array(array), Value::Number(index)) => {
-            let index = *index as usize;
-            if index < array.borrow().len() {
-                Ok(array.borrow()[index].clone())
-            } else {
-                bail!("Index out of bounds: {index}")
-            }
+            let array = array.borrow();
+            let position = resolve_index(arr, *index, array.len())?;
+            Ok(array[position].clone())
         }
         (Value::Buffer(buf), Value::Number(index)) => {
-            let index = *index as usize;
             let bytes = buf.borrow();
-            if index < bytes.len() {
-                Ok(Value::Number(bytes[index] as f64))
-            } else {
-                bail!("Index out of bounds: {index}")
-            }
+            let position = resolve_index(arr, *index, bytes.len())?;
+            Ok(Value::Number(bytes[position] as f64))
+        }
+        (Value::Str(s), Value::Number(index)) => {
+            let string = interner.lookup(s);
+            let position = resolve_index(arr, *index, string.chars().count())?;
+            let c = string.chars().nth(position).unwrap_or_default();
+            Ok(Value::Str(interner.intern(&c.to_string())))
         }
         (Value::Map(map), key) => {
             if let Some(val) = map.borrow().get(key) {
@@ -137,30 +309,24 @@ fn get_array(arr: &Value, index: &Value) -> anyhow::Result<Value, Error> {
     }
 }
 
-fn set_array(arr: &mut Value, index: &Value, new_value: Value) -> anyhow::Result<(), Error> {
-    match (arr, index) {
+fn set_array(arr: &mut Value, index: &Value, new_value: Value, gc: &mut Gc) -> anyhow::Result<(), Error> {
+    gc.track(arr, index);
+    gc.track(arr, &new_value);
+    match (&*arr, index) {
         (Value::Array(array), Value::Number(index)) => {
-            let index = *index as usize;
-            if index < array.borrow().len() {
-                array.borrow_mut()[index] = new_value;
-                Ok(())
-            } else {
-                bail!("Index out of bounds: {index}")
-            }
+            let position = resolve_index(arr, *index, array.borrow().len())?;
+            array.borrow_mut()[position] = new_value;
+            Ok(())
         }
         (Value::Buffer(buf), Value::Number(index)) => {
-            let index = *index as usize;
             let mut bytes = buf.borrow_mut();
-            if index < bytes.len() {
-                match new_value {
-                    Value::Number(n) => {
-                        bytes[index] = n as u8;
-                        Ok(())
-                    }
-                    _ => bail!("Buffer element must be a byte number"),
+            let position = resolve_index(arr, *index, bytes.len())?;
+            match new_value {
+                Value::Number(n) => {
+                    bytes[position] = n as u8;
+                    Ok(())
                 }
-            } else {
-                bail!("Index out of bounds: {index}")
+                _ => bail!("Buffer element must be a byte number"),
             }
         }
         (Value::Map(map), key) => {
@@ -190,21 +356,28 @@ where
             start_len: 0,
             slot_offset: 0,
             arg_count: 0,
+            closure: None,
         });
 
-        let vm = Vm {
+        Vm {
             frames,
             functions,
             stack: Vec::with_capacity(1024),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            completions: Vec::new(),
             interner,
             globals: FxHashMap::default(),
             global_error_id,
+            gc: Gc::default(),
+            error: None,
+            error_frames: None,
             read_async,
             sleep_async,
             loaded_libs: Vec::new(),
-        };
-
-        vm
+            debugger: None,
+            checks_in: 1,
+        }
     }
 
     pub fn new_repl(interner: &'src mut Interner, read_async: F, sleep_async: SF) -> Vm<'src, F, Fut, SF, SFut> {
@@ -213,46 +386,23 @@ where
             frames: Vec::with_capacity(10240),
             functions: Vec::new(),
             stack: Vec::with_capacity(1024),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            completions: Vec::new(),
             interner,
             globals: FxHashMap::default(),
             global_error_id,
+            gc: Gc::default(),
+            error: None,
+            error_frames: None,
             read_async,
             sleep_async,
             loaded_libs: Vec::new(),
+            debugger: None,
+            checks_in: 1,
         };
 
-        register_native!(vm, Clock);
-        register_native!(vm, Sleep);
-        register_native!(vm, TypeOf);
-        register_native!(vm, Print);
-        register_native!(vm, Printf);
-        register_native!(vm, ReadString);
-        register_native!(vm, StrCast);
-        register_native!(vm, BufCast);
-        register_native!(vm, ChrCast);
-        register_native!(vm, HelpCast);
-        register_native!(vm, IntCast);
-        register_native!(vm, FloatCast);
-        register_native!(vm, BoolCast);
-        register_native!(vm, StringAt);
-        register_native!(vm, Len);
-        register_native!(vm, Ceil);
-        register_native!(vm, Floor);
-        register_native!(vm, Abs);
-        register_native!(vm, Sort);
-        register_native!(vm, IndexOf);
-        register_native!(vm, Rand);
-        register_native!(vm, Clear);
-        register_native!(vm, Sin);
-        register_native!(vm, Cos);
-        register_native!(vm, Sqrt);
-        register_native!(vm, Pow);
-        register_native!(vm, Pi);
-        register_native!(vm, MapConstructor);
-        register_native!(vm, Keys);
-        register_native!(vm, Values);
-        register_native!(vm, Has);
-
+        vm.register_natives();
         vm
     }
 
@@ -261,17 +411,7 @@ where
     fn load_native_imports(&mut self, fun_idx: usize) {
         let imports = self.functions[fun_idx].native_imports.clone();
         for (path, alias) in imports {
-            let loaded_as_builtin = if let std::result::Result::Ok(guard) = BUILTIN_LOADER.read() {
-                if let Some(loader) = *guard {
-                    loader(&path, &alias, self.interner, &mut self.globals)
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
-
-            if loaded_as_builtin {
+            if load_builtin_module(&path, &alias, self.interner, &mut self.globals) {
                 continue;
             }
 
@@ -286,9 +426,8 @@ where
         }
     }
 
-    pub async fn run_repl_chunk(&mut self, fun: Fun) -> Result<()> {
-        self.stack.clear();
-        self.frames.clear();
+    pub async fn run_repl_chunk(&mut self, fun: Fun) -> Result<(), RuntimeError> {
+        self.reset();
 
         let fun_idx = self.functions.len();
         self.functions.push(fun);
@@ -301,6 +440,7 @@ where
             start_len: 0,
             slot_offset: 0,
             arg_count: 0,
+            closure: None,
         });
 
         self.reset_err_string();
@@ -324,9 +464,18 @@ where
         value
     }
 
-    fn read_constant(&mut self) -> &Value {
-        let index: usize = self.read_byte() as usize;
-        return self.constant(index);
+    /// Read an index operand, which takes three bytes for the `Long` variants of instructions
+    fn read_index(&mut self, long: bool) -> usize {
+        if long {
+            self.read_u24()
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_constant(&mut self, long: bool) -> &Value {
+        let index = self.read_index(long);
+        self.constant(index)
     }
 
     #[cfg(feature = "tracing")]
@@ -343,44 +492,330 @@ where
     }
 
     fn is_falsey(&self, value: &Value) -> bool {
-        match value {
-            Nil => true,
-            Bool(b) => !b,
-            Number(n) => (*n - 0.0).abs() < f64::EPSILON,
-            Array(arr) => arr.borrow().is_empty(),
-            Buffer(buf) => buf.borrow().is_empty(),
-            _ => false,
-        }
+        is_falsey(value)
     }
 
-    fn read_u16(&mut self) -> u16 {
-        frame_mut!(self).ip += 2;
+    fn read_u24(&mut self) -> usize {
+        frame_mut!(self).ip += 3;
 
-        let high_byte = self.code(frame!(self).ip - 2) as u16;
-        let low_byte = self.code(frame!(self).ip - 1) as u16;
-        (high_byte << 8) | low_byte
+        let high_byte = self.code(frame!(self).ip - 3) as usize;
+        let middle_byte = self.code(frame!(self).ip - 2) as usize;
+        let low_byte = self.code(frame!(self).ip - 1) as usize;
+        (high_byte << 16) | (middle_byte << 8) | low_byte
     }
 
     #[cfg(not(feature = "tracing"))]
     fn stack_trace(&self) {}
 
-    fn runtime_error(&self, msg: &str) -> ! {
-        xprintln!("Runtime error: {msg}");
-        xprintln!("Traceback (most recent call first):");
-        let mut idx = (self.frames.len() - 1) as isize;
-
-        while idx >= 0 {
-            let frame = &self.frames[idx as usize];
-            let fun: &Fun = &self.functions[frame.fun_idx];
-            let fun_name = match fun.name {
-                Some(name) => self.interner.lookup(&name),
-                None => "<script>",
+    /// Describe every active call-frame, most recent first
+    fn traceback(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let fun: &Fun = &self.functions[frame.fun_idx];
+                let function = match fun.name {
+                    Some(name) => self.interner.lookup(&name),
+                    None => "<script>",
+                };
+                // The instruction being run is the one before `ip`, if the frame has started running
+                let offset = frame.ip.checked_sub(1);
+                TraceFrame {
+                    function: function.to_string(),
+                    line: fun.chunk.lines.get(&offset.unwrap_or_default()).copied().unwrap_or_default(),
+                    span: offset.and_then(|offset| fun.chunk.spans.get(&offset).copied()),
+                    file: fun.file.as_deref().map(str::to_string),
+                }
+            })
+            .collect()
+    }
+
+    /// Pause before the instruction about to run, if a breakpoint or the step being taken stops there
+    fn debug_hook(&mut self) {
+        let Some(mut debugger) = self.debugger.take() else {
+            return;
+        };
+        let depth = self.frames.len();
+        let frame = frame!(self);
+        let fun = &self.functions[frame.fun_idx];
+        let line = fun.chunk.lines.get(&frame.ip).copied().unwrap_or_default();
+        let file = fun.file.clone();
+        let new_line = debugger.enter_line(depth, frame.fun_idx, line);
+        let requested = new_line && debugger.poll();
+
+        // Breakpoints only stop the program where a line starts
+        let mut reason = None;
+        let at_line = |breakpoint: &&debugger::Breakpoint| new_line && breakpoint.is_at(file.as_deref(), line);
+        for breakpoint in debugger.breakpoints.iter().filter(at_line) {
+            // Conditions see the locals of the current frame, and the globals they don't hide
+            let locals = self.frame_locals(frame!(self));
+            let locals: Vec<(String, Value)> = locals.into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+            let globals = &self.globals;
+            let mut lookup = |name: &str, interner: &mut Interner| match locals.iter().rev().find(|(local, _)| local == name) {
+                Some((_, value)) => Some(value.clone()),
+                None => globals.get(&interner.intern(name)).cloned(),
+            };
+            reason = match debugger::condition_holds(breakpoint, &mut lookup, &mut *self.interner) {
+                None | Some(std::result::Result::Ok(true)) => Some(PauseReason::Breakpoint(breakpoint.id)),
+                Some(std::result::Result::Ok(false)) => continue,
+                Some(std::result::Result::Err(e)) => Some(PauseReason::ConditionError(breakpoint.id, e)),
             };
-            xprintln!("[line {:3}] in {}", fun.chunk.lines[&frame.ip], fun_name);
-            idx -= 1;
+            break;
         }
 
-        panic!("Exiting due to runtime error");
+        let reason = reason.or_else(|| requested.then_some(PauseReason::Requested));
+        if let Some(reason) = reason.or_else(|| debugger.step_pause(depth, new_line)) {
+            let pause = self.pause_state(reason);
+            debugger.pause(&pause);
+        }
+        self.debugger = Some(debugger);
+    }
+
+    /// The locals of `frame` in scope at its current instruction, with their values, in the order of their slots
+    fn frame_locals<'a>(&'a self, frame: &CallFrame) -> Vec<(&'a str, Value)> {
+        let fun = &self.functions[frame.fun_idx];
+        let mut locals: Vec<_> = fun
+            .locals
+            .iter()
+            .filter(|local| local.start <= frame.ip && frame.ip < local.end)
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        let value = |slot: usize| self.stack.get(frame.slot_offset + slot).cloned();
+        locals
+            .into_iter()
+            .filter_map(|local| Some((local.name.as_str(), value(local.slot)?)))
+            .collect()
+    }
+
+    /// Describe the paused program for the debugger
+    fn pause_state(&self, reason: PauseReason) -> Pause {
+        let variable = |name: &str, value: &Value| Variable {
+            name: name.to_string(),
+            value: debugger::value_text(value, self.interner),
+        };
+        let traceback = self.traceback();
+        let frames = self.frames.iter().rev().zip(traceback).map(|(frame, trace)| {
+            let locals = self.frame_locals(frame);
+            // The line of the instruction about to run, rather than of the one last run
+            let lines = &self.functions[frame.fun_idx].chunk.lines;
+            StackFrame {
+                function: trace.function,
+                file: trace.file,
+                line: lines.get(&frame.ip).copied().unwrap_or(trace.line),
+                locals: locals.iter().map(|(name, value)| variable(name, value)).collect(),
+            }
+        });
+
+        let mut globals: Vec<Variable> = self
+            .globals
+            .iter()
+            .filter(|(&name, value)| !matches!(value, NativeFunction(_)) && name != self.global_error_id)
+            .map(|(name, value)| variable(self.interner.lookup(name), value))
+            .collect();
+        globals.sort_by(|a, b| a.name.cmp(&b.name));
+        Pause {
+            reason,
+            frames: frames.collect(),
+            globals,
+        }
+    }
+
+    /// Build the exception object for a runtime error, a map holding its message and traceback
+    fn error_value(&mut self, msg: &str) -> Value {
+        let frames = self.traceback();
+        let lines = frames.iter().map(|frame| frame.to_string());
+        let traceback = lines.map(|line| Value::Str(self.interner.intern(&line))).collect();
+        let traceback = Value::Array(Rc::new(RefCell::new(traceback)));
+        let entries = [
+            (Value::Str(self.interner.intern("message")), Value::Str(self.interner.intern(msg))),
+            (Value::Str(self.interner.intern("traceback")), traceback),
+        ];
+        let map = Rc::new(RefCell::new(LoxMap::from_iter(entries)));
+        self.error_frames = Some((Rc::downgrade(&map), frames));
+        Value::Map(map)
+    }
+
+    fn runtime_error(&mut self, msg: &str) {
+        let exception = self.error_value(msg);
+        self.throw(exception);
+    }
+
+    /// Unwind to the innermost handler for `exception` and jump to it. Uncaught exceptions stop the run loop.
+    fn throw(&mut self, exception: Value) {
+        let Some(handler_idx) = self.handlers.iter().rposition(Handler::can_handle) else {
+            self.error = Some(self.uncaught(&exception));
+            self.checks_in = 1;
+            return;
+        };
+
+        self.handlers.truncate(handler_idx + 1);
+        let handler = self.handlers.last_mut().unwrap();
+        let (frame_idx, stack_len) = (handler.frame, handler.stack_len);
+        self.completions.truncate(handler.completions_len);
+        self.frames.truncate(frame_idx + 1);
+
+        if let Some(catch_ip) = handler.catch_ip.take() {
+            // The handler stays active while the catch block runs, so that its finally block still runs afterwards
+            frame_mut!(self).ip = catch_ip;
+            self.unwind_stack(stack_len);
+            self.stack.push(exception);
+        } else {
+            let finally_ip = handler.finally_ip.unwrap();
+            self.handlers.pop();
+            frame_mut!(self).ip = finally_ip;
+            self.completions.push((frame_idx, Completion::Throw(exception)));
+            self.unwind_stack(stack_len);
+        }
+    }
+
+    fn unwind_stack(&mut self, len: usize) {
+        self.close_upvalues(len);
+        self.stack.truncate(len);
+    }
+
+    pub fn set_gc_config(&mut self, config: GcConfig) {
+        self.gc.set_config(config);
+    }
+
+    /// Free unreachable cycles, returning how many objects were emptied to break them
+    fn collect_garbage(&mut self) -> usize {
+        let mut roots = self.stack.clone();
+        roots.extend(self.globals.values().cloned());
+        for frame in &self.frames {
+            roots.extend(frame.closure.iter().map(|closure| Value::Closure(Rc::clone(closure))));
+        }
+        for (_, completion) in &self.completions {
+            if let Completion::Throw(value) | Completion::Return(value) = completion {
+                roots.push(value.clone());
+            }
+        }
+        for fun in &self.functions {
+            roots.extend(fun.chunk.constants.iter().cloned());
+            roots.extend(fun.name.map(Value::Str));
+        }
+        // Names the VM looks strings up by are marked like string values
+        roots.extend(self.globals.keys().map(|&name| Value::Str(name)));
+        roots.push(Value::Str(self.global_error_id));
+        self.gc.collect(roots, self.interner)
+    }
+
+    /// The result of `gc_stats()`
+    fn gc_stats_value(&mut self) -> Value {
+        let stats = self.gc.stats();
+        let config = self.gc.config();
+        let entries = [
+            ("collections", stats.collections as f64),
+            ("collected", stats.collected as f64),
+            ("last_collected", stats.last_collected as f64),
+            ("tracked", self.gc.tracked_count() as f64),
+            ("strings_collected", stats.strings_collected as f64),
+            ("runtime_strings", self.interner.runtime_count() as f64),
+            ("runtime_string_bytes", self.interner.runtime_bytes() as f64),
+            ("threshold", config.threshold as f64),
+            ("growth_factor", config.growth_factor),
+        ];
+        let entries = entries.map(|(key, value)| (Value::Str(self.interner.intern(key)), Value::Number(value)));
+        Value::Map(Rc::new(RefCell::new(LoxMap::from_iter(entries))))
+    }
+
+    /// Describe an exception nothing caught. Errors keep the traceback from where they were raised.
+    fn uncaught(&mut self, exception: &Value) -> RuntimeError {
+        let message_key = Value::Str(self.interner.intern("message"));
+        let traceback_key = Value::Str(self.interner.intern("traceback"));
+        let error = match exception {
+            Value::Map(map) => match (map.borrow().get(&message_key), map.borrow().get(&traceback_key)) {
+                (Some(Value::Str(msg)), Some(Value::Array(lines))) => {
+                    // Runtime errors raised by the VM still have their frames, with the spans the text leaves out
+                    let frames = match &self.error_frames {
+                        Some((error, frames)) if std::ptr::eq(error.as_ptr(), Rc::as_ptr(map)) => Some(frames.clone()),
+                        _ => None,
+                    };
+                    let parse = |line: &Value| TraceFrame::parse(&value_as_string(line, self.interner));
+                    let traceback = frames.or_else(|| lines.borrow().iter().map(parse).collect());
+                    Some(RuntimeError {
+                        kind: RuntimeErrorKind::Error,
+                        message: self.interner.lookup(msg).to_string(),
+                        traceback: traceback.unwrap_or_else(|| self.traceback()),
+                    })
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        error.unwrap_or_else(|| RuntimeError {
+            kind: RuntimeErrorKind::UncaughtException,
+            message: format!("Uncaught exception {}", value_as_string(exception, self.interner)),
+            traceback: self.traceback(),
+        })
+    }
+
+    /// Drop the state of the program that was running, keeping globals, so the VM can run another chunk
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.completions.clear();
+        self.error = None;
+        self.error_frames = None;
+        self.interner.set_runtime(false);
+    }
+
+    /// Remove the handlers of the `tries` innermost try blocks for a `break` or `continue`, running the next finally
+    /// block among them first if there is one. Once they are all gone, go on at `ip` with the stack cut to `stack_len`.
+    fn leave_tries(&mut self, tries: usize, ip: usize, stack_len: usize) {
+        let frame_idx = self.frames.len() - 1;
+        for left in 1..=tries {
+            let handler = self.handlers.pop().expect("Leave outside of a try block");
+            if let Some(finally_ip) = handler.finally_ip {
+                frame_mut!(self).ip = finally_ip;
+                self.completions.truncate(handler.completions_len);
+                let leave = Completion::Leave {
+                    tries: tries - left,
+                    ip,
+                    stack_len,
+                };
+                self.completions.push((frame_idx, leave));
+                self.unwind_stack(handler.stack_len);
+                return;
+            }
+        }
+        frame_mut!(self).ip = ip;
+        self.unwind_stack(stack_len);
+    }
+
+    /// Return `value` from the current frame, running any finally blocks protecting the return first.
+    /// Returns true once the outermost frame has returned.
+    fn return_value(&mut self, value: Value) -> bool {
+        let frame_idx = self.frames.len() - 1;
+        while self.handlers.last().is_some_and(|handler| handler.frame == frame_idx) {
+            let handler = self.handlers.pop().unwrap();
+            if let Some(finally_ip) = handler.finally_ip {
+                frame_mut!(self).ip = finally_ip;
+                self.completions.truncate(handler.completions_len);
+                self.completions.push((frame_idx, Completion::Return(value)));
+                self.unwind_stack(handler.stack_len);
+                return false;
+            }
+        }
+        // Returning from inside a finally block drops what it would have resumed
+        while self.completions.last().is_some_and(|(frame, _)| *frame == frame_idx) {
+            self.completions.pop();
+        }
+
+        let orig_len = frame!(self).start_len;
+        self.close_upvalues(frame!(self).slot_offset);
+        self.frames.pop();
+
+        if self.frames.is_empty() {
+            return true;
+        }
+
+        self.stack_trace();
+        dbgln!("Truncating to length {}", orig_len,);
+        self.stack.truncate(orig_len);
+        self.stack.push(value);
+        false
     }
 
     fn pop(&mut self) -> Result<Value> {
@@ -391,8 +826,8 @@ where
         unsafe { self.stack.pop().unwrap_unchecked() }
     }
 
-    fn read_string_or_id(&mut self) -> StrId {
-        let value = self.read_constant();
+    fn read_string_or_id(&mut self, long: bool) -> StrId {
+        let value = self.read_constant(long);
         match value {
             Value::Str(id) => *id,
             Value::Identifier(id) => *id,
@@ -400,41 +835,57 @@ where
         }
     }
 
+    /// Define every builtin function as a global
+    fn register_natives(&mut self) {
+        for native in crate::native::builtins() {
+            dbgln!("Registering native function {}", native.name());
+            let name = self.interner.intern(native.name());
+            self.globals.insert(name, Value::NativeFunction(native));
+        }
+    }
+
     fn reset_err_string(&mut self) {
         self.globals.insert(self.global_error_id, Value::Nil);
     }
 
-    async fn call_value(&mut self, arg_count: u8) -> bool {
-        let callee = self.peek(arg_count as usize).clone();
-        match &callee {
-            Function(idx) => {
-                let fun = &self.functions[*idx];
+    /// Push a call-frame for the function at `fun_idx`, whose arguments are on top of the stack
+    fn push_frame(&mut self, fun_idx: usize, closure: Option<Rc<ClosureData>>, arg_count: u8) -> Result<()> {
+        let fun = &self.functions[fun_idx];
+
+        let arg_count_usize = arg_count as usize;
+        if arg_count_usize < fun.min_arity || arg_count_usize > fun.arity {
+            bail!(
+                "Expected between {} and {} arguments but got {} instead",
+                fun.min_arity,
+                fun.arity,
+                arg_count
+            );
+        }
 
-                let arg_count_usize = arg_count as usize;
-                if arg_count_usize < fun.min_arity || arg_count_usize > fun.arity {
-                    self.runtime_error(&format!(
-                        "Expected between {} and {} arguments but got {} instead",
-                        fun.min_arity, fun.arity, arg_count
-                    ));
-                }
+        // If fewer than fun.arity arguments were passed, push Nil placeholders for the remaining parameters
+        for _ in arg_count_usize..fun.arity {
+            self.stack.push(Value::Nil);
+        }
 
-                // If fewer than fun.arity arguments were passed, push Nil placeholders for the remaining parameters
-                for _ in arg_count_usize..fun.arity {
-                    self.stack.push(Value::Nil);
-                }
+        let new_frame_offset = self.stack.len() - fun.arity;
+        let orig_len = self.stack.len() - 1 - fun.arity;
+        let frame: CallFrame = CallFrame {
+            fun_idx,
+            ip: 0,
+            start_len: orig_len,
+            slot_offset: new_frame_offset,
+            arg_count: arg_count_usize,
+            closure,
+        };
+        self.frames.push(frame);
+        Ok(())
+    }
 
-                let new_frame_offset = self.stack.len() - fun.arity;
-                let orig_len = self.stack.len() - 1 - fun.arity;
-                let frame: CallFrame = CallFrame {
-                    fun_idx: *idx,
-                    ip: 0,
-                    start_len: orig_len,
-                    slot_offset: new_frame_offset,
-                    arg_count: arg_count_usize,
-                };
-                self.frames.push(frame);
-                true
-            }
+    async fn call_value(&mut self, arg_count: u8) -> Result<()> {
+        let callee = self.peek(arg_count as usize).clone();
+        match &callee {
+            Function(idx) => self.push_frame(*idx, None, arg_count),
+            Closure(closure) => self.push_frame(closure.fun_idx, Some(Rc::clone(closure)), arg_count),
             Class(class) => {
                 let instance = Rc::new(RefCell::new(InstanceData {
                     class: Rc::clone(class),
@@ -444,80 +895,34 @@ where
                 let callee_slot = self.stack.len() - 1 - arg_count as usize;
                 self.stack[callee_slot] = Value::Instance(Rc::clone(&instance));
 
-                let constructor_id = class.name;
-                if let Some(method_idx) = class.methods.borrow().get(&constructor_id).copied() {
-                    let fun = &self.functions[method_idx];
-                    let arg_count_usize = arg_count as usize;
-                    if arg_count_usize < fun.min_arity || arg_count_usize > fun.arity {
-                        self.runtime_error(&format!(
-                            "Expected between {} and {} arguments but got {} instead",
-                            fun.min_arity, fun.arity, arg_count
-                        ));
-                    }
-                    for _ in arg_count_usize..fun.arity {
-                        self.stack.push(Value::Nil);
-                    }
-                    let new_frame_offset = self.stack.len() - fun.arity;
-                    let orig_len = self.stack.len() - 1 - fun.arity;
-                    let frame: CallFrame = CallFrame {
-                        fun_idx: method_idx,
-                        ip: 0,
-                        start_len: orig_len,
-                        slot_offset: new_frame_offset,
-                        arg_count: arg_count_usize,
-                    };
-                    self.frames.push(frame);
+                if let Some(method) = ClassData::initializer(class) {
+                    self.push_frame(method.fun_idx, Some(method), arg_count)
                 } else {
                     if arg_count > 0 {
-                        self.runtime_error("Constructor expected 0 arguments but got some");
+                        bail!("Constructor expected 0 arguments but got some");
                     }
                     self.stack.truncate(callee_slot + 1);
+                    Ok(())
                 }
-                true
             }
-            BoundMethod { instance, method_idx } => {
+            BoundMethod { instance, method } => {
                 let callee_slot = self.stack.len() - 1 - arg_count as usize;
                 self.stack[callee_slot] = Value::Instance(Rc::clone(instance));
-
-                let fun = &self.functions[*method_idx];
-                let arg_count_usize = arg_count as usize;
-                if arg_count_usize < fun.min_arity || arg_count_usize > fun.arity {
-                    self.runtime_error(&format!(
-                        "Expected between {} and {} arguments but got {} instead",
-                        fun.min_arity, fun.arity, arg_count
-                    ));
-                }
-                for _ in arg_count_usize..fun.arity {
-                    self.stack.push(Value::Nil);
-                }
-                let new_frame_offset = self.stack.len() - fun.arity;
-                let orig_len = self.stack.len() - 1 - fun.arity;
-                let frame: CallFrame = CallFrame {
-                    fun_idx: *method_idx,
-                    ip: 0,
-                    start_len: orig_len,
-                    slot_offset: new_frame_offset,
-                    arg_count: arg_count_usize,
-                };
-                self.frames.push(frame);
-                true
+                self.push_frame(method.fun_idx, Some(Rc::clone(method)), arg_count)
             }
             NativeFunction(fun) => {
                 let mut arg_count_usize = arg_count as usize;
                 let name = fun.name();
 
                 let is_input = name == "input";
-                let is_printf = name == "printf";
                 let valid_arity = if is_input {
                     arg_count_usize <= 1
-                } else if is_printf {
-                    arg_count_usize >= 1
                 } else {
                     arg_count_usize >= fun.arity()
                 };
 
                 if !valid_arity {
-                    self.runtime_error(&format!("Expected {} arguments but got {} instead", fun.arity(), arg_count));
+                    bail!("Expected {} arguments but got {} instead", fun.arity(), arg_count);
                 }
 
                 if is_input && arg_count_usize == 0 {
@@ -551,26 +956,99 @@ where
                     (self.sleep_async)(ms).await;
                     self.stack.truncate(self.stack.len() - 1 - arg_count_usize);
                     self.stack.push(Value::Nil);
-                    return true;
+                    return Ok(());
+                }
+
+                // Builtins that need the VM's roots and collector state
+                if let Some(vm_function) = function.vm_function() {
+                    let args = self.stack.split_off(self.stack.len() - arg_count_usize);
+                    self.stack.pop(); // The native function itself
+                    let result = match vm_function {
+                        VmFunction::CollectGarbage => {
+                            let mut config = self.gc.config();
+                            match args.as_slice() {
+                                [] => (),
+                                [Number(threshold)] => config.threshold = *threshold as usize,
+                                [Number(threshold), Number(growth_factor)] => {
+                                    config.threshold = *threshold as usize;
+                                    config.growth_factor = *growth_factor;
+                                }
+                                _ => bail!("Expected numbers as arguments to gc"),
+                            }
+                            if config.threshold < 1 || config.growth_factor < 1.0 {
+                                bail!("gc threshold and growth factor must be at least 1");
+                            }
+                            if !args.is_empty() {
+                                self.gc.set_config(config);
+                            }
+                            Value::Number(self.collect_garbage() as f64)
+                        }
+                        VmFunction::GarbageStats => self.gc_stats_value(),
+                    };
+                    self.stack.push(result);
+                    return Ok(());
                 }
 
                 let args = &self.stack[self.stack.len() - arg_count_usize..];
                 self.globals.insert(self.global_error_id, Value::Nil); // Reset error string
 
                 let result = function.call(self.interner, &mut self.globals, args);
+                if let Some(message) = take_raised() {
+                    bail!("{message}");
+                }
 
                 self.stack.truncate(self.stack.len() - 1 - arg_count_usize);
                 self.stack.push(result);
 
-                true
+                Ok(())
             }
-            other => {
-                self.runtime_error(&format!("Can only call functions, got {other}"));
+            other => bail!("Can only call functions, got {other}"),
+        }
+    }
+
+    /// Get the upvalue pointing at the given stack slot, creating it if no closure has captured that slot yet
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match *upvalue.borrow() {
+                Upvalue::Open(open_slot) if open_slot == slot => return Rc::clone(upvalue),
+                Upvalue::Open(open_slot) if open_slot < slot => break,
+                _ => insert_at = i,
             }
         }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Move every captured stack slot at or above `from` into its upvalue, so it outlives the stack slot
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => unreachable!("Closed upvalue in open upvalue list"),
+            };
+
+            if slot < from {
+                break;
+            }
+
+            let value = self.stack[slot].clone();
+            self.gc.track_upvalue(upvalue, &value);
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn frame_upvalue(&self, index: usize) -> Rc<RefCell<Upvalue>> {
+        let closure = frame!(self).closure.as_ref().expect("Upvalue access outside of a closure");
+        Rc::clone(&closure.upvalues[index])
     }
 
-    async fn run(&mut self) -> Result<()> {
+    /// Run until the script returns, or an exception nothing catches stops it.
+    /// Either way the interner goes back to interning compile time strings, and after an error the VM is reset.
+    async fn run(&mut self) -> Result<(), RuntimeError> {
         struct RunningFunctionsGuard;
         impl Drop for RunningFunctionsGuard {
             fn drop(&mut self) {
@@ -583,13 +1061,36 @@ where
             *funcs.borrow_mut() = Some(&self.functions as *const Vec<crate::fun::Fun>);
         });
         let _guard = RunningFunctionsGuard;
+        self.interner.set_runtime(true);
 
+        // Check before the first instruction, where the debugger pauses
+        self.checks_in = 1;
         loop {
+            self.checks_in -= 1;
+            if self.checks_in == 0 {
+                if let Some(error) = self.error.take() {
+                    self.reset();
+                    return Err(error);
+                }
+                if self.gc.should_collect(self.interner) {
+                    self.collect_garbage();
+                }
+                // The debugger looks at every instruction
+                self.checks_in = match self.debugger {
+                    Some(_) => {
+                        self.debug_hook();
+                        1
+                    }
+                    None => CHECK_INTERVAL,
+                };
+            }
+
             #[cfg(feature = "tracing")]
             {
                 self.stack_trace();
                 disassemble_instruction(&self.functions[frame!(self).fun_idx].chunk, frame!(self).ip, self.interner);
             }
+            // Only the compiler and bytecode::deserialize, which verifies the code, create chunks
             let instruction = unsafe { Opcode::try_from(self.read_byte()).unwrap_unchecked() };
             match instruction {
                 Opcode::Print => {
@@ -597,57 +1098,226 @@ where
                     xprintln!("");
                 }
                 Opcode::JumpIfFalse => {
-                    let offset: u16 = self.read_u16();
+                    let offset = self.read_u24();
                     if self.is_falsey(self.peek(0)) {
-                        frame_mut!(self).ip += offset as usize;
+                        frame_mut!(self).ip += offset;
                     }
                 }
                 Opcode::DefaultArg => {
                     let arg_index = self.read_byte() as usize;
-                    let offset = self.read_u16() as usize;
+                    let offset = self.read_u24();
                     if frame!(self).arg_count >= arg_index {
                         frame_mut!(self).ip += offset;
                     }
                 }
                 Opcode::Loop => {
-                    let offset = self.read_u16();
-                    frame_mut!(self).ip -= offset as usize;
+                    let offset = self.read_u24();
+                    frame_mut!(self).ip -= offset;
                 }
                 Opcode::Jump => {
-                    let offset: u16 = self.read_u16();
-                    frame_mut!(self).ip += offset as usize;
+                    let offset = self.read_u24();
+                    frame_mut!(self).ip += offset;
+                }
+                Opcode::Try => {
+                    let catch_jump = self.read_u24();
+                    let catch_ip = (catch_jump != 0).then_some(frame!(self).ip + catch_jump);
+                    let finally_jump = self.read_u24();
+                    let finally_ip = (finally_jump != 0).then_some(frame!(self).ip + finally_jump);
+
+                    self.handlers.push(Handler {
+                        frame: self.frames.len() - 1,
+                        catch_ip,
+                        finally_ip,
+                        stack_len: self.stack.len(),
+                        completions_len: self.completions.len(),
+                    });
+                }
+                Opcode::EndTry => {
+                    let handler = self.handlers.pop().expect("EndTry outside of a try block");
+                    if handler.finally_ip.is_some() {
+                        self.completions.push((handler.frame, Completion::Normal));
+                    }
+                }
+                Opcode::Leave => {
+                    let tries = self.read_byte() as usize;
+                    let slots = self.read_u24();
+                    let stack_len = frame!(self).slot_offset + slots;
+                    self.leave_tries(tries, frame!(self).ip, stack_len);
+                }
+                Opcode::EndFinally => match self.completions.pop().expect("EndFinally outside of a finally block").1 {
+                    Completion::Normal => {}
+                    Completion::Leave { tries, ip, stack_len } => self.leave_tries(tries, ip, stack_len),
+                    Completion::Throw(exception) => self.throw(exception),
+                    Completion::Return(value) => {
+                        if self.return_value(value) {
+                            break;
+                        }
+                    }
+                },
+                Opcode::Throw => {
+                    let exception = self.pop_unchecked();
+                    self.throw(exception);
+                }
+                Opcode::IterInit => {
+                    let iterable = self.pop_unchecked();
+                    let state = match &iterable {
+                        // Iterators are already positioned, so looping over one again continues where it stopped
+                        Value::Iterator(_) => {
+                            self.stack.push(iterable);
+                            continue;
+                        }
+                        Value::Instance(instance) => {
+                            let methods = &instance.borrow().class.methods;
+                            let phase = if methods.borrow().contains_key(&self.interner.intern("iter")) {
+                                ObjectPhase::CallIter
+                            } else if methods.borrow().contains_key(&self.interner.intern("next")) {
+                                ObjectPhase::CallNext
+                            } else {
+                                throw_error!(self, "Can't iterate over an instance without iter() or next() methods");
+                            };
+                            IterState::Object {
+                                object: Rc::clone(instance),
+                                index: 0,
+                                phase,
+                            }
+                        }
+                        other => match IterState::new(other) {
+                            Some(state) => state,
+                            None => throw_error!(self, "Can't iterate over {}", value_as_string(other, self.interner)),
+                        },
+                    };
+                    self.stack.push(Value::Iterator(Rc::new(RefCell::new(state))));
+                }
+                Opcode::IterNext => {
+                    let start = frame!(self).ip - 1;
+                    let slot = self.read_u24();
+                    let var_count = self.read_byte();
+                    let exit_jump = self.read_u24();
+
+                    let Value::Iterator(iterator) = self.stack[frame!(self).slot_offset + slot].clone() else {
+                        throw_error!(self, "Expected an iterator in slot {slot} of the loop");
+                    };
+
+                    // Objects are advanced by calling their methods, after which this instruction runs again to use the result.
+                    // The method may loop over this same iterator, so it must not stay borrowed during the call.
+                    let method_call = match &mut *iterator.borrow_mut() {
+                        IterState::Object { object, phase, .. } if matches!(phase, ObjectPhase::CallIter | ObjectPhase::CallNext) => {
+                            let (name, awaiting) = match phase {
+                                ObjectPhase::CallIter => ("iter", ObjectPhase::AwaitIter),
+                                _ => ("next", ObjectPhase::AwaitNext),
+                            };
+                            let name_id = self.interner.intern(name);
+                            let method = object.borrow().class.methods.borrow().get(&name_id).cloned();
+                            let Some(method) = method else {
+                                throw_error!(self, "Iterator object has no {name}() method");
+                            };
+                            *phase = awaiting;
+                            Some(Value::BoundMethod {
+                                instance: Rc::clone(object),
+                                method,
+                            })
+                        }
+                        _ => None,
+                    };
+                    if let Some(method_call) = method_call {
+                        frame_mut!(self).ip = start;
+                        self.stack.push(method_call);
+                        if let Err(err) = self.call_value(0).await {
+                            throw_error!(self, "{err}");
+                        }
+                        continue;
+                    }
+
+                    let mut state = iterator.borrow_mut();
+                    if let IterState::Object { object, index, phase } = &mut *state {
+                        if *phase == ObjectPhase::AwaitIter {
+                            match self.pop_unchecked() {
+                                Value::Instance(next_object) => *object = next_object,
+                                other => {
+                                    let got = value_as_string(&other, self.interner);
+                                    throw_error!(self, "iter() must return an instance, got {got}");
+                                }
+                            }
+                            *phase = ObjectPhase::CallNext;
+                            frame_mut!(self).ip = start;
+                            continue;
+                        }
+
+                        *phase = ObjectPhase::CallNext;
+                        match self.pop_unchecked() {
+                            Value::Nil => frame_mut!(self).ip += exit_jump,
+                            value => {
+                                if var_count == 2 {
+                                    self.stack.push(Value::Number(*index as f64));
+                                }
+                                self.stack.push(value);
+                                *index += 1;
+                            }
+                        }
+                        continue;
+                    }
+
+                    match state.next(self.interner) {
+                        Some((key, value)) if var_count == 2 => {
+                            self.stack.push(key);
+                            self.stack.push(value);
+                        }
+                        Some((key, _)) if state.yields_keys() => self.stack.push(key),
+                        Some((_, value)) => self.stack.push(value),
+                        None => frame_mut!(self).ip += exit_jump,
+                    }
                 }
                 Opcode::Call => {
                     let arg_count = self.read_byte();
-                    if !self.call_value(arg_count).await {
-                        self.runtime_error("Could not call value");
+                    // Calls to plain functions skip the future of call_value, which only natives need
+                    let called = match self.peek(arg_count as usize) {
+                        Function(idx) => self.push_frame(*idx, None, arg_count),
+                        _ => self.call_value(arg_count).await,
+                    };
+                    if let Err(err) = called {
+                        throw_error!(self, "{err}");
+                    }
+                }
+                Opcode::Closure | Opcode::ClosureLong => {
+                    let fun_idx = match self.read_constant(instruction.is_long()) {
+                        Function(idx) => *idx,
+                        other => panic!("Found {other} instead of a function"),
+                    };
+                    let upvalue_count = self.read_byte() as usize;
+
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = frame!(self).slot_offset + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame_upvalue(index));
+                        }
                     }
+
+                    self.stack.push(Value::Closure(Rc::new(ClosureData { fun_idx, upvalues })));
+                }
+                Opcode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop_unchecked();
                 }
                 Opcode::Return => {
                     let value = self.pop().expect("Nothing to return");
-                    let orig_len = frame!(self).start_len;
-                    self.frames.pop();
-
-                    if self.frames.is_empty() {
-                        return Ok(());
+                    if self.return_value(value) {
+                        break;
                     }
-
-                    self.stack_trace();
-                    dbgln!("Truncating to length {}", orig_len,);
-                    self.stack.truncate(orig_len);
-                    self.stack.push(value);
                 }
-                Opcode::Constant => {
-                    let constant = self.read_constant().clone();
+                Opcode::Constant | Opcode::ConstantLong => {
+                    let constant = self.read_constant(instruction.is_long()).clone();
                     self.stack.push(constant);
                 }
                 Opcode::Negate => {
                     let value = self.pop_unchecked();
-                    match value {
-                        Number(num) => self.stack.push(Value::Number(-num)),
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                        }
+                    match negate(value) {
+                        std::result::Result::Ok(value) => self.stack.push(value),
+                        std::result::Result::Err(message) => throw_error!(self, "{message}"),
                     }
                 }
                 Opcode::True => self.stack.push(Bool(true)),
@@ -659,37 +1329,57 @@ where
                     let val = self.peek(0).clone();
                     self.stack.push(val);
                 }
-                Opcode::GetLocal => {
+                Opcode::GetLocal | Opcode::GetLocalLong => {
                     let array_index = self.pop_unchecked();
-                    let slot = self.read_byte() as usize;
+                    let slot = self.read_index(instruction.is_long());
                     let value = &self.stack[frame!(self).slot_offset + slot];
 
                     if array_index == Value::Nil {
                         self.stack.push(value.clone());
                     } else {
-                        self.stack.push(get_array(value, &array_index).unwrap_or_else(|err| {
-                            self.runtime_error(&format!("Error getting array: {err}"));
-                        }));
+                        match get_array(value, &array_index, self.interner) {
+                            std::result::Result::Ok(value) => self.stack.push(value),
+                            Err(err) => throw_error!(self, "Error getting array: {err}"),
+                        }
                     }
                 }
-                Opcode::GetGlobal => {
-                    let name = self.read_string_or_id();
+                Opcode::GetUpvalue => {
                     let array_index = self.pop_unchecked();
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame_upvalue(index);
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
 
-                    if let Some(value) = self.globals.get(&name) {
-                        if array_index == Value::Nil {
-                            self.stack.push(value.clone());
-                        } else {
-                            self.stack.push(get_array(value, &array_index).unwrap_or_else(|err| {
-                                self.runtime_error(&format!("Error getting array: {err}"));
-                            }));
+                    if array_index == Value::Nil {
+                        self.stack.push(value);
+                    } else {
+                        match get_array(&value, &array_index, self.interner) {
+                            std::result::Result::Ok(value) => self.stack.push(value),
+                            Err(err) => throw_error!(self, "Error getting array: {err}"),
                         }
+                    }
+                }
+                Opcode::GetGlobal | Opcode::GetGlobalLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
+                    let array_index = self.pop_unchecked();
+
+                    let Some(value) = self.globals.get(&name) else {
+                        throw_error!(self, "Undefined variable {}", self.interner.lookup(&name));
+                    };
+
+                    if array_index == Value::Nil {
+                        self.stack.push(value.clone());
                     } else {
-                        self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name)));
+                        match get_array(value, &array_index, self.interner) {
+                            std::result::Result::Ok(value) => self.stack.push(value),
+                            Err(err) => throw_error!(self, "Error getting array: {err}"),
+                        }
                     }
                 }
-                Opcode::SetLocal => {
-                    let slot: usize = self.read_byte() as usize;
+                Opcode::SetLocal | Opcode::SetLocalLong => {
+                    let slot = self.read_index(instruction.is_long());
                     let new_value = self.pop_unchecked();
                     let array_index = self.pop_unchecked();
                     self.stack.push(new_value.clone());
@@ -697,34 +1387,52 @@ where
 
                     if array_index == Value::Nil {
                         *value_to_be_modified = new_value;
-                    } else {
-                        set_array(value_to_be_modified, &array_index, new_value).unwrap_or_else(|err| {
-                            self.runtime_error(&format!("Error setting array: {err}"));
-                        });
+                    } else if let Err(err) = set_array(value_to_be_modified, &array_index, new_value, &mut self.gc) {
+                        throw_error!(self, "Error setting array: {err}");
                     }
                 }
-                Opcode::SetGlobal => {
-                    let name = self.read_string_or_id();
+                Opcode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame_upvalue(index);
+                    let new_value = self.pop_unchecked();
+                    let array_index = self.pop_unchecked();
+                    self.stack.push(new_value.clone());
+
+                    if array_index == Value::Nil && matches!(*upvalue.borrow(), Upvalue::Closed(_)) {
+                        self.gc.track_upvalue(&upvalue, &new_value);
+                    }
+                    let mut upvalue = upvalue.borrow_mut();
+                    let value_to_be_modified = match &mut *upvalue {
+                        Upvalue::Open(slot) => &mut self.stack[*slot],
+                        Upvalue::Closed(value) => value,
+                    };
+
+                    if array_index == Value::Nil {
+                        *value_to_be_modified = new_value;
+                    } else if let Err(err) = set_array(value_to_be_modified, &array_index, new_value, &mut self.gc) {
+                        throw_error!(self, "Error setting array: {err}");
+                    }
+                }
+                Opcode::SetGlobal | Opcode::SetGlobalLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
 
                     if !self.globals.contains_key(&name) {
-                        self.runtime_error(&format!("Undefined variable {}", self.interner.lookup(&name)));
-                    } else {
-                        let new_value = self.pop_unchecked();
-                        let array_index = self.pop_unchecked();
-                        self.stack.push(new_value.clone());
-                        let value_to_be_modified = self.globals.get_mut(&name).unwrap();
+                        throw_error!(self, "Undefined variable {}", self.interner.lookup(&name));
+                    }
 
-                        if array_index == Value::Nil {
-                            *value_to_be_modified = new_value;
-                        } else {
-                            set_array(value_to_be_modified, &array_index, new_value).unwrap_or_else(|err| {
-                                self.runtime_error(&format!("Error setting array: {err}"));
-                            });
-                        }
+                    let new_value = self.pop_unchecked();
+                    let array_index = self.pop_unchecked();
+                    self.stack.push(new_value.clone());
+                    let value_to_be_modified = self.globals.get_mut(&name).unwrap();
+
+                    if array_index == Value::Nil {
+                        *value_to_be_modified = new_value;
+                    } else if let Err(err) = set_array(value_to_be_modified, &array_index, new_value, &mut self.gc) {
+                        throw_error!(self, "Error setting array: {err}");
                     }
                 }
-                Opcode::DefineGlobal => {
-                    let name = self.read_string_or_id();
+                Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
                     let value = self.pop_unchecked();
                     self.globals.insert(name, value);
                 }
@@ -735,7 +1443,7 @@ where
                             self.stack.push(Value::Array(Rc::new(RefCell::new(vec![Nil; len as usize]))));
                         }
                         other => {
-                            self.runtime_error(&format!("Expected number, got {other}"));
+                            throw_error!(self, "Expected number, got {other}");
                         }
                     }
                 }
@@ -745,65 +1453,74 @@ where
                     let elements: Vec<Value> = self.stack.drain(start..).collect();
                     self.stack.push(Value::Array(Rc::new(RefCell::new(elements))));
                 }
-                Opcode::Equal => {
-                    let a = self.pop_unchecked();
-                    let b = self.pop_unchecked();
-                    self.stack.push(Bool(a == b))
+                Opcode::MapLiteral => {
+                    let count = self.read_byte() as usize;
+                    let start = self.stack.len() - count * 2;
+                    let entries: Vec<Value> = self.stack.drain(start..).collect();
+                    let map = LoxMap::from_iter(entries.chunks_exact(2).map(|entry| (entry[0].clone(), entry[1].clone())));
+                    self.stack.push(Value::Map(Rc::new(RefCell::new(map))));
+                }
+                Opcode::Interpolate => {
+                    let count = self.read_byte() as usize;
+                    let start = self.stack.len() - count;
+                    let mut string = String::new();
+                    for part in self.stack.drain(start..) {
+                        string.push_str(&value_as_string(&part, self.interner));
+                    }
+                    let id = self.interner.intern(&string);
+                    self.stack.push(Str(id));
                 }
+                Opcode::Equal => binop!(self, Opcode::Equal),
                 Opcode::Nil => self.stack.push(Nil),
-                Opcode::Class => {
-                    let name = self.read_string_or_id();
+                Opcode::Class | Opcode::ClassLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
                     let class = Rc::new(ClassData {
                         name,
                         methods: RefCell::new(rustc_hash::FxHashMap::default()),
+                        superclass: RefCell::new(None),
                     });
                     self.stack.push(Value::Class(class));
                 }
-                Opcode::Method => {
-                    let name = self.read_string_or_id();
-                    let method_val = self.pop_unchecked();
-                    if let Value::Function(idx) = method_val {
-                        if let Some(Value::Class(class)) = self.stack.last() {
-                            class.methods.borrow_mut().insert(name, idx);
-                        }
+                Opcode::Inherit => {
+                    let Value::Class(subclass) = self.pop_unchecked() else {
+                        panic!("Inherit expects the subclass on top of the stack");
+                    };
+                    let Value::Class(superclass) = self.peek(0).clone() else {
+                        throw_error!(self, "Superclass must be a class, got {}", self.peek(0));
+                    };
+
+                    // Copy-down inheritance: methods defined in the subclass body later override these
+                    let inherited = superclass.methods.borrow().clone();
+                    subclass.methods.borrow_mut().extend(inherited);
+                    *subclass.superclass.borrow_mut() = Some(superclass);
+                }
+                Opcode::Method | Opcode::MethodLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
+                    let method = match self.pop_unchecked() {
+                        Value::Function(fun_idx) => Rc::new(ClosureData {
+                            fun_idx,
+                            upvalues: Vec::new(),
+                        }),
+                        Value::Closure(closure) => closure,
+                        other => panic!("Found {other} instead of a method"),
+                    };
+                    if let Some(Value::Class(class)) = self.stack.last() {
+                        class.methods.borrow_mut().insert(name, method);
                     }
                 }
-                Opcode::GetProperty => {
-                    let name = self.read_string_or_id();
+                Opcode::GetProperty | Opcode::GetPropertyLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
                     let object = self.pop_unchecked();
-                    match object {
-                        Value::Instance(instance) => {
-                            if let Some(value) = instance.borrow().fields.borrow().get(&name).cloned() {
-                                self.stack.push(value);
-                            } else {
-                                let method_idx = instance.borrow().class.methods.borrow().get(&name).copied();
-                                if let Some(idx) = method_idx {
-                                    self.stack.push(Value::BoundMethod {
-                                        instance: Rc::clone(&instance),
-                                        method_idx: idx,
-                                    });
-                                } else {
-                                    self.stack.push(Value::Nil);
-                                }
-                            }
-                        }
-                        Value::Map(map) => {
-                            let key = Value::Str(name);
-                            if let Some(value) = map.borrow().get(&key).cloned() {
-                                self.stack.push(value);
-                            } else {
-                                self.stack.push(Value::Nil);
-                            }
-                        }
-                        _ => {
-                            self.runtime_error("Only instances and maps have properties.");
-                        }
+                    match get_property(object, name) {
+                        std::result::Result::Ok(value) => self.stack.push(value),
+                        std::result::Result::Err(message) => throw_error!(self, "{message}"),
                     }
                 }
-                Opcode::SetProperty => {
-                    let name = self.read_string_or_id();
+                Opcode::SetProperty | Opcode::SetPropertyLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
                     let value = self.pop_unchecked();
                     let object = self.pop_unchecked();
+                    self.gc.track(&object, &value);
                     match object {
                         Value::Instance(instance) => {
                             instance.borrow().fields.borrow_mut().insert(name, value.clone());
@@ -815,7 +1532,26 @@ where
                             self.stack.push(value);
                         }
                         _ => {
-                            self.runtime_error("Only instances and maps have properties.");
+                            throw_error!(self, "Only instances and maps have properties.");
+                        }
+                    }
+                }
+                Opcode::GetSuper | Opcode::GetSuperLong => {
+                    let name = self.read_string_or_id(instruction.is_long());
+                    let superclass = self.pop_unchecked();
+                    let receiver = self.pop_unchecked();
+                    match (superclass, receiver) {
+                        (Value::Class(superclass), Value::Instance(instance)) => {
+                            let method = superclass.methods.borrow().get(&name).cloned();
+                            match method {
+                                Some(method) => self.stack.push(Value::BoundMethod { instance, method }),
+                                None => {
+                                    throw_error!(self, "Undefined superclass method {}", self.interner.lookup(&name));
+                                }
+                            }
+                        }
+                        (superclass, receiver) => {
+                            throw_error!(self, "Can't look up super method on {receiver} with superclass {superclass}");
                         }
                     }
                 }
@@ -826,94 +1562,74 @@ where
                 Opcode::GetIndex => {
                     let index = self.pop_unchecked();
                     let arr = self.pop_unchecked();
-                    self.stack.push(get_array(&arr, &index).unwrap_or_else(|err| {
-                        self.runtime_error(&format!("Error getting array: {err}"));
-                    }));
+                    match get_array(&arr, &index, self.interner) {
+                        std::result::Result::Ok(value) => self.stack.push(value),
+                        Err(err) => throw_error!(self, "Error getting array: {err}"),
+                    }
+                }
+                Opcode::Slice => {
+                    let end = self.pop_unchecked();
+                    let start = self.pop_unchecked();
+                    let value = self.pop_unchecked();
+                    match slice(&value, &start, &end, self.interner) {
+                        std::result::Result::Ok(value) => self.stack.push(value),
+                        Err(err) => throw_error!(self, "Error slicing: {err}"),
+                    }
                 }
                 Opcode::SetIndex => {
                     let new_value = self.pop_unchecked();
                     let index = self.pop_unchecked();
                     let mut arr = self.pop_unchecked();
-                    set_array(&mut arr, &index, new_value.clone()).unwrap_or_else(|err| {
-                        self.runtime_error(&format!("Error setting array: {err}"));
-                    });
-                    self.stack.push(new_value);
-                }
-                Opcode::Add => {
-                    let b = self.pop_unchecked();
-                    let a = self.pop_unchecked();
-                    match (b, a) {
-                        (Number(a), Number(b)) => {
-                            self.stack.push(Number(a + b));
-                        }
-                        (Str(b), Str(a)) => {
-                            let mut new_string = String::from(self.interner.lookup(&a));
-                            new_string.push_str(self.interner.lookup(&b));
-                            let id = self.interner.intern(&new_string);
-                            self.stack.push(Str(id));
-                        }
-                        (Number(b), Str(a)) => {
-                            let mut new_string = String::from(self.interner.lookup(&a));
-                            new_string.push_str(&b.to_string());
-                            let id = self.interner.intern(&new_string);
-                            self.stack.push(Str(id));
-                        }
-                        (left, right) => {
-                            self.runtime_error(&format!("Operands must be numbers but got {left} {right}"));
-                        }
+                    if let Err(err) = set_array(&mut arr, &index, new_value.clone(), &mut self.gc) {
+                        throw_error!(self, "Error setting array: {err}");
                     }
+                    self.stack.push(new_value);
                 }
-                Opcode::Subtract => binop!(self, Number, -),
-                Opcode::Multiply => binop!(self, Number, *),
-                Opcode::Modulo => binop!(self, Number, %),
-                Opcode::Divide => binop!(self, Number, /),
+                Opcode::Add => binop!(self, Opcode::Add),
+                Opcode::Subtract => binop!(self, Opcode::Subtract),
+                Opcode::Multiply => binop!(self, Opcode::Multiply),
+                Opcode::Modulo => binop!(self, Opcode::Modulo),
+                Opcode::Divide => binop!(self, Opcode::Divide),
                 Opcode::Not => {
                     let val = self.pop_unchecked();
                     self.stack.push(Bool(self.is_falsey(&val)))
                 }
-                Opcode::Greater => binop!(self, Bool, >),
-                Opcode::Less => binop!(self, Bool, <),
+                Opcode::Greater => binop!(self, Opcode::Greater),
+                Opcode::Less => binop!(self, Opcode::Less),
             }
         }
+        self.interner.set_runtime(false);
+        std::result::Result::Ok(())
     }
 
-    pub async fn interpret(functions: Vec<Fun>, interner: &'src mut Interner, read_async: F, sleep_async: SF) -> Result<()> {
+    pub async fn interpret(functions: Vec<Fun>, interner: &'src mut Interner, read_async: F, sleep_async: SF) -> Result<(), RuntimeError> {
+        Self::interpret_with(functions, interner, read_async, sleep_async, None).await
+    }
+
+    /// Run the program like `interpret`, pausing it whenever `debugger` asks to
+    pub async fn debug(
+        functions: Vec<Fun>,
+        interner: &'src mut Interner,
+        read_async: F,
+        sleep_async: SF,
+        debugger: Debugger,
+    ) -> Result<(), RuntimeError> {
+        Self::interpret_with(functions, interner, read_async, sleep_async, Some(debugger)).await
+    }
+
+    async fn interpret_with(
+        functions: Vec<Fun>,
+        interner: &'src mut Interner,
+        read_async: F,
+        sleep_async: SF,
+        debugger: Option<Debugger>,
+    ) -> Result<(), RuntimeError> {
         dbgln!("== Interpreter VM ==");
         let mut vm = Vm::new(interner, functions, read_async, sleep_async);
+        vm.debugger = debugger;
 
         vm.reset_err_string();
-
-        register_native!(vm, Clock);
-        register_native!(vm, Sleep);
-        register_native!(vm, TypeOf);
-        register_native!(vm, Print);
-        register_native!(vm, Printf);
-        register_native!(vm, ReadString);
-        register_native!(vm, StrCast);
-        register_native!(vm, BufCast);
-        register_native!(vm, ChrCast);
-        register_native!(vm, HelpCast);
-        register_native!(vm, IntCast);
-        register_native!(vm, FloatCast);
-        register_native!(vm, BoolCast);
-        register_native!(vm, StringAt);
-        register_native!(vm, Len);
-        register_native!(vm, Ceil);
-        register_native!(vm, Floor);
-        register_native!(vm, Abs);
-        register_native!(vm, Sort);
-        register_native!(vm, IndexOf);
-        register_native!(vm, Rand);
-        register_native!(vm, Clear);
-        register_native!(vm, Sin);
-        register_native!(vm, Cos);
-        register_native!(vm, Sqrt);
-        register_native!(vm, Pow);
-        register_native!(vm, Pi);
-        register_native!(vm, MapConstructor);
-        register_native!(vm, Keys);
-        register_native!(vm, Values);
-        register_native!(vm, Has);
+        vm.register_natives();
 
         vm.load_native_imports(vm.functions.len() - 1);
 
@@ -922,9 +1638,8 @@ where
     }
 
     fn peek(&self, distance: usize) -> &Value {
-        return self
-            .stack
+        self.stack
             .get(self.stack.len() - 1 - distance)
-            .unwrap_or_else(|| panic!("Failed to peek {distance} deep"));
+            .unwrap_or_else(|| panic!("Failed to peek {distance} deep"))
     }
 }
@@ -0,0 +1,20 @@
+const path = require('path');
+const { LanguageClient } = require('vscode-languageclient/node');
+
+let client;
+
+// Start the language server built from the `lsp` crate, which is packaged in `bin`
+function activate(context) {
+    const executable = process.platform === 'win32' ? 'lsp.exe' : 'lsp';
+    const command = context.asAbsolutePath(path.join('bin', executable));
+    client = new LanguageClient('lox', 'Lox', { command }, {
+        documentSelector: [{ scheme: 'file', language: 'lox' }],
+    });
+    client.start();
+}
+
+function deactivate() {
+    return client && client.stop();
+}
+
+module.exports = { activate, deactivate };
@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use compiler::ast::{self, Block, Class, Expr, Function, FunctionBody, Stmt};
+use compiler::compiler::Compiler;
+use compiler::diagnostic::{Diagnostic, Span};
+use compiler::fun::FunType;
+use compiler::interner::Interner;
+use compiler::lint::LintConfig;
+use compiler::native::{self, Callable, Globals};
+use compiler::parser;
+use compiler::scanner::{Scanner, Token, TokenType};
+use compiler::value::Value;
+
+/// What a name is, for listing and completing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Keyword,
+    /// Namespace of an import
+    Module,
+    Function,
+    Method,
+    Class,
+    Variable,
+}
+
+/// A function, class, method or global declared in a file
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: Kind,
+    /// Names of the parameters of functions and methods
+    pub params: Vec<String>,
+    /// Where the name is
+    pub name_span: Span,
+    /// Where the whole declaration is, including the body of functions and classes
+    pub span: Span,
+    /// Methods of a class
+    pub children: Vec<Symbol>,
+    /// The class a class inherits from, like `shapes.Shape`
+    pub superclass: Option<String>,
+    /// The docstring of a function or method, or else the `//` comments on the lines right above the declaration
+    pub doc: Option<String>,
+}
+
+impl Symbol {
+    /// How the declaration starts, like `function area(width, height)`
+    pub fn signature(&self) -> String {
+        let params = self.params.join(", ");
+        match self.kind {
+            Kind::Function => format!("function {}({params})", self.name),
+            Kind::Method => format!("{}({params})", self.name),
+            Kind::Class => format!("class {}", self.name),
+            _ => format!("var {}", self.name),
+        }
+    }
+
+    fn completion(&self) -> Completion {
+        Completion {
+            label: self.name.clone(),
+            kind: self.kind,
+            detail: Some(self.signature()),
+            doc: self.doc.clone(),
+        }
+    }
+}
+
+/// An `import` declaration
+#[derive(Debug, Clone)]
+pub struct Import {
+    /// Namespace of the globals the import declares
+    pub alias: String,
+    /// The imported Lox file, if it exists
+    pub file: Option<PathBuf>,
+    /// Name or path of the native module, for imports of anything but Lox files
+    pub module: Option<String>,
+}
+
+/// The declarations of a file, found by parsing it. The parser recovers from syntax errors, so code that doesn't compile has them too.
+#[derive(Debug, Default)]
+pub struct Outline {
+    /// Top-level declarations, in the order they appear
+    pub symbols: Vec<Symbol>,
+    pub imports: Vec<Import>,
+    /// Every statement of the file, to find what a variable holds where it is used
+    statements: Vec<Stmt>,
+}
+
+impl Outline {
+    /// Outline of `source`, whose imports are relative to `dir`, or to the working directory without one
+    pub fn new(source: &str, dir: Option<&Path>) -> Outline {
+        let (program, _) = parser::parse(Rc::from(source));
+        let mut outline = Outline::default();
+        for statement in &program.statements {
+            let span = statement.span();
+            let symbol = match statement {
+                Stmt::Function { name, function, .. } => function_symbol(source, function, &name.name, Kind::Function, span),
+                Stmt::Class(class) => class_symbol(source, class, span),
+                Stmt::Var { name, .. } => symbol(source, &name.name, Kind::Variable, span),
+                Stmt::Import(import) => {
+                    outline.imports.push(Import::new(import, dir));
+                    None
+                }
+                _ => None,
+            };
+            outline.symbols.extend(symbol);
+        }
+        outline.statements = program.statements;
+        outline
+    }
+
+    /// The top-level declaration named `name`
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn import(&self, alias: &str) -> Option<&Import> {
+        self.imports.iter().find(|import| import.alias == alias)
+    }
+
+    /// The class of the instance that `receiver` holds at `offset`, named like `["shapes", "Circle"]`.
+    /// It is known for `this` in a method, and for a variable declared with a new instance like `var c = Circle(2)`.
+    fn receiver_class(&self, receiver: &str, offset: usize) -> Option<Vec<String>> {
+        let mut class = None;
+        receiver_class(&self.statements, receiver, offset, &mut class);
+        class
+    }
+}
+
+impl Import {
+    /// The import declared by `import`, whose path is relative to `dir`
+    fn new(import: &ast::Import, dir: Option<&Path>) -> Import {
+        let alias = import.namespace.clone();
+        if !import.path.ends_with(".lox") {
+            let module = Some(import.path.clone());
+            return Import { alias, file: None, module };
+        }
+        let file = dir.map_or_else(|| PathBuf::from(&import.path), |dir| dir.join(&import.path));
+        Import {
+            alias,
+            file: file.canonicalize().ok(),
+            module: None,
+        }
+    }
+}
+
+/// Every token of `source`, without the end of file
+pub fn tokens(source: &str) -> Vec<Token> {
+    let mut scanner = Scanner::new(Rc::from(source));
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        if token.typ == TokenType::EOF {
+            return tokens;
+        }
+        tokens.push(token);
+    }
+}
+
+/// The `//` comments on the lines right above the code at `start`, if nothing comes before it on its line
+fn leading_comment(source: &str, start: usize) -> Option<String> {
+    let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    if !source[line_start..start].trim().is_empty() {
+        return None;
+    }
+    let mut lines: Vec<&str> = source[..line_start]
+        .lines()
+        .rev()
+        .map_while(|line| line.trim().strip_prefix("//"))
+        .map(|comment| comment.strip_prefix(' ').unwrap_or(comment))
+        .collect();
+    lines.reverse();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// The declaration of `name` over `span`, unless the name is missing from code that doesn't parse
+fn symbol(source: &str, name: &Token, kind: Kind, span: Span) -> Option<Symbol> {
+    if name.typ != TokenType::Identifier {
+        return None;
+    }
+    Some(Symbol {
+        name: name.source.to_string(),
+        kind,
+        params: Vec::new(),
+        name_span: name.span,
+        span,
+        children: Vec::new(),
+        superclass: None,
+        doc: leading_comment(source, span.start),
+    })
+}
+
+/// The function or method `function` named `name`, with its parameters
+fn function_symbol(source: &str, function: &Function, name: &Token, kind: Kind, span: Span) -> Option<Symbol> {
+    let mut symbol = symbol(source, name, kind, span)?;
+    let names = function.params.iter().map(|param| &param.name.name);
+    let names = names.filter(|name| name.typ == TokenType::Identifier);
+    symbol.params = names.map(|name| name.source.to_string()).collect();
+    if let FunctionBody::Block { doc: Some(doc), .. } = &function.body {
+        symbol.doc = Some(doc.source[1..doc.source.len() - 1].to_string());
+    }
+    Some(symbol)
+}
+
+/// The class `class`, with its methods
+fn class_symbol(source: &str, class: &Class, span: Span) -> Option<Symbol> {
+    let mut symbol = symbol(source, &class.name.name, Kind::Class, span)?;
+    if let Some(Expr::Variable(superclass)) = &class.superclass {
+        symbol.superclass = Some(superclass.name());
+    }
+    for method in &class.methods {
+        let Some(name) = &method.name else {
+            continue;
+        };
+        let span = Span {
+            start: method.start.span.start,
+            end: method.last_token().span.end,
+        };
+        symbol.children.extend(function_symbol(source, method, name, Kind::Method, span));
+    }
+    Some(symbol)
+}
+
+/// Set `class` to the class of the instance `receiver` holds at `offset`, going through the `statements` before `offset`
+/// and into those around it. Declarations of `receiver` that don't create an instance make the class unknown.
+fn receiver_class(statements: &[Stmt], receiver: &str, offset: usize, class: &mut Option<Vec<String>>) {
+    let declares = |name: &Token| name.source.as_ref() == receiver;
+    let started = |statement: &&Stmt| statement.first_token().span.start <= offset;
+    for statement in statements.iter().take_while(started) {
+        if let Stmt::Var { name, initializer, .. } = statement {
+            if declares(&name.name) {
+                *class = initializer.as_ref().and_then(constructed_class);
+            }
+        }
+
+        let span = statement.span();
+        if offset > span.end {
+            continue;
+        }
+        match statement {
+            Stmt::Function { function, .. } => function_receiver_class(function, receiver, offset, class),
+            Stmt::Class(declaration) => {
+                let methods = declaration.methods.iter();
+                for method in methods.filter(|method| method.start.span.start <= offset && offset <= method.last_token().span.end) {
+                    if receiver == "this" {
+                        *class = Some(vec![declaration.name.name.source.to_string()]);
+                    }
+                    function_receiver_class(method, receiver, offset, class);
+                }
+            }
+            Stmt::Block(block) => block_receiver_class(block, receiver, offset, class),
+            Stmt::If {
+                then_branch, else_branch, ..
+            } => {
+                receiver_class(std::slice::from_ref(then_branch), receiver, offset, class);
+                if let Some(else_branch) = else_branch {
+                    receiver_class(std::slice::from_ref(else_branch), receiver, offset, class);
+                }
+            }
+            Stmt::While { body, .. } => receiver_class(std::slice::from_ref(body), receiver, offset, class),
+            Stmt::For { initializer, body, .. } => {
+                if let Some(initializer) = initializer {
+                    receiver_class(std::slice::from_ref(initializer), receiver, offset, class);
+                }
+                receiver_class(std::slice::from_ref(body), receiver, offset, class);
+            }
+            Stmt::ForIn { variables, body, .. } => {
+                if variables.iter().any(|variable| declares(&variable.name)) {
+                    *class = None;
+                }
+                receiver_class(std::slice::from_ref(body), receiver, offset, class);
+            }
+            Stmt::Try { block, catch, finally, .. } => {
+                block_receiver_class(block, receiver, offset, class);
+                if let Some(catch) = catch {
+                    if catch.name.as_ref().is_some_and(|name| declares(&name.name)) && within(&catch.block, offset) {
+                        *class = None;
+                    }
+                    block_receiver_class(&catch.block, receiver, offset, class);
+                }
+                if let Some((_, finally)) = finally {
+                    block_receiver_class(finally, receiver, offset, class);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Set `class` to the class `receiver` holds at `offset` in `function`, whose parameters may hide it
+fn function_receiver_class(function: &Function, receiver: &str, offset: usize, class: &mut Option<Vec<String>>) {
+    if function.params.iter().any(|param| param.name.name.source.as_ref() == receiver) {
+        *class = None;
+    }
+    if let FunctionBody::Block { block, .. } = &function.body {
+        block_receiver_class(block, receiver, offset, class);
+    }
+}
+
+/// Set `class` to the class `receiver` holds at `offset` in `block`, if `offset` is in it
+fn block_receiver_class(block: &Block, receiver: &str, offset: usize, class: &mut Option<Vec<String>>) {
+    if within(block, offset) {
+        receiver_class(&block.statements, receiver, offset, class);
+    }
+}
+
+fn within(block: &Block, offset: usize) -> bool {
+    block.open.span.start <= offset && offset <= block.close.span.end
+}
+
+/// The class `expr` creates an instance of, when it calls a class by name like `Circle(2)` or `shapes.Circle(2)`.
+/// Functions are called the same way, so the name is only a class if a class by that name is found.
+fn constructed_class(expr: &Expr) -> Option<Vec<String>> {
+    let Expr::Call { callee, .. } = expr else {
+        return None;
+    };
+    let Expr::Variable(variable) = callee.as_ref() else {
+        return None;
+    };
+    Some(variable.path.iter().map(|name| name.source.to_string()).collect())
+}
+
+/// Names of the identifier at `offset` and of the namespaces or objects it is a member of,
+/// like `["util", "parse"]` for `util.parse`, with the span of the identifier
+pub fn path_at(tokens: &[Token], offset: usize) -> Option<(Vec<String>, Span)> {
+    let idx = tokens
+        .iter()
+        .position(|token| token.typ == TokenType::Identifier && token.span.start <= offset && offset <= token.span.end)?;
+    Some((qualified_name(tokens, idx), tokens[idx].span))
+}
+
+/// Names of what the member being typed at `offset` is a member of, if the cursor follows a `.`
+pub fn member_of(tokens: &[Token], offset: usize) -> Option<Vec<String>> {
+    let mut idx = tokens.iter().rposition(|token| token.span.start < offset)?;
+    if tokens[idx].typ == TokenType::Identifier && idx > 0 {
+        idx -= 1;
+    }
+    if tokens[idx].typ != TokenType::Dot || idx == 0 || !is_name(&tokens[idx - 1]) {
+        return None;
+    }
+    Some(qualified_name(tokens, idx - 1))
+}
+
+/// Whether `token` is a name that members are read from, which includes `this`
+fn is_name(token: &Token) -> bool {
+    token.typ == TokenType::Identifier || token.typ == TokenType::This
+}
+
+/// The identifier at `idx` with the names before it that are joined to it by dots
+fn qualified_name(tokens: &[Token], mut idx: usize) -> Vec<String> {
+    let mut names = vec![tokens[idx].source.to_string()];
+    while idx >= 2 && tokens[idx - 1].typ == TokenType::Dot && is_name(&tokens[idx - 2]) {
+        idx -= 2;
+        names.insert(0, tokens[idx].source.to_string());
+    }
+    names
+}
+
+/// The file imported under the chain of `namespaces`, with its outline
+fn imported(outline: &Outline, namespaces: &[String]) -> Option<(PathBuf, Outline)> {
+    let mut imported: Option<(PathBuf, Outline)> = None;
+    for namespace in namespaces {
+        let current = imported.as_ref().map_or(outline, |(_, outline)| outline);
+        let file = current.import(namespace)?.file.clone()?;
+        let source = fs::read_to_string(&file).ok()?;
+        let outline = Outline::new(&source, file.parent());
+        imported = Some((file, outline));
+    }
+    imported
+}
+
+/// Methods of the classes of `outline` and of the Lox files it imports, for instances whose class isn't known.
+/// Methods are paired with the imported file they are in, or `None` for the file of `outline`.
+fn methods(outline: &Outline) -> Vec<(Option<PathBuf>, Symbol)> {
+    let declared = |outline: &Outline| -> Vec<Symbol> { outline.symbols.iter().flat_map(|symbol| symbol.children.clone()).collect() };
+    let mut methods: Vec<_> = declared(outline).into_iter().map(|method| (None, method)).collect();
+    for file in outline.imports.iter().filter_map(|import| import.file.as_ref()) {
+        let Ok(source) = fs::read_to_string(file) else {
+            continue;
+        };
+        let imported = Outline::new(&source, file.parent());
+        methods.extend(declared(&imported).into_iter().map(|method| (Some(file.clone()), method)));
+    }
+    methods
+}
+
+/// Methods of the class named by `class`, like `["shapes", "Circle"]`, followed by those it inherits and doesn't override,
+/// or `None` if there is no such class. Methods are paired with the imported file they are in, or `None` for the file of `outline`.
+fn class_methods(outline: &Outline, class: &[String]) -> Option<Vec<(Option<PathBuf>, Symbol)>> {
+    let mut methods = Vec::new();
+    let mut classes = Vec::new();
+    collect_methods(outline, None, class, &mut methods, &mut classes)?;
+    Some(methods)
+}
+
+/// Add the methods of `class` to `methods`, where `outline` is the outline of `file`, and those of its superclasses.
+/// `classes` are the classes whose methods were added, as code that doesn't compile may have classes inherit from each other.
+fn collect_methods(
+    outline: &Outline,
+    file: Option<PathBuf>,
+    class: &[String],
+    methods: &mut Vec<(Option<PathBuf>, Symbol)>,
+    classes: &mut Vec<(Option<PathBuf>, String)>,
+) -> Option<()> {
+    let (name, namespaces) = class.split_last()?;
+    let imported = imported(outline, namespaces);
+    let (file, outline) = match &imported {
+        Some((file, imported)) => (Some(file.clone()), imported),
+        None if namespaces.is_empty() => (file, outline),
+        None => return None,
+    };
+    let symbol = outline.find(name).filter(|symbol| symbol.kind == Kind::Class)?;
+    if classes.contains(&(file.clone(), name.clone())) {
+        return Some(());
+    }
+    classes.push((file.clone(), name.clone()));
+
+    for method in &symbol.children {
+        if !methods.iter().any(|(_, overriding)| overriding.name == method.name) {
+            methods.push((file.clone(), method.clone()));
+        }
+    }
+    if let Some(superclass) = &symbol.superclass {
+        let superclass: Vec<String> = superclass.split('.').map(str::to_string).collect();
+        collect_methods(outline, file, &superclass, methods, classes);
+    }
+    Some(())
+}
+
+/// Methods that a member of `receiver` can be at `offset`, which are those of its class if it is known
+fn receiver_methods(outline: &Outline, receiver: &[String], offset: usize) -> Option<Vec<(Option<PathBuf>, Symbol)>> {
+    let [receiver] = receiver else {
+        return None;
+    };
+    class_methods(outline, &outline.receiver_class(receiver, offset)?)
+}
+
+/// The method `name` of `receiver` at `offset`. Without the class of the receiver,
+/// the method is only known when a single class has a method by that name.
+fn method(outline: &Outline, receiver: &[String], name: &str, offset: usize) -> Option<(Option<PathBuf>, Symbol)> {
+    if let Some(methods) = receiver_methods(outline, receiver, offset) {
+        return methods.into_iter().find(|(_, method)| method.name == name);
+    }
+    let mut named = methods(outline).into_iter().filter(|(_, method)| method.name == name);
+    let method = named.next()?;
+    named.next().is_none().then_some(method)
+}
+
+/// Where a name is declared
+#[derive(Debug)]
+pub struct Definition {
+    /// File of the declaration, or `None` for the file the name is used in
+    pub file: Option<PathBuf>,
+    /// The declaration, or `None` when the name is the namespace of an imported file, which leads to that file
+    pub symbol: Option<Symbol>,
+}
+
+/// Find the declaration of the name in `path` used at `offset`, following the namespaces of imported Lox files
+pub fn definition(outline: &Outline, path: &[String], offset: usize) -> Option<Definition> {
+    let (name, namespaces) = path.split_last()?;
+    let (file, imported) = match imported(outline, namespaces) {
+        Some((file, imported)) => (Some(file), Some(imported)),
+        None if namespaces.is_empty() => (None, None),
+        // A member of something that isn't a namespace is a field or a method of an instance
+        None => {
+            let (file, method) = method(outline, namespaces, name, offset)?;
+            return Some(Definition {
+                file,
+                symbol: Some(method),
+            });
+        }
+    };
+
+    let outline = imported.as_ref().unwrap_or(outline);
+    if let Some(symbol) = outline.find(name) {
+        let symbol = Some(symbol.clone());
+        return Some(Definition { file, symbol });
+    }
+    let file = outline.import(name)?.file.clone()?;
+    Some(Definition {
+        file: Some(file),
+        symbol: None,
+    })
+}
+
+/// A native function, with what it says about itself
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub arity: usize,
+    pub help: Option<String>,
+}
+
+impl Member {
+    fn new(name: &str, callable: &dyn Callable) -> Member {
+        Member {
+            name: name.to_string(),
+            arity: callable.arity(),
+            help: callable.help(),
+        }
+    }
+
+    /// How the function is called, which its help starts with when it has help
+    pub fn signature(&self) -> String {
+        let first_line = self.help.as_deref().and_then(|help| help.lines().next());
+        match first_line {
+            Some(line) if line.contains('(') => line.to_string(),
+            _ => format!("{}({})", self.name, vec!["_"; self.arity].join(", ")),
+        }
+    }
+
+    /// The help without the signature
+    pub fn description(&self) -> Option<String> {
+        let help = self.help.as_deref()?;
+        let has_signature = help.lines().next().is_some_and(|line| line.contains('('));
+        let description = if has_signature {
+            help.split_once('\n').map_or("", |(_, rest)| rest)
+        } else {
+            help
+        };
+        (!description.trim().is_empty()).then(|| description.to_string())
+    }
+
+    fn completion(&self) -> Completion {
+        Completion {
+            label: self.name.clone(),
+            kind: Kind::Function,
+            detail: Some(self.signature()),
+            doc: self.description(),
+        }
+    }
+}
+
+/// Every builtin function
+pub fn builtins() -> Vec<Member> {
+    let builtins = native::builtins();
+    builtins.iter().map(|native| Member::new(native.name(), native.as_ref())).collect()
+}
+
+/// What to show for a name when hovering over it
+#[derive(Debug)]
+pub struct Description {
+    pub signature: String,
+    pub doc: Option<String>,
+}
+
+/// A name that can be typed where the cursor is
+#[derive(Debug)]
+pub struct Completion {
+    pub label: String,
+    pub kind: Kind,
+    pub detail: Option<String>,
+    pub doc: Option<String>,
+}
+
+/// What the compiler knows about a file, found when it is opened and each time it is saved
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    /// Functions of the builtin modules the file imports, by the namespace they are imported as
+    modules: HashMap<String, Vec<Member>>,
+}
+
+impl Analysis {
+    /// Compile `source`, whose imports are relative to `dir`, and load the builtin modules its `outline` imports
+    pub fn new(source: &str, dir: Option<&Path>, outline: &Outline) -> Analysis {
+        let mut interner = Interner::with_capacity(1024);
+        let mut functions = Vec::new();
+        let lints = LintConfig::default();
+        let dir = dir.map(Path::to_path_buf);
+        let compiled = Compiler::compile(Rc::from(source), dir, &mut interner, &mut functions, FunType::Script, &lints);
+        let diagnostics = compiled.map(|(_, diagnostics)| diagnostics).unwrap_or_default();
+
+        // Libraries aren't loaded, as loading one runs its code
+        let mut modules = HashMap::new();
+        for import in &outline.imports {
+            let Some(module) = &import.module else {
+                continue;
+            };
+            let mut globals = Globals::default();
+            if !compiler::vm::load_builtin_module(module, &import.alias, &mut interner, &mut globals) {
+                continue;
+            }
+            let prefix = format!("{}.", import.alias);
+            let mut members: Vec<Member> = globals
+                .iter()
+                .filter_map(|(name, value)| match value {
+                    Value::NativeFunction(callable) => {
+                        let name = interner.lookup(name).strip_prefix(&prefix)?;
+                        Some(Member::new(name, callable.as_ref()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            members.sort_by(|a, b| a.name.cmp(&b.name));
+            modules.insert(import.alias.clone(), members);
+        }
+
+        Analysis { diagnostics, modules }
+    }
+
+    /// Describe the name in `path` used at `offset`, which is a builtin, a function of a builtin module or a declaration of the program
+    pub fn describe(&self, outline: &Outline, path: &[String], offset: usize) -> Option<Description> {
+        let (name, namespaces) = path.split_last()?;
+        let member = match namespaces {
+            [] => builtins().into_iter().find(|builtin| builtin.name == *name),
+            [namespace] => self
+                .modules
+                .get(namespace)
+                .and_then(|members| members.iter().find(|member| member.name == *name))
+                .cloned(),
+            _ => None,
+        };
+        // The program may declare a global named like a builtin, which hides it
+        let definition = definition(outline, path, offset);
+        let symbol = definition.as_ref().and_then(|definition| definition.symbol.as_ref());
+        if let (Some(member), None) = (&member, symbol) {
+            return Some(Description {
+                signature: member.signature(),
+                doc: member.description(),
+            });
+        }
+
+        let symbol = symbol?;
+        Some(Description {
+            signature: symbol.signature(),
+            doc: symbol.doc.clone(),
+        })
+    }
+
+    /// Names that can be typed at `offset` as a member of `namespaces`, or anywhere when there are none
+    pub fn completions(&self, outline: &Outline, namespaces: Option<&[String]>, offset: usize) -> Vec<Completion> {
+        let Some(namespaces) = namespaces else {
+            let keywords = Scanner::keywords().map(|keyword| Completion {
+                label: keyword.to_string(),
+                kind: Kind::Keyword,
+                detail: None,
+                doc: None,
+            });
+            let builtins = builtins().into_iter().map(|builtin| builtin.completion());
+            let symbols = outline.symbols.iter().map(Symbol::completion);
+            return keywords
+                .chain(builtins)
+                .chain(symbols)
+                .chain(namespace_completions(outline))
+                .collect();
+        };
+
+        if let [namespace] = namespaces {
+            if let Some(members) = self.modules.get(namespace) {
+                return members.iter().map(Member::completion).collect();
+            }
+        }
+        match imported(outline, namespaces) {
+            Some((_, imported)) => {
+                let symbols = imported.symbols.iter().map(Symbol::completion);
+                symbols.chain(namespace_completions(&imported)).collect()
+            }
+            None => {
+                let methods = receiver_methods(outline, namespaces, offset).unwrap_or_else(|| methods(outline));
+                methods.iter().map(|(_, method)| method.completion()).collect()
+            }
+        }
+    }
+}
+
+/// Completions of the namespaces that `outline` imports
+fn namespace_completions(outline: &Outline) -> impl Iterator<Item = Completion> + '_ {
+    outline.imports.iter().map(|import| Completion {
+        label: import.alias.clone(),
+        kind: Kind::Module,
+        detail: import
+            .module
+            .clone()
+            .or_else(|| Some(import.file.as_ref()?.to_string_lossy().to_string())),
+        doc: None,
+    })
+}
@@ -0,0 +1,395 @@
+mod analysis;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use analysis::{Analysis, Completion, Kind, Outline, Symbol};
+use compiler::diagnostic::{Diagnostic, Severity, Span};
+use compiler::init;
+use compiler::lint::Lint;
+use native::builtin;
+use native::protocol::{self, read_message};
+use serde_json::{json, Value};
+
+/// Send a message to the editor, tagged with the JSON-RPC version like every message of the Language Server Protocol
+fn send(mut message: Value) {
+    message["jsonrpc"] = json!("2.0");
+    protocol::write_message(&message);
+}
+
+fn respond(id: &Value, result: Value) {
+    send(json!({ "id": id, "result": result }));
+}
+
+fn notify(method: &str, params: Value) {
+    send(json!({ "method": method, "params": params }));
+}
+
+/// Stdout carries the protocol, so anything the compiler prints goes to stderr, which editors log
+fn print(output: String) {
+    eprint!("{output}");
+}
+
+fn println(output: String) {
+    eprintln!("{output}");
+}
+
+fn clear() {}
+
+/// Position of the byte `offset` of `text`, with lines counted from 0 and characters in UTF-16 code units as editors count them
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let character = before[line_start..].encode_utf16().count();
+    json!({ "line": before.matches('\n').count(), "character": character })
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+/// Byte offset of an editor position in `text`
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (idx, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + idx;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// Path of a `file:` URI
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut idx = 0;
+    while idx < encoded.len() {
+        let escaped = encoded.get(idx + 1..idx + 3).filter(|_| encoded[idx] == b'%');
+        let escaped = escaped.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                idx += 3;
+            }
+            None => {
+                bytes.push(encoded[idx]);
+                idx += 1;
+            }
+        }
+    }
+
+    let path = String::from_utf8(bytes).ok()?;
+    // Windows paths start with a drive, like `/c:/code/main.lox`
+    match path.as_bytes() {
+        [b'/', _, b':', ..] => Some(PathBuf::from(&path[1..])),
+        _ => Some(PathBuf::from(path)),
+    }
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let path = path.strip_prefix(r"\\?\").unwrap_or(&path).replace('\\', "/");
+    let mut uri = String::from(if path.starts_with('/') { "file://" } else { "file:///" });
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => {
+                let _ = write!(uri, "%{byte:02X}");
+            }
+        }
+    }
+    uri
+}
+
+/// A diagnostic of the compiler as the editor shows it, at its span of `source`
+fn diagnostic_json(diagnostic: &Diagnostic, source: &str) -> Value {
+    let mut message = diagnostic.message.clone();
+    if let Some(hint) = &diagnostic.hint {
+        let _ = write!(message, "\nhint: {hint}");
+    }
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+
+    let mut value = json!({
+        "range": range(source, diagnostic.span),
+        "severity": severity,
+        "source": "lox",
+        "message": message,
+    });
+    if let Some(lint) = diagnostic.lint {
+        value["code"] = json!(lint.name());
+        // Editors fade out code that does nothing
+        if lint == Lint::UnusedVariable || lint == Lint::UnreachableCode {
+            value["tags"] = json!([1]);
+        }
+    }
+    value
+}
+
+fn symbol_kind(kind: Kind) -> u32 {
+    match kind {
+        Kind::Module => 2,
+        Kind::Class => 5,
+        Kind::Method => 6,
+        Kind::Function => 12,
+        _ => 13,
+    }
+}
+
+fn completion_kind(kind: Kind) -> u32 {
+    match kind {
+        Kind::Method => 2,
+        Kind::Function => 3,
+        Kind::Variable => 6,
+        Kind::Class => 7,
+        Kind::Module => 9,
+        Kind::Keyword => 14,
+    }
+}
+
+fn symbol_json(symbol: &Symbol, text: &str) -> Value {
+    let children: Vec<Value> = symbol.children.iter().map(|child| symbol_json(child, text)).collect();
+    json!({
+        "name": symbol.name,
+        "detail": symbol.signature(),
+        "kind": symbol_kind(symbol.kind),
+        "range": range(text, symbol.span),
+        "selectionRange": range(text, symbol.name_span),
+        "children": children,
+    })
+}
+
+fn completion_json(completion: Completion) -> Value {
+    let mut value = json!({ "label": completion.label, "kind": completion_kind(completion.kind) });
+    if let Some(detail) = completion.detail {
+        value["detail"] = json!(detail);
+    }
+    if let Some(doc) = completion.doc {
+        value["documentation"] = json!(doc);
+    }
+    value
+}
+
+/// A file open in the editor
+struct Document {
+    /// Text in the editor, which may not be saved
+    text: String,
+    path: Option<PathBuf>,
+    /// Analysis of the text when it was last opened or saved
+    analysis: Analysis,
+    /// Other files that the last diagnostics were published for, which are the files the document imports
+    published: Vec<String>,
+}
+
+impl Document {
+    fn dir(&self) -> Option<&Path> {
+        self.path.as_deref().and_then(Path::parent)
+    }
+
+    fn outline(&self) -> Outline {
+        Outline::new(&self.text, self.dir())
+    }
+
+    fn analyze(&mut self) {
+        self.analysis = Analysis::new(&self.text, self.dir(), &self.outline());
+    }
+
+    /// Send the diagnostics of the last analysis grouped by the file they are in,
+    /// and clear those of the files that had diagnostics before but have none now
+    fn publish(&mut self, uri: &str) {
+        let mut files: HashMap<String, Vec<Value>> = HashMap::new();
+        files.insert(uri.to_string(), Vec::new());
+        for diagnostic in &self.analysis.diagnostics {
+            let (file_uri, source) = match &diagnostic.file {
+                None => (uri.to_string(), self.text.clone()),
+                Some(file) => (path_to_uri(Path::new(file)), fs::read_to_string(file).unwrap_or_default()),
+            };
+            files.entry(file_uri).or_default().push(diagnostic_json(diagnostic, &source));
+        }
+
+        for stale in self.published.drain(..) {
+            files.entry(stale).or_default();
+        }
+        for (file_uri, diagnostics) in files {
+            if file_uri != uri && !diagnostics.is_empty() {
+                self.published.push(file_uri.clone());
+            }
+            notify(
+                "textDocument/publishDiagnostics",
+                json!({ "uri": file_uri, "diagnostics": diagnostics }),
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    /// Open documents by URI
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl Server {
+    /// Handle a request or notification of the editor, returning false once it asks the server to exit
+    fn handle(&mut self, message: &Value) -> bool {
+        let id = &message["id"];
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match message["method"].as_str().unwrap_or_default() {
+            "initialize" => respond(id, capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                respond(id, Value::Null);
+            }
+            "exit" => return false,
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                let mut document = Document {
+                    text: text.to_string(),
+                    path: uri_to_path(uri),
+                    analysis: Analysis::default(),
+                    published: Vec::new(),
+                };
+                document.analyze();
+                document.publish(uri);
+                self.documents.insert(uri.to_string(), document);
+            }
+            "textDocument/didChange" => {
+                // Documents are synced in full, so the last change is the whole text
+                let changes = params["contentChanges"].as_array();
+                let text = changes.and_then(|changes| changes.last()?["text"].as_str());
+                if let (Some(document), Some(text)) = (self.documents.get_mut(uri), text) {
+                    document.text = text.to_string();
+                }
+            }
+            "textDocument/didSave" => {
+                if let Some(document) = self.documents.get_mut(uri) {
+                    if let Some(text) = params["text"].as_str() {
+                        document.text = text.to_string();
+                    }
+                    document.analyze();
+                    document.publish(uri);
+                }
+            }
+            "textDocument/didClose" => {
+                if let Some(mut document) = self.documents.remove(uri) {
+                    document.analysis.diagnostics.clear();
+                    document.publish(uri);
+                }
+            }
+            "textDocument/definition" => respond(id, self.definition(uri, params).unwrap_or_default()),
+            "textDocument/hover" => respond(id, self.hover(uri, params).unwrap_or_default()),
+            "textDocument/completion" => respond(id, self.completion(uri, params).unwrap_or_default()),
+            "textDocument/documentSymbol" => respond(id, self.symbols(uri).unwrap_or_default()),
+            method if !id.is_null() => {
+                let error = json!({ "code": -32601, "message": format!("Unsupported request '{method}'") });
+                send(json!({ "id": id, "error": error }));
+            }
+            // Notifications the server has no use for
+            _ => (),
+        }
+        true
+    }
+
+    /// The document of a request about a position, with the offset of the position
+    fn locate(&self, uri: &str, params: &Value) -> Option<(&Document, usize)> {
+        let document = self.documents.get(uri)?;
+        let offset = offset(&document.text, &params["position"]);
+        Some((document, offset))
+    }
+
+    fn definition(&self, uri: &str, params: &Value) -> Option<Value> {
+        let (document, offset) = self.locate(uri, params)?;
+        let tokens = analysis::tokens(&document.text);
+        let (path, _) = analysis::path_at(&tokens, offset)?;
+        let definition = analysis::definition(&document.outline(), &path, offset)?;
+
+        let span = definition.symbol.map_or_else(Span::default, |symbol| symbol.name_span);
+        let location = match definition.file {
+            None => json!({ "uri": uri, "range": range(&document.text, span) }),
+            Some(file) => {
+                let text = fs::read_to_string(&file).ok()?;
+                json!({ "uri": path_to_uri(&file), "range": range(&text, span) })
+            }
+        };
+        Some(location)
+    }
+
+    fn hover(&self, uri: &str, params: &Value) -> Option<Value> {
+        let (document, offset) = self.locate(uri, params)?;
+        let tokens = analysis::tokens(&document.text);
+        let (path, span) = analysis::path_at(&tokens, offset)?;
+        let description = document.analysis.describe(&document.outline(), &path, offset)?;
+
+        let mut value = format!("```lox\n{}\n```", description.signature);
+        if let Some(doc) = description.doc {
+            // Docstrings are laid out in lines, which markdown would join
+            let _ = write!(value, "\n\n{}", doc.trim().replace('\n', "  \n"));
+        }
+        Some(json!({
+            "contents": { "kind": "markdown", "value": value },
+            "range": range(&document.text, span),
+        }))
+    }
+
+    fn completion(&self, uri: &str, params: &Value) -> Option<Value> {
+        let (document, offset) = self.locate(uri, params)?;
+        let tokens = analysis::tokens(&document.text);
+        let namespaces = analysis::member_of(&tokens, offset);
+        let completions = document.analysis.completions(&document.outline(), namespaces.as_deref(), offset);
+        let items: Vec<Value> = completions.into_iter().map(completion_json).collect();
+        Some(json!(items))
+    }
+
+    fn symbols(&self, uri: &str) -> Option<Value> {
+        let document = self.documents.get(uri)?;
+        let outline = document.outline();
+        let symbols: Vec<Value> = outline.symbols.iter().map(|symbol| symbol_json(symbol, &document.text)).collect();
+        Some(json!(symbols))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            // Documents are sent in full on each change, and the server only compiles them when they are saved
+            "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": false } },
+            "definitionProvider": true,
+            "hoverProvider": true,
+            "completionProvider": { "triggerCharacters": ["."] },
+            "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "lox-lsp", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+fn main() {
+    compiler::vm::set_builtin_loader(builtin::load_builtin_module);
+    init(print, println, clear);
+
+    let mut server = Server::default();
+    let mut input = io::stdin().lock();
+    while let Some(message) = read_message(&mut input) {
+        match message {
+            Ok(message) => {
+                if !server.handle(&message) {
+                    break;
+                }
+            }
+            // The message can't be answered by its id, so the error has none
+            Err(error) => send(json!({ "id": null, "error": { "code": -32700, "message": error } })),
+        }
+    }
+    // The exit code tells the editor whether the server was shut down before it exited
+    std::process::exit(if server.shut_down { 0 } else { 1 });
+}
@@ -5,7 +5,7 @@ use serde_json::Value as JsonValue;
 use compiler::callable_struct;
 use compiler::interner::Interner;
 use compiler::native::{Callable, Globals, set_global_error};
-use compiler::value::{Value, value_as_string};
+use compiler::value::{LoxMap, Value, value_as_string};
 
 fn json_to_lox(json: &JsonValue, interner: &mut Interner) -> Value {
     match json {
@@ -18,7 +18,7 @@ fn json_to_lox(json: &JsonValue, interner: &mut Interner) -> Value {
             Value::Array(Rc::new(RefCell::new(elements)))
         }
         JsonValue::Object(map) => {
-            let mut lox_map = rustc_hash::FxHashMap::default();
+            let mut lox_map = LoxMap::default();
             for (k, v) in map {
                 let key_val = Value::Str(interner.intern(k));
                 let val_val = json_to_lox(v, interner);
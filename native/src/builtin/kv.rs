@@ -7,7 +7,7 @@ use serde_json::{Value as JsonValue, Map as JsonMap};
 use compiler::callable_struct;
 use compiler::interner::Interner;
 use compiler::native::{Callable, Globals, set_global_error};
-use compiler::value::{Value, value_as_string};
+use compiler::value::{LoxMap, Value, value_as_string};
 
 fn load_kv_store(path_str: &str) -> JsonMap<String, JsonValue> {
     if !Path::new(path_str).exists() {
@@ -41,7 +41,7 @@ fn json_to_lox(json: &JsonValue, interner: &mut Interner) -> Value {
             Value::Array(Rc::new(RefCell::new(elements)))
         }
         JsonValue::Object(map) => {
-            let mut lox_map = rustc_hash::FxHashMap::default();
+            let mut lox_map = LoxMap::default();
             for (k, v) in map {
                 let key_val = Value::Str(interner.intern(k));
                 let val_val = json_to_lox(v, interner);
@@ -217,7 +217,7 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value], {
     };
 
     let store = load_kv_store(&file_path);
-    let mut lox_map = rustc_hash::FxHashMap::default();
+    let mut lox_map = LoxMap::default();
     for (k, v) in store.iter() {
         let k_val = Value::Str(interner.intern(k));
         let v_val = json_to_lox(v, interner);
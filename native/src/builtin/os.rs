@@ -6,7 +6,7 @@ use std::cell::RefCell;
 use compiler::callable_struct;
 use compiler::interner::Interner;
 use compiler::native::{Callable, Globals, set_global_error};
-use compiler::value::{Value, value_as_string};
+use compiler::value::{LoxMap, Value, value_as_string};
 
 callable_struct!(Getenv, "getenv", 1, "getenv(name, [default])
 Gets an environment variable. Returns default (or Nil) if not set.
@@ -54,7 +54,7 @@ Returns a Map containing all current environment variables.
 Arguments: None.
 Returns: Map of key-value environment pairs.",
 interner: &mut Interner, globals: &mut Globals, args: &[Value], {
-    let mut map = rustc_hash::FxHashMap::default();
+    let mut map = LoxMap::default();
     for (k, v) in env::vars() {
         let k_val = Value::Str(interner.intern(&k));
         let v_val = Value::Str(interner.intern(&v));
@@ -99,7 +99,7 @@ interner: &mut Interner, globals: &mut Globals, args: &[Value], {
             let stderr_str = String::from_utf8_lossy(&output.stderr).to_string();
             let exit_code = output.status.code().unwrap_or(-1) as f64;
 
-            let mut map = rustc_hash::FxHashMap::default();
+            let mut map = LoxMap::default();
             map.insert(Value::Str(interner.intern("stdout")), Value::Str(interner.intern(&stdout_str)));
             map.insert(Value::Str(interner.intern("stderr")), Value::Str(interner.intern(&stderr_str)));
             map.insert(Value::Str(interner.intern("exit_code")), Value::Number(exit_code));
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+
+use compiler::debugger::{Breakpoints, DebugFrontend, Debugger, Pause, PauseReason, Resume, Variable};
+use compiler::diagnostic::{self, Diagnostic};
+use compiler::error::InterpretError;
+use compiler::lint::LintConfig;
+use compiler::load_file;
+use compiler::vm::Vm;
+use futures::executor;
+use native::protocol::{self, read_message};
+use serde_json::{json, Value};
+
+/// The VM runs a single thread of Lox code
+const THREAD_ID: u64 = 1;
+
+static SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// Send a message to the editor, numbered like every message of the Debug Adapter Protocol
+fn send(mut message: Value) {
+    message["seq"] = json!(SEQ.fetch_add(1, Ordering::Relaxed));
+    protocol::write_message(&message);
+}
+
+fn event(name: &str, body: Value) {
+    send(json!({ "type": "event", "event": name, "body": body }));
+}
+
+fn respond(request: &Value, body: Value) {
+    send(json!({
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+        "success": true,
+        "body": body,
+    }));
+}
+
+fn fail(request: &Value, message: &str) {
+    send(json!({
+        "type": "response",
+        "request_seq": request["seq"],
+        "command": request["command"],
+        "success": false,
+        "message": message,
+    }));
+}
+
+/// Output of the program, shown in the debug console since stdout carries the protocol
+pub fn print(output: String) {
+    event("output", json!({ "category": "stdout", "output": output }));
+}
+
+pub fn println(output: String) {
+    print(output + "\n");
+}
+
+pub fn clear() {}
+
+/// Stdin carries the protocol too, so `input()` gets an empty line
+async fn read_async(prompt: String) -> String {
+    println(prompt);
+    String::new()
+}
+
+async fn sleep_async(ms: u64) {
+    std::thread::sleep(std::time::Duration::from_millis(ms));
+}
+
+/// How the editor asked to run the program
+struct Launch {
+    program: String,
+    stop_on_entry: bool,
+}
+
+/// Frontend of the debugger answering the requests of an editor
+struct Adapter {
+    /// Shared with `serve`, which keeps answering requests once the program has ended
+    requests: Rc<Receiver<Value>>,
+    launch: Option<Launch>,
+    configured: bool,
+    /// Ids of the breakpoints set in each source, since the editor sets all the breakpoints of a source at once
+    source_breakpoints: HashMap<PathBuf, Vec<usize>>,
+    /// Set by a pause request while the program runs
+    pause_requested: bool,
+}
+
+/// What handling a request means for the program
+enum Outcome {
+    Handled,
+    Resume(Resume),
+}
+
+impl Adapter {
+    fn program(&self) -> &str {
+        self.launch.as_ref().map_or("", |launch| launch.program.as_str())
+    }
+
+    /// The file of a breakpoint in `path`, which is `None` for the program itself
+    fn breakpoint_file(&self, path: &Path) -> Option<String> {
+        let program = std::fs::canonicalize(self.program()).ok();
+        (program.as_deref() != Some(path)).then(|| path.to_string_lossy().into_owned())
+    }
+
+    /// Replace the breakpoints of a source with those of a setBreakpoints request
+    fn set_breakpoints(&mut self, request: &Value, breakpoints: &mut Breakpoints) {
+        let args = &request["arguments"];
+        let Some(path) = args["source"]["path"].as_str() else {
+            return fail(request, "Breakpoints can only be set in files");
+        };
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            breakpoints.remove(id);
+        }
+
+        let file = self.breakpoint_file(&path);
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or_default() as usize;
+            let condition = breakpoint["condition"].as_str().filter(|condition| !condition.trim().is_empty());
+            match breakpoints.add(file.clone(), line, condition.map(str::to_string)) {
+                Ok(id) => {
+                    ids.push(id);
+                    results.push(json!({ "id": id, "verified": true, "line": line }));
+                }
+                Err(e) => results.push(json!({ "verified": false, "line": line, "message": e })),
+            }
+        }
+        self.source_breakpoints.insert(path, ids);
+        respond(request, json!({ "breakpoints": results }));
+    }
+
+    fn source(&self, file: Option<&str>) -> Value {
+        let path = file.unwrap_or(self.program());
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+        json!({ "name": name, "path": path })
+    }
+
+    fn stack_trace(&self, request: &Value, pause: Option<&Pause>) {
+        let frames = pause.map(|pause| pause.frames.as_slice()).unwrap_or_default();
+        let frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .map(|(idx, frame)| {
+                json!({
+                    "id": idx,
+                    "name": frame.function,
+                    "source": self.source(frame.file.as_deref()),
+                    "line": frame.line,
+                    "column": 1,
+                })
+            })
+            .collect();
+        respond(request, json!({ "stackFrames": frames, "totalFrames": frames.len() }));
+    }
+
+    /// Every frame has a scope for its locals and one for the globals, numbered from 1 as 0 means no variables
+    fn scopes(&self, request: &Value) {
+        let frame = request["arguments"]["frameId"].as_u64().unwrap_or_default();
+        let scopes = json!([
+            { "name": "Locals", "presentationHint": "locals", "variablesReference": frame * 2 + 1, "expensive": false },
+            { "name": "Globals", "variablesReference": frame * 2 + 2, "expensive": false },
+        ]);
+        respond(request, json!({ "scopes": scopes }));
+    }
+
+    fn variables(&self, request: &Value, pause: Option<&Pause>) {
+        let reference = request["arguments"]["variablesReference"].as_u64().unwrap_or_default() as usize;
+        let variables: &[Variable] = match (pause, reference.checked_sub(1)) {
+            (Some(pause), Some(scope)) if scope % 2 == 0 => {
+                pause.frames.get(scope / 2).map(|frame| frame.locals.as_slice()).unwrap_or_default()
+            }
+            (Some(pause), Some(_)) => &pause.globals,
+            _ => &[],
+        };
+        let variables: Vec<Value> = variables
+            .iter()
+            .map(|variable| json!({ "name": variable.name, "value": variable.value, "variablesReference": 0 }))
+            .collect();
+        respond(request, json!({ "variables": variables }));
+    }
+
+    /// Evaluate a variable name, as editors do when hovering over one
+    fn evaluate(&self, request: &Value, pause: Option<&Pause>) {
+        let args = &request["arguments"];
+        let name = args["expression"].as_str().unwrap_or_default().trim();
+        let frame = args["frameId"].as_u64().unwrap_or_default() as usize;
+        let Some(pause) = pause else {
+            return fail(request, "The program isn't paused");
+        };
+        // Later locals hide earlier ones of the same name, and locals hide globals
+        let locals = pause.frames.get(frame).map(|frame| frame.locals.as_slice()).unwrap_or_default();
+        let local = locals.iter().rev().find(|variable| variable.name == name);
+        match local.or_else(|| pause.globals.iter().find(|variable| variable.name == name)) {
+            Some(variable) => respond(request, json!({ "result": variable.value, "variablesReference": 0 })),
+            None => fail(request, &format!("Undefined variable '{name}'")),
+        }
+    }
+
+    /// Handle a request, with the state of the program if it is paused
+    fn handle(&mut self, request: &Value, breakpoints: &mut Breakpoints, pause: Option<&Pause>) -> Outcome {
+        let args = &request["arguments"];
+        let resume = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsEvaluateForHovers": true,
+                    "supportsTerminateRequest": true,
+                });
+                respond(request, capabilities);
+                event("initialized", json!({}));
+                return Outcome::Handled;
+            }
+            "launch" => {
+                let Some(program) = args["program"].as_str() else {
+                    fail(request, "Launch needs the path of the program");
+                    return Outcome::Handled;
+                };
+                self.launch = Some(Launch {
+                    program: program.to_string(),
+                    stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+                });
+                respond(request, json!({}));
+                return Outcome::Handled;
+            }
+            "setBreakpoints" => {
+                self.set_breakpoints(request, breakpoints);
+                return Outcome::Handled;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                respond(request, json!({}));
+                return Outcome::Handled;
+            }
+            "threads" => {
+                respond(request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }));
+                return Outcome::Handled;
+            }
+            "stackTrace" => {
+                self.stack_trace(request, pause);
+                return Outcome::Handled;
+            }
+            "scopes" => {
+                self.scopes(request);
+                return Outcome::Handled;
+            }
+            "variables" => {
+                self.variables(request, pause);
+                return Outcome::Handled;
+            }
+            "evaluate" => {
+                self.evaluate(request, pause);
+                return Outcome::Handled;
+            }
+            "pause" => {
+                self.pause_requested = pause.is_none();
+                respond(request, json!({}));
+                return Outcome::Handled;
+            }
+            "disconnect" | "terminate" => {
+                respond(request, json!({}));
+                std::process::exit(0);
+            }
+            "continue" => Resume::Continue,
+            "next" => Resume::StepOver,
+            "stepIn" => Resume::StepInto,
+            "stepOut" => Resume::StepOut,
+            command => {
+                fail(request, &format!("Unsupported request '{command}'"));
+                return Outcome::Handled;
+            }
+        };
+
+        let body = match resume {
+            Resume::Continue => json!({ "allThreadsContinued": true }),
+            _ => json!({}),
+        };
+        respond(request, body);
+        match pause {
+            Some(_) => Outcome::Resume(resume),
+            None => Outcome::Handled,
+        }
+    }
+}
+
+impl DebugFrontend for Adapter {
+    fn paused(&mut self, pause: &Pause, breakpoints: &mut Breakpoints) -> Resume {
+        let stop_on_entry = self.launch.as_ref().is_some_and(|launch| launch.stop_on_entry);
+        let (reason, description, hit) = match &pause.reason {
+            PauseReason::Entry if !stop_on_entry => return Resume::Continue,
+            PauseReason::Entry => ("entry", None, Vec::new()),
+            PauseReason::Step => ("step", None, Vec::new()),
+            PauseReason::Breakpoint(id) => ("breakpoint", None, vec![*id]),
+            PauseReason::ConditionError(id, e) => ("breakpoint", Some(format!("Breakpoint condition failed: {e}")), vec![*id]),
+            PauseReason::Requested => ("pause", None, Vec::new()),
+        };
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+            "hitBreakpointIds": hit,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        event("stopped", body);
+
+        // Without an editor to ask, let the program finish
+        while let Ok(request) = self.requests.recv() {
+            if let Outcome::Resume(resume) = self.handle(&request, breakpoints, Some(pause)) {
+                return resume;
+            }
+        }
+        Resume::Continue
+    }
+
+    fn poll(&mut self, breakpoints: &mut Breakpoints) -> bool {
+        while let Ok(request) = self.requests.try_recv() {
+            self.handle(&request, breakpoints, None);
+        }
+        std::mem::take(&mut self.pause_requested)
+    }
+}
+
+/// Serve the Debug Adapter Protocol over stdin and stdout: wait for the editor to launch a program, run it in the debugger,
+/// report when it ends, and keep answering the editor until it disconnects
+pub fn serve(lints: &LintConfig) {
+    // Requests are read on their own thread, so they can be handled while the program runs
+    let (sender, requests) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        while let Some(message) = read_message(&mut stdin) {
+            match message {
+                Ok(message) => {
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    let output = format!("Skipped a message: {error}\n");
+                    event("output", json!({ "category": "console", "output": output }));
+                }
+            }
+        }
+    });
+
+    let requests = Rc::new(requests);
+    let mut adapter = Adapter {
+        requests: Rc::clone(&requests),
+        launch: None,
+        configured: false,
+        source_breakpoints: HashMap::new(),
+        pause_requested: false,
+    };
+    let mut breakpoints = Breakpoints::default();
+    while adapter.launch.is_none() || !adapter.configured {
+        let Ok(request) = adapter.requests.recv() else {
+            return;
+        };
+        adapter.handle(&request, &mut breakpoints, None);
+    }
+
+    let program = adapter.program().to_string();
+    let mut interner = compiler::interner::Interner::with_capacity(1024);
+    let loaded = load_file(&program, &mut interner, lints);
+    let diagnostics: &[Diagnostic] = match &loaded {
+        Ok((_, warnings)) => warnings,
+        Err(InterpretError::Compile(diagnostics)) => diagnostics,
+        Err(_) => &[],
+    };
+    if !diagnostics.is_empty() {
+        let source = std::fs::read_to_string(&program).unwrap_or_default();
+        println(diagnostic::render_all(diagnostics, &source));
+    }
+
+    let result = loaded.and_then(|(functions, _)| {
+        let mut debugger = Debugger::new(Box::new(adapter));
+        debugger.breakpoints = breakpoints;
+        let run = Vm::debug(functions, &mut interner, read_async, sleep_async, debugger);
+        executor::block_on(run).map_err(InterpretError::from)
+    });
+
+    // Exit codes follow those of running the program from the command line
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(InterpretError::Compile(_)) => 65,
+        Err(InterpretError::Bytecode(msg)) => {
+            println(format!("Can't load {program}: {msg}"));
+            65
+        }
+        Err(InterpretError::Io(msg)) => {
+            println(msg);
+            74
+        }
+        Err(InterpretError::Runtime(error)) => {
+            match crate::error_source(&error, &program) {
+                Some(source) => println(error.render(&source)),
+                None => println(error.to_string()),
+            }
+            70
+        }
+    };
+    event("exited", json!({ "exitCode": exit_code }));
+    event("terminated", json!({}));
+
+    // The editor still asks for threads and such until it disconnects
+    while let Ok(request) = requests.recv() {
+        match request["command"].as_str() {
+            Some("disconnect") => return respond(&request, json!({})),
+            Some("terminate") => respond(&request, json!({})),
+            Some("threads") => respond(&request, json!({ "threads": [] })),
+            _ => fail(&request, "The program has ended"),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use compiler::bytecode;
+use compiler::debugger::{Breakpoints, DebugFrontend, Pause, PauseReason, Resume, StackFrame, Variable};
+
+const HELP: &str = "c, continue                     Run until a breakpoint\n\
+    s, step                         Run to the next line, stepping into calls\n\
+    n, next                         Run to the next line of this function, stepping over calls\n\
+    o, out                          Run until this function returns\n\
+    b, break [FILE:]LINE [if EXPR]  Pause at LINE of the program, or of an imported FILE, when EXPR is truthy\n\
+    d, delete ID                    Remove a breakpoint\n\
+    breakpoints                     List the breakpoints\n\
+    l, list                         Show the code around the current line\n\
+    locals [FRAME]                  Show the locals of a frame, counting from 0 for the current call\n\
+    globals                         Show the globals of the program\n\
+    p, print NAME                   Show a local of the current call, or a global\n\
+    bt, backtrace                   Show the active calls, most recent first\n\
+    q, quit                         Stop the program";
+
+/// Debugger frontend reading commands from the terminal
+pub struct Terminal {
+    /// Path of the program being debugged, which is the file of functions that have none
+    program: String,
+    /// Lines of the files shown so far, or `None` for files that can't be shown
+    sources: HashMap<String, Option<Vec<String>>>,
+}
+
+impl Terminal {
+    pub fn new(program: &str) -> Terminal {
+        Terminal {
+            program: program.to_string(),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Line `line` of the file of `frame`, if it is a source file that can be read
+    fn source_line(&mut self, frame: &StackFrame, line: usize) -> Option<&str> {
+        let file = frame.file.clone().unwrap_or_else(|| self.program.clone());
+        let lines = self.sources.entry(file).or_insert_with_key(|file| {
+            let source = std::fs::read_to_string(file).ok()?;
+            (!bytecode::is_bytecode(source.as_bytes())).then(|| source.lines().map(str::to_string).collect())
+        });
+        let text = lines.as_ref()?.get(line.checked_sub(1)?)?;
+        Some(text.as_str())
+    }
+
+    /// Print the lines from `first` to `last` of the file of `frame`, marking the current line
+    fn show_lines(&mut self, frame: &StackFrame, first: usize, last: usize) {
+        let width = last.to_string().len();
+        for line in first..=last {
+            let Some(text) = self.source_line(frame, line) else {
+                continue;
+            };
+            let marker = if line == frame.line { ">" } else { " " };
+            println!("{marker} {line:>width$} | {text}");
+        }
+    }
+
+    /// The file of a breakpoint given on the command line, which is `None` for the program itself
+    fn breakpoint_file(&self, file: &str) -> Option<String> {
+        let is_program = Path::new(&self.program).ends_with(file) || Path::new(file) == Path::new(&self.program);
+        (!is_program).then(|| file.to_string())
+    }
+
+    fn add_breakpoint(&mut self, args: &str, breakpoints: &mut Breakpoints) {
+        let (location, condition) = match args.split_once(" if ") {
+            Some((location, condition)) => (location.trim(), Some(condition.trim().to_string())),
+            None => (args.trim(), None),
+        };
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) => (self.breakpoint_file(file), line),
+            None => (None, location),
+        };
+        let Ok(line) = line.parse::<usize>() else {
+            println!("Expect a line number, like 'break 12' or 'break lib.lox:5'");
+            return;
+        };
+        match breakpoints.add(file.clone(), line, condition) {
+            Ok(id) => println!("Breakpoint {id} at {}:{line}", file.as_deref().unwrap_or(&self.program)),
+            Err(e) => println!("Invalid condition: {e}"),
+        }
+    }
+
+    fn list_breakpoints(&self, breakpoints: &Breakpoints) {
+        if breakpoints.iter().next().is_none() {
+            println!("No breakpoints");
+        }
+        for breakpoint in breakpoints.iter() {
+            let file = breakpoint.file.as_deref().unwrap_or(&self.program);
+            match &breakpoint.condition {
+                Some(condition) => println!("{}: {file}:{} if {condition}", breakpoint.id, breakpoint.line),
+                None => println!("{}: {file}:{}", breakpoint.id, breakpoint.line),
+            }
+        }
+    }
+
+    /// Print where the program paused and why
+    fn show_pause(&mut self, pause: &Pause) {
+        match &pause.reason {
+            PauseReason::Entry => println!("Paused before the program starts. Type 'help' for the commands"),
+            PauseReason::Step => (),
+            PauseReason::Breakpoint(id) => println!("Breakpoint {id}"),
+            PauseReason::ConditionError(id, e) => println!("Breakpoint {id}, whose condition failed: {e}"),
+            PauseReason::Requested => println!("Paused"),
+        }
+        let Some(frame) = pause.frames.first() else {
+            return;
+        };
+        let file = frame.file.as_deref().unwrap_or(&self.program);
+        println!("{file}:{} in {}", frame.line, frame.function);
+        self.show_lines(frame, frame.line, frame.line);
+    }
+}
+
+impl DebugFrontend for Terminal {
+    fn paused(&mut self, pause: &Pause, breakpoints: &mut Breakpoints) -> Resume {
+        self.show_pause(pause);
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().unwrap();
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+                // Nobody is left to type commands, so let the program finish
+                return Resume::Continue;
+            }
+
+            let input = input.trim();
+            let (command, args) = input.split_once(' ').unwrap_or((input, ""));
+            let args = args.trim();
+            match command {
+                "c" | "continue" => return Resume::Continue,
+                "s" | "step" => return Resume::StepInto,
+                "n" | "next" => return Resume::StepOver,
+                "o" | "out" => return Resume::StepOut,
+                "b" | "break" => self.add_breakpoint(args, breakpoints),
+                "d" | "delete" => match args.parse() {
+                    Ok(id) if breakpoints.remove(id) => println!("Deleted breakpoint {id}"),
+                    _ => println!("No breakpoint '{args}'"),
+                },
+                "breakpoints" => self.list_breakpoints(breakpoints),
+                "l" | "list" => {
+                    if let Some(frame) = pause.frames.first() {
+                        self.show_lines(frame, frame.line.saturating_sub(5).max(1), frame.line + 5);
+                    }
+                }
+                "locals" => match pause.frames.get(args.parse().unwrap_or(0)) {
+                    Some(frame) => show_variables(&frame.locals, "No locals"),
+                    None => println!("No frame '{args}'"),
+                },
+                "globals" => show_variables(&pause.globals, "No globals"),
+                "p" | "print" => {
+                    let locals = pause.frames.first().map(|frame| frame.locals.as_slice()).unwrap_or_default();
+                    // Later locals hide earlier ones of the same name, and locals hide globals
+                    let local = locals.iter().rev().find(|variable| variable.name == args);
+                    match local.or_else(|| pause.globals.iter().find(|variable| variable.name == args)) {
+                        Some(variable) => println!("{} = {}", variable.name, variable.value),
+                        None => println!("Undefined variable '{args}'"),
+                    }
+                }
+                "bt" | "backtrace" => {
+                    for (idx, frame) in pause.frames.iter().enumerate() {
+                        let file = frame.file.as_deref().unwrap_or(&self.program);
+                        println!("#{idx} {} at {file}:{}", frame.function, frame.line);
+                    }
+                }
+                "q" | "quit" => std::process::exit(0),
+                "h" | "help" => println!("{HELP}"),
+                "" => (),
+                other => println!("Unknown command '{other}'. Type 'help' for the commands"),
+            }
+        }
+    }
+}
+
+fn show_variables(variables: &[Variable], empty: &str) {
+    if variables.is_empty() {
+        println!("{empty}");
+    }
+    for variable in variables {
+        println!("{} = {}", variable.name, variable.value);
+    }
+}
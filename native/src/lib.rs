@@ -0,0 +1,2 @@
+pub mod builtin;
+pub mod protocol;
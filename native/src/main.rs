@@ -1,19 +1,19 @@
 use compiler::compiler::Compiler;
+use compiler::debug::{self, Operand};
+use compiler::debugger::Debugger;
+use compiler::diagnostic::{self, Diagnostic};
+use compiler::error::{InterpretError, RuntimeError};
 use compiler::fun::FunType;
+use compiler::lint::{Lint, LintConfig};
 use compiler::vm::Vm;
-use compiler::{init, run_file};
+use compiler::{bytecode, check_file, compile_file, init, load_file};
 use futures::executor;
 use futures::FutureExt;
+use native::builtin;
 use std::io::{self, Write};
 use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
 
-use std::cell::Cell;
-
-thread_local! {
-    static SUPPRESS_OUTPUT: Cell<bool> = Cell::new(false);
-}
-
 #[cfg(debug_assertions)]
 fn flush_if_debug() {
     use std::io::Write;
@@ -24,30 +24,43 @@ fn flush_if_debug() {
 fn flush_if_debug() {}
 
 fn print(output: String) {
-    if !SUPPRESS_OUTPUT.with(|s| s.get()) {
-        print!("{}", output);
-        flush_if_debug();
-    }
+    print!("{}", output);
+    flush_if_debug();
 }
 
 fn println(output: String) {
-    if !SUPPRESS_OUTPUT.with(|s| s.get()) {
-        println!("{}", output);
-        flush_if_debug();
-    }
+    println!("{}", output);
+    flush_if_debug();
 }
 
 fn clear() {
-    if !SUPPRESS_OUTPUT.with(|s| s.get()) {
-        // ESC[2J clears the screen; ESC[H moves cursor to top-left.
-        // This works on all modern terminals (Linux, macOS, Windows Terminal, etc.).
-        print!("\x1B[2J\x1B[H");
-        flush_if_debug();
-    }
+    // ESC[2J clears the screen; ESC[H moves cursor to top-left.
+    // This works on all modern terminals (Linux, macOS, Windows Terminal, etc.).
+    print!("\x1B[2J\x1B[H");
+    flush_if_debug();
 }
 
 fn help(args: &[String]) {
-    println(format!("Usage: {} <FILE> \nInterpret the program in FILE", args[0]));
+    println(format!(
+        "Usage: {0} <FILE> [--json]\nInterpret the program in FILE, which can be source or compiled bytecode.\n\
+         With --json, compile errors and warnings are printed as a line of JSON before the program runs\n\n\
+         Usage: {0} compile <FILE> [OUTPUT]\nCompile FILE to bytecode in OUTPUT, which defaults to FILE with the .{1} extension\n\n\
+         Usage: {0} disasm <FILE> [--json]\nList the bytecode of every function in FILE, as text or as JSON\n\n\
+         Usage: {0} debug <FILE>\nRun FILE in the debugger, paused before it starts, with breakpoints, stepping and variables\n\n\
+         Usage: {0} dap\nServe the Debug Adapter Protocol over stdin and stdout, for debugging from an editor\n\n\
+         Usage: {0} check <PATH>... [--json]\nCompile every file, and every .lox file in the directories, without running them.\n\
+         Imports and native modules are resolved, and every global used has to be declared\n\n\
+         Usage: {0} fmt <PATH>... [--check]\nFormat every file, and every .lox file in the directories, in the canonical style.\n\
+         With --check, list the files that aren't formatted instead of changing them, and fail if there are any\n\n\
+         Any command compiling FILE also takes --allow=LINT[,LINT...] to stop reporting those warnings,\n\
+         and --deny=LINT[,LINT...] to report them as errors. check always denies undefined-variable and undeclared-global.\n\
+         Lints: {2}\n\
+         A '// lox-ignore: LINT' comment suppresses warnings on its line, or on the next line when it is alone on its line,\n\
+         and '// lox-ignore-file: LINT' suppresses them in the whole file. Without a list of lints they suppress every lint.",
+        args[0],
+        bytecode::EXTENSION,
+        Lint::ALL.map(|lint| lint.name()).join(", ")
+    ));
 }
 
 async fn read_async(prompt: String) -> String {
@@ -116,8 +129,10 @@ impl InputChecker {
     }
 }
 
-fn run_repl() {
+fn run_repl(lints: LintConfig) {
     println!("Lox REPL. Press Ctrl+C or Ctrl+D to exit.");
+    // Each input compiles on its own, so globals declared by earlier inputs aren't known to the compiler
+    let lints = lints.allow(Lint::UndeclaredGlobal);
 
     let mut interner = compiler::interner::Interner::with_capacity(1024);
     let mut vm = Vm::new_repl(&mut interner, read_async, sleep_async);
@@ -167,28 +182,38 @@ fn run_repl() {
 
                 if is_potential_expression {
                     // Try compiling as a REPL expression first
-                    SUPPRESS_OUTPUT.with(|s| s.set(true));
-                    let spec_res = Compiler::compile(source.clone(), None, vm.interner, &mut vm.functions, FunType::ReplExpression);
-                    SUPPRESS_OUTPUT.with(|s| s.set(false));
+                    let spec_res = Compiler::compile(
+                        source.clone(),
+                        None,
+                        vm.interner,
+                        &mut vm.functions,
+                        FunType::ReplExpression,
+                        &lints,
+                    );
 
-                    if let Ok((fun, false)) = spec_res {
-                        compile_result = Some((fun, false));
+                    if let Ok((fun, diagnostics)) = spec_res {
+                        if !diagnostics.iter().any(Diagnostic::is_error) {
+                            compile_result = Some((fun, diagnostics));
+                        }
                     }
                 }
 
                 let compile_res = match compile_result {
                     Some(res) => Ok(res),
-                    None => Compiler::compile(source, None, vm.interner, &mut vm.functions, FunType::Script),
+                    None => Compiler::compile(source, None, vm.interner, &mut vm.functions, FunType::Script, &lints),
                 };
 
                 match compile_res {
-                    Ok((fun, had_error)) => {
-                        if !had_error {
+                    Ok((fun, diagnostics)) => {
+                        if !diagnostics.is_empty() {
+                            println!("{}", diagnostic::render_all(&diagnostics, code));
+                        }
+                        if !diagnostics.iter().any(Diagnostic::is_error) {
                             let result = executor::block_on(async { AssertUnwindSafe(vm.run_repl_chunk(fun)).catch_unwind().await });
                             match result {
                                 Ok(Ok(())) => {}
                                 Ok(Err(e)) => {
-                                    println!("Runtime error: {}", e);
+                                    println!("{}", e);
                                 }
                                 Err(_) => {}
                             }
@@ -206,21 +231,24 @@ fn run_repl() {
     }
 }
 
-pub mod builtin;
+mod dap;
+mod debugger;
 
 fn main() {
-    init(print, println, clear);
     compiler::vm::set_builtin_loader(builtin::load_builtin_module);
+    let (args, lints) = lint_options(std::env::args().collect());
 
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 1 {
-        run_repl();
+    // Stdout carries the protocol, so the output of the program is sent as messages
+    if args.len() == 2 && args[1] == "dap" {
+        init(dap::print, dap::println, dap::clear);
+        dap::serve(&lints);
         return;
     }
 
-    if args.len() != 2 {
-        help(&args);
-        std::process::exit(1);
+    init(print, println, clear);
+    if args.len() == 1 {
+        run_repl(lints);
+        return;
     }
 
     if args[1] == "-h" || args[1] == "--help" {
@@ -228,6 +256,424 @@ fn main() {
         std::process::exit(0);
     }
 
-    let file_path = &args[1];
-    executor::block_on(run_file(file_path, read_async, sleep_async));
+    match args[1].as_str() {
+        "compile" => {
+            let command = command_args(&args, 2, &[]);
+            if !matches!(command.operands.len(), 1 | 2) {
+                usage_error(&args);
+            }
+            compile(&command.operands[0], command.operands.get(1), &lints);
+        }
+        "disasm" => {
+            let command = command_args(&args, 2, &["--json"]);
+            if command.operands.len() != 1 {
+                usage_error(&args);
+            }
+            disasm(&command.operands[0], command.has("--json"), &lints);
+        }
+        "debug" => {
+            let command = command_args(&args, 2, &[]);
+            if command.operands.len() != 1 {
+                usage_error(&args);
+            }
+            debug(&command.operands[0], &lints);
+        }
+        "check" => {
+            let command = command_args(&args, 2, &["--json"]);
+            if command.operands.is_empty() {
+                usage_error(&args);
+            }
+            check(&command.operands, &lints, command.has("--json"));
+        }
+        "fmt" => {
+            let command = command_args(&args, 2, &["--check"]);
+            if command.operands.is_empty() {
+                usage_error(&args);
+            }
+            fmt(&command.operands, command.has("--check"));
+        }
+        _ => {
+            let command = command_args(&args, 1, &["--json"]);
+            if command.operands.len() != 1 {
+                usage_error(&args);
+            }
+            run(&command.operands[0], &lints, command.has("--json"));
+        }
+    }
+}
+
+/// The arguments of a command, split into its operands and its flags
+struct CommandArgs {
+    operands: Vec<String>,
+    flags: Vec<String>,
+}
+
+impl CommandArgs {
+    fn has(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+/// Split the arguments from `args[first]` on into operands and flags, which can come in any order.
+/// Exits with the usage if a flag isn't one of `known`.
+fn command_args(args: &[String], first: usize, known: &[&str]) -> CommandArgs {
+    let mut command = CommandArgs {
+        operands: Vec::new(),
+        flags: Vec::new(),
+    };
+    for arg in &args[first..] {
+        if !arg.starts_with("--") {
+            command.operands.push(arg.clone());
+        } else if known.contains(&arg.as_str()) {
+            command.flags.push(arg.clone());
+        } else {
+            println!("Unknown option '{}'", arg);
+            usage_error(args);
+        }
+    }
+    command
+}
+
+fn usage_error(args: &[String]) -> ! {
+    help(args);
+    std::process::exit(1);
+}
+
+/// Take the `--allow` and `--deny` options out of the arguments, returning the other arguments and how lints are reported
+fn lint_options(args: Vec<String>) -> (Vec<String>, LintConfig) {
+    let mut lints = LintConfig::default();
+    let mut rest = Vec::new();
+    for arg in args {
+        let (names, deny) = match (arg.strip_prefix("--allow="), arg.strip_prefix("--deny=")) {
+            (Some(names), _) => (names, false),
+            (_, Some(names)) => (names, true),
+            _ => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        for name in names.split(',') {
+            match Lint::from_name(name) {
+                Some(lint) if deny => lints = lints.deny(lint),
+                Some(lint) => lints = lints.allow(lint),
+                None => {
+                    let known = Lint::ALL.map(|lint| lint.name()).join(", ");
+                    println!("Unknown lint '{}'. Lints are: {}", name, known);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    (rest, lints)
+}
+
+/// Report why the program in `file_path` failed, if it wasn't reported already, and exit.
+/// Exit codes follow the book's clox: 65 for compile errors, 70 for runtime errors.
+fn exit_with(error: InterpretError, file_path: &str) -> ! {
+    match error {
+        InterpretError::Compile(_) => std::process::exit(65),
+        InterpretError::Bytecode(msg) => {
+            println!("Can't load {}: {}", file_path, msg);
+            std::process::exit(65);
+        }
+        InterpretError::Io(msg) => {
+            println!("{}", msg);
+            std::process::exit(74);
+        }
+        InterpretError::Runtime(error) => {
+            match error_source(&error, file_path) {
+                Some(source) => println!("{}", error.render(&source)),
+                None => println!("{}", error),
+            }
+            std::process::exit(70);
+        }
+    }
+}
+
+/// Run the program in `file_path` after printing its compile errors and warnings,
+/// which are printed as one line of JSON with `json`
+fn run(file_path: &str, lints: &LintConfig, json: bool) {
+    let mut interner = compiler::interner::Interner::with_capacity(1024);
+    let loaded = load_file(file_path, &mut interner, lints);
+    let diagnostics: &[Diagnostic] = match &loaded {
+        Ok((_, warnings)) => warnings,
+        Err(InterpretError::Compile(diagnostics)) => diagnostics,
+        Err(_) => &[],
+    };
+    if json {
+        let diagnostics: Vec<serde_json::Value> = diagnostics.iter().map(diagnostic_json).collect();
+        println!("{}", serde_json::json!({ "diagnostics": diagnostics }));
+    } else {
+        print_diagnostics(diagnostics, file_path);
+    }
+
+    let result = loaded.and_then(|(functions, _)| {
+        let run = Vm::interpret(functions, &mut interner, read_async, sleep_async);
+        executor::block_on(run).map_err(InterpretError::from)
+    });
+    if let Err(error) = result {
+        exit_with(error, file_path);
+    }
+}
+
+/// Run the program in `file_path` under a debugger controlled from the terminal
+fn debug(file_path: &str, lints: &LintConfig) {
+    let mut interner = compiler::interner::Interner::with_capacity(1024);
+    let loaded = load_file(file_path, &mut interner, lints);
+    match &loaded {
+        Ok((_, warnings)) => print_diagnostics(warnings, file_path),
+        Err(InterpretError::Compile(diagnostics)) => print_diagnostics(diagnostics, file_path),
+        Err(_) => (),
+    }
+
+    let result = loaded.and_then(|(functions, _)| {
+        let debugger = Debugger::new(Box::new(debugger::Terminal::new(file_path)));
+        let run = Vm::debug(functions, &mut interner, read_async, sleep_async, debugger);
+        executor::block_on(run).map_err(InterpretError::from)
+    });
+    if let Err(error) = result {
+        exit_with(error, file_path);
+    }
+}
+
+fn diagnostic_json(diagnostic: &Diagnostic) -> serde_json::Value {
+    serde_json::json!({
+        "severity": diagnostic.severity.to_string().to_lowercase(),
+        "message": diagnostic.message,
+        "line": diagnostic.line,
+        "column": diagnostic.column,
+        "span": {"start": diagnostic.span.start, "end": diagnostic.span.end},
+        "hint": diagnostic.hint,
+        "file": diagnostic.file,
+        "lint": diagnostic.lint.map(|lint| lint.name()),
+    })
+}
+
+/// Print diagnostics found in the program in `file_path`, with the code they point at
+fn print_diagnostics(diagnostics: &[Diagnostic], file_path: &str) {
+    if !diagnostics.is_empty() {
+        let source = std::fs::read_to_string(file_path).unwrap_or_default();
+        println!("{}", diagnostic::render_all(diagnostics, &source));
+    }
+}
+
+/// Source of the file the innermost frame of `error` was running, to show the code that raised it
+fn error_source(error: &RuntimeError, file_path: &str) -> Option<String> {
+    let frame = error.traceback.first()?;
+    let source = std::fs::read_to_string(frame.file.as_deref().unwrap_or(file_path)).ok()?;
+    (!bytecode::is_bytecode(source.as_bytes())).then_some(source)
+}
+
+fn compile(file_path: &str, output: Option<&String>, lints: &LintConfig) {
+    let output = match output {
+        Some(output) => std::path::PathBuf::from(output),
+        None => std::path::Path::new(file_path).with_extension(bytecode::EXTENSION),
+    };
+    let bytes = match compile_file(file_path, lints) {
+        Ok((bytes, warnings)) => {
+            print_diagnostics(&warnings, file_path);
+            bytes
+        }
+        Err(InterpretError::Compile(diagnostics)) => {
+            print_diagnostics(&diagnostics, file_path);
+            std::process::exit(65);
+        }
+        Err(InterpretError::Io(msg)) => {
+            println!("{}", msg);
+            std::process::exit(74);
+        }
+        Err(error) => {
+            println!("Can't compile {}: {}", file_path, error);
+            std::process::exit(65);
+        }
+    };
+    if let Err(error) = std::fs::write(&output, bytes) {
+        println!("Can't write {}: {}", output.display(), error);
+        std::process::exit(74);
+    }
+}
+
+/// Check the files, and the .lox files in the directories, without running them. Exits with 65 if any has errors.
+fn check(paths: &[String], lints: &LintConfig, json: bool) {
+    let mut failed = false;
+    for path in paths {
+        let mut files = Vec::new();
+        if let Err(error) = lox_files(std::path::Path::new(path), &mut files) {
+            println!("Can't read {}: {}", path, error);
+            failed = true;
+        }
+
+        for file in files {
+            let file_path = file.to_string_lossy();
+            let result = check_file(&file_path, lints);
+            let diagnostics: &[Diagnostic] = match &result {
+                Ok(warnings) => warnings,
+                Err(InterpretError::Compile(diagnostics)) => diagnostics,
+                Err(_) => &[],
+            };
+
+            if json {
+                let diagnostics: Vec<serde_json::Value> = diagnostics.iter().map(diagnostic_json).collect();
+                let file_json = serde_json::json!({ "file": file_path, "ok": result.is_ok(), "diagnostics": diagnostics });
+                println!("{}", file_json);
+            } else {
+                print_diagnostics(diagnostics, &file_path);
+                match &result {
+                    Ok(_) => println!("{}: ok", file_path),
+                    Err(InterpretError::Compile(_)) => println!("{}: failed", file_path),
+                    Err(InterpretError::Io(msg)) => println!("{}", msg),
+                    Err(error) => println!("{}: {}", file_path, error),
+                }
+            }
+            failed |= result.is_err();
+        }
+    }
+
+    if failed {
+        std::process::exit(65);
+    }
+}
+
+/// Format the files, and the .lox files in the directories, in place. With `only_check` the files are left as they are,
+/// and the ones that aren't formatted are listed. Exits with 65 if any can't be formatted, or with 1 if any isn't formatted.
+fn fmt(paths: &[String], only_check: bool) {
+    let mut failed = false;
+    let mut unformatted = false;
+    for path in paths {
+        let mut files = Vec::new();
+        if let Err(error) = lox_files(std::path::Path::new(path), &mut files) {
+            println!("Can't read {}: {}", path, error);
+            failed = true;
+        }
+
+        for file in files {
+            let file_path = file.to_string_lossy();
+            let source = match std::fs::read_to_string(&file) {
+                Ok(source) => source,
+                Err(error) => {
+                    println!("Can't read {}: {}", file_path, error);
+                    failed = true;
+                    continue;
+                }
+            };
+            let formatted = match compiler::format::format(&source) {
+                Ok(formatted) => formatted,
+                Err(diagnostics) => {
+                    println!("{}", diagnostic::render_all(&diagnostics, &source));
+                    println!("{}: failed", file_path);
+                    failed = true;
+                    continue;
+                }
+            };
+            if formatted == source {
+                continue;
+            }
+
+            if only_check {
+                println!("{}: not formatted", file_path);
+                unformatted = true;
+            } else if let Err(error) = std::fs::write(&file, formatted) {
+                println!("Can't write {}: {}", file_path, error);
+                failed = true;
+            } else {
+                println!("{}: formatted", file_path);
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(65);
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+/// Add `path` to `files` if it is a file, or the .lox files in it if it is a directory, in the order of their names
+fn lox_files(path: &std::path::Path, files: &mut Vec<std::path::PathBuf>) -> io::Result<()> {
+    if !std::fs::metadata(path)?.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<std::path::PathBuf> = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|extension| extension == "lox") {
+            lox_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+fn disasm(file_path: &str, json: bool, lints: &LintConfig) {
+    let mut interner = compiler::interner::Interner::with_capacity(1024);
+    let functions = match load_file(file_path, &mut interner, lints) {
+        Ok((functions, _)) => functions,
+        Err(InterpretError::Compile(diagnostics)) => {
+            print_diagnostics(&diagnostics, file_path);
+            std::process::exit(65);
+        }
+        Err(InterpretError::Bytecode(msg)) => {
+            println!("Can't load {}: {}", file_path, msg);
+            std::process::exit(65);
+        }
+        Err(InterpretError::Io(msg)) => {
+            println!("{}", msg);
+            std::process::exit(74);
+        }
+        Err(InterpretError::Runtime(_)) => unreachable!("Loading doesn't run the program"),
+    };
+    if !json {
+        print!("{}", debug::disassemble_program(&functions, &interner));
+        return;
+    }
+
+    let mut listing = Vec::new();
+    for (idx, fun) in functions.iter().enumerate() {
+        let chunk = &fun.chunk;
+        let constants: Vec<String> = chunk
+            .constants
+            .iter()
+            .map(|c| debug::constant_text(c, &functions, &interner))
+            .collect();
+
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        // Loaded code is verified, so every offset starts a valid instruction
+        while offset < chunk.code.len() {
+            let instruction = debug::decode_instruction(chunk, offset).expect("Invalid opcode");
+            let operands: Vec<serde_json::Value> = instruction
+                .operands
+                .iter()
+                .map(|operand| match *operand {
+                    Operand::Constant(index) => serde_json::json!({"kind": "constant", "index": index, "value": constants[index]}),
+                    Operand::Number { name, value } => serde_json::json!({"kind": "number", "name": name, "value": value}),
+                    Operand::Jump { name, target } => serde_json::json!({"kind": "jump", "name": name, "target": target}),
+                    Operand::Capture { is_local, index } => serde_json::json!({"kind": "capture", "local": is_local, "index": index}),
+                })
+                .collect();
+            instructions.push(serde_json::json!({
+                "offset": instruction.offset,
+                "line": instruction.line,
+                "opcode": instruction.opcode.to_string(),
+                "operands": operands,
+                "text": instruction.text(chunk, &functions, &interner),
+            }));
+            offset = instruction.next;
+        }
+
+        listing.push(serde_json::json!({
+            "index": idx,
+            "name": debug::function_name(fun, &interner),
+            "arity": fun.arity,
+            "min_arity": fun.min_arity,
+            "upvalue_count": fun.upvalue_count,
+            "constants": constants,
+            "code": instructions,
+        }));
+    }
+    println!("{}", serde_json::json!({ "functions": listing }));
 }
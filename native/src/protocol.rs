@@ -0,0 +1,45 @@
+//! The framing shared by the Language Server Protocol and the Debug Adapter Protocol:
+//! every message is a JSON body preceded by a `Content-Length` header.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Write a message to stdout, framed with its length
+pub fn write_message(message: &Value) {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+/// Read the next message from the editor, or `None` once it has gone away.
+/// A message without a valid `Content-Length` or JSON body is skipped, and read as an `Err` saying what was wrong with it.
+pub fn read_message(input: &mut impl BufRead) -> Option<Result<Value, String>> {
+    let mut length = None;
+    let mut header = Vec::new();
+    loop {
+        header.clear();
+        if input.read_until(b'\n', &mut header).ok()? == 0 {
+            return None;
+        }
+        let header = String::from_utf8_lossy(&header);
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            let value = value.trim();
+            length = Some(value.parse::<usize>().map_err(|_| format!("Invalid Content-Length '{value}'")));
+        }
+    }
+
+    let length = match length {
+        Some(Ok(length)) => length,
+        Some(Err(error)) => return Some(Err(error)),
+        None => return Some(Err("Message without a Content-Length header".to_string())),
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).ok()?;
+    Some(serde_json::from_slice(&body).map_err(|error| format!("Message isn't valid JSON: {error}")))
+}
@@ -0,0 +1,352 @@
+#!/usr/bin/env python3
+"""Test the commands of the native binary that don't just run a program: check, compile, disasm, fmt and debug,
+and the language server and debug adapter."""
+
+import argparse
+import json
+import os
+import queue
+import shutil
+import subprocess
+import sys
+import tempfile
+import threading
+from pathlib import Path
+
+# Force current working directory to repository root (parent of tests/)
+# so the paths below and `cargo build` work from anywhere.
+ROOT = Path(__file__).resolve().parent.parent
+os.chdir(ROOT)
+
+CLI = Path("tests") / "cli"
+failures = []
+
+
+def native(binary, *args, stdin=None):
+    proc = subprocess.run([binary, *args], input=stdin, capture_output=True, text=True)
+    return proc.returncode, proc.stdout + proc.stderr
+
+
+def expect(name, condition, output=""):
+    if condition:
+        print(f"ok   {name}")
+    else:
+        print(f"FAIL {name}")
+        if output:
+            print("     " + output.strip().replace("\n", "\n     "))
+        failures.append(name)
+
+
+def test_check(binary):
+    code, out = native(binary, "check", str(CLI / "check_ok.lox"))
+    expect("check passes a correct file", code == 0 and out.strip().endswith(": ok"), out)
+
+    code, out = native(binary, "check", str(CLI / "check_errors.lox"))
+    expect("check fails on undeclared globals", code == 65 and "Undefined variable 'missing'" in out, out)
+    expect("check fails on assignments to undeclared globals", "undeclared global 'undeclared'" in out, out)
+    expect("check points at a jump out of a finally", "[line 12, column 9] Error: Can't use 'break' to leave a 'finally'" in out, out)
+    expect("check warns about unreachable code at the top level", "[line 18, column 1] Warning (unreachable-code)" in out, out)
+
+    code, out = native(binary, "check", str(CLI / "check_recovery.lox"))
+    expect("check reports the missing expression before a '}'", "[line 4, column 1] Error: Expect expression" in out, out)
+    expect("check ends a broken block at its '}'", "Expect '}' after block" not in out, out)
+    expect("check skips what can't start a method", "[line 8, column 5] Error: Expect method name." in out, out)
+    expect("check reports errors in the methods after it", "[line 11, column 5] Error: Expect ';' after return value" in out, out)
+
+    # Shadowing a builtin is only a warning, so tests may name a variable `buf` without suppressing it
+    code, out = native(binary, "check", str(Path("tests") / "test_builtin_io.lox"))
+    expect("check passes a file that shadows a builtin", code == 0 and "Warning (shadowed-builtin)" in out, out)
+
+    # Flags may come before the paths
+    code, out = native(binary, "check", "--json", str(CLI / "check_ok.lox"), str(CLI / "check_errors.lox"))
+    lines = [json.loads(line) for line in out.splitlines()]
+    expect("check --json prints a line per file", code == 65 and [line["ok"] for line in lines] == [True, False], out)
+
+    code, out = native(binary, "check", str(CLI / "missing.lox"))
+    expect("check reports missing files", code == 65 and "Can't read" in out and "panicked" not in out, out)
+
+    code, out = native(binary, "check", "--bogus", str(CLI / "check_ok.lox"))
+    expect("check rejects unknown flags", code == 1 and "Unknown option '--bogus'" in out, out)
+
+
+def test_compile(binary, tmp):
+    source = Path("tests") / "test_closures.lox"
+    compiled = tmp / "closures.loxc"
+    code, out = native(binary, "compile", str(source), str(compiled))
+    expect("compile writes bytecode", code == 0 and compiled.exists(), out)
+
+    _, from_source = native(binary, str(source))
+    code, from_bytecode = native(binary, str(compiled))
+    expect("compiled bytecode runs like its source", code == 0 and from_source == from_bytecode, from_bytecode)
+
+    corrupt = tmp / "corrupt.loxc"
+    corrupt.write_bytes(compiled.read_bytes()[:40])
+    code, out = native(binary, str(corrupt))
+    expect("truncated bytecode is rejected", code == 65 and "Can't load" in out, out)
+
+    # Replace the code of `print 1;` with code the VM can't run safely
+    source = tmp / "print.lox"
+    source.write_text("print 1;\n")
+    native(binary, "compile", str(source), str(compiled))
+    code_bytes = bytes([0, 0, 3, 10, 46])  # Constant 0, Print, Nil, Return
+    crafted = (
+        ("pops an empty stack", bytes([10, 3, 3, 10, 46]), "pops more values"),  # Nil, Print, Print, Nil, Return
+        ("reads a local off the stack", bytes([10, 27, 5, 3, 46]), "uses local slot 5"),  # Nil, GetLocal 5, Print, Return
+        ("reads a missing upvalue", bytes([10, 31, 0, 3, 46]), "missing upvalue 0"),  # Nil, GetUpvalue 0, Print, Return
+    )
+    for name, replacement, error in crafted:
+        corrupt.write_bytes(compiled.read_bytes().replace(code_bytes, replacement))
+        code, out = native(binary, str(corrupt))
+        expect(f"bytecode that {name} is rejected", code == 65 and error in out and "panicked" not in out, out)
+
+    code, out = native(binary, "compile", str(CLI / "missing.lox"))
+    expect("compile reports missing files", code == 74 and "Can't read" in out and "panicked" not in out, out)
+
+    code, out = native(binary, str(CLI / "missing.lox"))
+    expect("run reports missing files", code == 74 and "Can't read" in out and "panicked" not in out, out)
+
+
+def test_disasm(binary, tmp):
+    source = Path("tests") / "test_closures.lox"
+    compiled = tmp / "closures.loxc"
+    native(binary, "compile", str(source), str(compiled))
+
+    _, from_source = native(binary, "disasm", str(source))
+    code, from_bytecode = native(binary, "disasm", str(compiled))
+    expect("disasm lists bytecode like its source", code == 0 and from_source == from_bytecode, from_bytecode)
+
+    code, out = native(binary, "disasm", "--json", str(source))
+    try:
+        functions = json.loads(out)["functions"]
+    except (ValueError, KeyError):
+        functions = []
+    expect("disasm --json lists every function", code == 0 and functions and functions[-1]["name"] == "<script>", out)
+
+
+def test_fmt(binary, tmp):
+    expected = (CLI / "fmt_expected.lox").read_text()
+    code, out = native(binary, "fmt", "--check", str(CLI / "fmt_expected.lox"))
+    expect("fmt --check passes a formatted file", code == 0 and out == "", out)
+
+    unformatted = tmp / "fmt_input.lox"
+    shutil.copy(CLI / "fmt_input.lox", unformatted)
+    original = unformatted.read_text()
+    for place, args in (("before", ["--check", str(unformatted)]), ("after", [str(unformatted), "--check"])):
+        code, out = native(binary, "fmt", *args)
+        unchanged = unformatted.read_text() == original
+        expect(f"fmt with --check {place} the file only lists it", code == 1 and "not formatted" in out and unchanged, out)
+
+    code, out = native(binary, "fmt", str(unformatted))
+    formatted = unformatted.read_text()
+    expect("fmt formats in the canonical style", code == 0 and formatted == expected, formatted)
+
+    code, out = native(binary, "fmt", str(unformatted))
+    expect("fmt leaves formatted files alone", code == 0 and out == "", out)
+
+
+def test_debug(binary):
+    program = str(CLI / "debug.lox")
+    code, out = native(binary, "debug", program, stdin="c\n")
+    expect("debug pauses on the first line run", code == 0 and "debug.lox:2 in <script>" in out, out)
+
+    commands = "b 9 if i >= 3 and p.x != 4\nc\np i\nc\n"
+    code, out = native(binary, "debug", program, stdin=commands)
+    expect("debug pauses where a breakpoint condition holds", code == 0 and "i = 3" in out, out)
+
+    code, out = native(binary, "debug", program, stdin="b 9 if len(p) > 0\nc\n")
+    expect("debug rejects conditions that call functions", "Invalid condition" in out, out)
+
+
+def frame(message):
+    body = json.dumps(message).encode()
+    return f"Content-Length: {len(body)}\r\n\r\n".encode() + body
+
+
+def session(binary, messages):
+    """Send `messages` to a server speaking over stdio, returning its exit code and the messages it sent back"""
+    proc = subprocess.run([binary], input=b"".join(map(frame, messages)), capture_output=True)
+    received = []
+    output = proc.stdout
+    while output:
+        header, _, rest = output.partition(b"\r\n\r\n")
+        length = int(header.split(b":")[1])
+        received.append(json.loads(rest[:length]))
+        output = rest[length:]
+    return proc.returncode, received
+
+
+def position(text, needle, offset=0):
+    """Editor position of `offset` characters into the first `needle` of `text`"""
+    before = text[: text.index(needle) + offset]
+    return {"line": before.count("\n"), "character": len(before) - before.rfind("\n") - 1}
+
+
+def test_lsp(binary):
+    path = (CLI / "lsp_main.lox").resolve()
+    uri = path.as_uri()
+    text = path.read_text()
+    broken = text.replace("print(square.area());", "print(square.area();")
+    # Typing a member of the math module, after a loop whose variable isn't a global
+    typing = text + "math."
+
+    def request(id, method, params):
+        return {"jsonrpc": "2.0", "id": id, "method": method, "params": {"textDocument": {"uri": uri}, **params}}
+
+    def notification(method, params):
+        return {"jsonrpc": "2.0", "method": method, "params": params}
+
+    messages = [
+        {"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}},
+        notification("textDocument/didOpen", {"textDocument": {"uri": uri, "text": broken}}),
+        notification("textDocument/didChange", {"textDocument": {"uri": uri}, "contentChanges": [{"text": text}]}),
+        notification("textDocument/didSave", {"textDocument": {"uri": uri}}),
+        request(2, "textDocument/hover", {"position": position(text, "square.area", 8)}),
+        request(3, "textDocument/hover", {"position": position(text, "side")}),
+        request(4, "textDocument/definition", {"position": position(text, "shapes.perimeter", 8)}),
+        notification("textDocument/didChange", {"textDocument": {"uri": uri}, "contentChanges": [{"text": typing}]}),
+        request(5, "textDocument/completion", {"position": position(typing, "\nmath.", 6)}),
+        request(6, "textDocument/documentSymbol", {}),
+        {"jsonrpc": "2.0", "id": 7, "method": "shutdown"},
+        notification("exit", None),
+    ]
+    code, received = session(binary, messages)
+    output = "\n".join(map(json.dumps, received))
+    responses = {message["id"]: message.get("result") for message in received if "id" in message}
+    published = [message["params"] for message in received if message.get("method") == "textDocument/publishDiagnostics"]
+    diagnostics = [params["diagnostics"] for params in published]
+
+    capabilities = (responses.get(1) or {}).get("capabilities", {})
+    expect("lsp answers initialize with its capabilities", capabilities.get("hoverProvider"), output)
+    errors = [diagnostic["message"] for diagnostic in diagnostics[0]] if diagnostics else []
+    expect("lsp publishes the errors of an opened file", any("Expect ')'" in error for error in errors), output)
+    expect("lsp clears the errors once the fixed file is saved", len(diagnostics) == 2 and diagnostics[1] == [], output)
+
+    hover = (responses.get(2) or {}).get("contents", {}).get("value", "")
+    expect("lsp hover shows the docstring of the receiver's method", "Area of the square." in hover, output)
+    hover = (responses.get(3) or {}).get("contents", {}).get("value", "")
+    expect("lsp hover shows the comment above a global", "Side of the squares drawn" in hover, output)
+
+    definition = responses.get(4) or {}
+    shapes = (CLI / "lsp_shapes.lox").resolve().as_uri()
+    start = definition.get("range", {}).get("start")
+    found = definition.get("uri") == shapes and start == {"line": 1, "character": 9}
+    expect("lsp finds a definition in an imported file", found, output)
+
+    labels = [item["label"] for item in responses.get(5) or []]
+    expect("lsp completes the members of a builtin module", "sqrt" in labels and "print" not in labels, output)
+
+    symbols = [(symbol["name"], symbol["kind"]) for symbol in responses.get(6) or []]
+    expect("lsp lists the globals of a file that doesn't parse", symbols == [("side", 13), ("square", 13)], output)
+    expect("lsp exits cleanly after a shutdown", code == 0, output)
+
+
+class Adapter:
+    """A debug adapter sent one request at a time, since what it answers depends on where the program is"""
+
+    def __init__(self, binary):
+        self.proc = subprocess.Popen([binary, "dap"], stdin=subprocess.PIPE, stdout=subprocess.PIPE)
+        self.received = queue.Queue()
+        self.log = []
+        self.seq = 0
+        threading.Thread(target=self.read, daemon=True).start()
+
+    def read(self):
+        while True:
+            header = b""
+            while not header.endswith(b"\r\n\r\n"):
+                byte = self.proc.stdout.read(1)
+                if not byte:
+                    return
+                header += byte
+            length = int(header.split(b":")[1])
+            self.received.put(json.loads(self.proc.stdout.read(length)))
+
+    def request(self, command, **arguments):
+        self.seq += 1
+        seq = self.seq
+        self.proc.stdin.write(frame({"seq": seq, "type": "request", "command": command, "arguments": arguments}))
+        self.proc.stdin.flush()
+        return self.wait(lambda message: message.get("request_seq") == seq)
+
+    def event(self, name):
+        return self.wait(lambda message: message.get("event") == name)
+
+    def wait(self, matches):
+        """The body of the next message that `matches`, or None if it doesn't come"""
+        while True:
+            try:
+                message = self.received.get(timeout=10)
+            except queue.Empty:
+                return None
+            self.log.append(message)
+            if matches(message):
+                return message.get("body", {})
+
+    def output(self):
+        return "\n".join(map(json.dumps, self.log))
+
+
+def test_dap(binary):
+    program = str((CLI / "debug.lox").resolve())
+    adapter = Adapter(binary)
+    capabilities = adapter.request("initialize", adapterID="lox") or {}
+    expect("dap supports conditional breakpoints", capabilities.get("supportsConditionalBreakpoints"), adapter.output())
+
+    adapter.request("launch", program=program)
+    adapter.request("setBreakpoints", source={"path": program}, breakpoints=[{"line": 9, "condition": "i == 3"}])
+    adapter.request("configurationDone")
+    stopped = adapter.event("stopped") or {}
+    expect("dap stops where a breakpoint condition holds", stopped.get("reason") == "breakpoint", adapter.output())
+    expect("dap leaves out the description of a stop without one", "description" not in stopped, adapter.output())
+
+    frames = (adapter.request("stackTrace", threadId=1) or {}).get("stackFrames", [])
+    expect("dap lists the frames", [(frame["name"], frame["line"]) for frame in frames] == [("<script>", 9)], adapter.output())
+    scopes = (adapter.request("scopes", frameId=0) or {}).get("scopes", [])
+    reference = scopes[0]["variablesReference"] if scopes else 0
+    variables = (adapter.request("variables", variablesReference=reference) or {}).get("variables", [])
+    values = {variable["name"]: variable["value"] for variable in variables}
+    expect("dap shows the locals of a frame", values.get("i") == "3", adapter.output())
+
+    adapter.request("next", threadId=1)
+    stepped = adapter.event("stopped") or {}
+    frames = (adapter.request("stackTrace", threadId=1) or {}).get("stackFrames", [])
+    expect("dap steps over a line", stepped.get("reason") == "step" and frames and frames[0]["line"] != 9, adapter.output())
+
+    adapter.request("continue", threadId=1)
+    exited = adapter.event("exited") or {}
+    terminated = adapter.event("terminated")
+    printed = "".join(message["body"]["output"] for message in adapter.log if message.get("event") == "output")
+    finished = exited.get("exitCode") == 0 and terminated is not None and "i=4, x=4" in printed
+    expect("dap runs the program to the end once continued", finished, adapter.output())
+
+    adapter.request("disconnect")
+    try:
+        code = adapter.proc.wait(timeout=10)
+    except subprocess.TimeoutExpired:
+        adapter.proc.kill()
+        code = None
+    expect("dap exits once disconnected", code == 0, adapter.output())
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("--release", action="store_true", help="Test the release build.")
+    args = parser.parse_args()
+
+    build = ["cargo", "build", "-p", "native", "-p", "lsp"] + (["--release"] if args.release else [])
+    subprocess.run(build, check=True)
+    target = ROOT / "target" / ("release" if args.release else "debug")
+    binary = str(target / "native")
+
+    with tempfile.TemporaryDirectory() as tmp:
+        test_check(binary)
+        test_compile(binary, Path(tmp))
+        test_disasm(binary, Path(tmp))
+        test_fmt(binary, Path(tmp))
+        test_debug(binary)
+        test_lsp(str(target / "lsp"))
+        test_dap(binary)
+
+    if failures:
+        sys.exit(f"\n{len(failures)} failed")
+    print("\nall passed")
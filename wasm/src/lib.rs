@@ -1,4 +1,4 @@
-use compiler::{init, run_code};
+use compiler::{diagnostic::Diagnostic, error::InterpretError, format, init, native, run_code, scanner::Scanner};
 use std::{panic, sync::atomic::AtomicBool};
 use wasm_bindgen::prelude::*;
 
@@ -25,6 +25,7 @@ extern "C" {
     pub fn end();
     pub async fn sleep(ms: u32);
     pub async fn readAsync(text: String) -> JsValue;
+    pub fn reportDiagnostic(severity: String, line: u32, column: u32, end_column: u32, message: String, hint: Option<String>);
 }
 
 async fn read_async(text: String) -> String {
@@ -42,5 +43,59 @@ pub async fn run(code: &str) {
         init(print, println, clear);
     }
 
-    run_code(code, read_async, sleep_async).await;
+    match run_code(code, read_async, sleep_async).await {
+        Err(InterpretError::Compile(diagnostics)) => {
+            for diagnostic in &diagnostics {
+                report_diagnostic(diagnostic, code);
+            }
+        }
+        Err(InterpretError::Runtime(error)) => {
+            println(error.render(code));
+            if let Some(diagnostic) = error.diagnostic(code) {
+                report_diagnostic(&diagnostic, code);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Format `code` in the canonical style, or report why it can't be formatted and return it as it is
+#[wasm_bindgen]
+pub fn format_code(code: &str) -> String {
+    match format::format(code) {
+        Ok(formatted) => formatted,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                report_diagnostic(diagnostic, code);
+            }
+            code.to_string()
+        }
+    }
+}
+
+/// Keywords of the language, for the editor to highlight and complete
+#[wasm_bindgen]
+pub fn keywords() -> Vec<String> {
+    let mut keywords: Vec<String> = Scanner::keywords().map(String::from).collect();
+    keywords.sort();
+    keywords
+}
+
+/// Names of the builtin functions every program has, for the editor to highlight and complete
+#[wasm_bindgen]
+pub fn builtin_names() -> Vec<String> {
+    native::builtins().iter().map(|builtin| builtin.name().to_string()).collect()
+}
+
+/// Send a diagnostic to the editor, which underlines the code it points at as an error or a warning
+fn report_diagnostic(diagnostic: &Diagnostic, source: &str) {
+    let end_column = diagnostic.column + diagnostic.width(source).max(1);
+    reportDiagnostic(
+        diagnostic.severity.to_string(),
+        diagnostic.line as u32,
+        diagnostic.column as u32,
+        end_column as u32,
+        diagnostic.message.clone(),
+        diagnostic.hint.clone(),
+    );
 }
@@ -44,4 +44,15 @@ export function end() {
     postMessage({
         type: "run-end"
     });
-}
\ No newline at end of file
+}
+export function reportDiagnostic(severity, line, column, endColumn, message, hint) {
+    postMessage({
+        type: "diagnostic",
+        severity: severity,
+        line: line,
+        column: column,
+        endColumn: endColumn,
+        message: message,
+        hint: hint
+    });
+}
@@ -1,24 +1,22 @@
 const myWorker = new Worker('worker.js', { type: 'module' });
 
+// Keywords and builtin functions of the language, sent by the worker once the compiler is loaded
+var resolveLanguage;
+const language = new Promise(resolve => { resolveLanguage = resolve; });
+
 ///// Monaco
 console.log("Setting up Monaco Editor");
 
 require.config({ paths: { 'vs': 'https://cdnjs.cloudflare.com/ajax/libs/monaco-editor/0.48.0/min/vs' } });
-require(["vs/editor/editor.main"], function () {
+require(["vs/editor/editor.main"], async function () {
     // Register Lox language
     monaco.languages.register({ id: 'lox' });
+    const { keywords, builtins } = await language;
 
     // Define syntax rules
     monaco.languages.setMonarchTokensProvider('lox', {
-        keywords: [
-            'and', 'class', 'else', 'false', 'function', 'for', 'if', 'nil', 'or',
-            'print', 'printf', 'return', 'super', 'this', 'true', 'var', 'while', 'import', 'as'
-        ],
-        builtins: [
-            'clock', 'sleep', 'typeof', 'str', 'buf', 'chr', 'int', 'float', 'bool', 'stringat',
-            'len', 'ceil', 'floor', 'abs', 'sort', 'indexof', 'rand', 'input', 'clear',
-            'sin', 'cos', 'sqrt', 'pow', 'pi', 'printf', 'help',
-        ],
+        keywords: keywords,
+        builtins: builtins,
         tokenizer: {
             root: [
                 [/[a-zA-Z_]\w*/, {
@@ -66,20 +64,13 @@ require(["vs/editor/editor.main"], function () {
             );
 
             const suggestions = [
-                ...[
-                    'and', 'class', 'else', 'false', 'function', 'for', 'if', 'nil', 'or',
-                    'print', 'return', 'super', 'this', 'true', 'var', 'while', 'import', 'as'
-                ].map(k => ({
+                ...keywords.map(k => ({
                     label: k,
                     kind: monaco.languages.CompletionItemKind.Keyword,
                     insertText: k,
                     range: range
                 })),
-                ...[
-                    'clock', 'sleep', 'typeof', 'str', 'buf', 'chr', 'int', 'float', 'bool', 'stringat',
-                    'len', 'ceil', 'floor', 'abs', 'sort', 'indexof', 'rand', 'input', 'clear',
-                    'sin', 'cos', 'sqrt', 'pow', 'pi', 'printf', 'help',
-                ].map(b => ({
+                ...builtins.map(b => ({
                     label: b,
                     kind: monaco.languages.CompletionItemKind.Function,
                     insertText: b + '($1)',
@@ -113,7 +104,12 @@ const resetButton = document.getElementById('resetButton');
 const statsP = document.getElementById('stats');
 var starts = 0;
 
+// Errors from the last run, shown as markers in the editor
+var diagnosticMarkers = [];
+
 const runFn = () => {
+    diagnosticMarkers = [];
+    monaco.editor.setModelMarkers(window.editor.getModel(), 'lox', diagnosticMarkers);
     runButton.disabled = true;
     resetButton.disabled = true;
     runButton.innerText = 'Running...';
@@ -128,6 +124,15 @@ const runFn = () => {
     outputTextarea.focus();
 }
 
+const formatFn = () => {
+    diagnosticMarkers = [];
+    monaco.editor.setModelMarkers(window.editor.getModel(), 'lox', diagnosticMarkers);
+    myWorker.postMessage({
+        type: 'format',
+        code: window.editor.getValue()
+    });
+}
+
 myWorker.onmessage = async function (e) {
     let message = e.data
     if (message.type == "output") {
@@ -140,6 +145,26 @@ myWorker.onmessage = async function (e) {
         runButton.disabled = false;
         resetButton.disabled = false;
         runButton.innerText = 'Run';
+    } else if (message.type == "diagnostic") {
+        diagnosticMarkers.push({
+            severity: message.severity == "Warning" ? monaco.MarkerSeverity.Warning : monaco.MarkerSeverity.Error,
+            message: message.hint ? `${message.message}\nHint: ${message.hint}` : message.message,
+            startLineNumber: message.line,
+            startColumn: message.column,
+            endLineNumber: message.line,
+            endColumn: message.endColumn,
+        });
+        monaco.editor.setModelMarkers(window.editor.getModel(), 'lox', diagnosticMarkers);
+    } else if (message.type == "formatted") {
+        const model = window.editor.getModel();
+        if (message.code !== model.getValue()) {
+            // As an edit rather than setValue, so it can be undone
+            window.editor.pushUndoStop();
+            window.editor.executeEdits('format', [{ range: model.getFullModelRange(), text: message.code }]);
+            window.editor.pushUndoStop();
+        }
+    } else if (message.type == "language") {
+        resolveLanguage({ keywords: message.keywords, builtins: message.builtins });
     } else if (message.type == "clear") {
         outputTextarea.value = '';
     } else if (message.type == "input-request") {
@@ -168,6 +193,15 @@ document.addEventListener("keydown", (event) => {
         event.preventDefault();
         runFn();
     }
+
+    const isSaveShortcut =
+        (event.ctrlKey || event.metaKey) &&
+        event.key.toLowerCase() === "s";
+
+    if (isSaveShortcut) {
+        event.preventDefault();
+        formatFn();
+    }
 });
 
 resetButton.addEventListener('click', () => {
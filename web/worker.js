@@ -1,4 +1,4 @@
-import init, { run } from './generated/wasm.js';
+import init, { run, format_code, keywords, builtin_names } from './generated/wasm.js';
 
 self.userInput = null;
 
@@ -12,6 +12,12 @@ onmessage = function (e) {
                 });
             });
         }
+        else if (message.type === "format") {
+            this.postMessage({
+                type: "formatted",
+                code: format_code(message.code)
+            });
+        }
         else if (message.type === "input-response") {
             self.userInput = message.data;
         }
@@ -33,4 +39,11 @@ onmessage = function (e) {
     }
 }
 
-await init();
\ No newline at end of file
+await init();
+
+// The editor highlights and completes the names the compiler knows
+postMessage({
+    type: "language",
+    keywords: keywords(),
+    builtins: builtin_names()
+});